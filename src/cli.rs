@@ -0,0 +1,62 @@
+//!
+//! The subcommands of the `magnetar` command line tool. Public only for `main.rs`.
+//!
+
+use crate::{comparator, compactor, dupes, exporter, importer, indexer, merger, query, verifier};
+use crate::ConvertibleResult;
+
+pub use crate::consts::PROGRAM_NAME;
+
+pub fn subcommands<'a>() -> Vec<clap::App<'a, 'a>> {
+    vec![
+        indexer::cmdline(),
+        comparator::cmdline(),
+        comparator::three_way::cmdline(),
+        dupes::cmdline(),
+        compactor::cmdline(),
+        merger::cmdline(),
+        exporter::cmdline(),
+        importer::cmdline(),
+        query::cmdline(),
+        verifier::cmdline(),
+        verifier::chain::cmdline(),
+    ]
+}
+
+pub fn run(args: &clap::ArgMatches<'_>) -> ConvertibleResult<()> {
+    if let Some(args) = args.subcommand_matches("idx") {
+        indexer::run(args)?;
+    }
+    else if let Some(args) = args.subcommand_matches("cmp") {
+        comparator::run(args)?;
+    }
+    else if let Some(args) = args.subcommand_matches("cmp3") {
+        comparator::three_way::run(args)?;
+    }
+    else if let Some(args) = args.subcommand_matches("dup") {
+        dupes::run(args)?;
+    }
+    else if let Some(args) = args.subcommand_matches("compact") {
+        compactor::run(args)?;
+    }
+    else if let Some(args) = args.subcommand_matches("merge") {
+        merger::run(args)?;
+    }
+    else if let Some(args) = args.subcommand_matches("export") {
+        exporter::run(args)?;
+    }
+    else if let Some(args) = args.subcommand_matches("import") {
+        importer::run(args)?;
+    }
+    else if let Some(args) = args.subcommand_matches("query") {
+        query::run(args)?;
+    }
+    else if let Some(args) = args.subcommand_matches("verify-index") {
+        verifier::run(args)?;
+    }
+    else if let Some(args) = args.subcommand_matches("verify-chain") {
+        verifier::chain::run(args)?;
+    }
+
+    Ok(())
+}
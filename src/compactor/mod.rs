@@ -0,0 +1,105 @@
+use crate::ConvertibleResult;
+use crate::db_models::fs_node::FsNode;
+use crate::db_models::indexing_run::{IndexingRun, RunRef};
+use crate::errorwrapper::app_error;
+
+pub fn run(args: &clap::ArgMatches<'_>) -> ConvertibleResult<()> {
+    let db_ref = args.value_of("index").expect("path to database is required");
+    let run_ref = RunRef::parse(db_ref);
+
+    match args.value_of("max-chain") {
+        Some(max_chain) => {
+            let max_chain = match max_chain.parse::<usize>() {
+                Ok(max_chain) if run_ref.run_id.is_none() => max_chain,
+                Ok(_) => return Err(app_error(format!("'{}': --max-chain applies to all runs of the database; leave out @RUN", db_ref))),
+                Err(_) => return Err(app_error(format!("'{}': --max-chain must be a number", max_chain))),
+            };
+            let mut conn = crate::schema::open(&run_ref.db_path)?;
+            for run in IndexingRun::select(&conn)? {
+                let deltas = run.chain(&conn)?.len() - 1;
+                if deltas > max_chain {
+                    log::debug!("run {}: {} deltas to reconstruct, more than {}", run.id, deltas, max_chain);
+                    rebaseline(&mut conn, &run)?;
+                }
+            }
+        },
+        None => {
+            let (mut conn, run) = run_ref.open()?;
+            rebaseline(&mut conn, &run)?;
+        },
+    }
+
+    Ok(())
+}
+
+/// Stores the run in full, so that it and the runs after it no longer depend on the runs before it.
+/// The run keeps its signature and chain link: its digest covers the nodes as reconstructed, which do not change.
+fn rebaseline(conn: &mut rusqlite::Connection, run: &IndexingRun) -> ConvertibleResult<()> {
+    if !run.delta {
+        log::info!("run {}: already stored in full", run.id);
+        return Ok(());
+    }
+
+    let transaction = conn.transaction()?;
+    let fs_nodes = FsNode::select_run(&transaction, run)?;
+    let deleted = FsNode::delete(&transaction, run.id)?;
+    for fs_node in fs_nodes.iter() {
+        fs_node.insert(&transaction)?;
+    }
+    IndexingRun { delta: false, ..run.clone() }.update(&transaction)?;
+    transaction.commit()?;
+
+    log::info!("run {}: stored in full ({} nodes, was {} delta rows)", run.id, fs_nodes.len(), deleted);
+    Ok(())
+}
+
+pub fn cmdline<'a>() -> clap::App<'a, 'a> {
+    clap::App::new("compact")
+        .about("Re-baselines runs of a catalog that are stored as deltas, by storing them in full.\n\
+                The runs keep their contents, so their signatures and chain links stay valid.")
+        .arg(clap::Arg::with_name("index")
+            .value_name("FILE[@RUN]")
+            .index(1)
+            .help("The run to store in full (default: the latest run of FILE).")
+            .required(true))
+        .arg(clap::Arg::with_name("max-chain")
+            .long("max-chain")
+            .value_name("N")
+            .help("Instead of a single run, store every run in full that needs more than N deltas\n\
+                   to be reconstructed."))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::indexer::test_runs::{self, Catalog};
+    use crate::verifier::digest;
+
+    /// Storing the runs of a delta chain in full, starting in the middle of the chain,
+    /// changes neither the contents nor the digest of any run.
+    #[test]
+    fn compact_keeps_contents_and_digests() {
+        let catalog = Catalog::new("compact");
+        for state in 0..test_runs::STATES {
+            catalog.make_state(state);
+            catalog.index("delta.db", true);
+        }
+
+        let mut conn = catalog.open("delta.db");
+        let contents = |conn: &rusqlite::Connection| IndexingRun::select(conn).unwrap().iter()
+            .map(|run| (test_runs::nodes(conn, run), digest::run_digest(conn, run).unwrap()))
+            .collect::<Vec<_>>();
+        let before = contents(&conn);
+
+        let runs = IndexingRun::select(&conn).unwrap();
+        for &index in [2, 3, 1, 0].iter() {
+            let run = IndexingRun::select_one(&conn, runs[index].id).unwrap().unwrap();
+            rebaseline(&mut conn, &run).unwrap();
+            for ((nodes, digest), (nodes_before, digest_before)) in contents(&conn).iter().zip(before.iter()) {
+                test_runs::assert_same_nodes(nodes, nodes_before);
+                assert_eq!(digest, digest_before);
+            }
+        }
+        assert!(IndexingRun::select(&conn).unwrap().iter().all(|run| !run.delta));
+    }
+}
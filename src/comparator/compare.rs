@@ -1,26 +1,43 @@
+use crate::ConvertibleResult;
 use crate::apperror::AppError;
 use crate::comparator::delta::{Delta, Attribute};
-use crate::comparator::virtual_fs_node::VirtualFsNode;
-use crate::db_models::fs_node::FsNode;
-use std::collections::{BTreeMap, BTreeSet, HashSet};
+pub use crate::comparator::id_map::IdMap;
+use crate::comparator::virtual_fs_node;
+pub use crate::comparator::virtual_fs_node::VirtualFsNode;
+use crate::db_models::fs_node::{FsNode, SortedNodes, SortedRun};
+use crate::db_models::indexing_run::IndexingRun;
+use crate::db_models::mount::Mount;
+use crate::errorwrapper::app_error;
+use std::cmp::Ordering;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
 use std::iter::FromIterator;
 
 
 pub type VFsNodeMap<'a> = BTreeMap<String, VirtualFsNode<'a>>;
 pub type DeltaMap<'a> = BTreeMap<String, Delta<'a>>;
 
+/// Mounted filesystems of an index, by merge source and device number (see [FsNode::source_id]()).
+pub type MountTable = HashMap<(i64, i64), Mount>;
+
 /// Creates a pool where the virtual nodes are sorted by path.
-pub fn make_pool(fs_nodes: &Vec<FsNode>, roots: Vec<String>) -> Result<VFsNodeMap<'_>, AppError> {
+/// Each node is linked to its filesystem in `mounts`, if present.
+pub fn make_pool<'a>(fs_nodes: &'a Vec<FsNode>, roots: Vec<String>, mounts: &'a MountTable) -> Result<VFsNodeMap<'a>, AppError> {
 
     log::debug!("make_pool: start...");
 
     let relevant: Vec<(String, &FsNode)> = filter_by_roots(fs_nodes, roots);
     log::debug!("FsNodes supplied: {}. FsNodes in given roots: {}", fs_nodes.len(), relevant.len());
 
-    let virtual_nodes: Vec<VirtualFsNode<'_>> = relevant.into_iter()
-        .map(|tuple| VirtualFsNode::from(tuple))
+    let mut virtual_nodes: Vec<VirtualFsNode<'_>> = relevant.into_iter()
+        .map(|tuple| {
+            let mut virtual_node = VirtualFsNode::from(tuple);
+            virtual_node.mount = mounts.get(&(virtual_node.fs_node.source_id, virtual_node.fs_node.device));
+            virtual_node
+        })
         .collect();
 
+    assign_link_partners(&mut virtual_nodes);
+
     // BTreeMap because the implicit ordering by the key (i.e. the virtual path) is important!
     let mut v_node_map: VFsNodeMap<'_> = BTreeMap::new();
 
@@ -46,7 +63,8 @@ pub fn make_pool(fs_nodes: &Vec<FsNode>, roots: Vec<String>) -> Result<VFsNodeMa
 
 /// for each pool, the virtual path must be unique.
 /// **pool_a** is defined as the old index, and **pool_b** is the new.
-pub fn compare<'a>(mut pool_a: VFsNodeMap<'a>, mut pool_b: VFsNodeMap<'a>, attr_types: &HashSet<Attribute>) -> Vec<Delta<'a>> {
+/// Ownership of nodes in **pool_a** is translated with `id_map` before being compared.
+pub fn compare<'a>(mut pool_a: VFsNodeMap<'a>, mut pool_b: VFsNodeMap<'a>, attr_types: &HashSet<Attribute>, id_map: &IdMap) -> Vec<Delta<'a>> {
 
     let v_paths_a_set: BTreeSet<String> = BTreeSet::from_iter(pool_a.keys().cloned());
     let v_paths_b_set: BTreeSet<String> = BTreeSet::from_iter(pool_b.keys().cloned());
@@ -54,14 +72,14 @@ pub fn compare<'a>(mut pool_a: VFsNodeMap<'a>, mut pool_b: VFsNodeMap<'a>, attr_
 
     let mut deletions: DeltaMap<'_> = BTreeMap::from_iter(
         v_paths_b_set.difference(&v_paths_a_set)
-        .map(|v_path| (v_path.clone(), Delta::new(None, Some(pool_b.remove(v_path).unwrap()), attr_types)))
+        .map(|v_path| (v_path.clone(), Delta::new(None, Some(pool_b.remove(v_path).unwrap()), attr_types, id_map)))
     );
 
     log::debug!("compare: found {} deletions", deletions.len());
 
     let mut creations: DeltaMap<'_> = BTreeMap::from_iter(
         v_paths_a_set.difference(&v_paths_b_set)
-        .map(|v_path| (v_path.clone(), Delta::new(Some(pool_a.remove(v_path).unwrap()), None, attr_types)))
+        .map(|v_path| (v_path.clone(), Delta::new(Some(pool_a.remove(v_path).unwrap()), None, attr_types, id_map)))
     );
 
     log::debug!("compare: found {} creations", creations.len());
@@ -69,7 +87,7 @@ pub fn compare<'a>(mut pool_a: VFsNodeMap<'a>, mut pool_b: VFsNodeMap<'a>, attr_
     // the intersection contains both modified and unmodified files
     let mut intersection: DeltaMap<'_> = BTreeMap::from_iter(
         v_paths_a_set.intersection(&v_paths_b_set)
-        .map(|v_path| (v_path.clone(), Delta::new(Some(pool_a.remove(v_path).unwrap()), Some(pool_b.remove(v_path).unwrap()), attr_types)))
+        .map(|v_path| (v_path.clone(), Delta::new(Some(pool_a.remove(v_path).unwrap()), Some(pool_b.remove(v_path).unwrap()), attr_types, id_map)))
     );
 
     log::debug!("compare: found {} intersections", intersection.len());
@@ -101,6 +119,217 @@ pub fn compare<'a>(mut pool_a: VFsNodeMap<'a>, mut pool_b: VFsNodeMap<'a>, attr_
     result
 }
 
+/// Where [compare_streaming]() reads a pool from: the nodes of `run` under `roots`, like [make_pool]() takes them.
+#[derive(Debug)]
+pub struct PoolSource<'a> {
+    pub conn: &'a rusqlite::Connection,
+    pub run: &'a IndexingRun,
+    pub roots: Vec<String>,
+    pub mounts: &'a MountTable,
+}
+
+/// Like [compare](), but reads both pools from their databases ordered by virtual path and merge-joins them,
+/// passing each delta to `emit` as soon as it is found. Neither the pools nor the deltas are held in memory,
+/// except for the virtual paths of hard-linked nodes when [Attribute::HardLinks]() is compared.
+/// The deltas are the same, and in the same order, as those of `compare` on pools made with [make_pool]().
+pub fn compare_streaming<F>(
+    source_a: &PoolSource<'_>,
+    source_b: &PoolSource<'_>,
+    attr_types: &HashSet<Attribute>,
+    id_map: &IdMap,
+    mut emit: F
+) -> ConvertibleResult<()>
+    where F: FnMut(Delta<'_>) -> ConvertibleResult<()>
+{
+    let link_groups_a = link_groups(source_a, attr_types)?;
+    let link_groups_b = link_groups(source_b, attr_types)?;
+
+    let mut runs_a = prepare_roots(source_a)?;
+    let mut runs_b = prepare_roots(source_b)?;
+    let mut pool_a = PoolStream::new(&mut runs_a)?;
+    let mut pool_b = PoolStream::new(&mut runs_b)?;
+
+    let (mut deletions, mut creations, mut intersections) = (0, 0, 0);
+    loop {
+        let order = match (pool_a.peek(), pool_b.peek()) {
+            (None, None) => break,
+            (Some(_), None) => Ordering::Less,
+            (None, Some(_)) => Ordering::Greater,
+            (Some(v_path_a), Some(v_path_b)) => v_path_a.cmp(v_path_b),
+        };
+        let pooled_a = if order != Ordering::Greater { pool_a.next()? } else { None };
+        let pooled_b = if order != Ordering::Less { pool_b.next()? } else { None };
+        match order {
+            Ordering::Less => creations += 1,
+            Ordering::Greater => deletions += 1,
+            Ordering::Equal => intersections += 1,
+        }
+
+        let delta = Delta::new(
+            pooled_a.as_ref().map(|pooled| pooled.virtual_node(source_a.mounts, &link_groups_a)),
+            pooled_b.as_ref().map(|pooled| pooled.virtual_node(source_b.mounts, &link_groups_b)),
+            attr_types,
+            id_map
+        );
+        log::trace!("{:?}", delta);
+        emit(delta)?;
+    }
+
+    log::debug!("compare: found {} deletions, {} creations and {} intersections", deletions, creations, intersections);
+    Ok(())
+}
+
+/// A node of a pool being streamed.
+#[derive(Debug)]
+struct Pooled {
+    root: String,
+    virtual_path: String,
+    fs_node: FsNode,
+}
+
+impl Pooled {
+
+    fn virtual_node<'a>(&'a self, mounts: &'a MountTable, link_groups: &HashMap<i64, BTreeSet<String>>) -> VirtualFsNode<'a> {
+        let link_partners = match link_groups.get(&self.fs_node.link_group) {
+            Some(v_paths) if self.fs_node.link_group != 0 => v_paths.iter()
+                .filter(|v_path| **v_path != self.virtual_path)
+                .cloned()
+                .collect(),
+            _ => Vec::new(),
+        };
+        VirtualFsNode {
+            root: self.root.clone(),
+            virtual_path: self.virtual_path.clone(),
+            fs_node: &self.fs_node,
+            mount: mounts.get(&(self.fs_node.source_id, self.fs_node.device)),
+            link_partners,
+        }
+    }
+}
+
+/// The nodes of a pool in the order of their virtual paths, merged from the nodes under each root.
+#[derive(Debug)]
+struct PoolStream<'s> {
+    roots: BTreeSet<String>,
+
+    /// For each root, its nodes and the next of them that is in the pool.
+    cursors: Vec<(&'s str, SortedNodes<'s>, Option<Pooled>)>,
+
+    last_path: Option<String>,
+}
+
+impl<'s> PoolStream<'s> {
+
+    fn new(runs: &'s mut Vec<(String, SortedRun<'_>)>) -> ConvertibleResult<PoolStream<'s>> {
+        let roots = runs.iter().map(|(root, _)| root.clone()).collect();
+        let mut cursors = Vec::new();
+        for (root, sorted_run) in runs.iter_mut() {
+            cursors.push((root.as_str(), sorted_run.nodes()?, None));
+        }
+
+        let mut pool = PoolStream { roots, cursors, last_path: None };
+        for i in 0..pool.cursors.len() {
+            pool.advance(i)?;
+        }
+        Ok(pool)
+    }
+
+    /// The virtual path of the next node.
+    fn peek(&self) -> Option<&str> {
+        self.cursors.iter()
+            .filter_map(|(_, _, head)| head.as_ref().map(|pooled| pooled.virtual_path.as_str()))
+            .min()
+    }
+
+    fn next(&mut self) -> ConvertibleResult<Option<Pooled>> {
+        let v_path = match self.peek() {
+            Some(v_path) => v_path.to_string(),
+            None => return Ok(None),
+        };
+        let mut at_path = self.cursors.iter()
+            .enumerate()
+            .filter(|(_, (_, _, head))| head.as_ref().is_some_and(|pooled| pooled.virtual_path == v_path))
+            .map(|(i, _)| i);
+        let i = at_path.next().expect("peeked path has a cursor");
+        if at_path.next().is_some() {
+            return Err(app_error(format!("duplicate virtual path for the given roots: '{}'", v_path)));
+        }
+        if self.last_path.as_ref().is_some_and(|last_path| *last_path >= v_path) {
+            return Err(app_error(format!(
+                "'{}' is not read in the order of virtual paths; compare without --streaming", v_path
+            )));
+        }
+
+        let pooled = self.cursors[i].2.take();
+        self.advance(i)?;
+        self.last_path = Some(v_path);
+        Ok(pooled)
+    }
+
+    /// Reads the next node of the root that is in the pool. Nodes that are under another root as well
+    /// belong to the root [find_root]() picks, as in [make_pool]().
+    fn advance(&mut self, i: usize) -> ConvertibleResult<()> {
+        let (root, nodes, head) = &mut self.cursors[i];
+        *head = None;
+        for fs_node in nodes {
+            let fs_node = fs_node?;
+            if find_root(&fs_node, &self.roots).is_some_and(|(found, _)| found == *root) {
+                *head = Some(Pooled {
+                    root: root.to_string(),
+                    virtual_path: virtual_fs_node::virtual_path(root, &fs_node),
+                    fs_node,
+                });
+                break;
+            }
+        }
+        Ok(())
+    }
+}
+
+fn prepare_roots<'c>(source: &PoolSource<'c>) -> ConvertibleResult<Vec<(String, SortedRun<'c>)>> {
+    let roots: BTreeSet<&String> = source.roots.iter().collect();
+    let mut runs = Vec::new();
+    for root in roots {
+        runs.push((root.clone(), SortedRun::prepare(source.conn, source.run, root)?));
+    }
+    Ok(runs)
+}
+
+/// The virtual paths of the hard-linked nodes of the pool, by link group; empty unless hard links are compared.
+fn link_groups(source: &PoolSource<'_>, attr_types: &HashSet<Attribute>) -> ConvertibleResult<HashMap<i64, BTreeSet<String>>> {
+    let mut link_groups: HashMap<i64, BTreeSet<String>> = HashMap::new();
+    if !attr_types.contains(&Attribute::HardLinks) {
+        return Ok(link_groups);
+    }
+
+    log::debug!("run {}: collecting hard links...", source.run.id);
+    let mut runs = prepare_roots(source)?;
+    let mut pool = PoolStream::new(&mut runs)?;
+    while let Some(pooled) = pool.next()? {
+        if pooled.fs_node.link_group != 0 {
+            link_groups.entry(pooled.fs_node.link_group).or_default().insert(pooled.virtual_path);
+        }
+    }
+    Ok(link_groups)
+}
+
+/// Lets each hard-linked node know what other nodes in the pool link to the same inode.
+fn assign_link_partners(virtual_nodes: &mut Vec<VirtualFsNode<'_>>) {
+    let mut link_groups: HashMap<i64, BTreeSet<String>> = HashMap::new();
+    for virtual_node in virtual_nodes.iter().filter(|v_node| v_node.fs_node.link_group != 0) {
+        link_groups.entry(virtual_node.fs_node.link_group)
+            .or_default()
+            .insert(virtual_node.virtual_path.clone());
+    }
+
+    for virtual_node in virtual_nodes.iter_mut().filter(|v_node| v_node.fs_node.link_group != 0) {
+        virtual_node.link_partners = link_groups[&virtual_node.fs_node.link_group].iter()
+            .filter(|v_path| **v_path != virtual_node.virtual_path)
+            .cloned()
+            .collect();
+    }
+}
+
 fn filter_by_roots(fs_nodes: &Vec<FsNode>, roots: Vec<String>) -> Vec<(String, &FsNode)> {
     let roots = BTreeSet::from_iter(roots.iter().cloned());
     let children_in_root: Vec<(String, &FsNode)> = fs_nodes.into_iter()
@@ -120,3 +349,61 @@ fn find_root<'a, 'b>(fs_node: &'a FsNode, parents: &'b BTreeSet<String>) -> Opti
     }
     return None;
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::indexer::test_runs::{self, Catalog};
+
+    /// The deltas as the report would show them: by root and virtual path, with what changed.
+    fn summary(deltas: &[Delta<'_>]) -> Vec<String> {
+        deltas.iter()
+            .map(|delta| format!("{} {} {:?}", delta.root_path_str(), delta.virtual_path_str(), delta.delta_type()))
+            .collect()
+    }
+
+    fn mounts(conn: &rusqlite::Connection, run: &IndexingRun) -> MountTable {
+        Mount::select(conn, run.id).unwrap().into_iter()
+            .map(|mount| ((mount.source_id, mount.device), mount))
+            .collect()
+    }
+
+    /// Both ways of comparing find the same deltas, in the same order, between any two runs of a delta chain,
+    /// whether the whole tree or several roots within it are compared.
+    #[test]
+    fn streaming_same_as_in_memory() {
+        let catalog = Catalog::new("compare-streaming");
+        for state in 0..test_runs::STATES {
+            catalog.make_state(state);
+            catalog.index("delta.db", true);
+        }
+        let tree = catalog.tree().to_string_lossy().to_string();
+        let all_roots = vec![vec![tree.clone()], vec![format!("{}/sub", tree), format!("{}/other", tree)]];
+
+        let conn = catalog.open("delta.db");
+        let runs = IndexingRun::select(&conn).unwrap();
+        let attrs = Attribute::all();
+        let id_map = IdMap::new();
+        for run_a in runs.iter() {
+            for run_b in runs.iter() {
+                let (nodes_a, nodes_b) = (FsNode::select_run(&conn, run_a).unwrap(), FsNode::select_run(&conn, run_b).unwrap());
+                let (mounts_a, mounts_b) = (mounts(&conn, run_a), mounts(&conn, run_b));
+                for roots in all_roots.iter() {
+                    let pool_a = make_pool(&nodes_a, roots.clone(), &mounts_a).unwrap();
+                    let pool_b = make_pool(&nodes_b, roots.clone(), &mounts_b).unwrap();
+                    let expected = summary(&compare(pool_a, pool_b, &attrs, &id_map));
+
+                    let source_a = PoolSource { conn: &conn, run: run_a, roots: roots.clone(), mounts: &mounts_a };
+                    let source_b = PoolSource { conn: &conn, run: run_b, roots: roots.clone(), mounts: &mounts_b };
+                    let mut streamed = Vec::new();
+                    compare_streaming(&source_a, &source_b, &attrs, &id_map, |delta| {
+                        streamed.extend(summary(&[delta]));
+                        Ok(())
+                    }).unwrap();
+
+                    assert_eq!(streamed, expected, "run {} against run {}, roots {:?}", run_a.id, run_b.id, roots);
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,58 @@
+use crate::ConvertibleResult;
+use crate::comparator::DeltaWriter;
+use crate::comparator::delta::Delta;
+use crate::comparator::virtual_fs_node::VirtualFsNode;
+use crate::util::quote;
+use std::io::Write;
+
+const HEADER: [&str; 9] = ["type", "root", "path", "from", "node_type_a", "node_type_b", "attribute", "old", "new"];
+
+/// CSV as in RFC 4180, with a header row and one row per changed attribute,
+/// or a single row with empty attribute fields for deltas without changed attributes.
+pub struct CsvWriter<W: Write> {
+    output: W,
+    rows: usize,
+}
+
+impl<W: Write> CsvWriter<W> {
+
+    pub fn new(mut output: W) -> ConvertibleResult<CsvWriter<W>> {
+        write!(output, "{}\r\n", HEADER.join(","))?;
+        Ok(CsvWriter { output, rows: 0 })
+    }
+
+    fn write_row(&mut self, delta: &Delta<'_>, change: [String; 3]) -> ConvertibleResult<()> {
+        let node_type = |vnode: Option<&VirtualFsNode<'_>>| vnode.map_or("", |vnode| vnode.fs_node.node_type.name()).to_string();
+        let fields = [
+            quote::csv(delta.delta_type().name()),
+            quote::csv(delta.root_path_str()),
+            quote::csv(delta.virtual_path_str()),
+            quote::csv(delta.moved_from().unwrap_or_default()),
+            node_type(delta.a()),
+            node_type(delta.b()),
+        ];
+        let change: Vec<String> = change.iter().map(|field| quote::csv(field)).collect();
+        write!(self.output, "{},{}\r\n", fields.join(","), change.join(","))?;
+        self.rows += 1;
+        Ok(())
+    }
+}
+
+impl<W: Write> DeltaWriter for CsvWriter<W> {
+
+    fn write(&mut self, delta: &Delta<'_>) -> ConvertibleResult<()> {
+        if delta.changes().is_empty() {
+            return self.write_row(delta, Default::default());
+        }
+        for change in delta.changes() {
+            self.write_row(delta, [change.name().to_string(), change.old.to_text(), change.new.to_text()])?;
+        }
+        Ok(())
+    }
+
+    fn finish(mut self: Box<Self>) -> ConvertibleResult<()> {
+        self.output.flush()?;
+        log::debug!("wrote {} rows", self.rows);
+        Ok(())
+    }
+}
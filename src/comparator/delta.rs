@@ -1,8 +1,10 @@
 use crate::comparator::virtual_fs_node::VirtualFsNode;
-use crate::db_models::fs_node::NodeType;
-use chrono::TimeZone;
+use crate::db_models::fs_node::{self, FsNode, NodeType};
 use std::collections::HashSet;
 use crate::apperror::AppError;
+use crate::comparator::id_map::IdMap;
+use crate::exporter::iso_timestamp;
+use crate::util::quote;
 use crate::util::unix_perms::Permission;
 
 #[derive(Debug)]
@@ -11,14 +13,91 @@ pub struct Delta<'a> {
     delta_trigger_attrs: HashSet<Attribute>,
     a: Option<VirtualFsNode<'a>>,
     b: Option<VirtualFsNode<'a>>,
+
+    /// For a creation or deletion, the virtual paths on the other side it may have been moved from or to,
+    /// if rename detection found more than one.
+    move_candidates: Vec<String>,
 }
 
 #[derive(Eq, PartialEq, Debug)]
 pub enum DeltaType {
     Creation,
     Deletion,
-    Modification(Vec<String>),
-    NoChange
+    Modification(Vec<AttributeChange>),
+    /// Exists on both sides, but changed while being hashed on at least one side,
+    /// so the checksums can't be compared. Holds the other modified attributes, if any.
+    Unstable(Vec<AttributeChange>),
+    NoChange,
+    /// The node at `from` in the 'b' index is the node at `to` in the 'a' index, see `cmp --detect-renames`.
+    /// Holds the attributes that changed besides the path, if any. A moved directory stands for all nodes below it.
+    Move { from: String, to: String, changes: Vec<AttributeChange> },
+}
+
+/// A change of an attribute, from its value in the 'b' index (`old`) to its value in the 'a' index (`new`),
+/// since the report shows what would have to change to make 'b' like 'a'.
+#[derive(Eq, PartialEq, Debug, Clone)]
+pub struct AttributeChange {
+    pub attr: Attribute,
+    pub old: Value,
+    pub new: Value,
+}
+
+impl AttributeChange {
+
+    /// The name of the changed attribute in machine-readable output;
+    /// checksum changes are named after the digest compared, e.g. `sha1`.
+    pub fn name(&self) -> &'static str {
+        match &self.new {
+            Value::Digest(digest, _) => digest,
+            _ => self.attr.name(),
+        }
+    }
+}
+
+/// The value of an attribute of a node, as compared.
+#[derive(Eq, PartialEq, Debug, Clone)]
+pub enum Value {
+    /// Size, inode or hard link count.
+    Number(i64),
+
+    /// A uid or gid.
+    Id(u32),
+
+    NodeType(NodeType),
+
+    /// Permission bits, including the file type bits.
+    Permissions(u32),
+
+    /// Unix time.
+    Time(i64),
+
+    /// A symlink target.
+    Text(String),
+
+    /// A hex digest, with the name of the digest (`sha1`, `sha256` or `md5`).
+    Digest(&'static str, String),
+
+    /// The virtual paths of the other nodes hard linked with the node, sorted.
+    Paths(Vec<String>),
+}
+
+impl Value {
+
+    /// The value in machine-readable output: times in ISO 8601 (UTC), permissions as in `ls -l`,
+    /// and hard linked paths one per line, with backslashes and control characters escaped
+    /// (see [quote::visible]()) so that a path can't span lines.
+    pub fn to_text(&self) -> String {
+        match self {
+            Value::Number(number) => number.to_string(),
+            Value::Id(id) => id.to_string(),
+            Value::NodeType(node_type) => node_type.name().to_string(),
+            Value::Permissions(permissions) => Permission::from_val(*permissions),
+            Value::Time(time) => iso_timestamp(*time).unwrap_or_else(|| time.to_string()),
+            Value::Text(text) => text.clone(),
+            Value::Digest(_, digest) => digest.clone(),
+            Value::Paths(paths) => paths.iter().map(|path| quote::visible(path)).collect::<Vec<_>>().join("\n"),
+        }
+    }
 }
 
 impl DeltaType {
@@ -27,7 +106,21 @@ impl DeltaType {
             DeltaType::Creation => "creation",
             DeltaType::Deletion => "deletion",
             DeltaType::Modification(_) => "modification",
+            DeltaType::Unstable(_) => "unstable",
             DeltaType::NoChange => "no-change",
+            DeltaType::Move { .. } => "move",
+        }
+    }
+
+    /// The name of the delta type in machine-readable output, see `cmp --format`.
+    pub fn name(&self) -> &'static str {
+        match &self {
+            DeltaType::Creation => "creation",
+            DeltaType::Deletion => "deletion",
+            DeltaType::Modification(_) => "modification",
+            DeltaType::Unstable(_) => "unstable",
+            DeltaType::NoChange => "unchanged",
+            DeltaType::Move { .. } => "move",
         }
     }
 
@@ -59,7 +152,8 @@ pub enum Attribute {
     ModifiedDate,
     LinksTo,
     Inode,
-    NLinks
+    NLinks,
+    HardLinks
 }
 
 impl Attribute {
@@ -76,6 +170,7 @@ impl Attribute {
         set.insert(Attribute::LinksTo);
         set.insert(Attribute::Inode);
         set.insert(Attribute::NLinks);
+        set.insert(Attribute::HardLinks);
         set
     }
 
@@ -87,6 +182,7 @@ impl Attribute {
         set.insert(Attribute::Group);
         set.insert(Attribute::Permissions);
         set.insert(Attribute::ModifiedDate);
+        set.insert(Attribute::HardLinks);
         set
     }
 
@@ -98,6 +194,24 @@ impl Attribute {
         set
     }
 
+    /// The name of the attribute in machine-readable output.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Attribute::NodeType => "type",
+            Attribute::Checksum => "checksum",
+            Attribute::Size => "size",
+            Attribute::User => "uid",
+            Attribute::Group => "gid",
+            Attribute::Permissions => "permissions",
+            Attribute::CreationDate => "created",
+            Attribute::ModifiedDate => "modified",
+            Attribute::LinksTo => "links_to",
+            Attribute::Inode => "inode",
+            Attribute::NLinks => "nlinks",
+            Attribute::HardLinks => "hard_links",
+        }
+    }
+
     pub fn from_arg(arg: &str) -> Result<HashSet<Attribute>, AppError> {
         let mut set = HashSet::new();
         for c in arg.chars() {
@@ -107,6 +221,25 @@ impl Attribute {
         Ok(set)
     }
 
+    /// The `fs_node::ATTR_*` bits the attribute is compared by. The checksum is compared
+    /// by whichever digest both sides have, see [common_digest]().
+    fn node_attrs(&self) -> u32 {
+        match self {
+            Attribute::NodeType => fs_node::ATTR_NODE_TYPE,
+            Attribute::Checksum => 0,
+            Attribute::Size => fs_node::ATTR_SIZE,
+            Attribute::User => fs_node::ATTR_UID,
+            Attribute::Group => fs_node::ATTR_GID,
+            Attribute::Permissions => fs_node::ATTR_PERMISSIONS,
+            Attribute::CreationDate => fs_node::ATTR_CREATION_DATE,
+            Attribute::ModifiedDate => fs_node::ATTR_MODIFIED_DATE,
+            Attribute::LinksTo => fs_node::ATTR_LINKS_TO,
+            Attribute::Inode => fs_node::ATTR_INODE,
+            Attribute::NLinks => fs_node::ATTR_NLINKS,
+            Attribute::HardLinks => fs_node::ATTR_LINK_GROUP,
+        }
+    }
+
     fn from_char(c: char) -> Result<Attribute, AppError> {
         match c {
             't' => Ok(Attribute::NodeType),
@@ -120,6 +253,7 @@ impl Attribute {
             'l' => Ok(Attribute::LinksTo),
             'i' => Ok(Attribute::Inode),
             'n' => Ok(Attribute::NLinks),
+            'h' => Ok(Attribute::HardLinks),
             _ => Err(AppError::WithMessage("'{}' is not a valid attribute change option.".to_string()))
         }
     }
@@ -129,18 +263,20 @@ impl<'a> Delta<'a> {
 
     /// ### params
     /// `delta_trigger_attrs`: what field changes shall count as a `DeltaType::Modification`
-    pub fn new(a: Option<VirtualFsNode<'a>>, b: Option<VirtualFsNode<'a>>, delta_trigger_attrs: &HashSet<Attribute>) -> Delta<'a> {
+    /// `id_map`: translates uids/gids of `a` into their `b` counterparts before comparing ownership
+    pub fn new(a: Option<VirtualFsNode<'a>>, b: Option<VirtualFsNode<'a>>, delta_trigger_attrs: &HashSet<Attribute>, id_map: &IdMap) -> Delta<'a> {
         let mut comparison = Delta {
             delta_type: DeltaType::NoChange,
             delta_trigger_attrs: delta_trigger_attrs.clone(),
             a,
             b,
+            move_candidates: Vec::new(),
         };
-        comparison.delta_type = comparison.calculate_delta_type();
+        comparison.delta_type = comparison.calculate_delta_type(id_map);
         return comparison;
     }
 
-    fn calculate_delta_type(&self) -> DeltaType {
+    fn calculate_delta_type(&self, id_map: &IdMap) -> DeltaType {
         if self.a.is_some() && self.b.is_none() {
             return DeltaType::Creation;
         }
@@ -148,9 +284,11 @@ impl<'a> Delta<'a> {
             return DeltaType::Deletion;
         }
         else if let (Some(_), Some(_)) = (&self.a, &self.b) {
-            let modified_attrs: Vec<String> = self.modifications();
+            let modified_attrs: Vec<AttributeChange> = self.modifications(id_map);
             return
-                if modified_attrs.is_empty() {
+                if self.is_unstable() {
+                    DeltaType::Unstable(modified_attrs)
+                } else if modified_attrs.is_empty() {
                     DeltaType::NoChange
                 } else {
                     DeltaType::Modification(modified_attrs)
@@ -159,10 +297,90 @@ impl<'a> Delta<'a> {
         unreachable!("comparison: delta_type exhausted");
     }
 
+    /// Pairs a creation with a deletion of the same node into a [DeltaType::Move]().
+    /// Which other nodes a node is hard linked with is not compared, since their paths may have moved as well.
+    pub fn moved(creation: Delta<'a>, deletion: Delta<'a>, id_map: &IdMap) -> Delta<'a> {
+        let from = deletion.b_path().to_string();
+        let mut delta = Delta {
+            delta_type: DeltaType::NoChange,
+            delta_trigger_attrs: creation.delta_trigger_attrs,
+            a: creation.a,
+            b: deletion.b,
+            move_candidates: Vec::new(),
+        };
+        delta.delta_trigger_attrs.remove(&Attribute::HardLinks);
+        delta.delta_type = DeltaType::Move {
+            from,
+            to: delta.virtual_path_str().to_string(),
+            changes: delta.modifications(id_map),
+        };
+        delta
+    }
+
+    fn b_path(&self) -> &str {
+        self.b.as_ref().expect("deletion has a 'b' node").virtual_path.as_str()
+    }
+
+    /// The virtual path in the 'b' index a moved node was moved from.
+    pub fn moved_from(&self) -> Option<&str> {
+        match &self.delta_type {
+            DeltaType::Move { from, .. } => Some(from),
+            _ => None,
+        }
+    }
+
+    /// See [Delta::move_candidates]().
+    pub fn set_move_candidates(&mut self, move_candidates: Vec<String>) {
+        self.move_candidates = move_candidates;
+    }
+
+    /// For a creation or deletion that may have been moved from or to more than one path on the other side,
+    /// those paths; rename detection leaves such nodes unpaired.
+    pub fn move_candidates(&self) -> &[String] {
+        &self.move_candidates
+    }
+
+    /// True if any side of this delta changed while being hashed.
+    pub fn is_unstable(&self) -> bool {
+        self.a.iter().chain(self.b.iter()).any(|vnode| vnode.fs_node.unstable)
+    }
+
+    /// True if the contents of both files were to be compared, but the indexes recorded no digest in common,
+    /// e.g. an index with sha1 checksums and a manifest with sha256 checksums only.
+    pub fn lacks_common_digest(&self) -> bool {
+        match (&self.a, &self.b) {
+            (Some(vnode_a), Some(vnode_b)) => {
+                let (aaa, bbb) = (vnode_a.fs_node, vnode_b.fs_node);
+                aaa.node_type == NodeType::File && bbb.node_type == NodeType::File
+                    && self.compares(&Attribute::Checksum, aaa, bbb)
+                    && common_digest(aaa, bbb).is_none()
+            },
+            _ => false,
+        }
+    }
+
     pub fn delta_type(&self) -> &DeltaType {
         &self.delta_type
     }
 
+    /// The node in the 'a' index, unless the delta is a deletion.
+    pub fn a(&self) -> Option<&VirtualFsNode<'a>> {
+        self.a.as_ref()
+    }
+
+    /// The node in the 'b' index, unless the delta is a creation.
+    pub fn b(&self) -> Option<&VirtualFsNode<'a>> {
+        self.b.as_ref()
+    }
+
+    /// The attribute changes of a modification or unstable delta.
+    pub fn changes(&self) -> &[AttributeChange] {
+        match &self.delta_type {
+            DeltaType::Modification(changes) | DeltaType::Unstable(changes) | DeltaType::Move { changes, .. } => changes,
+            _ => &[],
+        }
+    }
+
     pub fn root_path_str(&self) -> &str {
         // order is important, we want a first, because a represents source state.
         if let Some(vnode) = &self.a {
@@ -189,68 +407,120 @@ impl<'a> Delta<'a> {
             DeltaType::Creation => { &self.a.as_ref().unwrap().fs_node.node_type },
             DeltaType::Deletion => { &self.b.as_ref().unwrap().fs_node.node_type },
             DeltaType::Modification(_) => { &self.a.as_ref().unwrap().fs_node.node_type },
+            DeltaType::Unstable(_) => { &self.a.as_ref().unwrap().fs_node.node_type },
             DeltaType::NoChange => { &self.a.as_ref().unwrap().fs_node.node_type },
+            DeltaType::Move { .. } => { &self.a.as_ref().unwrap().fs_node.node_type },
         }
     }
 
-    pub fn delta_info(&self) -> String {
-        match &self.delta_type {
-            DeltaType::NoChange => { String::new() },
-            DeltaType::Creation => { format!("[creation]") },
-            DeltaType::Deletion => { format!("[deletion]") },
-            DeltaType::Modification(changes) => {
-                String::from(changes.join(", "))
-            },
+    /// Which sides of an unstable delta changed while being hashed: `a`, `b` or `a, b`.
+    pub fn unstable_sides(&self) -> &'static str {
+        let is_unstable = |vnode: &Option<VirtualFsNode<'_>>| vnode.as_ref().is_some_and(|v| v.fs_node.unstable);
+        match (is_unstable(&self.a), is_unstable(&self.b)) {
+            (true, true) => "a, b",
+            (true, false) => "a",
+            _ => "b",
         }
     }
 
-    pub fn modifications(&self) -> Vec<String> {
+    /// Whether the attribute is to be compared, and is known on both sides.
+    /// Nodes imported from manifests may not know every attribute.
+    fn compares(&self, attr: &Attribute, aaa: &FsNode, bbb: &FsNode) -> bool {
+        self.delta_trigger_attrs.contains(attr) && aaa.knows(attr.node_attrs()) && bbb.knows(attr.node_attrs())
+    }
+
+    pub fn modifications(&self, id_map: &IdMap) -> Vec<AttributeChange> {
         let mut deltas = Vec::new();
+        let mut changed = |attr: Attribute, old: Value, new: Value| deltas.push(AttributeChange { attr, old, new });
 
-        let aaa = &self.a.as_ref().expect("modified_attributes must never be called on a creation or deletion delta").fs_node;
-        let bbb = &self.b.as_ref().expect("modified_attributes must never be called on a creation or deletion delta").fs_node;
+        let vnode_a = self.a.as_ref().expect("modified_attributes must never be called on a creation or deletion delta");
+        let vnode_b = self.b.as_ref().expect("modified_attributes must never be called on a creation or deletion delta");
+        let aaa = vnode_a.fs_node;
+        let bbb = vnode_b.fs_node;
 
-        // TODO: this is kinda ugly
-        if self.delta_trigger_attrs.contains(&Attribute::Size) && aaa.size != bbb.size {
-            deltas.push(format!("size: {} -> {}", bbb.size, aaa.size));
+        // inode numbers are only comparable within the same filesystem. if unknown, assume they are.
+        let same_filesystem = match (vnode_a.mount, vnode_b.mount) {
+            (Some(mount_a), Some(mount_b)) => mount_a.is_same_filesystem(mount_b),
+            _ => true,
+        };
+        // FAT only stores modification times with a 2 second resolution
+        let mtime_tolerance = if vnode_a.mount.iter().chain(vnode_b.mount.iter()).any(|mount| mount.has_coarse_mtime()) {
+            2
+        } else {
+            0
+        };
+
+        if self.compares(&Attribute::Size, aaa, bbb) && aaa.size != bbb.size {
+            changed(Attribute::Size, Value::Number(bbb.size), Value::Number(aaa.size));
+        }
+        if self.compares(&Attribute::NodeType, aaa, bbb) && aaa.node_type != bbb.node_type {
+            changed(Attribute::NodeType, Value::NodeType(bbb.node_type.clone()), Value::NodeType(aaa.node_type.clone()));
         }
-        if self.delta_trigger_attrs.contains(&Attribute::NodeType) && aaa.node_type != bbb.node_type {
-            deltas.push(format!("type: {} -> {}", bbb.node_type, aaa.node_type));
+        if self.compares(&Attribute::User, aaa, bbb) && id_map.uid(aaa.uid) != bbb.uid {
+            changed(Attribute::User, Value::Id(bbb.uid), Value::Id(aaa.uid));
         }
-        if self.delta_trigger_attrs.contains(&Attribute::User) && aaa.uid != bbb.uid {
-            deltas.push(format!("uid: {} -> {}", bbb.uid, aaa.uid));
+        if self.compares(&Attribute::Group, aaa, bbb) && id_map.gid(aaa.gid) != bbb.gid {
+            changed(Attribute::Group, Value::Id(bbb.gid), Value::Id(aaa.gid));
         }
-        if self.delta_trigger_attrs.contains(&Attribute::Group) && aaa.gid != bbb.gid {
-            deltas.push(format!("gid: {} -> {}", bbb.gid, aaa.gid));
+        if self.compares(&Attribute::Permissions, aaa, bbb) && aaa.permissions != bbb.permissions {
+            changed(Attribute::Permissions, Value::Permissions(bbb.permissions), Value::Permissions(aaa.permissions));
         }
-        if self.delta_trigger_attrs.contains(&Attribute::Permissions) && aaa.permissions != bbb.permissions {
-            let aaa = Permission::from_val(aaa.permissions);
-            let bbb = Permission::from_val(bbb.permissions);
-            deltas.push(format!("perms: {} -> {}", bbb.as_str(), aaa.as_str()));
+        if self.compares(&Attribute::CreationDate, aaa, bbb) && aaa.creation_date != bbb.creation_date {
+            changed(Attribute::CreationDate, Value::Time(bbb.creation_date), Value::Time(aaa.creation_date));
         }
-        if self.delta_trigger_attrs.contains(&Attribute::CreationDate) && aaa.creation_date != bbb.creation_date {
-            let time_a = chrono::Local.timestamp(aaa.creation_date, 0);
-            let time_b = chrono::Local.timestamp(bbb.creation_date, 0);
-            deltas.push(format!("date created: {} -> {}", time_b.to_string(), time_a.to_string()));
+        if self.compares(&Attribute::ModifiedDate, aaa, bbb) && (aaa.modified_date - bbb.modified_date).abs() > mtime_tolerance {
+            changed(Attribute::ModifiedDate, Value::Time(bbb.modified_date), Value::Time(aaa.modified_date));
         }
-        if self.delta_trigger_attrs.contains(&Attribute::ModifiedDate) && aaa.modified_date != bbb.modified_date {
-            let time_a = chrono::Local.timestamp(aaa.modified_date, 0);
-            let time_b = chrono::Local.timestamp(bbb.modified_date, 0);
-            deltas.push(format!("date modified: {} -> {}", time_b.to_string(), time_a.to_string()));
+        if self.compares(&Attribute::LinksTo, aaa, bbb) && aaa.links_to != bbb.links_to {
+            changed(Attribute::LinksTo, Value::Text(bbb.links_to.clone()), Value::Text(aaa.links_to.clone()));
         }
-        if self.delta_trigger_attrs.contains(&Attribute::LinksTo) && aaa.links_to != bbb.links_to {
-            deltas.push(format!("symlink to: {} -> {}", bbb.links_to, aaa.links_to));
+        // the checksum of a node that changed while being hashed matches neither its old nor new contents
+        let checksums_reliable = !aaa.unstable && !bbb.unstable;
+        if self.compares(&Attribute::Checksum, aaa, bbb) && checksums_reliable {
+            if let Some((digest, digest_a, digest_b)) = common_digest(aaa, bbb) {
+                if digest_a != digest_b {
+                    changed(Attribute::Checksum, Value::Digest(digest, digest_b.to_string()), Value::Digest(digest, digest_a.to_string()));
+                }
+            }
         }
-        if self.delta_trigger_attrs.contains(&Attribute::Checksum) && aaa.sha1_checksum != bbb.sha1_checksum {
-            deltas.push(format!("sha1: {} -> {}", bbb.sha1_checksum, aaa.sha1_checksum));
+        if self.compares(&Attribute::Inode, aaa, bbb) && same_filesystem && aaa.inode != bbb.inode {
+            changed(Attribute::Inode, Value::Number(bbb.inode), Value::Number(aaa.inode));
         }
-        if self.delta_trigger_attrs.contains(&Attribute::Inode) && aaa.inode != bbb.inode {
-            deltas.push(format!("inode: {} -> {}", bbb.inode, aaa.inode));
+        if self.compares(&Attribute::NLinks, aaa, bbb) && aaa.nlinks != bbb.nlinks {
+            changed(Attribute::NLinks, Value::Number(bbb.nlinks), Value::Number(aaa.nlinks));
         }
-        if self.delta_trigger_attrs.contains(&Attribute::NLinks) && aaa.nlinks != bbb.nlinks {
-            deltas.push(format!("hardlink count: {} -> {}", bbb.nlinks, aaa.nlinks));
+        // a hard link relationship was broken or created, e.g. by copying without preserving hard links
+        if self.compares(&Attribute::HardLinks, aaa, bbb) && vnode_a.link_partners != vnode_b.link_partners {
+            changed(Attribute::HardLinks, Value::Paths(vnode_b.link_partners.clone()), Value::Paths(vnode_a.link_partners.clone()));
         }
 
         deltas
     }
-}
\ No newline at end of file
+}
+
+/// The first digest both nodes have, as (name, digest of a, digest of b).
+/// sha1 is known unless the node was imported from a manifest without it;
+/// the other digests are only recorded if asked for.
+pub fn common_digest<'n>(aaa: &'n FsNode, bbb: &'n FsNode) -> Option<(&'static str, &'n str, &'n str)> {
+    if aaa.knows(fs_node::ATTR_SHA1) && bbb.knows(fs_node::ATTR_SHA1) {
+        Some(("sha1", &aaa.sha1_checksum, &bbb.sha1_checksum))
+    } else if !aaa.sha256_checksum.is_empty() && !bbb.sha256_checksum.is_empty() {
+        Some(("sha256", &aaa.sha256_checksum, &bbb.sha256_checksum))
+    } else if !aaa.md5_checksum.is_empty() && !bbb.md5_checksum.is_empty() {
+        Some(("md5", &aaa.md5_checksum, &bbb.md5_checksum))
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn paths_one_per_line() {
+        let paths = Value::Paths(vec!["/a b".to_string(), "/new\nline".to_string(), "/back\\slash".to_string()]);
+        assert_eq!(paths.to_text(), "/a b\n/new\\nline\n/back\\\\slash");
+        assert_eq!(Value::Paths(Vec::new()).to_text(), "");
+    }
+}
@@ -0,0 +1,179 @@
+use crate::apperror::AppError;
+use crate::db_models::unix_group::UnixGroup;
+use crate::db_models::unix_user::UnixUser;
+use crate::db_models::indexing_run::IndexingRun;
+use std::collections::HashMap;
+use std::fs;
+
+/// The user and group name tables captured in an index database at index time.
+#[derive(Debug, Default)]
+pub struct NameTables {
+    pub users: HashMap<String, u32>,
+    pub groups: HashMap<String, u32>,
+}
+
+/// Translates the uids and gids of the 'a' index into the ids the same user or group
+/// has in the 'b' index. Ids without a mapping translate to themselves.
+#[derive(Debug, Default, Clone)]
+pub struct IdMap {
+    uids: HashMap<u32, u32>,
+    gids: HashMap<u32, u32>,
+}
+
+/// Whether a mapping entry refers to users or groups.
+#[derive(Debug, Clone, Copy)]
+pub enum IdKind {
+    User,
+    Group,
+}
+
+impl NameTables {
+    /// The name tables of the run, referred to as `db_ref` in messages.
+    pub fn select(conn: &rusqlite::Connection, run: &IndexingRun, db_ref: &str) -> crate::ConvertibleResult<NameTables> {
+        log::debug!("fetching user and group name tables from '{}'", db_ref);
+
+        let users = unambiguous(db_ref, IdKind::User, UnixUser::select(conn, run.id)?.into_iter()
+            .map(|user| (user.name, user.uid)));
+        let groups = unambiguous(db_ref, IdKind::Group, UnixGroup::select(conn, run.id)?.into_iter()
+            .map(|group| (group.name, group.gid)));
+
+        Ok(NameTables { users, groups })
+    }
+
+    fn names(&self, kind: IdKind) -> &HashMap<String, u32> {
+        match kind {
+            IdKind::User => &self.users,
+            IdKind::Group => &self.groups,
+        }
+    }
+
+    /// Resolves a mapping file token, which is either a numeric id or a name in this table.
+    fn resolve(&self, kind: IdKind, token: &str) -> Result<u32, AppError> {
+        if let Ok(id) = token.parse::<u32>() {
+            return Ok(id);
+        }
+        self.names(kind).get(token).copied().ok_or_else(|| AppError::WithMessage(
+            format!("'{}' is not a numeric id nor a known {:?} name in the index", token, kind)
+        ))
+    }
+}
+
+/// `a_to_ref` followed by the inverse of `b_to_ref`. If several ids of 'b' translate into the same id,
+/// the smallest is taken.
+fn compose(a_to_ref: &HashMap<u32, u32>, b_to_ref: &HashMap<u32, u32>) -> HashMap<u32, u32> {
+    let mut ref_to_b: HashMap<u32, u32> = HashMap::new();
+    for (id_b, id_ref) in b_to_ref.iter() {
+        let id = ref_to_b.entry(*id_ref).or_insert(*id_b);
+        *id = (*id).min(*id_b);
+    }
+    let translate = |id_a: u32| {
+        let id_ref = *a_to_ref.get(&id_a).unwrap_or(&id_a);
+        *ref_to_b.get(&id_ref).unwrap_or(&id_ref)
+    };
+    // ids of 'a' that are not translated into the reference may still have to be translated into 'b'
+    a_to_ref.keys().chain(ref_to_b.keys())
+        .map(|id_a| (*id_a, translate(*id_a)))
+        .filter(|(id_a, id_b)| id_a != id_b)
+        .collect()
+}
+
+/// The id of each name. Names with several ids, e.g. a user with different uids on the hosts a run was
+/// merged from, are left out rather than mapped to one of them.
+fn unambiguous(db_ref: &str, kind: IdKind, entries: impl Iterator<Item = (String, u32)>) -> HashMap<String, u32> {
+    let mut ids: HashMap<String, Option<u32>> = HashMap::new();
+    for (name, id) in entries {
+        let known = ids.entry(name).or_insert(Some(id));
+        if *known != Some(id) {
+            *known = None;
+        }
+    }
+    ids.into_iter()
+        .filter_map(|(name, id)| match id {
+            Some(id) => Some((name, id)),
+            None => {
+                log::warn!("'{}': {:?} '{}' has several ids in the index; not mapping it", db_ref, kind, name);
+                None
+            },
+        })
+        .collect()
+}
+
+impl IdMap {
+
+    pub fn new() -> IdMap {
+        IdMap::default()
+    }
+
+    /// The uid that `uid_a` (from the 'a' index) is expected to have in the 'b' index.
+    pub fn uid(&self, uid_a: u32) -> u32 {
+        *self.uids.get(&uid_a).unwrap_or(&uid_a)
+    }
+
+    /// The gid that `gid_a` (from the 'a' index) is expected to have in the 'b' index.
+    pub fn gid(&self, gid_a: u32) -> u32 {
+        *self.gids.get(&gid_a).unwrap_or(&gid_a)
+    }
+
+    fn ids_mut(&mut self, kind: IdKind) -> &mut HashMap<u32, u32> {
+        match kind {
+            IdKind::User => &mut self.uids,
+            IdKind::Group => &mut self.gids,
+        }
+    }
+
+    /// Translates the ids of the 'a' index into those of the 'b' index, given how both translate into
+    /// the ids of a third index, e.g. of the two sides of `cmp3` into the base they diverged from.
+    pub fn via(a_to_ref: &IdMap, b_to_ref: &IdMap) -> IdMap {
+        IdMap {
+            uids: compose(&a_to_ref.uids, &b_to_ref.uids),
+            gids: compose(&a_to_ref.gids, &b_to_ref.gids),
+        }
+    }
+
+    /// Maps every user and group name present in both indexes to each other.
+    pub fn map_by_name(&mut self, names_a: &NameTables, names_b: &NameTables) {
+        for kind in [IdKind::User, IdKind::Group].iter().copied() {
+            let mut mapped = 0;
+            for (name, id_a) in names_a.names(kind) {
+                if let Some(id_b) = names_b.names(kind).get(name) {
+                    self.ids_mut(kind).insert(*id_a, *id_b);
+                    mapped += 1;
+                }
+            }
+            log::debug!("map_by_name: mapped {} {:?} names", mapped, kind);
+            if mapped == 0 {
+                log::warn!("map_by_name: no {:?} names in common; the indexes may lack name tables", kind);
+            }
+        }
+    }
+
+    /// Loads explicit mappings from a file with one `A:B` entry per line, where `A` is
+    /// the id or name in the 'a' index and `B` the id or name in the 'b' index.
+    /// Blank lines and lines starting with `#` are ignored.
+    /// Explicit entries take precedence over mappings made by name.
+    pub fn load_file(&mut self, path: &str, kind: IdKind, names_a: &NameTables, names_b: &NameTables) -> crate::ConvertibleResult<()> {
+        log::debug!("'{}': loading {:?} id map...", path, kind);
+        let contents = fs::read_to_string(path)?;
+
+        for (line_no, line) in contents.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let (token_a, token_b) = match line.find(':') {
+                Some(i) => (line[..i].trim(), line[i + 1..].trim()),
+                None => {
+                    return Err(AppError::WithMessage(
+                        format!("'{}', line {}: expected 'A:B', got '{}'", path, line_no + 1, line)
+                    ).into());
+                }
+            };
+            let id_a = names_a.resolve(kind, token_a)?;
+            let id_b = names_b.resolve(kind, token_b)?;
+            log::trace!("{:?} id map: {} -> {}", kind, id_a, id_b);
+            self.ids_mut(kind).insert(id_a, id_b);
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,85 @@
+use crate::ConvertibleResult;
+use crate::comparator::DeltaWriter;
+use crate::comparator::delta::{Delta, Value};
+use crate::comparator::report::ReportSummary;
+use crate::comparator::virtual_fs_node::VirtualFsNode;
+use crate::util::quote;
+use std::io::Write;
+
+/// JSON: one document with the compared indexes and roots, and the deltas in order, one per line.
+/// With `lines`, JSON Lines instead: just the deltas, one object per line.
+pub struct JsonWriter<W: Write> {
+    output: W,
+    lines: bool,
+    deltas: usize,
+}
+
+impl<W: Write> JsonWriter<W> {
+
+    pub fn new(mut output: W, lines: bool, summary: &ReportSummary) -> ConvertibleResult<JsonWriter<W>> {
+        if !lines {
+            let side = |db: &str, roots: &[String]| {
+                let roots: Vec<String> = roots.iter().map(|root| quote::json(root)).collect();
+                format!("{{\"db\":{},\"roots\":[{}]}}", quote::json(db), roots.join(","))
+            };
+            write!(output, "{{\n\"a\":{},\n\"b\":{},\n\"deltas\":[",
+                side(&summary.db_a_name, &summary.roots_a), side(&summary.db_b_name, &summary.roots_b))?;
+        }
+        Ok(JsonWriter { output, lines, deltas: 0 })
+    }
+}
+
+impl<W: Write> DeltaWriter for JsonWriter<W> {
+
+    fn write(&mut self, delta: &Delta<'_>) -> ConvertibleResult<()> {
+        if self.lines {
+            writeln!(self.output, "{}", object(delta))?;
+        } else {
+            let separator = if self.deltas == 0 { "\n" } else { ",\n" };
+            write!(self.output, "{}{}", separator, object(delta))?;
+        }
+        self.deltas += 1;
+        Ok(())
+    }
+
+    fn finish(mut self: Box<Self>) -> ConvertibleResult<()> {
+        if !self.lines {
+            write!(self.output, "\n]\n}}\n")?;
+        }
+        self.output.flush()?;
+        log::debug!("wrote {} deltas", self.deltas);
+        Ok(())
+    }
+}
+
+/// The delta as a JSON object: its type, root and path, the path it was moved from, the node types on both sides,
+/// and the changed attributes with their old ('b') and new ('a') values.
+pub fn object(delta: &Delta<'_>) -> String {
+    let optional = |text: Option<&str>| text.map_or("null".to_string(), quote::json);
+    let node_type = |vnode: Option<&VirtualFsNode<'_>>| optional(vnode.map(|vnode| vnode.fs_node.node_type.name()));
+    let changes: Vec<String> = delta.changes().iter()
+        .map(|change| format!("{{\"attribute\":{},\"old\":{},\"new\":{}}}",
+            quote::json(change.name()), value(&change.old), value(&change.new)))
+        .collect();
+    let candidates: Vec<String> = delta.move_candidates().iter().map(|path| quote::json(path)).collect();
+
+    format!("{{\"type\":{},\"root\":{},\"path\":{},\"from\":{},\"node_type_a\":{},\"node_type_b\":{},\"changes\":[{}],\"move_candidates\":[{}]}}",
+        quote::json(delta.delta_type().name()),
+        quote::json(delta.root_path_str()),
+        quote::json(delta.virtual_path_str()),
+        optional(delta.moved_from()),
+        node_type(delta.a()),
+        node_type(delta.b()),
+        changes.join(","),
+        candidates.join(","))
+}
+
+/// Numbers as numbers, hard linked paths as an array, anything else as a string (see [Value::to_text]()).
+fn value(value: &Value) -> String {
+    match value {
+        Value::Number(number) => number.to_string(),
+        Value::Id(id) => id.to_string(),
+        Value::Paths(paths) => format!("[{}]", paths.iter().map(|path| quote::json(path)).collect::<Vec<_>>().join(",")),
+        value => quote::json(&value.to_text()),
+    }
+}
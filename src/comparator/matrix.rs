@@ -0,0 +1,399 @@
+use crate::ConvertibleResult;
+use crate::comparator::compare::{self, VFsNodeMap};
+use crate::comparator::delta::{Attribute, Delta};
+use crate::comparator::id_map::IdMap;
+use crate::comparator::virtual_fs_node::VirtualFsNode;
+use crate::comparator::IndexSnapshot;
+use crate::db_models::fs_node::FsNode;
+use crate::errorwrapper::app_error;
+use crate::util::quote;
+use crate::verifier;
+use clap;
+use std::collections::{BTreeMap, HashSet};
+use std::io::{self, Write};
+
+/// The nodes of several indexes that don't differ from each other, see [matrix]().
+#[derive(Debug)]
+pub struct Variant<'a> {
+    /// The node of the first index with this variant.
+    pub node: VirtualFsNode<'a>,
+    /// The positions of the indexes with this variant, in order.
+    pub indexes: Vec<usize>,
+}
+
+/// What most indexes have at a path.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum Majority {
+    Variant(usize),
+    Absent,
+}
+
+/// A virtual path across all indexes: the variants of the node, in the order of the first index
+/// that has each, and the indexes without the path.
+#[derive(Debug)]
+pub struct MatrixRow<'a> {
+    pub virtual_path: String,
+    pub variants: Vec<Variant<'a>>,
+    pub absent: Vec<usize>,
+    /// The variant, or absence, more indexes have than any other; `None` on a tie.
+    pub majority: Option<Majority>,
+}
+
+impl MatrixRow<'_> {
+
+    /// Whether all indexes have the same node.
+    pub fn is_uniform(&self) -> bool {
+        self.variants.len() == 1 && self.absent.is_empty()
+    }
+
+    /// The variant the other variants are described against: the majority, or else the first.
+    fn reference(&self) -> Option<usize> {
+        match self.majority {
+            Some(Majority::Variant(i)) => Some(i),
+            Some(Majority::Absent) | None if !self.variants.is_empty() => Some(0),
+            _ => None,
+        }
+    }
+}
+
+/// The id translations between any two of the indexes, made from those of each index into the first.
+#[derive(Debug)]
+struct Translations(Vec<Vec<IdMap>>);
+
+impl Translations {
+
+    fn new(id_maps: &[IdMap]) -> Translations {
+        Translations(id_maps.iter()
+            .map(|from| id_maps.iter().map(|to| IdMap::via(from, to)).collect())
+            .collect())
+    }
+
+    /// Translates the ids of index `from` into those of index `to`.
+    fn between(&self, from: usize, to: usize) -> &IdMap {
+        &self.0[from][to]
+    }
+}
+
+/// Compares the pools of several indexes at once (see [compare::make_pool]()): for each virtual path
+/// in any of them, the nodes are grouped into variants that don't differ in `attr_types`.
+/// Ownership is compared by id, with `id_maps` translating the ids of each index into those of the first
+/// (so the first is empty). The rows are ordered by path.
+pub fn matrix<'a>(pools: Vec<VFsNodeMap<'a>>, attr_types: &HashSet<Attribute>, id_maps: &[IdMap]) -> Vec<MatrixRow<'a>> {
+    let count = pools.len();
+    let mut by_path: BTreeMap<String, Vec<Option<VirtualFsNode<'a>>>> = BTreeMap::new();
+    for (i, pool) in pools.into_iter().enumerate() {
+        for (virtual_path, vnode) in pool {
+            by_path.entry(virtual_path).or_insert_with(|| vec![None; count])[i] = Some(vnode);
+        }
+    }
+
+    let translations = Translations::new(id_maps);
+    let mut rows = Vec::with_capacity(by_path.len());
+    for (virtual_path, vnodes) in by_path {
+        let mut variants: Vec<Variant<'a>> = Vec::new();
+        let mut absent = Vec::new();
+        for (i, vnode) in vnodes.into_iter().enumerate() {
+            let vnode = match vnode {
+                Some(vnode) => vnode,
+                None => {
+                    absent.push(i);
+                    continue;
+                },
+            };
+            let same = |variant: &&mut Variant<'a>| {
+                differences(&variant.node, &vnode, attr_types, translations.between(i, variant.indexes[0])).is_none()
+            };
+            match variants.iter_mut().find(same) {
+                Some(variant) => variant.indexes.push(i),
+                None => variants.push(Variant { node: vnode, indexes: vec![i] }),
+            }
+        }
+        let majority = majority(&variants, &absent);
+        rows.push(MatrixRow { virtual_path, variants, absent, majority });
+    }
+    rows
+}
+
+fn majority(variants: &[Variant<'_>], absent: &[usize]) -> Option<Majority> {
+    let counts = variants.iter().enumerate()
+        .map(|(i, variant)| (Majority::Variant(i), variant.indexes.len()))
+        .chain(Some((Majority::Absent, absent.len())));
+    let mut best: Option<(Majority, usize)> = None;
+    let mut tie = false;
+    for (candidate, count) in counts {
+        match best {
+            Some((_, best_count)) if count < best_count => {},
+            Some((_, best_count)) if count == best_count => tie = true,
+            _ => {
+                best = Some((candidate, count));
+                tie = false;
+            },
+        }
+    }
+    if tie { None } else { best.map(|(candidate, _)| candidate) }
+}
+
+/// The attributes in which `vnode` differs from `reference`, if it does. `id_map` translates from the index
+/// of `vnode` into the index of `reference`.
+fn differences(reference: &VirtualFsNode<'_>, vnode: &VirtualFsNode<'_>, attr_types: &HashSet<Attribute>, id_map: &IdMap) -> Option<Vec<&'static str>> {
+    let delta = Delta::new(Some(vnode.clone()), Some(reference.clone()), attr_types, id_map);
+    if delta.delta_type().is_unchanged() {
+        return None;
+    }
+    let mut names: Vec<&'static str> = delta.changes().iter().map(|change| change.name()).collect();
+    if delta.is_unstable() {
+        names.push("changed while indexing");
+    }
+    Some(names)
+}
+
+/// `cmp --index FILE --index FILE ...`: writes the matrix of the indexes, see [matrix]().
+pub fn run(args: &clap::ArgMatches<'_>) -> ConvertibleResult<()> {
+    let indexes = indexes(args)?;
+    if indexes.len() < 2 {
+        return Err(app_error("give at least two indexes to compare with --index".to_string()));
+    }
+
+    let snapshots = indexes.iter()
+        .map(|(db_path, _)| IndexSnapshot::open(db_path))
+        .collect::<ConvertibleResult<Vec<_>>>()?;
+    if let Some(path) = args.value_of("verify-key") {
+        let trusted_key = verifier::read_verifying_key(path)?;
+        for snapshot in snapshots.iter() {
+            snapshot.require_valid(&trusted_key)?;
+        }
+    }
+
+    let formats: Vec<&str> = args.values_of("format").map(|values| values.collect()).unwrap_or_else(|| vec!["text"]);
+    let format = match formats.as_slice() {
+        [format] if *format == "text" || *format == "json" => *format,
+        _ => return Err(app_error("the comparison of several indexes is written as text or json only".to_string())),
+    };
+
+    let attrs = super::attributes(args)?;
+    let mut nodes: Vec<Vec<FsNode>> = Vec::with_capacity(indexes.len());
+    let mut mounts: Vec<compare::MountTable> = Vec::with_capacity(indexes.len());
+    for (i, (db_path, roots)) in indexes.iter().enumerate() {
+        if let Err(invalid_roots) = super::validate_roots(roots) {
+            return Err(app_error(format!(
+                "index {} ('{}'): invalid roots: {:?}\nroots cannot be direct descendants of each other", i + 1, db_path, invalid_roots
+            )));
+        }
+        mounts.push(snapshots[i].mounts()?);
+        nodes.push(snapshots[i].fs_nodes()?);
+    }
+    let mut pools = Vec::with_capacity(indexes.len());
+    for (i, (_, roots)) in indexes.iter().enumerate() {
+        pools.push(compare::make_pool(&nodes[i], roots.clone(), &mounts[i])?);
+    }
+
+    let mut id_maps = vec![IdMap::new()];
+    for snapshot in snapshots.iter().skip(1) {
+        id_maps.push(super::id_map(args, snapshot, &snapshots[0])?);
+    }
+    let translations = Translations::new(&id_maps);
+
+    let rows = matrix(pools, &attrs, &id_maps);
+    let keep_unchanged = args.is_present("keep-unchanged");
+    let differing = rows.iter().filter(|row| !row.is_uniform()).count();
+    log::info!("{} of {} paths differ between the {} indexes", differing, rows.len(), indexes.len());
+    let rows: Vec<&MatrixRow<'_>> = rows.iter().filter(|row| keep_unchanged || !row.is_uniform()).collect();
+
+    let mut output = io::BufWriter::new(io::stdout());
+    match format {
+        "json" => write_json(&mut output, &indexes, &rows, &attrs, &translations)?,
+        _ => write_text(&mut output, &indexes, &rows, &attrs, &translations)?,
+    }
+    output.flush()?;
+    Ok(())
+}
+
+/// The `--index` options with the `--root` options given after each, '/' if none.
+fn indexes(args: &clap::ArgMatches<'_>) -> ConvertibleResult<Vec<(String, Vec<String>)>> {
+    let db_paths: Vec<(usize, &str)> = match (args.indices_of("index"), args.values_of("index")) {
+        (Some(indices), Some(values)) => indices.zip(values).collect(),
+        _ => Vec::new(),
+    };
+    let mut indexes: Vec<(String, Vec<String>)> = db_paths.iter().map(|(_, db_path)| (db_path.to_string(), Vec::new())).collect();
+    if let (Some(indices), Some(values)) = (args.indices_of("root"), args.values_of("root")) {
+        for (position, root) in indices.zip(values) {
+            match db_paths.iter().rposition(|(index_position, _)| *index_position < position) {
+                Some(i) => indexes[i].1.push(root.to_string()),
+                None => return Err(app_error(format!("--root '{}' is given before any --index it would belong to", root))),
+            }
+        }
+    }
+    for (_, roots) in indexes.iter_mut() {
+        if roots.is_empty() {
+            roots.push(String::from("/"));
+        }
+    }
+    Ok(indexes)
+}
+
+/// The letter of the `i`th variant of a path.
+fn letter(i: usize) -> char {
+    match i {
+        0..=25 => (b'A' + i as u8) as char,
+        26..=51 => (b'a' + (i - 26) as u8) as char,
+        _ => '?',
+    }
+}
+
+/// A column per index, numbered as in the header, and a row per path: `.` for the majority variant,
+/// a letter for each other variant, `-` where the path is missing. Each row ends with what the other variants
+/// differ in from the majority, or from variant `A` if there is no majority.
+fn write_text(
+    output: &mut impl Write,
+    indexes: &[(String, Vec<String>)],
+    rows: &[&MatrixRow<'_>],
+    attrs: &HashSet<Attribute>,
+    translations: &Translations
+) -> ConvertibleResult<()> {
+    let width = indexes.len().to_string().len() + 1;
+    for (i, (db_path, roots)) in indexes.iter().enumerate() {
+        writeln!(output, "# {:>w$}: {} {}", i + 1, db_path, roots.join(" "), w = width - 1)?;
+    }
+    write!(output, "#")?;
+    for i in 0..indexes.len() {
+        write!(output, "{:>w$}", i + 1, w = width)?;
+    }
+    writeln!(output)?;
+
+    for row in rows.iter() {
+        let mut cells = vec!['-'; indexes.len()];
+        for (v, variant) in row.variants.iter().enumerate() {
+            let cell = if row.majority == Some(Majority::Variant(v)) { '.' } else { letter(v) };
+            for &i in variant.indexes.iter() {
+                cells[i] = cell;
+            }
+        }
+        write!(output, " ")?;
+        for cell in cells {
+            write!(output, "{:>w$}", cell, w = width)?;
+        }
+        let suffix = if row.variants.iter().any(|variant| variant.node.fs_node.node_type.is_dir()) { "/" } else { "" };
+        write!(output, "   {}{}", row.virtual_path, suffix)?;
+
+        let mut notes = Vec::new();
+        if let Some(reference) = row.reference() {
+            for (v, variant) in row.variants.iter().enumerate().filter(|(v, _)| *v != reference) {
+                let id_map = translations.between(variant.indexes[0], row.variants[reference].indexes[0]);
+                let names = differences(&row.variants[reference].node, &variant.node, attrs, id_map).unwrap_or_default();
+                notes.push(format!("{}: {}", letter(v), names.join(", ")));
+            }
+        }
+        if !notes.is_empty() {
+            write!(output, "  ({})", notes.join("; "))?;
+        }
+        writeln!(output)?;
+    }
+    Ok(())
+}
+
+/// One document with the indexes and a row per path: each variant with the indexes that have it, the node type,
+/// its sha1 checksum and what it differs in from the majority (or first) variant, and the indexes without the path.
+fn write_json(
+    output: &mut impl Write,
+    indexes: &[(String, Vec<String>)],
+    rows: &[&MatrixRow<'_>],
+    attrs: &HashSet<Attribute>,
+    translations: &Translations
+) -> ConvertibleResult<()> {
+    let numbers = |indexes: &[usize]| indexes.iter().map(|i| (i + 1).to_string()).collect::<Vec<_>>().join(",");
+
+    write!(output, "{{\n\"indexes\":[")?;
+    for (i, (db_path, roots)) in indexes.iter().enumerate() {
+        let separator = if i == 0 { "\n" } else { ",\n" };
+        let roots: Vec<String> = roots.iter().map(|root| quote::json(root)).collect();
+        write!(output, "{}{{\"index\":{},\"db\":{},\"roots\":[{}]}}", separator, i + 1, quote::json(db_path), roots.join(","))?;
+    }
+    write!(output, "\n],\n\"paths\":[")?;
+
+    for (r, row) in rows.iter().enumerate() {
+        let reference = row.reference();
+        let variants: Vec<String> = row.variants.iter().enumerate()
+            .map(|(v, variant)| {
+                let differs: Vec<String> = match reference {
+                    Some(reference) if reference != v => {
+                        let id_map = translations.between(variant.indexes[0], row.variants[reference].indexes[0]);
+                        differences(&row.variants[reference].node, &variant.node, attrs, id_map).unwrap_or_default()
+                            .into_iter().map(quote::json).collect()
+                    },
+                    _ => Vec::new(),
+                };
+                let fs_node = variant.node.fs_node;
+                format!("{{\"variant\":{},\"majority\":{},\"indexes\":[{}],\"node_type\":{},\"sha1\":{},\"differs\":[{}]}}",
+                    quote::json(&letter(v).to_string()),
+                    row.majority == Some(Majority::Variant(v)),
+                    numbers(&variant.indexes),
+                    quote::json(fs_node.node_type.name()),
+                    if fs_node.sha1_checksum.is_empty() { "null".to_string() } else { quote::json(&fs_node.sha1_checksum) },
+                    differs.join(","))
+            })
+            .collect();
+        let separator = if r == 0 { "\n" } else { ",\n" };
+        write!(output, "{}{{\"path\":{},\"variants\":[{}],\"absent\":[{}],\"absent_majority\":{}}}", separator,
+            quote::json(&row.virtual_path), variants.join(","), numbers(&row.absent), row.majority == Some(Majority::Absent))?;
+    }
+    writeln!(output, "\n]\n}}")?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::comparator::test_nodes::{alice, file, pool};
+
+    /// The variants of the only row, as the indexes that have each, the indexes without it and the majority.
+    fn row(indexes: &[Vec<FsNode>], id_maps: &[IdMap]) -> (Vec<Vec<usize>>, Vec<usize>, Option<Majority>) {
+        let attrs: HashSet<Attribute> = vec![Attribute::Size, Attribute::User].into_iter().collect();
+        let mut rows = matrix(indexes.iter().map(|fs_nodes| pool(fs_nodes)).collect(), &attrs, id_maps);
+        assert_eq!(rows.len(), 1);
+        let row = rows.remove(0);
+        (row.variants.into_iter().map(|variant| variant.indexes).collect(), row.absent, row.majority)
+    }
+
+    fn compared(indexes: &[Vec<FsNode>]) -> (Vec<Vec<usize>>, Vec<usize>, Option<Majority>) {
+        row(indexes, &vec![IdMap::new(); indexes.len()])
+    }
+
+    #[test]
+    fn variant_majority() {
+        let indexes = [vec![file("x", 1, 1000)], vec![file("x", 2, 1000)], vec![file("x", 2, 1000)]];
+        assert_eq!(compared(&indexes), (vec![vec![0], vec![1, 2]], vec![], Some(Majority::Variant(1))));
+        let indexes = [vec![file("x", 1, 1000)], vec![file("x", 1, 1000)]];
+        assert_eq!(compared(&indexes), (vec![vec![0, 1]], vec![], Some(Majority::Variant(0))));
+    }
+
+    #[test]
+    fn absent_majority() {
+        let indexes = [vec![], vec![file("x", 1, 1000)], vec![]];
+        assert_eq!(compared(&indexes), (vec![vec![1]], vec![0, 2], Some(Majority::Absent)));
+        let indexes = [vec![], vec![file("x", 1, 1000)], vec![], vec![file("x", 2, 1000)]];
+        assert_eq!(compared(&indexes), (vec![vec![1], vec![3]], vec![0, 2], Some(Majority::Absent)));
+    }
+
+    #[test]
+    fn ties() {
+        let indexes = [vec![file("x", 1, 1000)], vec![file("x", 2, 1000)]];
+        assert_eq!(compared(&indexes), (vec![vec![0], vec![1]], vec![], None));
+        let indexes = [vec![file("x", 1, 1000)], vec![file("x", 2, 1000)], vec![file("x", 2, 1000)], vec![file("x", 1, 1000)]];
+        assert_eq!(compared(&indexes), (vec![vec![0, 3], vec![1, 2]], vec![], None));
+        let indexes = [vec![file("x", 1, 1000)], vec![]];
+        assert_eq!(compared(&indexes), (vec![vec![0]], vec![1], None));
+        let indexes = [vec![file("x", 1, 1000)], vec![file("x", 2, 1000)], vec![]];
+        assert_eq!(compared(&indexes), (vec![vec![0], vec![1]], vec![2], None));
+    }
+
+    #[test]
+    fn owners_translated_into_the_first() {
+        let indexes = [vec![file("x", 1, 1000)], vec![file("x", 1, 2000)], vec![file("x", 1, 3000)]];
+        assert_eq!(compared(&indexes), (vec![vec![0], vec![1], vec![2]], vec![], None));
+        let id_maps = [IdMap::new(), alice(2000), alice(3000)];
+        assert_eq!(row(&indexes, &id_maps), (vec![vec![0, 1, 2]], vec![], Some(Majority::Variant(0))));
+        // the variants of the other indexes are compared with each other, not only with the first
+        let indexes = [vec![], vec![file("x", 1, 2000)], vec![file("x", 1, 3000)]];
+        assert_eq!(row(&indexes, &id_maps), (vec![vec![1, 2]], vec![0], Some(Majority::Variant(0))));
+    }
+}
@@ -1,18 +1,33 @@
 //mod fs_tree;
-mod compare;
-mod delta;
+pub mod compare;
+mod csv_writer;
+pub mod delta;
+pub(crate) mod id_map;
+mod json_writer;
+pub(crate) mod matrix;
+mod output;
+pub(crate) mod renames;
 mod report;
-mod virtual_fs_node;
+mod script_writer;
+mod text_writer;
+#[cfg(test)]
+mod test_nodes;
+pub(crate) mod three_way;
+pub(crate) mod virtual_fs_node;
 
 use clap;
 use crate::ConvertibleResult;
 use crate::apperror::AppError;
 use crate::db_models::fs_node::FsNode;
+use crate::db_models::mount::Mount;
 use crate::errorwrapper::ErrorWrapper;
+use crate::verifier;
+use crate::db_models::indexing_run::{IndexingRun, RunRef};
 use std::collections::HashSet;
-use std::path::PathBuf;
-use std::io;
-use crate::comparator::delta::Attribute;
+use std::path::{Path, PathBuf};
+use std::io::{self, Write};
+use crate::comparator::delta::{Attribute, Delta};
+use crate::comparator::id_map::{IdKind, IdMap, NameTables};
 
 
 macro_rules! validate_roots {
@@ -31,29 +46,24 @@ macro_rules! validate_roots {
     }
 }
 
-pub fn run(args: &clap::ArgMatches<'_>) -> ConvertibleResult<()> {
+pub(crate) fn run(args: &clap::ArgMatches<'_>) -> ConvertibleResult<()> {
+    if args.is_present("index") {
+        return matrix::run(args);
+    }
+
     let db_path_a = args.value_of("first-index").expect("path to database is required");
     let db_path_b = args.value_of("second-index").expect("path to database is required");
 
-    let first_index = FsNode::select_n(db_path_a)?;
-    let second_index = FsNode::select_n(db_path_b)?;
+    let index_a = IndexSnapshot::open(db_path_a)?;
+    let index_b = IndexSnapshot::open(db_path_b)?;
 
-    let attrs_opt =
-        if args.is_present("mode-all") {
-            Some(Attribute::all())
-        } else if args.is_present("mode-min") {
-            Some(Attribute::minimum())
-        } else { None };
+    if let Some(path) = args.value_of("verify-key") {
+        let trusted_key = verifier::read_verifying_key(path)?;
+        index_a.require_valid(&trusted_key)?;
+        index_b.require_valid(&trusted_key)?;
+    }
 
-    let attrs = match attrs_opt {
-        Some(a) => a,
-        None => {
-            match args.value_of("mode") {
-                Some(m) => Attribute::from_arg(m)?,
-                None => Attribute::medium(),
-            }
-        }
-    };
+    let attrs = attributes(args)?;
 
     let roots_a = roots(args, "root-a");
     let roots_b = roots(args, "root-b");
@@ -61,31 +71,162 @@ pub fn run(args: &clap::ArgMatches<'_>) -> ConvertibleResult<()> {
     validate_roots!(&roots_a, "a");
     validate_roots!(&roots_b, "b");
 
+    let id_map = id_map(args, &index_a, &index_b)?;
+
     let summary = report::ReportSummary {
         db_a_name: db_path_a.to_string(),
         db_b_name: db_path_b.to_string(),
         roots_a: roots_a.clone(),
-        roots_b: roots_b.clone()
+        roots_b: roots_b.clone(),
+        detect_renames: args.is_present("detect-renames"),
     };
 
-    let pool_a = compare::make_pool(&first_index,  roots_a)?;
-    let pool_b = compare::make_pool(&second_index, roots_b)?;
+    let mounts_a = index_a.mounts()?;
+    let mounts_b = index_b.mounts()?;
 
-    let deltas = compare::compare(pool_a, pool_b, &attrs);
+    let keep_unchanged = args.is_present("keep-unchanged");
+    let collapse = args.is_present("collapse");
+    let mut formats: Vec<&str> = match args.values_of("format") {
+        Some(values) => values.collect(),
+        // just the script, e.g. to redirect it to a file
+        None if args.is_present("emit-script") => Vec::new(),
+        None => vec!["html"],
+    };
+    formats.extend(args.value_of("emit-script"));
+    // e.g. '--format html,html' would write the same report twice
+    let mut seen = HashSet::new();
+    formats.retain(|format| seen.insert(*format));
 
-    let output_stream = match args.value_of("directory") {
-        None => { io::stdout() },
-        Some(_dir) => { unimplemented!("writing to file not implemented") },
+    let (mut report, report_paths) = match args.value_of("directory") {
+        None if formats.len() > 1 => {
+            let error = AppError::WithMessage("several formats can only be written with --output-dir".to_string());
+            log::error!("{}", error);
+            return Err(ErrorWrapper::AppError(error));
+        },
+        None => (writer(formats[0], io::BufWriter::new(io::stdout()), keep_unchanged, collapse, summary, &id_map)?, Vec::new()),
+        Some(dir) => {
+            let name = output::report_name(db_path_a, &roots_a, db_path_b, &roots_b);
+            output::open(&Path::new(dir).join(name), &formats, args.is_present("split"), keep_unchanged, collapse, &summary, &id_map)?
+        },
     };
 
-    let keep_unchanged = args.is_present("keep-unchanged");
-    let collapse = args.is_present("collapse");
+    let mut lacking_digests = 0;
+    if args.is_present("streaming") {
+        let source_a = compare::PoolSource { conn: &index_a.conn, run: &index_a.run, roots: roots_a, mounts: &mounts_a };
+        let source_b = compare::PoolSource { conn: &index_b.conn, run: &index_b.run, roots: roots_b, mounts: &mounts_b };
+
+        compare::compare_streaming(&source_a, &source_b, &attrs, &id_map, |delta| {
+            if delta.lacks_common_digest() {
+                lacking_digests += 1;
+            }
+            if keep_unchanged || !delta.delta_type().is_unchanged() {
+                report.write(&delta)?;
+            }
+            Ok(())
+        })?;
+    } else {
+        let first_index = index_a.fs_nodes()?;
+        let second_index = index_b.fs_nodes()?;
 
-    report::write(output_stream, deltas, keep_unchanged, collapse, summary)?;
+        let pool_a = compare::make_pool(&first_index,  roots_a, &mounts_a)?;
+        let pool_b = compare::make_pool(&second_index, roots_b, &mounts_b)?;
+
+        let mut deltas = compare::compare(pool_a, pool_b, &attrs, &id_map);
+        if args.is_present("detect-renames") {
+            deltas = renames::detect_renames(deltas, &id_map);
+        }
+
+        lacking_digests = deltas.iter().filter(|delta| delta.lacks_common_digest()).count();
+        for delta in deltas.iter().filter(|delta| keep_unchanged || !delta.delta_type().is_unchanged()) {
+            report.write(delta)?;
+        }
+    }
+    report.finish()?;
+    if lacking_digests > 0 {
+        log::warn!("the contents of {} files were not compared: the indexes share no digest for them \
+                    (index with idx --digest md5 or --digest sha256 to compare against such manifests)", lacking_digests);
+    }
 
+    for report_path in report_paths {
+        report_path.commit()?;
+    }
     Ok(())
 }
 
+/// An index opened for comparing. Its connection holds a read transaction (see [RunRef::open_snapshot]()),
+/// so that the run checked by `--verify-key` is the run that is compared, even if the database is written to meanwhile.
+struct IndexSnapshot<'r> {
+    db_ref: &'r str,
+    conn: rusqlite::Connection,
+    run: IndexingRun,
+}
+
+impl<'r> IndexSnapshot<'r> {
+
+    fn open(db_ref: &'r str) -> ConvertibleResult<IndexSnapshot<'r>> {
+        let (conn, run) = RunRef::parse(db_ref).open_snapshot()?;
+        Ok(IndexSnapshot { db_ref, conn, run })
+    }
+
+    fn require_valid(&self, trusted_key: &ed25519_dalek::VerifyingKey) -> ConvertibleResult<()> {
+        verifier::require_valid(&self.conn, &self.run, self.db_ref, trusted_key)
+    }
+
+    fn fs_nodes(&self) -> ConvertibleResult<Vec<FsNode>> {
+        log::debug!("fetching fs_nodes from '{}'", self.db_ref);
+        let fs_nodes = FsNode::select_run(&self.conn, &self.run)?;
+        log::debug!("{}: retrieved {} rows.", self.db_ref, fs_nodes.len());
+        Ok(fs_nodes)
+    }
+
+    /// The filesystems recorded in the index, used to tell what attributes are comparable.
+    fn mounts(&self) -> ConvertibleResult<compare::MountTable> {
+        log::debug!("fetching mounts from '{}'", self.db_ref);
+        let mounts = Mount::select(&self.conn, self.run.id)?.into_iter()
+            .map(|mount| ((mount.source_id, mount.device), mount))
+            .collect();
+        Ok(mounts)
+    }
+
+    fn names(&self) -> ConvertibleResult<NameTables> {
+        NameTables::select(&self.conn, &self.run, self.db_ref)
+    }
+}
+
+/// Writes deltas in one of the `cmp --format` formats. The deltas must come ordered by path.
+pub trait DeltaWriter {
+    fn write(&mut self, delta: &Delta<'_>) -> ConvertibleResult<()>;
+
+    /// Writes whatever is still buffered, and the trailer of the format if any.
+    fn finish(self: Box<Self>) -> ConvertibleResult<()>;
+}
+
+/// Opens the writer for `format`. `keep_unchanged` and `collapse` only affect the HTML report;
+/// the caller leaves out unchanged deltas unless `keep_unchanged` is set.
+fn writer<W: Write + 'static>(
+    format: &str,
+    output: W,
+    keep_unchanged: bool,
+    collapse: bool,
+    summary: report::ReportSummary,
+    id_map: &IdMap
+) -> ConvertibleResult<Box<dyn DeltaWriter>> {
+    Ok(match format {
+        "html" => Box::new(report::ReportWriter::new(output, keep_unchanged, collapse, summary)?),
+        "json" => Box::new(json_writer::JsonWriter::new(output, false, &summary)?),
+        "jsonl" => Box::new(json_writer::JsonWriter::new(output, true, &summary)?),
+        "csv" => Box::new(csv_writer::CsvWriter::new(output)?),
+        "text" => Box::new(text_writer::TextWriter::new(output)),
+        "sh" => Box::new(script_writer::ShellScriptWriter::new(output, summary, id_map.clone())?),
+        "rsync-filelist" => Box::new(script_writer::RsyncFileListWriter::new(output, &summary)?),
+        format => {
+            let error = AppError::WithMessage(format!("unknown report format '{}'", format));
+            log::error!("{}", error);
+            return Err(ErrorWrapper::AppError(error));
+        },
+    })
+}
+
 fn roots(args: &clap::ArgMatches<'_>, arg_name: &str) -> Vec<String> {
     log::debug!("collecting roots for '{}'...", arg_name);
     let mut roots = Vec::new();
@@ -103,6 +244,52 @@ fn roots(args: &clap::ArgMatches<'_>, arg_name: &str) -> Vec<String> {
     return roots;
 }
 
+/// The attributes that count towards being a change, from the `--mode`, `--mode-all` and `--mode-min` options.
+fn attributes(args: &clap::ArgMatches<'_>) -> ConvertibleResult<HashSet<Attribute>> {
+    let attrs_opt =
+        if args.is_present("mode-all") {
+            Some(Attribute::all())
+        } else if args.is_present("mode-min") {
+            Some(Attribute::minimum())
+        } else { None };
+
+    let attrs = match attrs_opt {
+        Some(a) => a,
+        None => {
+            match args.value_of("mode") {
+                Some(m) => Attribute::from_arg(m)?,
+                None => Attribute::medium(),
+            }
+        }
+    };
+    Ok(attrs)
+}
+
+/// Builds the uid/gid translation from the 'a' to the 'b' index from the `--map-users`,
+/// `--user-map` and `--group-map` options. Explicit map files override name-based mappings.
+fn id_map(args: &clap::ArgMatches<'_>, index_a: &IndexSnapshot<'_>, index_b: &IndexSnapshot<'_>) -> ConvertibleResult<IdMap> {
+    let mut id_map = IdMap::new();
+
+    if !(args.is_present("map-users") || args.is_present("user-map") || args.is_present("group-map")) {
+        return Ok(id_map);
+    }
+
+    let names_a = index_a.names()?;
+    let names_b = index_b.names()?;
+
+    if args.value_of("map-users") == Some("by-name") {
+        id_map.map_by_name(&names_a, &names_b);
+    }
+    if let Some(path) = args.value_of("user-map") {
+        id_map.load_file(path, IdKind::User, &names_a, &names_b)?;
+    }
+    if let Some(path) = args.value_of("group-map") {
+        id_map.load_file(path, IdKind::Group, &names_a, &names_b)?;
+    }
+
+    Ok(id_map)
+}
+
 fn validate_roots(roots: &Vec<String>) -> Result<(), HashSet<String>> {
     let mut invalid_roots: HashSet<String> = HashSet::new();
 
@@ -123,27 +310,62 @@ fn validate_roots(roots: &Vec<String>) -> Result<(), HashSet<String>> {
     Err(invalid_roots)
 }
 
-pub fn cmdline<'a>() -> clap::App<'a, 'a> {
+pub(crate) fn cmdline<'a>() -> clap::App<'a, 'a> {
     clap::App::new("cmp")
         .about("Compare two database files of indexing-runs and generate html report of differences.")
         .arg(clap::Arg::with_name("first-index")
             .short("a")
             .long("first-index")
-            .value_name("FILE")
-            .help("The first input database file.")
-            .required(true))
+            .value_name("FILE[@RUN]")
+            .help("The first input database file. For databases with several indexing runs,\n\
+                   RUN selects the run to compare (default: the latest).")
+            .required_unless("index"))
         .arg(clap::Arg::with_name("second-index")
             .short("b")
             .long("second-index")
-            .value_name("FILE")
-            .help("Second input database file.")
-            .required(true))
+            .value_name("FILE[@RUN]")
+            .help("Second input database file. Same format as --first-index.")
+            .required_unless("index"))
+        .arg(clap::Arg::with_name("index")
+            .long("index")
+            .value_name("FILE[@RUN]")
+            .multiple(true)
+            .number_of_values(1)
+            .conflicts_with_all(&[
+                "first-index", "second-index", "root-a", "root-b", "directory", "split", "emit-script", "collapse",
+                "streaming", "detect-renames"
+            ])
+            .next_line_help(true)
+            .help("Compare several indexes at once instead of 'a' with 'b', e.g. of the same deployment on many hosts.\n\
+                  Writes a matrix with a row per path that differs between the indexes and a column per index,\n\
+                  showing which variant of the node each index has: '.' for the majority, a letter for the others\n\
+                  and '-' where the path is missing. Give it for each index, each followed by its --root options.\n\
+                  --format text (default) or json; -u also lists the paths that are the same everywhere."))
+        .arg(clap::Arg::with_name("root")
+            .long("root")
+            .value_name("ROOT")
+            .multiple(true)
+            .number_of_values(1)
+            .requires("index")
+            .next_line_help(true)
+            .help("Add ROOT as a comparison root for the --index given before it. If an index has no root, '/' is assumed."))
         .arg(clap::Arg::with_name("directory")
             .short("o")
             .long("output-dir")
             .value_name("DIRECTORY")
-            .help("The directory to store the generated comparison report in.")
+            .next_line_help(true)
+            .help("Write the report into DIRECTORY instead of to stdout, as cmp_A_B_HASH.EXTENSION, named after both\n\
+                  databases and a hash of the databases and roots, so comparing the same again replaces the report.\n\
+                  Reports are written under a temporary name and renamed when complete.")
             .required(false))
+        .arg(clap::Arg::with_name("split")
+            .long("split")
+            .takes_value(false)
+            .requires("directory")
+            .next_line_help(true)
+            .help("Split the HTML report into an index page with the nodes right under the roots, and a page\n\
+                  for the nodes below each of those directories, in a directory next to the index page.\n\
+                  Use this if the report is too large for a browser to show."))
         .arg(clap::Arg::with_name("root-a")
             .long("root-a")
             .value_name("ROOT")
@@ -166,21 +388,43 @@ pub fn cmdline<'a>() -> clap::App<'a, 'a> {
             .value_name("MODE")
             .next_line_help(true)
             .help("What attributes should count towards being a change.\n\
-                  If not specified, defaults to 'csugpm'.\n\
+                  If not specified, defaults to 'csugpmh'.\n\
                   node(t)ype, (c)hecksum, (s)ize, (u)ser, (g)roup, (p)ermissions,\n\
-                  (b)irthdate, (m)odifieddate, (l)inksto, (i)node, (n)links"))
+                  (b)irthdate, (m)odifieddate, (l)inksto, (i)node, (n)links, (h)ardlinks"))
         .arg(clap::Arg::with_name("mode-all")
             .long("mode-all")
             .short("A")
             .conflicts_with_all(&["mode", "mode-min"])
             .takes_value(false)
-            .help("Enable all flags for mode. Equivalent to --mode tcsugpbmlin"))
+            .help("Enable all flags for mode. Equivalent to --mode tcsugpbmlinh"))
         .arg(clap::Arg::with_name("mode-min")
             .long("mode-min")
             .short("M")
             .conflicts_with_all(&["mode", "mode-all"])
             .takes_value(false)
             .help("Enable a small subset of flags for mode. Equivalent to --mode csm"))
+        .arg(clap::Arg::with_name("format")
+            .long("format")
+            .value_name("FORMAT")
+            .possible_values(&["html", "json", "jsonl", "csv", "text"])
+            .multiple(true)
+            .use_delimiter(true)
+            .number_of_values(1)
+            .next_line_help(true)
+            .help("Output format (default: html). Several formats, e.g. '--format html,json', need --output-dir.\n\
+                  json, jsonl and csv list each delta with its type, root, path, the node types on both sides\n\
+                  and every changed attribute with its old and new value;\n\
+                  text writes one line per delta like 'git status --short'."))
+        .arg(clap::Arg::with_name("emit-script")
+            .long("emit-script")
+            .value_name("KIND")
+            .possible_values(&["sh", "rsync-filelist"])
+            .next_line_help(true)
+            .help("Write what it takes to make the 'b' side like the 'a' side, instead of the report (or next to it\n\
+                  with --output-dir). 'sh': a shell script to review and run, with cp, mv, chmod, chown and touch;\n\
+                  deletions and replacements are grouped at the end and only run with 'sh SCRIPT --delete'.\n\
+                  'rsync-filelist': the paths to sync, separated by NULs, for\n\
+                  'rsync -a --from0 --files-from=FILE ROOT-A/ ROOT-B/'."))
         .arg(clap::Arg::with_name("collapse")
             .long("collapse")
             .short("c")
@@ -188,9 +432,52 @@ pub fn cmdline<'a>() -> clap::App<'a, 'a> {
             .help("If a directory is created or deleted, this option will collapse\n\
                    those directories and only write the parent directory to the report.\n\
                    Use this option if the report file becomes too large otherwise."))
+        .arg(clap::Arg::with_name("map-users")
+            .long("map-users")
+            .value_name("STRATEGY")
+            .possible_values(&["by-name"])
+            .next_line_help(true)
+            .help("Translate uids and gids between the indexes before comparing ownership.\n\
+                  'by-name': users and groups with the same name on both hosts are considered equal,\n\
+                  using the name tables recorded at index time. With --index, the ids of each index\n\
+                  are translated into those of the first."))
+        .arg(clap::Arg::with_name("user-map")
+            .long("user-map")
+            .value_name("FILE")
+            .next_line_help(true)
+            .help("Translate uids using FILE, which has one 'A:B' entry per line.\n\
+                  A and B are a uid or user name in the 'a' and 'b' index respectively,\n\
+                  e.g. '1000:1001' or 'www-data:nginx'. Overrides --map-users.\n\
+                  With --index, A is in each of the other indexes and B in the first."))
+        .arg(clap::Arg::with_name("group-map")
+            .long("group-map")
+            .value_name("FILE")
+            .next_line_help(true)
+            .help("Translate gids using FILE. Same format as --user-map."))
         .arg(clap::Arg::with_name("keep-unchanged")
             .long("keep-unchanged")
             .short("u")
             .takes_value(false)
             .help("Write unchanged files to report. Caution: this can lead to large report files."))
+        .arg(clap::Arg::with_name("streaming")
+            .long("streaming")
+            .takes_value(false)
+            .next_line_help(true)
+            .help("Read both indexes ordered by path and compare them as they are read, writing the report\n\
+                  as it goes, instead of loading them into memory. Use this for indexes too large for memory;\n\
+                  the report is the same."))
+        .arg(clap::Arg::with_name("detect-renames")
+            .long("detect-renames")
+            .takes_value(false)
+            .conflicts_with("streaming")
+            .next_line_help(true)
+            .help("Report a file deleted at one path and created at another with the same contents (checksum and size,\n\
+                  and inode if on the same filesystem) as moved, and likewise whole directories. Files matching\n\
+                  more than one file on the other side are not paired, but reported with their candidates."))
+        .arg(clap::Arg::with_name("verify-key")
+            .long("verify-key")
+            .value_name("FILE")
+            .next_line_help(true)
+            .help("Before comparing, check that both runs are signed with the Ed25519 public key (PEM) in FILE\n\
+                  and were not modified since, like verify-index does. Fails otherwise."))
 }
\ No newline at end of file
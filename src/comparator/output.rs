@@ -0,0 +1,200 @@
+use crate::ConvertibleResult;
+use crate::comparator::delta::Delta;
+use crate::comparator::id_map::IdMap;
+use crate::comparator::report::{ReportSummary, SplitReportWriter};
+use crate::comparator::{DeltaWriter, writer};
+use crate::db_models::indexing_run::RunRef;
+use crate::errorwrapper::app_error;
+use sha2::{Digest, Sha256};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufWriter};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// Numbers the temporary names of this process, see [ReportPath::unique_path]().
+static TEMP_COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+/// A report file or directory written under a temporary name in the output directory, and renamed into place
+/// by [ReportPath::commit]() once complete, so that a report is never seen half written.
+/// Left uncommitted, e.g. when the comparison fails, the temporary file or directory is removed.
+#[derive(Debug)]
+pub struct ReportPath {
+    dir: PathBuf,
+    name: String,
+    /// The temporary file or directory, once this run created it.
+    temp: Option<PathBuf>,
+    committed: bool,
+}
+
+impl ReportPath {
+
+    fn new(dir: &Path, name: &str) -> ReportPath {
+        ReportPath { dir: dir.to_path_buf(), name: name.to_string(), temp: None, committed: false }
+    }
+
+    fn path(&self) -> PathBuf {
+        self.dir.join(&self.name)
+    }
+
+    /// Creates a file or directory `.NAME.PID-N.SUFFIX` with `create`, which must fail if it exists,
+    /// so that concurrent comparisons, and files left behind by one that crashed, are never written to or removed.
+    fn unique_path<T>(&self, suffix: &str, create: impl Fn(&Path) -> io::Result<T>) -> ConvertibleResult<(PathBuf, T)> {
+        loop {
+            let n = TEMP_COUNTER.fetch_add(1, Ordering::Relaxed);
+            let path = self.dir.join(format!(".{}.{}-{}.{}", self.name, process::id(), n, suffix));
+            match create(&path) {
+                Ok(created) => return Ok((path, created)),
+                Err(e) if e.kind() == io::ErrorKind::AlreadyExists => continue,
+                Err(e) => return Err(e.into()),
+            }
+        }
+    }
+
+    fn create_file(&mut self) -> ConvertibleResult<BufWriter<File>> {
+        let (temp, file) = self.unique_path("tmp", |path| OpenOptions::new().write(true).create_new(true).open(path))?;
+        log::debug!("'{}': writing...", temp.display());
+        self.temp = Some(temp);
+        Ok(BufWriter::new(file))
+    }
+
+    fn create_dir(&mut self) -> ConvertibleResult<PathBuf> {
+        let (temp, ()) = self.unique_path("tmp", |path| fs::create_dir(path))?;
+        log::debug!("'{}': writing...", temp.display());
+        self.temp = Some(temp.clone());
+        Ok(temp)
+    }
+
+    /// Renames the report into place, replacing an earlier report of the same name. An earlier directory
+    /// is renamed aside first and removed once the new one is in place, so the report is never missing.
+    pub fn commit(mut self) -> ConvertibleResult<()> {
+        let temp = self.temp.clone().expect("report was written");
+        let path = self.path();
+        if temp.is_dir() && path.is_dir() {
+            // rename would replace an empty directory of that name, so take one that does not exist
+            let (old, ()) = self.unique_path("old", |old| match fs::symlink_metadata(old) {
+                Ok(_) => Err(io::ErrorKind::AlreadyExists.into()),
+                Err(_) => fs::rename(&path, old),
+            })?;
+            if let Err(e) = fs::rename(&temp, &path) {
+                let _ = fs::rename(&old, &path);
+                return Err(e.into());
+            }
+            self.committed = true;
+            if let Err(e) = fs::remove_dir_all(&old) {
+                log::warn!("'{}': could not remove the earlier report: {}", old.display(), e);
+            }
+        } else {
+            if !temp.is_dir() {
+                File::open(&temp)?.sync_all()?;
+            }
+            fs::rename(&temp, &path)?;
+            self.committed = true;
+        }
+        log::info!("wrote '{}'", path.display());
+        Ok(())
+    }
+}
+
+impl Drop for ReportPath {
+    fn drop(&mut self) {
+        let temp = match &self.temp {
+            Some(temp) if !self.committed => temp,
+            _ => return,
+        };
+        let removed = if temp.is_dir() { fs::remove_dir_all(temp) } else { fs::remove_file(temp) };
+        if removed.is_ok() {
+            log::debug!("'{}': removed incomplete report", temp.display());
+        }
+    }
+}
+
+/// Writes every delta to each of the writers, for several formats in one run.
+struct Tee(Vec<Box<dyn DeltaWriter>>);
+
+impl DeltaWriter for Tee {
+
+    fn write(&mut self, delta: &Delta<'_>) -> ConvertibleResult<()> {
+        for writer in self.0.iter_mut() {
+            writer.write(delta)?;
+        }
+        Ok(())
+    }
+
+    fn finish(self: Box<Self>) -> ConvertibleResult<()> {
+        for writer in self.0 {
+            writer.finish()?;
+        }
+        Ok(())
+    }
+}
+
+/// The base name of the reports comparing `db_ref_a` under `roots_a` with `db_ref_b` under `roots_b`:
+/// both database file names, with the run if given, and a hash of both database references and roots,
+/// e.g. `cmp_magnetar-1606312134@2_magnetar-1606312135_3f2a9c1d`. Comparing the same again gives the same name.
+pub fn report_name(db_ref_a: &str, roots_a: &[String], db_ref_b: &str, roots_b: &[String]) -> String {
+    let mut hasher = Sha256::new();
+    for (db_ref, roots) in [(db_ref_a, roots_a), (db_ref_b, roots_b)].iter() {
+        hasher.update(db_ref.as_bytes());
+        for root in roots.iter() {
+            hasher.update(b"\0");
+            hasher.update(root.as_bytes());
+        }
+        hasher.update(b"\n");
+    }
+    let hash = hex::encode(hasher.finalize());
+
+    let name = |db_ref: &str| {
+        let run_ref = RunRef::parse(db_ref);
+        let stem = Path::new(&run_ref.db_path).file_stem()
+            .map(|stem| stem.to_string_lossy().into_owned())
+            .unwrap_or_default();
+        match run_ref.run_id {
+            Some(run_id) => format!("{}@{}", stem, run_id),
+            None => stem,
+        }
+    };
+    format!("cmp_{}_{}_{}", name(db_ref_a), name(db_ref_b), &hash[..8])
+}
+
+/// Opens a writer for each of `formats`, writing to `DIR/NAME.EXTENSION` given `base` as `DIR/NAME`. With `split`,
+/// the HTML report is split into an index page `NAME.html` and a page per top-level directory in `NAME/`.
+/// The reports are to be committed once the writer is finished.
+pub fn open(
+    base: &Path,
+    formats: &[&str],
+    split: bool,
+    keep_unchanged: bool,
+    collapse: bool,
+    summary: &ReportSummary,
+    id_map: &IdMap
+) -> ConvertibleResult<(Box<dyn DeltaWriter>, Vec<ReportPath>)> {
+    let dir = base.parent().filter(|dir| !dir.as_os_str().is_empty()).unwrap_or_else(|| Path::new("."));
+    let name = base.file_name().map(|name| name.to_string_lossy().into_owned()).unwrap_or_default();
+    if !dir.is_dir() {
+        return Err(app_error(format!("'{}': output directory does not exist", dir.display())));
+    }
+
+    let mut writers: Vec<Box<dyn DeltaWriter>> = Vec::new();
+    let mut paths = Vec::new();
+    for &format in formats.iter() {
+        let extension = match format {
+            "text" => "txt",
+            "rsync-filelist" => "files",
+            format => format,
+        };
+        let mut path = ReportPath::new(dir, &format!("{}.{}", name, extension));
+        if format == "html" && split {
+            let mut pages = ReportPath::new(dir, &name);
+            let pages_dir = pages.create_dir()?;
+            writers.push(Box::new(SplitReportWriter::new(
+                path.create_file()?, pages_dir, name.clone(), keep_unchanged, collapse, summary.clone()
+            )));
+            paths.push(pages);
+        } else {
+            writers.push(writer(format, path.create_file()?, keep_unchanged, collapse, summary.clone(), id_map)?);
+        }
+        paths.push(path);
+    }
+    Ok((Box::new(Tee(writers)), paths))
+}
@@ -0,0 +1,373 @@
+use crate::comparator::delta::{self, Delta, DeltaType};
+use crate::comparator::id_map::IdMap;
+use crate::comparator::virtual_fs_node::VirtualFsNode;
+use crate::db_models::fs_node::{self, NodeType};
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+
+/// Pairs creations with deletions of the same node into moves, see `cmp --detect-renames`.
+///
+/// Directories are paired first: a deleted directory is moved to a created directory if both hold
+/// the same paths with the same node types, and at least one file below it has the same contents on both sides.
+/// The nodes below a moved directory are left out unless they changed besides being moved.
+/// The remaining files are paired if their contents match; files that match more than one file on the
+/// other side are left unpaired, and annotated with their candidates instead.
+///
+/// `deltas` must be ordered by path, and are returned ordered by path, with moves at their new path.
+pub fn detect_renames<'a>(deltas: Vec<Delta<'a>>, id_map: &IdMap) -> Vec<Delta<'a>> {
+    let mut created: BTreeMap<String, Delta<'a>> = BTreeMap::new();
+    let mut deleted: BTreeMap<String, Delta<'a>> = BTreeMap::new();
+    let mut others = Vec::with_capacity(deltas.len());
+    for delta in deltas {
+        match delta.delta_type() {
+            DeltaType::Creation => { created.insert(delta.virtual_path_str().to_string(), delta); },
+            DeltaType::Deletion => { deleted.insert(delta.virtual_path_str().to_string(), delta); },
+            _ => others.push(delta),
+        }
+    }
+    log::debug!("detecting renames among {} creations and {} deletions...", created.len(), deleted.len());
+
+    let by_content = ContentIndex::new(&created);
+    // (from, to, whether below a moved directory)
+    let mut pairs: Vec<(String, String, bool)> = Vec::new();
+    let mut paired_from: HashSet<String> = HashSet::new();
+    let mut paired_to: HashSet<String> = HashSet::new();
+    let mut candidates: HashMap<String, Vec<String>> = HashMap::new();
+
+    // directories, outermost first
+    for (from, deletion) in deleted.iter() {
+        if !deletion.file_type().is_dir() || from.is_empty() || paired_from.contains(from) {
+            continue;
+        }
+        let targets: BTreeSet<String> = dir_targets(from, &deleted, &created, &by_content).into_iter()
+            .filter(|to| !paired_to.contains(to) && same_tree(from, to, &deleted, &created))
+            .collect();
+        match targets.len() {
+            0 => {},
+            1 => {
+                let to = targets.into_iter().next().unwrap();
+                log::debug!("directory moved: '{}' -> '{}'", from, to);
+                for (path, _) in below(&deleted, from) {
+                    let relative = &path[from.len()..];
+                    pairs.push((path.clone(), format!("{}{}", to, relative), true));
+                    paired_from.insert(path.clone());
+                }
+                paired_to.extend(below(&created, &to).map(|(path, _)| path.clone()));
+                pairs.push((from.clone(), to.clone(), false));
+                paired_from.insert(from.clone());
+                paired_to.insert(to);
+            },
+            _ => { candidates.insert(from.clone(), targets.into_iter().collect()); },
+        }
+    }
+
+    // the remaining files
+    let mut from_candidates: HashMap<String, Vec<String>> = HashMap::new();
+    for (from, deletion) in deleted.iter() {
+        if paired_from.contains(from) || !is_movable_file(deletion.b().unwrap()) {
+            continue;
+        }
+        let targets: Vec<String> = by_content.matches(deletion.b().unwrap()).into_iter()
+            .filter(|to| !paired_to.contains(*to))
+            .map(|to| to.to_string())
+            .collect();
+        for to in targets.iter() {
+            from_candidates.entry(to.clone()).or_default().push(from.clone());
+        }
+        if !targets.is_empty() {
+            candidates.insert(from.clone(), targets);
+        }
+    }
+    for (to, froms) in from_candidates.into_iter() {
+        let from = &froms[0];
+        if froms.len() == 1 && candidates[from].len() == 1 {
+            pairs.push((from.clone(), to.clone(), false));
+            paired_from.insert(from.clone());
+            paired_to.insert(to);
+        } else {
+            candidates.insert(to, froms);
+        }
+    }
+    candidates.retain(|path, _| !paired_from.contains(path) && !paired_to.contains(path));
+    log::debug!("{} nodes moved", pairs.len());
+
+    let mut moved: Vec<Delta<'a>> = Vec::with_capacity(pairs.len());
+    for (from, to, in_moved_dir) in pairs {
+        let creation = created.remove(&to).expect("paired creation");
+        let deletion = deleted.remove(&from).expect("paired deletion");
+        let delta = Delta::moved(creation, deletion, id_map);
+        // below a moved directory, only nodes that changed besides being moved are of interest
+        if !in_moved_dir || !delta.changes().is_empty() {
+            moved.push(delta);
+        }
+    }
+
+    let mut result: Vec<Delta<'a>> = others;
+    result.extend(moved);
+    for (path, mut delta) in created.into_iter().chain(deleted) {
+        if let Some(paths) = candidates.remove(&path) {
+            let side = if delta.delta_type() == &DeltaType::Creation { "created" } else { "deleted" };
+            log::warn!("'{}' ({}) may have been moved from or to any of {}; not pairing it", path, side, paths.join(", "));
+            delta.set_move_candidates(paths);
+        }
+        result.push(delta);
+    }
+    result.sort_by(|a, b| a.virtual_path_str().cmp(b.virtual_path_str()));
+    result
+}
+
+/// The created files, by each of their digests; digests of different kinds differ in length, so they can share a map.
+struct ContentIndex<'c, 'a> {
+    by_digest: HashMap<&'c str, Vec<(&'c str, &'c VirtualFsNode<'a>)>>,
+}
+
+impl<'c, 'a> ContentIndex<'c, 'a> {
+
+    fn new(created: &'c BTreeMap<String, Delta<'a>>) -> ContentIndex<'c, 'a> {
+        let mut by_digest: HashMap<&'c str, Vec<(&'c str, &'c VirtualFsNode<'a>)>> = HashMap::new();
+        for (path, creation) in created.iter() {
+            let vnode = creation.a().unwrap();
+            if !is_movable_file(vnode) {
+                continue;
+            }
+            let fs_node = vnode.fs_node;
+            let digests = [&fs_node.sha1_checksum, &fs_node.sha256_checksum, &fs_node.md5_checksum];
+            for digest in digests.iter().filter(|digest| !digest.is_empty()) {
+                by_digest.entry(digest.as_str()).or_default().push((path.as_str(), vnode));
+            }
+        }
+        ContentIndex { by_digest }
+    }
+
+    /// The paths of the created files with the same contents as the deleted file.
+    fn matches(&self, vnode_b: &VirtualFsNode<'_>) -> Vec<&'c str> {
+        let fs_node = vnode_b.fs_node;
+        let digests = [&fs_node.sha1_checksum, &fs_node.sha256_checksum, &fs_node.md5_checksum];
+        let mut paths: Vec<&'c str> = digests.iter()
+            .filter(|digest| !digest.is_empty())
+            .filter_map(|digest| self.by_digest.get(digest.as_str()))
+            .flatten()
+            .filter(|(_, vnode_a)| same_contents(vnode_a, vnode_b))
+            .map(|(path, _)| *path)
+            .collect();
+        paths.sort_unstable();
+        paths.dedup();
+        paths
+    }
+}
+
+/// Files whose contents can be matched: not empty, since empty files all look alike, and not changed while hashed.
+fn is_movable_file(vnode: &VirtualFsNode<'_>) -> bool {
+    let fs_node = vnode.fs_node;
+    fs_node.node_type == NodeType::File && !fs_node.unstable && fs_node.size != 0
+}
+
+/// Whether the files have the same contents (digest and size), and the same inode if both are known to be
+/// on the same filesystem, since a rename within a filesystem keeps the inode.
+fn same_contents(vnode_a: &VirtualFsNode<'_>, vnode_b: &VirtualFsNode<'_>) -> bool {
+    let (aaa, bbb) = (vnode_a.fs_node, vnode_b.fs_node);
+    let same_digest = match delta::common_digest(aaa, bbb) {
+        Some((_, digest_a, digest_b)) => digest_a == digest_b,
+        None => false,
+    };
+    let knows = |attr| aaa.knows(attr) && bbb.knows(attr);
+    let same_size = !knows(fs_node::ATTR_SIZE) || aaa.size == bbb.size;
+    let same_filesystem = match (vnode_a.mount, vnode_b.mount) {
+        (Some(mount_a), Some(mount_b)) => mount_a.is_same_filesystem(mount_b),
+        _ => false,
+    };
+    let same_inode = !same_filesystem || !knows(fs_node::ATTR_INODE) || aaa.inode == bbb.inode;
+    same_digest && same_size && same_inode
+}
+
+/// The created directories the deleted directory `from` may have been moved to,
+/// going by where the files below it were moved to.
+fn dir_targets(
+    from: &str,
+    deleted: &BTreeMap<String, Delta<'_>>,
+    created: &BTreeMap<String, Delta<'_>>,
+    by_content: &ContentIndex<'_, '_>,
+) -> BTreeSet<String> {
+    let mut targets = BTreeSet::new();
+    for (path, deletion) in below(deleted, from) {
+        let vnode = deletion.b().unwrap();
+        if !is_movable_file(vnode) {
+            continue;
+        }
+        let relative = &path[from.len()..];
+        for to in by_content.matches(vnode) {
+            if let Some(target) = to.strip_suffix(relative) {
+                if created.get(target).is_some_and(|creation| creation.file_type().is_dir()) {
+                    targets.insert(target.to_string());
+                }
+            }
+        }
+    }
+    targets
+}
+
+/// Whether the deleted directory `from` and the created directory `to` hold the same relative paths,
+/// with the same node types and symlink targets.
+fn same_tree(from: &str, to: &str, deleted: &BTreeMap<String, Delta<'_>>, created: &BTreeMap<String, Delta<'_>>) -> bool {
+    let mut below_from = below(deleted, from);
+    let mut below_to = below(created, to);
+    loop {
+        match (below_from.next(), below_to.next()) {
+            (None, None) => return true,
+            (Some((path_b, deletion)), Some((path_a, creation))) => {
+                let (bbb, aaa) = (deletion.b().unwrap().fs_node, creation.a().unwrap().fs_node);
+                if path_b[from.len()..] != path_a[to.len()..]
+                    || aaa.node_type != bbb.node_type
+                    || (aaa.node_type == NodeType::Symlink && aaa.links_to != bbb.links_to) {
+                    return false;
+                }
+            },
+            _ => return false,
+        }
+    }
+}
+
+/// The entries of `deltas` below the directory `dir`, in order.
+fn below<'m, 'a>(deltas: &'m BTreeMap<String, Delta<'a>>, dir: &str) -> impl Iterator<Item = (&'m String, &'m Delta<'a>)> {
+    let prefix = format!("{}/", dir);
+    deltas.range(prefix.clone()..).take_while(move |(path, _)| path.starts_with(&prefix))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::comparator::compare::{self, VFsNodeMap};
+    use crate::comparator::delta::Attribute;
+    use crate::comparator::test_nodes::{file, pool};
+    use crate::db_models::fs_node::FsNode;
+    use crate::db_models::mount::Mount;
+
+    /// A file with `contents` as its checksum, and the inode given by its first character.
+    fn with(path: &str, contents: &str) -> FsNode {
+        FsNode {
+            sha1_checksum: contents.to_string(),
+            inode: i64::from(contents.as_bytes()[0]),
+            ..file(path, contents.len() as i64, 1000)
+        }
+    }
+
+    fn dir(path: &str) -> FsNode {
+        FsNode { node_type: NodeType::Directory, size: 4096, ..file(path, 0, 1000) }
+    }
+
+    fn on<'a>(mount: &'a Mount, mut pool: VFsNodeMap<'a>) -> VFsNodeMap<'a> {
+        for vnode in pool.values_mut() {
+            vnode.mount = Some(mount);
+        }
+        pool
+    }
+
+    /// A line per delta: `+`, `-` or `M` and the path, or the path a node was moved to and from.
+    /// Followed by the changed attributes, and the move candidates if any.
+    fn summary(deltas: Vec<Delta<'_>>) -> Vec<String> {
+        deltas.iter()
+            .filter(|delta| !delta.delta_type().is_unchanged())
+            .map(|delta| {
+                let mut line = match delta.delta_type() {
+                    DeltaType::Creation => format!("+ {}", delta.virtual_path_str()),
+                    DeltaType::Deletion => format!("- {}", delta.virtual_path_str()),
+                    DeltaType::Move { from, .. } => format!("{} <- {}", delta.virtual_path_str(), from),
+                    _ => format!("M {}", delta.virtual_path_str()),
+                };
+                if !delta.changes().is_empty() {
+                    let names: Vec<&str> = delta.changes().iter().map(|change| change.name()).collect();
+                    line.push_str(&format!(" ({})", names.join(", ")));
+                }
+                if !delta.move_candidates().is_empty() {
+                    line.push_str(&format!(" [{}]", delta.move_candidates().join(", ")));
+                }
+                line
+            })
+            .collect()
+    }
+
+    fn renames<'a>(pool_a: VFsNodeMap<'a>, pool_b: VFsNodeMap<'a>) -> Vec<String> {
+        let attrs: HashSet<Attribute> = vec![
+            Attribute::NodeType, Attribute::Checksum, Attribute::Size, Attribute::Permissions, Attribute::Inode,
+        ].into_iter().collect();
+        let id_map = IdMap::new();
+        summary(detect_renames(compare::compare(pool_a, pool_b, &attrs, &id_map), &id_map))
+    }
+
+    fn detected(a: &[FsNode], b: &[FsNode]) -> Vec<String> {
+        renames(pool(a), pool(b))
+    }
+
+    #[test]
+    fn file_moved() {
+        assert_eq!(detected(&[with("b", "one")], &[with("a", "one")]), vec!["b <- a"]);
+        assert_eq!(detected(&[with("b", "two")], &[with("a", "one")]), vec!["- a", "+ b"]);
+        let moved = FsNode { permissions: 0o100600, ..with("d/b", "one") };
+        assert_eq!(detected(&[dir("d"), moved], &[with("a", "one")]), vec!["+ d", "d/b <- a (permissions)"]);
+    }
+
+    #[test]
+    fn empty_and_unstable_files_not_paired() {
+        assert_eq!(detected(&[file("b", 0, 1000)], &[file("a", 0, 1000)]), vec!["- a", "+ b"]);
+        let unstable = |path| FsNode { unstable: true, ..with(path, "one") };
+        assert_eq!(detected(&[unstable("b")], &[with("a", "one")]), vec!["- a", "+ b"]);
+        assert_eq!(detected(&[with("b", "one")], &[unstable("a")]), vec!["- a", "+ b"]);
+    }
+
+    #[test]
+    fn ambiguous_candidates() {
+        assert_eq!(detected(&[with("b", "one")], &[with("a", "one"), with("c", "one")]),
+            vec!["- a [b]", "+ b [a, c]", "- c [b]"]);
+        assert_eq!(detected(&[with("a", "one"), with("c", "one")], &[with("b", "one")]),
+            vec!["+ a [b]", "- b [a, c]", "+ c [b]"]);
+        // the other files are paired regardless
+        assert_eq!(detected(&[with("b", "one"), with("y", "two")], &[with("a", "one"), with("c", "one"), with("x", "two")]),
+            vec!["- a [b]", "+ b [a, c]", "- c [b]", "y <- x"]);
+    }
+
+    #[test]
+    fn directory_moved_as_a_whole() {
+        let b = [dir("old"), with("old/x", "one"), dir("old/sub"), with("old/sub/y", "two")];
+        let a = [dir("new"), with("new/x", "one"), dir("new/sub"), with("new/sub/y", "two")];
+        assert_eq!(detected(&a, &b), vec!["new <- old"]);
+
+        // nodes below it that changed besides being moved are listed too
+        let a = [dir("new"), with("new/x", "one"), dir("new/sub"), with("new/sub/y", "three")];
+        assert_eq!(detected(&a, &b), vec!["new <- old", "new/sub/y <- old/sub/y (size, sha1)"]);
+        let a = [dir("new"), with("new/x", "one"), FsNode { permissions: 0o40700, ..dir("new/sub") }, with("new/sub/y", "two")];
+        assert_eq!(detected(&a, &b), vec!["new <- old", "new/sub <- old/sub (permissions)"]);
+    }
+
+    #[test]
+    fn directory_with_other_contents_not_moved() {
+        let b = [dir("old"), with("old/x", "one"), with("old/y", "two")];
+        assert_eq!(detected(&[dir("new"), with("new/x", "one")], &b), vec!["+ new", "new/x <- old/x", "- old", "- old/y"]);
+        let a = [dir("new"), with("new/x", "one"), with("new/y", "two"), with("new/z", "three")];
+        assert_eq!(detected(&a, &b), vec!["+ new", "new/x <- old/x", "new/y <- old/y", "+ new/z", "- old"]);
+        // with nothing to go by but the paths
+        let b = [dir("old"), with("old/x", "one")];
+        assert_eq!(detected(&[dir("new"), with("new/x", "two")], &b), vec!["+ new", "+ new/x", "- old", "- old/x"]);
+    }
+
+    #[test]
+    fn ambiguous_directories() {
+        let b = [dir("old"), with("old/x", "one")];
+        let a = [dir("new1"), with("new1/x", "one"), dir("new2"), with("new2/x", "one")];
+        assert_eq!(detected(&a, &b), vec![
+            "+ new1", "+ new1/x [old/x]", "+ new2", "+ new2/x [old/x]", "- old [new1, new2]", "- old/x [new1/x, new2/x]",
+        ]);
+    }
+
+    #[test]
+    fn inodes_compared_on_same_filesystem() {
+        let host = |host: &str| Mount { device: 0x801, fs_type: "ext4".to_string(), host: Some(host.to_string()), ..Default::default() };
+        let (web1, web2) = (host("web1"), host("web2"));
+        let b = [with("a", "one")];
+        let a = [FsNode { inode: 7, ..with("b", "one") }];
+        assert_eq!(renames(on(&web1, pool(&a)), on(&web1, pool(&b))), vec!["- a", "+ b"]);
+        assert_eq!(renames(on(&web1, pool(&a)), on(&web2, pool(&b))), vec!["b <- a"]);
+        // paired if the filesystem is unknown, but then the inodes are taken to be comparable, like in any delta
+        assert_eq!(renames(pool(&a), on(&web1, pool(&b))), vec!["b <- a (inode)"]);
+        let a = [with("b", "one")];
+        assert_eq!(renames(on(&web1, pool(&a)), on(&web1, pool(&b))), vec!["b <- a"]);
+    }
+}
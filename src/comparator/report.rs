@@ -1,8 +1,13 @@
-use crate::comparator::delta::{Delta, DeltaType};
-use std::io::Write;
+use crate::ConvertibleResult;
+use crate::comparator::DeltaWriter;
+use crate::comparator::delta::{Attribute, AttributeChange, Delta, DeltaType, Value};
+use crate::util::unix_perms::Permission;
+use chrono::TimeZone;
+use std::collections::{BTreeMap, HashSet};
+use std::fs::File;
+use std::io::{BufWriter, Write};
 use std::io;
 use std::path::PathBuf;
-use crate::db_models::fs_node::NodeType;
 
 const TR: &str =
     r#"<tr class="${class}">
@@ -12,140 +17,372 @@ const TR: &str =
         <td>${info}</td>
     </tr>"#;
 
+/// The row of the index page of a split report linking to the page of a top-level directory.
+const PAGE_TR: &str =
+    r#"<tr class="page">
+        <td>${root}</td>
+        <td class="slim">D</td>
+        <td><a href="${href}">${vpath}/</a></td>
+        <td>${info}</td>
+    </tr>"#;
+
 const SPAN: &str = "<span class=\"path\">{}/</span>";
 
+#[derive(Clone)]
 pub struct ReportSummary {
     pub db_a_name: String,
     pub db_b_name: String,
     pub roots_a: Vec<String>,
     pub roots_b: Vec<String>,
+    pub detect_renames: bool,
 }
 
-/// Prints the generated report to the given stream.
-pub fn write(mut out_stream: impl Write, deltas: Vec<Delta<'_>>, keep_unchanged: bool, collapse: bool, summary: ReportSummary) -> io::Result<()> {
-    let template = include_str!("report.html");
+/// Writes the HTML report to the given stream as the deltas come in; they must come ordered by path.
+/// With `collapse`, only the topmost of created or deleted directories is written.
+pub struct ReportWriter<W: Write> {
+    out_stream: W,
 
-    let output_html = summary.into_html(template);
+    /// The rest of the template, after the rows.
+    tail: String,
 
-    let deltas = process_unchanged(deltas, keep_unchanged);
-    let deltas = process_collapse(deltas, collapse);
+    collapse: bool,
 
-    let rows = make_rows(&deltas, collapse);
+    /// The created or deleted directory being collapsed.
+    current_parent: Option<PathBuf>,
 
-    let mut output_html = output_html.replace("${rows}", rows.as_str());
-    output_html = output_html.replace("${keep-unchanged}", if keep_unchanged {""} else {"hidden"});
+    rows: usize,
+    skipped: usize,
+}
 
-    let bytes_written = out_stream.write(output_html.as_bytes())?;
+impl<W: Write> ReportWriter<W> {
 
-    log::debug!("wrote {} bytes to output stream.", bytes_written);
+    /// Writes the report up to the rows. `keep_unchanged` only shows the filter for unchanged rows,
+    /// the caller decides which deltas to write.
+    pub fn new(mut out_stream: W, keep_unchanged: bool, collapse: bool, summary: ReportSummary) -> io::Result<ReportWriter<W>> {
+        let template = include_str!("report.html");
 
-    Ok(())
-}
+        let output_html = summary.into_html(template);
+        let output_html = output_html.replace("${keep-unchanged}", if keep_unchanged {""} else {"hidden"});
 
-fn make_rows(deltas: &Vec<Delta<'_>>, pre_collapsed: bool) -> String {
-    let mut rows = String::new();
-
-    for delta in deltas {
-        let is_delta_precollapsed = pre_collapsed && delta.delta_type().is_created_or_deleted();
-        let mut row = TR.replace("${class}", delta.delta_type().css_class());
-        row = row.replace("${root}", delta.root_path_str());
-        row = row.replace("${ftype}", delta.file_type().to_str());
-        row = row.replace("${vpath_attrs}",
-                          if !is_delta_precollapsed && delta.file_type().is_dir() {
-                              " class=\"dir\" onclick=\"onclickCollapse(this)\""
-                          } else {
-                              ""
-                          });
-
-        let v_path_str = delta.virtual_path_str();
-        let v_path_buf = PathBuf::from(v_path_str);
-        let v_path = match v_path_buf.parent() {
-            None => {
-                format!("{}", v_path_str)
-            },
-            Some(parent) => {
-                let parent = parent.to_str().unwrap();
-                if parent.is_empty() {
-                    format!("{}", v_path_str)
-                } else {
-                    let parent_span = SPAN.replace("{}", parent);
-                    let name = v_path_buf.file_name().unwrap().to_str().unwrap();
-                    format!("{}{}", parent_span, name)
-                }
-            },
-        };
-        let v_path = format!("{}{}", v_path.as_str(), if is_delta_precollapsed && delta.file_type().is_dir() {" [...]"} else {""});
-        row = row.replace("${vpath}", v_path.as_str());
+        let (head, tail) = output_html.split_at(output_html.find("${rows}").expect("report template has rows"));
+        out_stream.write_all(head.as_bytes())?;
 
-        let delta_info = delta.delta_info();
-        row = row.replace("${info}", delta_info.as_str());
+        if collapse {
+            log::debug!("collapsing created or deleted directories...");
+        }
 
-        rows.push_str(row.as_str());
-        rows.push_str("\n");
+        Ok(ReportWriter {
+            out_stream,
+            tail: tail["${rows}".len()..].to_string(),
+            collapse,
+            current_parent: None,
+            rows: 0,
+            skipped: 0,
+        })
     }
 
-    rows
+    fn write_row(&mut self, row: &str) -> io::Result<()> {
+        self.out_stream.write_all(row.as_bytes())?;
+        self.out_stream.write_all(b"\n")?;
+        self.rows += 1;
+        Ok(())
+    }
+
+    /// Whether the delta is below a created or deleted directory, that is written as one row instead.
+    /// IMPORTANT: this relies on the deltas coming ordered by path.
+    fn is_collapsed(&mut self, delta: &Delta<'_>) -> bool {
+        if !delta.delta_type().is_created_or_deleted() {
+            return false;
+        }
+        let vpath = PathBuf::from(delta.virtual_path_str());
+
+        if delta.file_type().is_dir() {
+            let new_parent = vpath.clone();
+            match &self.current_parent {
+                None => {
+                    log::trace!("new parent: '{}'", new_parent.to_string_lossy());
+                    self.current_parent = Some(new_parent);
+                },
+                Some(parent) => {
+                    if !new_parent.starts_with(parent) {
+                        log::trace!("new parent: '{}'", new_parent.to_string_lossy());
+                        self.current_parent = Some(new_parent);
+                    }
+                },
+            }
+        }
+
+        if let Some(parent) = &self.current_parent {
+            if vpath != *parent && vpath.starts_with(parent) {
+                log::trace!("skipping '{}'", vpath.to_string_lossy());
+                return true;
+            }
+        }
+        false
+    }
 }
 
-/// If `keep_unchanged` is set, returns the original list. Otherwise, returns the
-/// list without deltas of type unchanged.
-fn process_unchanged(deltas: Vec<Delta<'_>>, keep_unchanged: bool) -> Vec<Delta<'_>> {
-    if keep_unchanged {
-        deltas
-    } else {
-        deltas
-            .into_iter()
-            .filter(|delta| !delta.delta_type().is_unchanged())
-            .collect()
+impl<W: Write> DeltaWriter for ReportWriter<W> {
+
+    fn write(&mut self, delta: &Delta<'_>) -> ConvertibleResult<()> {
+        if self.collapse && self.is_collapsed(delta) {
+            self.skipped += 1;
+            return Ok(());
+        }
+
+        self.write_row(&make_row(delta, self.collapse))?;
+        Ok(())
+    }
+
+    /// Writes the rest of the report.
+    fn finish(mut self: Box<Self>) -> ConvertibleResult<()> {
+        if self.collapse {
+            log::debug!("collapse done. items skipped: {}", self.skipped);
+        }
+        self.out_stream.write_all(self.tail.as_bytes())?;
+        self.out_stream.flush()?;
+
+        log::debug!("wrote {} rows to output stream.", self.rows);
+        Ok(())
     }
 }
 
-/// IMPORTANT: this function assumes the input `deltas` are ordered by path.
-/// If `collapse` is set, collapses the result such that created or deleted
-/// directories only show up as 1 row with how many nodes there are under that node.
-fn process_collapse(deltas: Vec<Delta<'_>>, collapse: bool) -> Vec<Delta<'_>> {
-    if !collapse {
-        return deltas;
+/// Writes the HTML report split into pages, for comparisons too large for a browser to show on one page:
+/// the nodes below each top-level directory (a directory right under a root) go on a page of their own
+/// in `pages_dir`, and the top-level nodes on the index page, with a link to the page after each directory.
+/// Since the deltas come ordered by path, only a few pages are open at a time.
+pub struct SplitReportWriter<W: Write> {
+    /// Written when finished, once the pages are known.
+    index: W,
+    pages_dir: PathBuf,
+
+    /// The name of the pages directory once in place, relative to the index page.
+    pages_name: String,
+
+    keep_unchanged: bool,
+    collapse: bool,
+    summary: ReportSummary,
+
+    /// The rows of the index page, by path; the link to a page comes right after the row of its directory.
+    index_rows: Vec<((String, bool), String)>,
+
+    /// The pages being written, by top-level directory.
+    open_pages: BTreeMap<String, Page>,
+    page_names: HashSet<String>,
+
+    /// With `collapse`, the top-level directories created or deleted as a whole, whose nodes are left out.
+    collapsed: HashSet<String>,
+}
+
+struct Page {
+    root: String,
+    file_name: String,
+    writer: ReportWriter<BufWriter<File>>,
+}
+
+impl<W: Write> SplitReportWriter<W> {
+
+    pub fn new(index: W, pages_dir: PathBuf, pages_name: String, keep_unchanged: bool, collapse: bool, summary: ReportSummary)
+        -> SplitReportWriter<W>
+    {
+        SplitReportWriter {
+            index,
+            pages_dir,
+            pages_name,
+            keep_unchanged,
+            collapse,
+            summary,
+            index_rows: Vec::new(),
+            open_pages: BTreeMap::new(),
+            page_names: HashSet::new(),
+            collapsed: HashSet::new(),
+        }
     }
 
-    log::debug!("collapsing created or deleted directories...");
-    let start_count = deltas.len();
-    let mut current_parent = None;
-    let mut filtered = Vec::new();
+    fn open_page(&mut self, top: &str, root: &str) -> io::Result<Page> {
+        // a file name of letters, digits, '.', '_' and '-', unique among the pages
+        let mut stem: String = top.chars()
+            .map(|c| if c.is_ascii_alphanumeric() || c == '.' || c == '_' || c == '-' { c } else { '_' })
+            .collect();
+        if stem.starts_with('.') {
+            stem.insert(0, '_');
+        }
+        let mut file_name = format!("{}.html", stem);
+        let mut n = 1;
+        while !self.page_names.insert(file_name.clone()) {
+            n += 1;
+            file_name = format!("{}-{}.html", stem, n);
+        }
 
-    for delta in deltas {
-        if delta.delta_type().is_created_or_deleted() {
-            let vpath = PathBuf::from(delta.virtual_path_str());
+        log::debug!("'{}': opening page '{}'", top, file_name);
+        let output = BufWriter::new(File::create(self.pages_dir.join(&file_name))?);
+        let writer = ReportWriter::new(output, self.keep_unchanged, self.collapse, self.summary.clone())?;
+        Ok(Page { root: root.to_string(), file_name, writer })
+    }
 
-            if delta.file_type().is_dir() {
-                let new_parent = vpath.clone();
-                match &current_parent {
-                    None => {
-                        log::trace!("new parent: '{}'", new_parent.to_string_lossy());
-                        current_parent = Some(new_parent);
-                    },
-                    Some(parent) => {
-                        if !new_parent.starts_with(parent) {
-                            log::trace!("new parent: '{}'", new_parent.to_string_lossy());
-                            current_parent = Some(new_parent);
-                        }
-                    },
-                }
-            }
+    /// Finishes the page and adds the link to it to the index.
+    fn close_page(&mut self, top: String, page: Page) -> ConvertibleResult<()> {
+        let rows = page.writer.rows;
+        Box::new(page.writer).finish()?;
 
-            if let Some(parent) = &current_parent {
-                if vpath != *parent && vpath.starts_with(parent) {
-                    log::trace!("skipping '{}'", vpath.to_string_lossy());
-                    continue;
+        let row = PAGE_TR.replace("${root}", &page.root)
+            .replace("${href}", &format!("{}/{}", self.pages_name, page.file_name))
+            .replace("${vpath}", &top)
+            .replace("${info}", &format!("{} rows", rows));
+        self.index_rows.push(((top, true), row));
+        Ok(())
+    }
+}
+
+impl<W: Write> DeltaWriter for SplitReportWriter<W> {
+
+    fn write(&mut self, delta: &Delta<'_>) -> ConvertibleResult<()> {
+        let path = delta.virtual_path_str();
+
+        // the paths below a directory come in one stretch, so its page is done once a path after them comes
+        let done: Vec<String> = self.open_pages.keys()
+            .filter(|top| path > top.as_str() && !(path.starts_with(top.as_str()) && path[top.len()..].starts_with('/')))
+            .cloned()
+            .collect();
+        for top in done {
+            let page = self.open_pages.remove(&top).unwrap();
+            self.close_page(top, page)?;
+        }
+
+        match path.split_once('/') {
+            None => {
+                if self.collapse && delta.delta_type().is_created_or_deleted() && delta.file_type().is_dir() {
+                    self.collapsed.insert(path.to_string());
                 }
-            }
+                self.index_rows.push(((path.to_string(), false), make_row(delta, self.collapse)));
+            },
+            Some((top, _)) if self.collapsed.contains(top) => {},
+            Some((top, _)) => {
+                if !self.open_pages.contains_key(top) {
+                    let page = self.open_page(top, delta.root_path_str())?;
+                    self.open_pages.insert(top.to_string(), page);
+                }
+                self.open_pages.get_mut(top).unwrap().writer.write(delta)?;
+            },
         }
+        Ok(())
+    }
 
-        filtered.push(delta);
+    /// Finishes the open pages and writes the index page.
+    fn finish(mut self: Box<Self>) -> ConvertibleResult<()> {
+        for (top, page) in std::mem::take(&mut self.open_pages) {
+            self.close_page(top, page)?;
+        }
+        log::debug!("wrote {} pages", self.page_names.len());
+
+        let this = *self;
+        let mut index_rows = this.index_rows;
+        index_rows.sort_by(|a, b| a.0.cmp(&b.0));
+        let mut index = ReportWriter::new(this.index, this.keep_unchanged, this.collapse, this.summary)?;
+        for (_, row) in index_rows.iter() {
+            index.write_row(row)?;
+        }
+        Box::new(index).finish()
     }
+}
+
+fn make_row(delta: &Delta<'_>, pre_collapsed: bool) -> String {
+    let is_delta_precollapsed = pre_collapsed && delta.delta_type().is_created_or_deleted();
+    let mut row = TR.replace("${class}", delta.delta_type().css_class());
+    row = row.replace("${root}", delta.root_path_str());
+    row = row.replace("${ftype}", delta.file_type().to_str());
+    row = row.replace("${vpath_attrs}",
+                      if !is_delta_precollapsed && delta.file_type().is_dir() {
+                          " class=\"dir\" onclick=\"onclickCollapse(this)\""
+                      } else {
+                          ""
+                      });
+
+    let v_path_str = delta.virtual_path_str();
+    let v_path_buf = PathBuf::from(v_path_str);
+    let v_path = match v_path_buf.parent() {
+        None => {
+            format!("{}", v_path_str)
+        },
+        Some(parent) => {
+            let parent = parent.to_str().unwrap();
+            if parent.is_empty() {
+                format!("{}", v_path_str)
+            } else {
+                let parent_span = SPAN.replace("{}", parent);
+                let name = v_path_buf.file_name().unwrap().to_str().unwrap();
+                format!("{}{}", parent_span, name)
+            }
+        },
+    };
+    let v_path = format!("{}{}", v_path.as_str(), if is_delta_precollapsed && delta.file_type().is_dir() {" [...]"} else {""});
+    row = row.replace("${vpath}", v_path.as_str());
 
-    log::debug!("collapse done. items skipped: {}", start_count - filtered.len());
-    filtered
+    let delta_info = delta_info(delta);
+    row = row.replace("${info}", delta_info.as_str());
+
+    row
+}
+
+fn delta_info(delta: &Delta<'_>) -> String {
+    match delta.delta_type() {
+        DeltaType::NoChange => { String::new() },
+        DeltaType::Creation if !delta.move_candidates().is_empty() => {
+            format!("[creation; ambiguous move from: {}]", delta.move_candidates().join(", "))
+        },
+        DeltaType::Deletion if !delta.move_candidates().is_empty() => {
+            format!("[deletion; ambiguous move to: {}]", delta.move_candidates().join(", "))
+        },
+        DeltaType::Creation => { "[creation]".to_string() },
+        DeltaType::Deletion => { "[deletion]".to_string() },
+        DeltaType::Move { from, changes, .. } => {
+            let mut info = vec![format!("[moved from {}]", from)];
+            info.extend(changes.iter().map(change_text));
+            info.join(", ")
+        },
+        DeltaType::Modification(changes) => {
+            changes.iter().map(change_text).collect::<Vec<_>>().join(", ")
+        },
+        DeltaType::Unstable(changes) => {
+            let mut info = vec![format!("[unstable: changed while indexing {}]", delta.unstable_sides())];
+            info.extend(changes.iter().map(change_text));
+            info.join(", ")
+        },
+    }
+}
+
+/// The change as shown in the report, e.g. `size: 10 -> 20`.
+fn change_text(change: &AttributeChange) -> String {
+    let label = match (&change.attr, &change.new) {
+        (Attribute::Checksum, Value::Digest(digest, _)) => digest,
+        (Attribute::NodeType, _) => "type",
+        (Attribute::Checksum, _) => "checksum",
+        (Attribute::Size, _) => "size",
+        (Attribute::User, _) => "uid",
+        (Attribute::Group, _) => "gid",
+        (Attribute::Permissions, _) => "perms",
+        (Attribute::CreationDate, _) => "date created",
+        (Attribute::ModifiedDate, _) => "date modified",
+        (Attribute::LinksTo, _) => "symlink to",
+        (Attribute::Inode, _) => "inode",
+        (Attribute::NLinks, _) => "hardlink count",
+        (Attribute::HardLinks, _) => "hard linked with",
+    };
+    format!("{}: {} -> {}", label, value_text(&change.old), value_text(&change.new))
+}
+
+fn value_text(value: &Value) -> String {
+    match value {
+        Value::Number(number) => number.to_string(),
+        Value::Id(id) => id.to_string(),
+        Value::NodeType(node_type) => node_type.to_string(),
+        Value::Permissions(permissions) => Permission::from_val(*permissions),
+        Value::Time(time) => chrono::Local.timestamp_opt(*time, 0).unwrap().to_string(),
+        Value::Text(text) => text.clone(),
+        Value::Digest(_, digest) => digest.clone(),
+        Value::Paths(paths) if paths.is_empty() => "(none)".to_string(),
+        Value::Paths(paths) => paths.join(" "),
+    }
 }
 
 impl ReportSummary {
@@ -159,6 +396,6 @@ impl ReportSummary {
         let joined = String::from(self.roots_b.join(", "));
         let html = html.replace("${roots-b}", joined.as_str());
 
-        html
+        html.replace("${detect-renames}", if self.detect_renames {""} else {"hidden"})
     }
 }
\ No newline at end of file
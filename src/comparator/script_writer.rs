@@ -0,0 +1,441 @@
+use crate::ConvertibleResult;
+use crate::comparator::DeltaWriter;
+use crate::comparator::delta::{Attribute, Delta, DeltaType, Value};
+use crate::comparator::id_map::IdMap;
+use crate::comparator::report::ReportSummary;
+use crate::comparator::virtual_fs_node::VirtualFsNode;
+use crate::db_models::fs_node::{self, FsNode, NodeType};
+use crate::errorwrapper::app_error;
+use crate::util::quote;
+use std::collections::BTreeMap;
+use std::io::Write;
+use std::path::Path;
+
+/// Where the nodes of the 'a' roots go in the 'b' index: to the only 'b' root, or to the 'b' root
+/// given in the same position as their 'a' root.
+struct RootMap {
+    roots_a: Vec<String>,
+    roots_b: Vec<String>,
+}
+
+impl RootMap {
+
+    fn new(summary: &ReportSummary) -> ConvertibleResult<RootMap> {
+        if summary.roots_b.len() != 1 && summary.roots_b.len() != summary.roots_a.len() {
+            return Err(app_error(format!(
+                "cannot tell where the nodes of {} 'a' roots go in {} 'b' roots; give one --root-b, \
+                 or one for each --root-a, in the same order", summary.roots_a.len(), summary.roots_b.len()
+            )));
+        }
+        Ok(RootMap { roots_a: summary.roots_a.clone(), roots_b: summary.roots_b.clone() })
+    }
+
+    /// The path of the node in the 'b' index once synced.
+    fn destination(&self, root_a: &str, virtual_path: &str) -> String {
+        let root_b = match self.roots_a.iter().position(|root| root == root_a) {
+            Some(i) if self.roots_b.len() > 1 => &self.roots_b[i],
+            _ => &self.roots_b[0],
+        };
+        full_path(root_b, virtual_path)
+    }
+
+    /// The 'a' root of the nodes of the 'b' root, unless nodes of several 'a' roots go there.
+    fn source_root(&self, root_b: &str) -> Option<&str> {
+        if self.roots_a.len() == 1 {
+            return Some(&self.roots_a[0]);
+        }
+        match self.roots_b.iter().position(|root| root == root_b) {
+            Some(i) if self.roots_b.len() > 1 => Some(&self.roots_a[i]),
+            _ => None,
+        }
+    }
+}
+
+/// A shell script that makes the 'b' side like the 'a' side, for review before running: `cp` for content changes,
+/// `chmod`, `chown` and `touch` for metadata-only changes, `mv` for moves (see `--detect-renames`) and `rm` for deletions.
+///
+/// The commands run in an order that works: directories are created, nodes moved and files copied in path order,
+/// so that parents come before their contents, then metadata is updated. Deletions and replacements of nodes whose type changed come last, and only
+/// run if the script is run with `--delete`. Directory metadata is set at the very end, since changes in a
+/// directory update its modification time. Changes that can't be synced (e.g. inodes) are listed as comments.
+pub struct ShellScriptWriter<W: Write> {
+    output: W,
+    roots: RootMap,
+    id_map: IdMap,
+    summary: ReportSummary,
+
+    /// `mkdir`, `mv` and `cp`, in path order.
+    transfers: Vec<String>,
+    metadata: Vec<String>,
+
+    /// Deletions and replacements, and the creations below replaced nodes, run only with `--delete`.
+    removals: Vec<String>,
+    removal_dir_metadata: Vec<String>,
+    dir_metadata: Vec<String>,
+
+    /// The directories whose contents the script changes, by destination, with the source directory to take
+    /// their modification time from.
+    parents: BTreeMap<String, String>,
+    notes: Vec<String>,
+
+    /// The virtual path of the deleted or replaced directory whose contents are removed with it.
+    removed_dir: Option<String>,
+
+    /// The virtual path of the node replaced by a directory, whose contents are created after the replacement.
+    replaced_by_dir: Option<String>,
+
+    /// The old and new virtual paths of the moved directory whose contents are moved with it.
+    moved_dir: Option<(String, String)>,
+}
+
+impl<W: Write> ShellScriptWriter<W> {
+
+    pub fn new(output: W, summary: ReportSummary, id_map: IdMap) -> ConvertibleResult<ShellScriptWriter<W>> {
+        Ok(ShellScriptWriter {
+            output,
+            roots: RootMap::new(&summary)?,
+            id_map,
+            summary,
+            transfers: Vec::new(),
+            metadata: Vec::new(),
+            removals: Vec::new(),
+            removal_dir_metadata: Vec::new(),
+            dir_metadata: Vec::new(),
+            parents: BTreeMap::new(),
+            notes: Vec::new(),
+            removed_dir: None,
+            replaced_by_dir: None,
+            moved_dir: None,
+        })
+    }
+
+    /// Notes that the script adds or removes a node in the parent directory of the node at `virtual_path`
+    /// below `root_a`, which updates the modification time of the directory.
+    fn parent_changed(&mut self, root_a: &str, virtual_path: &str) {
+        let parent = virtual_path.rfind('/').map_or("", |i| &virtual_path[..i]);
+        let destination = self.roots.destination(root_a, parent);
+        self.parents.entry(destination).or_insert_with(|| full_path(root_a, parent));
+    }
+
+    fn write_creation(&mut self, vnode_a: &VirtualFsNode<'_>, destination: &str, after_removals: bool) {
+        let fs_node = vnode_a.fs_node;
+        let source = full_path(&vnode_a.root, &vnode_a.virtual_path);
+        if fs_node.node_type.is_dir() {
+            let transfers = if after_removals { &mut self.removals } else { &mut self.transfers };
+            transfers.push(format!("mkdir -p -- {}", quote::shell(destination)));
+
+            let dir_metadata = if after_removals { &mut self.removal_dir_metadata } else { &mut self.dir_metadata };
+            dir_metadata.extend(all_metadata(fs_node, destination, &self.id_map));
+        } else {
+            let transfers = if after_removals { &mut self.removals } else { &mut self.transfers };
+            transfers.push(format!("cp -a -- {} {}", quote::shell(&source), quote::shell(destination)));
+        }
+    }
+
+    /// The commands for the changes of a node that exists on both sides, at `destination` once moved.
+    fn write_changes(&mut self, delta: &Delta<'_>, destination: &str) {
+        let vnode_a = delta.a().unwrap();
+        let vnode_b = delta.b().unwrap();
+        let source = full_path(&vnode_a.root, &vnode_a.virtual_path);
+        let changes = delta.changes();
+        let changed = |attr: Attribute| changes.iter().find(|change| change.attr == attr);
+
+        // whether or not node types are compared (see --mode)
+        if vnode_a.fs_node.node_type != vnode_b.fs_node.node_type {
+            self.removals.push(format!("rm -rf -- {}", quote::shell(destination)));
+            if vnode_b.fs_node.node_type.is_dir() {
+                self.removed_dir = Some(delta.virtual_path_str().to_string());
+            }
+            if vnode_a.fs_node.node_type.is_dir() {
+                self.replaced_by_dir = Some(delta.virtual_path_str().to_string());
+            }
+            self.write_creation(vnode_a, destination, true);
+            self.parent_changed(&vnode_a.root, &vnode_a.virtual_path);
+            return;
+        }
+
+        let node_type = &vnode_a.fs_node.node_type;
+        let content_changed = *node_type == NodeType::File
+            && (changed(Attribute::Checksum).is_some() || changed(Attribute::Size).is_some());
+        if content_changed {
+            // cp -a takes the metadata along
+            self.transfers.push(format!("cp -a -- {} {}", quote::shell(&source), quote::shell(destination)));
+        }
+        // the size of a symlink is the length of its target, whether or not targets are compared
+        let target_changed = *node_type == NodeType::Symlink
+            && (changed(Attribute::LinksTo).is_some() || changed(Attribute::Size).is_some());
+        if target_changed {
+            let fs_node = vnode_a.fs_node;
+            self.parent_changed(&vnode_a.root, &vnode_a.virtual_path);
+            self.transfers.push(format!("ln -sfn -- {} {}", quote::shell(&fs_node.links_to), quote::shell(destination)));
+            // unlike cp -a, ln gives the new link the current owner and time
+            if fs_node.knows(fs_node::ATTR_UID | fs_node::ATTR_GID) {
+                let (uid, gid) = (self.id_map.uid(fs_node.uid), self.id_map.gid(fs_node.gid));
+                self.metadata.push(format!("chown -h {}:{} -- {}", uid, gid, quote::shell(destination)));
+            }
+            if fs_node.knows(fs_node::ATTR_MODIFIED_DATE) {
+                self.metadata.push(format!("touch -h -m -d @{} -- {}", fs_node.modified_date, quote::shell(destination)));
+            }
+        }
+        let replaced = content_changed || target_changed;
+
+        let mut commands = Vec::new();
+        for change in changes.iter() {
+            match (&change.attr, &change.new) {
+                (Attribute::Checksum, _) | (Attribute::Size, _) | (Attribute::LinksTo, _) => {},
+                (Attribute::Permissions, _) | (Attribute::User, _) | (Attribute::Group, _) | (Attribute::ModifiedDate, _)
+                    if replaced => {},
+                (Attribute::Permissions, Value::Permissions(permissions)) => {
+                    // the permissions of symlinks are not used
+                    if *node_type != NodeType::Symlink {
+                        commands.push(format!("chmod {:o} -- {}", permissions & 0o7777, quote::shell(destination)));
+                    }
+                },
+                (Attribute::User, Value::Id(uid)) => {
+                    commands.push(format!("chown -h {} -- {}", self.id_map.uid(*uid), quote::shell(destination)));
+                },
+                (Attribute::Group, Value::Id(gid)) => {
+                    commands.push(format!("chgrp -h {} -- {}", self.id_map.gid(*gid), quote::shell(destination)));
+                },
+                (Attribute::ModifiedDate, Value::Time(time)) => {
+                    commands.push(format!("touch -h -m -d @{} -- {}", time, quote::shell(destination)));
+                },
+                _ => {
+                    self.notes.push(format!("# {}: {} differs ({} -> {}), not synced", quote::visible(destination),
+                        change.name(), quote::visible(&change.old.to_text()), quote::visible(&change.new.to_text())));
+                },
+            }
+        }
+        if node_type.is_dir() {
+            self.dir_metadata.extend(commands);
+        } else {
+            self.metadata.extend(commands);
+        }
+    }
+
+    fn write_section(&mut self, title: &str, lines: &[String]) -> ConvertibleResult<()> {
+        if lines.is_empty() {
+            return Ok(());
+        }
+        writeln!(self.output, "\n# {} ({})", title, lines.len())?;
+        for line in lines.iter() {
+            writeln!(self.output, "{}", line)?;
+        }
+        Ok(())
+    }
+}
+
+impl<W: Write> DeltaWriter for ShellScriptWriter<W> {
+
+    fn write(&mut self, delta: &Delta<'_>) -> ConvertibleResult<()> {
+        let virtual_path = delta.virtual_path_str();
+        let unreadable = delta.a().into_iter().chain(delta.b())
+            .find(|vnode| vnode.fs_node.node_type == NodeType::Error);
+        if let Some(vnode) = unreadable {
+            let path = full_path(&vnode.root, &vnode.virtual_path);
+            self.notes.push(format!("# {}: could not be read when indexed, not synced", quote::visible(&path)));
+            return Ok(());
+        }
+        let below = |dir: &Option<String>| dir.as_ref().is_some_and(|dir| is_below(virtual_path, dir));
+        let below_removed_dir = below(&self.removed_dir);
+        let below_replacement = below(&self.replaced_by_dir);
+
+        match delta.delta_type() {
+            DeltaType::NoChange => {},
+            DeltaType::Unstable(_) => {
+                let vnode_b = delta.b().unwrap();
+                let destination = full_path(&vnode_b.root, &vnode_b.virtual_path);
+                self.notes.push(format!("# {}: changed while indexing, not synced", quote::visible(&destination)));
+            },
+            // removed along with the directory
+            DeltaType::Deletion if below_removed_dir => {},
+            DeltaType::Deletion => {
+                let vnode_b = delta.b().unwrap();
+                let destination = full_path(&vnode_b.root, &vnode_b.virtual_path);
+                if vnode_b.fs_node.node_type.is_dir() {
+                    self.removals.push(format!("rm -rf -- {}", quote::shell(&destination)));
+                    self.removed_dir = Some(virtual_path.to_string());
+                } else {
+                    self.removals.push(format!("rm -f -- {}", quote::shell(&destination)));
+                }
+                if let Some(root_a) = self.roots.source_root(&vnode_b.root) {
+                    let root_a = root_a.to_string();
+                    self.parent_changed(&root_a, virtual_path);
+                }
+            },
+            DeltaType::Creation => {
+                let vnode_a = delta.a().unwrap();
+                let destination = self.roots.destination(&vnode_a.root, virtual_path);
+                self.write_creation(vnode_a, &destination, below_replacement);
+                // the parent is created after the replacement
+                if !below_replacement {
+                    self.parent_changed(&vnode_a.root, virtual_path);
+                }
+            },
+            DeltaType::Modification(_) => {
+                let vnode_b = delta.b().unwrap();
+                let destination = full_path(&vnode_b.root, &vnode_b.virtual_path);
+                self.write_changes(delta, &destination);
+            },
+            DeltaType::Move { from: from_path, .. } => {
+                let vnode_b = delta.b().unwrap();
+                let from = full_path(&vnode_b.root, &vnode_b.virtual_path);
+                let destination = self.roots.destination(&delta.a().unwrap().root, virtual_path);
+                // already moved along with the directory; only its other changes are left
+                let moved_along = self.moved_dir.as_ref().is_some_and(|(dir_from, dir_to)| {
+                    is_below(virtual_path, dir_to) && is_below(from_path, dir_from)
+                        && virtual_path[dir_to.len()..] == from_path[dir_from.len()..]
+                });
+                if moved_along {
+                    self.write_changes(delta, &destination);
+                    return Ok(());
+                }
+                if vnode_b.fs_node.node_type.is_dir() {
+                    self.moved_dir = Some((from_path.clone(), virtual_path.to_string()));
+                }
+                self.transfers.push(format!("mv -- {} {}", quote::shell(&from), quote::shell(&destination)));
+                self.parent_changed(&delta.a().unwrap().root, virtual_path);
+                if let Some(root_a) = self.roots.source_root(&vnode_b.root) {
+                    let root_a = root_a.to_string();
+                    self.parent_changed(&root_a, &vnode_b.virtual_path);
+                }
+                self.write_changes(delta, &destination);
+            },
+        }
+        Ok(())
+    }
+
+    fn finish(mut self: Box<Self>) -> ConvertibleResult<()> {
+        writeln!(self.output, "#!/bin/sh")?;
+        writeln!(self.output, "# Makes the destination like the source, as compared by magnetar cmp.")?;
+        // in comments, paths are escaped: a newline would end the comment
+        let side = |db: &str, roots: &[String]| {
+            let roots: Vec<String> = roots.iter().map(|root| quote::visible(root)).collect();
+            format!("{} {}", quote::visible(db), roots.join(" "))
+        };
+        writeln!(self.output, "#   source:      {}", side(&self.summary.db_a_name, &self.summary.roots_a))?;
+        writeln!(self.output, "#   destination: {}", side(&self.summary.db_b_name, &self.summary.roots_b))?;
+        writeln!(self.output, "# Review before running. Deletions and replacements only run with --delete.")?;
+        writeln!(self.output, "set -eu")?;
+
+        let transfers = std::mem::take(&mut self.transfers);
+        self.write_section("created, moved and modified nodes", &transfers)?;
+        let metadata = std::mem::take(&mut self.metadata);
+        self.write_section("metadata changes", &metadata)?;
+
+        let mut removals = std::mem::take(&mut self.removals);
+        if !removals.is_empty() {
+            let count = removals.len();
+            removals.append(&mut self.removal_dir_metadata);
+            writeln!(self.output, "\n# DANGER: deletions and replacements ({})", count)?;
+            writeln!(self.output, "# These remove nodes from the destination, that are not in the source or changed type there.")?;
+            writeln!(self.output, "if [ \"${{1:-}}\" = --delete ]; then")?;
+            for line in removals.iter() {
+                writeln!(self.output, "    {}", line)?;
+            }
+            writeln!(self.output, "else")?;
+            let commands = if count == 1 { "command" } else { "commands" };
+            writeln!(self.output, "    echo \"skipping {} {} of deletions and replacements; run with --delete to run them\" >&2", count, commands)?;
+            writeln!(self.output, "fi")?;
+        }
+
+        let parents: Vec<String> = std::mem::take(&mut self.parents).into_iter()
+            .map(|(destination, source)| format!("sync_mtime {} {}", quote::shell(&source), quote::shell(&destination)))
+            .collect();
+        if !parents.is_empty() {
+            // either directory may be gone, e.g. not replaced yet without --delete
+            writeln!(self.output, "\nsync_mtime() {{ [ ! -e \"$1\" ] || touch -c -m -r \"$1\" -- \"$2\"; }}")?;
+        }
+        self.write_section("modification times of the directories changed above, from the source", &parents)?;
+        let dir_metadata = std::mem::take(&mut self.dir_metadata);
+        self.write_section("directory metadata, last since changes in a directory update its modification time", &dir_metadata)?;
+        let notes = std::mem::take(&mut self.notes);
+        self.write_section("not synced", &notes)?;
+
+        self.output.flush()?;
+        log::debug!("wrote sync script: {} transfers, {} removals", transfers.len(), removals.len());
+        Ok(())
+    }
+}
+
+/// The paths to sync, relative to the roots and each ended by a NUL, since paths may hold newlines,
+/// for `rsync -a --from0 --files-from=FILE ROOT-A/ ROOT-B/`:
+/// created and modified nodes, and the new paths of moved nodes. rsync does not delete nodes
+/// that are not listed, so deletions and moves away are left to the user.
+pub struct RsyncFileListWriter<W: Write> {
+    output: W,
+    paths: usize,
+    left_out: usize,
+}
+
+impl<W: Write> RsyncFileListWriter<W> {
+
+    pub fn new(output: W, summary: &ReportSummary) -> ConvertibleResult<RsyncFileListWriter<W>> {
+        if summary.roots_a.len() != 1 || summary.roots_b.len() != 1 {
+            return Err(app_error("an rsync file list needs exactly one root on each side".to_string()));
+        }
+        Ok(RsyncFileListWriter { output, paths: 0, left_out: 0 })
+    }
+}
+
+impl<W: Write> DeltaWriter for RsyncFileListWriter<W> {
+
+    fn write(&mut self, delta: &Delta<'_>) -> ConvertibleResult<()> {
+        let listed = match delta.delta_type() {
+            DeltaType::Creation | DeltaType::Modification(_) => true,
+            // the old path is left behind
+            DeltaType::Move { .. } => {
+                self.left_out += 1;
+                true
+            },
+            DeltaType::Deletion | DeltaType::Unstable(_) => {
+                self.left_out += 1;
+                false
+            },
+            DeltaType::NoChange => false,
+        };
+        // the root itself is synced by rsync anyway
+        if listed && !delta.virtual_path_str().is_empty() {
+            write!(self.output, "{}\0", delta.virtual_path_str())?;
+            self.paths += 1;
+        }
+        Ok(())
+    }
+
+    fn finish(mut self: Box<Self>) -> ConvertibleResult<()> {
+        self.output.flush()?;
+        log::debug!("wrote {} paths to the rsync file list", self.paths);
+        if self.left_out > 0 {
+            log::warn!("{} deletions, moves away and nodes changed while indexing are not in the rsync file list", self.left_out);
+        }
+        Ok(())
+    }
+}
+
+/// The commands that give a created directory the metadata of `fs_node`, as far as known.
+fn all_metadata(fs_node: &FsNode, destination: &str, id_map: &IdMap) -> Vec<String> {
+    let path = quote::shell(destination);
+    let mut commands = Vec::new();
+    if fs_node.knows(fs_node::ATTR_PERMISSIONS) {
+        commands.push(format!("chmod {:o} -- {}", fs_node.permissions & 0o7777, path));
+    }
+    if fs_node.knows(fs_node::ATTR_UID | fs_node::ATTR_GID) {
+        commands.push(format!("chown {}:{} -- {}", id_map.uid(fs_node.uid), id_map.gid(fs_node.gid), path));
+    }
+    if fs_node.knows(fs_node::ATTR_MODIFIED_DATE) {
+        commands.push(format!("touch -m -d @{} -- {}", fs_node.modified_date, path));
+    }
+    commands
+}
+
+fn full_path(root: &str, virtual_path: &str) -> String {
+    if virtual_path.is_empty() {
+        return root.to_string();
+    }
+    Path::new(root).join(virtual_path).to_string_lossy().into_owned()
+}
+
+fn is_below(path: &str, dir: &str) -> bool {
+    path.len() > dir.len() && path.starts_with(dir) && path[dir.len()..].starts_with('/')
+}
+
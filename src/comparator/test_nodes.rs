@@ -0,0 +1,33 @@
+use crate::comparator::compare::VFsNodeMap;
+use crate::comparator::id_map::{IdMap, NameTables};
+use crate::comparator::virtual_fs_node::VirtualFsNode;
+use crate::db_models::fs_node::FsNode;
+use std::path::Path;
+
+/// A file at `path` below '/', e.g. `dir/x`.
+pub fn file(path: &str, size: i64, uid: u32) -> FsNode {
+    let name = format!("/{}", path);
+    FsNode {
+        parent_path: Path::new(&name).parent().unwrap().to_string_lossy().to_string(),
+        name,
+        size,
+        uid,
+        ..Default::default()
+    }
+}
+
+/// The nodes by their virtual path, with '/' as the root.
+pub fn pool(fs_nodes: &[FsNode]) -> VFsNodeMap<'_> {
+    fs_nodes.iter()
+        .map(|fs_node| VirtualFsNode::from(("/".to_string(), fs_node)))
+        .map(|vnode| (vnode.virtual_path.clone(), vnode))
+        .collect()
+}
+
+/// Maps user 'alice' from `uid` to 1000, her uid in the index compared with.
+pub fn alice(uid: u32) -> IdMap {
+    let names = |uid: u32| NameTables { users: vec![("alice".to_string(), uid)].into_iter().collect(), ..Default::default() };
+    let mut id_map = IdMap::new();
+    id_map.map_by_name(&names(uid), &names(1000));
+    id_map
+}
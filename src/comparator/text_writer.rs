@@ -0,0 +1,53 @@
+use crate::ConvertibleResult;
+use crate::comparator::DeltaWriter;
+use crate::comparator::delta::{Attribute, Delta, DeltaType};
+use std::io::Write;
+
+/// One line per delta, like `git status --short`: a status column and the path, directories with a trailing `/`.
+/// `A` created, `D` deleted, `M` modified, `T` type changed, `R` moved (`R  FROM -> TO`),
+/// `U` changed while indexing, and a blank for unchanged nodes.
+pub struct TextWriter<W: Write> {
+    output: W,
+    lines: usize,
+}
+
+impl<W: Write> TextWriter<W> {
+
+    pub fn new(output: W) -> TextWriter<W> {
+        TextWriter { output, lines: 0 }
+    }
+}
+
+impl<W: Write> DeltaWriter for TextWriter<W> {
+
+    fn write(&mut self, delta: &Delta<'_>) -> ConvertibleResult<()> {
+        let status = status(delta);
+        let suffix = if delta.file_type().is_dir() { "/" } else { "" };
+        match delta.moved_from() {
+            Some(from) => writeln!(self.output, "{}  {}{} -> {}{}", status, from, suffix, delta.virtual_path_str(), suffix)?,
+            None => writeln!(self.output, "{}  {}{}", status, delta.virtual_path_str(), suffix)?,
+        }
+        self.lines += 1;
+        Ok(())
+    }
+
+    fn finish(mut self: Box<Self>) -> ConvertibleResult<()> {
+        self.output.flush()?;
+        log::debug!("wrote {} lines", self.lines);
+        Ok(())
+    }
+}
+
+/// The status column of the delta, see [TextWriter]().
+pub fn status(delta: &Delta<'_>) -> char {
+    let type_changed = delta.changes().iter().any(|change| change.attr == Attribute::NodeType);
+    match delta.delta_type() {
+        DeltaType::Creation => 'A',
+        DeltaType::Deletion => 'D',
+        DeltaType::Modification(_) if type_changed => 'T',
+        DeltaType::Modification(_) => 'M',
+        DeltaType::Unstable(_) => 'U',
+        DeltaType::NoChange => ' ',
+        DeltaType::Move { .. } => 'R',
+    }
+}
@@ -0,0 +1,381 @@
+use crate::ConvertibleResult;
+use crate::comparator::compare::{self, VFsNodeMap};
+use crate::comparator::delta::{Attribute, Delta, DeltaType};
+use crate::comparator::id_map::IdMap;
+use crate::comparator::{json_writer, text_writer, IndexSnapshot};
+use crate::errorwrapper::app_error;
+use crate::util::quote;
+use clap;
+use std::collections::{BTreeMap, HashSet};
+use std::io::{self, Write};
+
+/// How a path changed on the 'a' and 'b' sides since the base, see [compare3]().
+/// Ordered as reported: conflicts first.
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Ord, PartialOrd)]
+pub enum Divergence {
+    /// Changed on both sides, differently.
+    Conflict,
+    /// Changed on both sides, to the same.
+    Identical,
+    AOnly,
+    BOnly,
+}
+
+impl Divergence {
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Divergence::Conflict => "conflict",
+            Divergence::Identical => "identical",
+            Divergence::AOnly => "a",
+            Divergence::BOnly => "b",
+        }
+    }
+
+    fn title(&self) -> &'static str {
+        match self {
+            Divergence::Conflict => "conflicts",
+            Divergence::Identical => "changed identically in both",
+            Divergence::AOnly => "changed in a only",
+            Divergence::BOnly => "changed in b only",
+        }
+    }
+}
+
+/// A path that changed on at least one side: the deltas of each side against the base,
+/// `None` for a side if the path is neither on that side nor in the base.
+#[derive(Debug)]
+pub struct ThreeWayDelta<'a> {
+    pub divergence: Divergence,
+    pub virtual_path: String,
+    pub a: Option<Delta<'a>>,
+    pub b: Option<Delta<'a>>,
+}
+
+impl ThreeWayDelta<'_> {
+
+    fn sides(&self) -> impl Iterator<Item = &Delta<'_>> {
+        self.a.iter().chain(self.b.iter())
+    }
+}
+
+/// Compares `pool_a` and `pool_b` with the base they diverged from, each with its own pool of the base
+/// (see [compare::make_pool]()), and classifies every path that changed on either side.
+/// Paths changed on both sides are identical if the nodes of 'a' and 'b' don't differ in `attr_types`,
+/// or are both gone. Ownership is compared by id, translated from 'a' and 'b' into the ids of the base
+/// with `id_map_a` and `id_map_b`. The result is ordered by divergence, then path.
+pub fn compare3<'a>(
+    base_for_a: VFsNodeMap<'a>,
+    pool_a: VFsNodeMap<'a>,
+    base_for_b: VFsNodeMap<'a>,
+    pool_b: VFsNodeMap<'a>,
+    attr_types: &HashSet<Attribute>,
+    id_map_a: &IdMap,
+    id_map_b: &IdMap
+) -> Vec<ThreeWayDelta<'a>> {
+    let deltas_a = compare::compare(pool_a, base_for_a, attr_types, id_map_a);
+    let mut deltas_b: BTreeMap<String, Delta<'a>> = compare::compare(pool_b, base_for_b, attr_types, id_map_b).into_iter()
+        .map(|delta| (delta.virtual_path_str().to_string(), delta))
+        .collect();
+
+    let mut pairs: Vec<(Option<Delta<'a>>, Option<Delta<'a>>)> = Vec::new();
+    for delta_a in deltas_a {
+        let delta_b = deltas_b.remove(delta_a.virtual_path_str());
+        pairs.push((Some(delta_a), delta_b));
+    }
+    pairs.extend(deltas_b.into_values().map(|delta_b| (None, Some(delta_b))));
+
+    let a_to_b = IdMap::via(id_map_a, id_map_b);
+    let mut result = Vec::new();
+    for (a, b) in pairs {
+        let divergence = match (changed(&a), changed(&b)) {
+            (false, false) => continue,
+            (true, false) => Divergence::AOnly,
+            (false, true) => Divergence::BOnly,
+            (true, true) if same_outcome(&a, &b, attr_types, &a_to_b) => Divergence::Identical,
+            (true, true) => Divergence::Conflict,
+        };
+        let virtual_path = a.iter().chain(b.iter()).next().unwrap().virtual_path_str().to_string();
+        result.push(ThreeWayDelta { divergence, virtual_path, a, b });
+    }
+    result.sort_by(|x, y| (x.divergence, &x.virtual_path).cmp(&(y.divergence, &y.virtual_path)));
+    log::debug!("compare3: {} paths changed", result.len());
+    result
+}
+
+fn changed(delta: &Option<Delta<'_>>) -> bool {
+    delta.as_ref().is_some_and(|delta| !delta.delta_type().is_unchanged())
+}
+
+/// Whether both sides ended up with the same node, or without one. `id_map` translates from 'a' into 'b'.
+fn same_outcome<'a>(a: &Option<Delta<'a>>, b: &Option<Delta<'a>>, attr_types: &HashSet<Attribute>, id_map: &IdMap) -> bool {
+    let node = |delta: &Option<Delta<'a>>| delta.as_ref().and_then(|delta| delta.a().cloned());
+    match (node(a), node(b)) {
+        (None, None) => true,
+        (Some(vnode_a), Some(vnode_b)) => Delta::new(Some(vnode_a), Some(vnode_b), attr_types, id_map).delta_type().is_unchanged(),
+        _ => false,
+    }
+}
+
+pub fn run(args: &clap::ArgMatches<'_>) -> ConvertibleResult<()> {
+    let db_path_base = args.value_of("base").expect("path to database is required");
+    let db_path_a = args.value_of("first-index").expect("path to database is required");
+    let db_path_b = args.value_of("second-index").expect("path to database is required");
+
+    let attrs = super::attributes(args)?;
+
+    let roots_base = super::roots(args, "root-base");
+    let roots_a = super::roots(args, "root-a");
+    let roots_b = super::roots(args, "root-b");
+    for (roots, index_name) in [(&roots_base, "base"), (&roots_a, "a"), (&roots_b, "b")].iter() {
+        if let Err(invalid_roots) = super::validate_roots(roots) {
+            return Err(app_error(format!(
+                "index '{}': invalid roots: {:?}\nroots cannot be direct descendants of each other", index_name, invalid_roots
+            )));
+        }
+    }
+
+    let index_base = IndexSnapshot::open(db_path_base)?;
+    let index_a = IndexSnapshot::open(db_path_a)?;
+    let index_b = IndexSnapshot::open(db_path_b)?;
+
+    let mounts_base = index_base.mounts()?;
+    let mounts_a = index_a.mounts()?;
+    let mounts_b = index_b.mounts()?;
+
+    let base_index = index_base.fs_nodes()?;
+    let first_index = index_a.fs_nodes()?;
+    let second_index = index_b.fs_nodes()?;
+
+    let base_for_a = compare::make_pool(&base_index, roots_base.clone(), &mounts_base)?;
+    let base_for_b = compare::make_pool(&base_index, roots_base.clone(), &mounts_base)?;
+    let pool_a = compare::make_pool(&first_index, roots_a.clone(), &mounts_a)?;
+    let pool_b = compare::make_pool(&second_index, roots_b.clone(), &mounts_b)?;
+
+    let id_map_a = super::id_map(args, &index_a, &index_base)?;
+    let id_map_b = super::id_map(args, &index_b, &index_base)?;
+
+    let deltas = compare3(base_for_a, pool_a, base_for_b, pool_b, &attrs, &id_map_a, &id_map_b);
+    let conflicts = deltas.iter().filter(|delta| delta.divergence == Divergence::Conflict).count();
+    if conflicts > 0 {
+        log::warn!("{} of {} changed paths conflict", conflicts, deltas.len());
+    }
+
+    let indexes = [("base", db_path_base, &roots_base), ("a", db_path_a, &roots_a), ("b", db_path_b, &roots_b)];
+    let mut output = io::BufWriter::new(io::stdout());
+    match args.value_of("format") {
+        Some("json") => write_json(&mut output, &indexes, &deltas)?,
+        _ => write_text(&mut output, &indexes, &deltas)?,
+    }
+    output.flush()?;
+    Ok(())
+}
+
+/// One line per path like `git status --short`, with a status column for each side (see [text_writer::status]()),
+/// under a heading per divergence. Conflicts also list what changed on each side.
+fn write_text(output: &mut impl Write, indexes: &[(&str, &str, &Vec<String>)], deltas: &[ThreeWayDelta<'_>]) -> ConvertibleResult<()> {
+    for (name, db_path, roots) in indexes.iter() {
+        writeln!(output, "# {:<5} {} {}", format!("{}:", name), db_path, roots.join(" "))?;
+    }
+    let status = |delta: &Option<Delta<'_>>| delta.as_ref().map_or(' ', text_writer::status);
+    let mut divergence = None;
+    for delta in deltas.iter() {
+        if divergence != Some(delta.divergence) {
+            divergence = Some(delta.divergence);
+            let count = deltas.iter().filter(|other| other.divergence == delta.divergence).count();
+            writeln!(output, "\n# {} ({})", delta.divergence.title(), count)?;
+        }
+        let suffix = if delta.sides().any(|side| side.file_type().is_dir()) { "/" } else { "" };
+        write!(output, "{}{} {}{}", status(&delta.a), status(&delta.b), delta.virtual_path, suffix)?;
+        if let (Divergence::Conflict, Some(a), Some(b)) = (delta.divergence, &delta.a, &delta.b) {
+            write!(output, "  (a: {}; b: {})", describe(a), describe(b))?;
+        }
+        writeln!(output)?;
+    }
+    Ok(())
+}
+
+/// The changed attributes of a modification, or the type of any other delta.
+fn describe(delta: &Delta<'_>) -> String {
+    match delta.delta_type() {
+        DeltaType::Modification(changes) | DeltaType::Unstable(changes) if !changes.is_empty() => {
+            changes.iter().map(|change| change.name()).collect::<Vec<_>>().join(", ")
+        },
+        delta_type => delta_type.name().to_string(),
+    }
+}
+
+/// One document with the compared indexes and the changed paths, each with its divergence
+/// and the deltas of both sides against the base as `cmp --format json` writes them.
+fn write_json(output: &mut impl Write, indexes: &[(&str, &str, &Vec<String>)], deltas: &[ThreeWayDelta<'_>]) -> ConvertibleResult<()> {
+    write!(output, "{{")?;
+    for (name, db_path, roots) in indexes.iter() {
+        let roots: Vec<String> = roots.iter().map(|root| quote::json(root)).collect();
+        write!(output, "\n\"{}\":{{\"db\":{},\"roots\":[{}]}},", name, quote::json(db_path), roots.join(","))?;
+    }
+    write!(output, "\n\"paths\":[")?;
+    let object = |delta: &Option<Delta<'_>>| delta.as_ref().map_or("null".to_string(), json_writer::object);
+    for (i, delta) in deltas.iter().enumerate() {
+        let separator = if i == 0 { "\n" } else { ",\n" };
+        write!(output, "{}{{\"path\":{},\"divergence\":{},\"a\":{},\"b\":{}}}", separator,
+            quote::json(&delta.virtual_path), quote::json(delta.divergence.name()), object(&delta.a), object(&delta.b))?;
+    }
+    writeln!(output, "\n]\n}}")?;
+    Ok(())
+}
+
+pub fn cmdline<'a>() -> clap::App<'a, 'a> {
+    clap::App::new("cmp3")
+        .about("Three-way comparison of two indexes that diverged from a common base index: which paths changed\n\
+                on one side only, on both sides alike, and which conflict.")
+        .arg(clap::Arg::with_name("base")
+            .long("base")
+            .value_name("FILE[@RUN]")
+            .help("The index both sides diverged from, e.g. the last snapshot they were in sync.")
+            .required(true))
+        .arg(clap::Arg::with_name("first-index")
+            .short("a")
+            .long("first-index")
+            .value_name("FILE[@RUN]")
+            .help("The first side, e.g. one replica. Same format as --base.")
+            .required(true))
+        .arg(clap::Arg::with_name("second-index")
+            .short("b")
+            .long("second-index")
+            .value_name("FILE[@RUN]")
+            .help("The second side. Same format as --base.")
+            .required(true))
+        .arg(clap::Arg::with_name("root-base")
+            .long("root-base")
+            .value_name("ROOT")
+            .multiple(true)
+            .help("Add ROOT as a comparison root for the base index, like cmp --root-a. Default: '/'."))
+        .arg(clap::Arg::with_name("root-a")
+            .long("root-a")
+            .value_name("ROOT")
+            .multiple(true)
+            .help("Add ROOT as a comparison root for the 'a' index. Default: '/'."))
+        .arg(clap::Arg::with_name("root-b")
+            .long("root-b")
+            .value_name("ROOT")
+            .multiple(true)
+            .help("Add ROOT as a comparison root for the 'b' index. Default: '/'."))
+        .arg(clap::Arg::with_name("mode")
+            .long("mode")
+            .short("m")
+            .value_name("MODE")
+            .help("What attributes should count towards being a change, like cmp --mode. Default: 'csugpmh'."))
+        .arg(clap::Arg::with_name("mode-all")
+            .long("mode-all")
+            .short("A")
+            .conflicts_with_all(&["mode", "mode-min"])
+            .takes_value(false)
+            .help("Enable all flags for mode. Equivalent to --mode tcsugpbmlinh"))
+        .arg(clap::Arg::with_name("mode-min")
+            .long("mode-min")
+            .short("M")
+            .conflicts_with_all(&["mode", "mode-all"])
+            .takes_value(false)
+            .help("Enable a small subset of flags for mode. Equivalent to --mode csm"))
+        .arg(clap::Arg::with_name("map-users")
+            .long("map-users")
+            .value_name("STRATEGY")
+            .possible_values(&["by-name"])
+            .next_line_help(true)
+            .help("Translate the uids and gids of 'a' and 'b' into those of the base before comparing ownership,\n\
+                  like cmp --map-users."))
+        .arg(clap::Arg::with_name("user-map")
+            .long("user-map")
+            .value_name("FILE")
+            .next_line_help(true)
+            .help("Translate uids using FILE, which has one 'A:B' entry per line, like cmp --user-map.\n\
+                  A is a uid or user name in the 'a' and 'b' indexes, B in the base. Overrides --map-users."))
+        .arg(clap::Arg::with_name("group-map")
+            .long("group-map")
+            .value_name("FILE")
+            .next_line_help(true)
+            .help("Translate gids using FILE. Same format as --user-map."))
+        .arg(clap::Arg::with_name("format")
+            .long("format")
+            .value_name("FORMAT")
+            .possible_values(&["text", "json"])
+            .next_line_help(true)
+            .help("Output format (default: text). text writes a line per changed path like 'git status --short',\n\
+                  with a status column for 'a' and one for 'b', conflicts first; json writes the deltas of both sides."))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::comparator::test_nodes::{alice, file, pool};
+    use crate::db_models::fs_node::FsNode;
+
+    fn divergences(base: &[FsNode], a: &[FsNode], b: &[FsNode], id_map_a: &IdMap, id_map_b: &IdMap) -> Vec<(String, Divergence)> {
+        let attrs: HashSet<Attribute> = vec![Attribute::Size, Attribute::User].into_iter().collect();
+        compare3(pool(base), pool(a), pool(base), pool(b), &attrs, id_map_a, id_map_b).into_iter()
+            .map(|delta| (delta.virtual_path, delta.divergence))
+            .collect()
+    }
+
+    fn compared(base: &[FsNode], a: &[FsNode], b: &[FsNode]) -> Vec<(String, Divergence)> {
+        divergences(base, a, b, &IdMap::new(), &IdMap::new())
+    }
+
+    fn one(path: &str, divergence: Divergence) -> Vec<(String, Divergence)> {
+        vec![(path.to_string(), divergence)]
+    }
+
+    #[test]
+    fn one_side_changed() {
+        let base = [file("x", 1, 1000), file("y", 1, 1000)];
+        assert_eq!(compared(&base, &[file("x", 2, 1000), file("y", 1, 1000)], &base), one("x", Divergence::AOnly));
+        assert_eq!(compared(&base, &base, &[file("x", 1, 1000), file("y", 2, 1000)]), one("y", Divergence::BOnly));
+        assert_eq!(compared(&base, &[file("y", 1, 1000)], &base), one("x", Divergence::AOnly));
+        assert_eq!(compared(&base, &base, &[file("x", 1, 1000), file("y", 1, 1000), file("z", 1, 1000)]), one("z", Divergence::BOnly));
+        assert_eq!(compared(&base, &base, &base), Vec::new());
+    }
+
+    #[test]
+    fn both_sides_changed() {
+        let base = [file("x", 1, 1000)];
+        assert_eq!(compared(&base, &[file("x", 2, 1000)], &[file("x", 3, 1000)]), one("x", Divergence::Conflict));
+        assert_eq!(compared(&base, &[file("x", 2, 1000)], &[file("x", 2, 1000)]), one("x", Divergence::Identical));
+        assert_eq!(compared(&[], &[file("x", 2, 1000)], &[file("x", 3, 1000)]), one("x", Divergence::Conflict));
+        assert_eq!(compared(&[], &[file("x", 2, 1000)], &[file("x", 2, 1000)]), one("x", Divergence::Identical));
+    }
+
+    #[test]
+    fn deletions() {
+        let base = [file("x", 1, 1000)];
+        assert_eq!(compared(&base, &[], &[]), one("x", Divergence::Identical));
+        assert_eq!(compared(&base, &[], &[file("x", 2, 1000)]), one("x", Divergence::Conflict));
+        assert_eq!(compared(&base, &[file("x", 2, 1000)], &[]), one("x", Divergence::Conflict));
+    }
+
+    #[test]
+    fn ordered_by_divergence_then_path() {
+        let base = [file("a", 1, 1000), file("b", 1, 1000), file("c", 1, 1000), file("d", 1, 1000)];
+        let a = [file("a", 2, 1000), file("b", 2, 1000), file("c", 1, 1000), file("d", 2, 1000)];
+        let b = [file("a", 1, 1000), file("b", 3, 1000), file("c", 2, 1000), file("d", 2, 1000)];
+        assert_eq!(compared(&base, &a, &b), vec![
+            ("b".to_string(), Divergence::Conflict),
+            ("d".to_string(), Divergence::Identical),
+            ("a".to_string(), Divergence::AOnly),
+            ("c".to_string(), Divergence::BOnly),
+        ]);
+    }
+
+    #[test]
+    fn owners_translated_into_the_base() {
+        let base = [file("x", 1, 1000), file("y", 1, 1000)];
+        let a = [file("x", 2, 2000), file("y", 1, 2000)];
+        let b = [file("x", 2, 3000), file("y", 1, 3000)];
+        assert_eq!(divergences(&base, &a, &b, &alice(2000), &alice(3000)), one("x", Divergence::Identical));
+        assert_eq!(compared(&base, &a, &b), vec![
+            ("x".to_string(), Divergence::Conflict),
+            ("y".to_string(), Divergence::Conflict),
+        ]);
+        let b = [file("x", 2, 1000), file("y", 1, 1000)];
+        assert_eq!(divergences(&base, &a, &b, &alice(2000), &IdMap::new()), one("x", Divergence::Identical));
+    }
+}
@@ -1,11 +1,12 @@
 
 use crate::db_models::fs_node::FsNode;
+use crate::db_models::mount::Mount;
 use std::path::PathBuf;
 
 /// A "virtual" FsNode is a wrapper that adds the concept of a _root path_.
 /// When comparing files, we add them to a virtual pool, and the root path
 /// specifies what folder we want to source files into the pool from.
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct VirtualFsNode<'fsnode> {
 
     /// The root of this virtual node; a substring of the full path.
@@ -16,24 +17,36 @@ pub struct VirtualFsNode<'fsnode> {
 
     ///
     pub fs_node: &'fsnode FsNode,
+
+    /// The filesystem the node resides on, if the index recorded it.
+    pub mount: Option<&'fsnode Mount>,
+
+    /// Virtual paths of the other nodes in the pool that are hard links to the same inode, sorted.
+    pub link_partners: Vec<String>,
 }
 
 impl<'a> From<(String, &'a FsNode)> for VirtualFsNode<'a> {
     fn from(tuple: (String, &'a FsNode)) -> Self {
         let (root_string, fs_node) = tuple;
 
-        let mut full_path = PathBuf::from(&fs_node.parent_path);
-        full_path.push(&fs_node.name);
-
-        let root = PathBuf::from(&root_string);
-        let virtual_path = full_path.clone();
-        let virtual_path = virtual_path.strip_prefix(&root)
-            .expect("root path was not prefix of the full path");
-
         VirtualFsNode {
             fs_node,
+            mount: None,
+            link_partners: Vec::new(),
+            virtual_path: virtual_path(&root_string, fs_node),
             root: root_string,
-            virtual_path: String::from(virtual_path.to_str().unwrap())
         }
     }
-}
\ No newline at end of file
+}
+
+/// The full path of the node, minus the root.
+pub fn virtual_path(root: &str, fs_node: &FsNode) -> String {
+    let mut full_path = PathBuf::from(&fs_node.parent_path);
+    full_path.push(&fs_node.name);
+
+    let root = PathBuf::from(root);
+    let virtual_path = full_path.strip_prefix(&root)
+        .expect("root path was not prefix of the full path");
+
+    String::from(virtual_path.to_str().unwrap())
+}
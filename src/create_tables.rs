@@ -1,7 +0,0 @@
-use rusqlite::{Connection};
-
-pub fn execute(conn: &Connection) -> rusqlite::Result<()> {
-    conn.execute_batch(
-        include_str!("create_tables.sql")
-    )
-}
\ No newline at end of file
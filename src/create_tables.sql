@@ -1,19 +1,15 @@
--- exported from magnetar server v. 0.0.1-SNAPSHOT.
--- removed unapplicable tables.
+-- the schema of new index databases, at the version given by schema::SCHEMA_VERSION.
+-- changes to this file need a corresponding migration in schema.rs.
 
 BEGIN TRANSACTION;
 
-CREATE TABLE IF NOT EXISTS "user" (
-	"id"	bigint NOT NULL,
-	"password_hash"	varchar(255),
-	"user_name"	varchar(255),
-	PRIMARY KEY("id")
-);
 CREATE TABLE IF NOT EXISTS "unix_user" (
 	"id"	bigint NOT NULL,
 	"name"	TEXT,
 	"uid"	integer NOT NULL,
 	"host_id"	bigint,
+	"run_id"	INTEGER NOT NULL DEFAULT 1,
+	"source_id"	INTEGER NOT NULL DEFAULT 0,
 	PRIMARY KEY("id")
 );
 CREATE TABLE IF NOT EXISTS "unix_group" (
@@ -21,11 +17,8 @@ CREATE TABLE IF NOT EXISTS "unix_group" (
 	"gid"	integer NOT NULL,
 	"name"	TEXT,
 	"host_id"	bigint,
-	PRIMARY KEY("id")
-);
-CREATE TABLE IF NOT EXISTS "sshkey" (
-	"id"	bigint NOT NULL,
-	"user_id"	bigint,
+	"run_id"	INTEGER NOT NULL DEFAULT 1,
+	"source_id"	INTEGER NOT NULL DEFAULT 0,
 	PRIMARY KEY("id")
 );
 CREATE TABLE IF NOT EXISTS "indexing_run" (
@@ -33,6 +26,7 @@ CREATE TABLE IF NOT EXISTS "indexing_run" (
 	"timestamp"	datetime,
 	"host_id"	bigint,
 	"parent_run_id"	bigint,
+	"delta"	INTEGER NOT NULL DEFAULT 0,
 	PRIMARY KEY("id")
 );
 CREATE TABLE IF NOT EXISTS "host_address" (
@@ -47,6 +41,17 @@ CREATE TABLE IF NOT EXISTS "host" (
 	"fqdn"	TEXT,
 	PRIMARY KEY("id")
 );
+CREATE TABLE IF NOT EXISTS "mount" (
+	"id"	INTEGER PRIMARY KEY AUTOINCREMENT,
+	"device"	INTEGER NOT NULL,
+	"mount_point"	TEXT NOT NULL,
+	"fs_type"	TEXT NOT NULL,
+	"source"	TEXT NOT NULL,
+	"options"	TEXT NOT NULL,
+	"super_options"	TEXT NOT NULL,
+	"run_id"	INTEGER NOT NULL DEFAULT 1,
+	"source_id"	INTEGER NOT NULL DEFAULT 0
+);
 CREATE TABLE IF NOT EXISTS "fs_node" (
 	"id"	INTEGER PRIMARY KEY AUTOINCREMENT,
 	"node_type"	INTEGER,
@@ -62,7 +67,40 @@ CREATE TABLE IF NOT EXISTS "fs_node" (
 	"links_to"	TEXT,
 	"inode" INTEGER ,
 	"nlinks" INTEGER ,
+	"unstable" INTEGER NOT NULL DEFAULT 0,
+	"device" INTEGER NOT NULL DEFAULT 0,
+	"link_group" INTEGER NOT NULL DEFAULT 0,
+	"run_id"	INTEGER NOT NULL DEFAULT 1,
+	"removed"	INTEGER NOT NULL DEFAULT 0,
+	"source_id"	INTEGER NOT NULL DEFAULT 0,
+	"md5_checksum"	TEXT NOT NULL DEFAULT '',
+	"sha256_checksum"	TEXT NOT NULL DEFAULT '',
+	"unknown_attrs"	INTEGER NOT NULL DEFAULT 0,
 	"parent_id"	INTEGER
 );
+CREATE TABLE IF NOT EXISTS "merge_source" (
+	"id"	INTEGER PRIMARY KEY AUTOINCREMENT,
+	"run_id"	INTEGER NOT NULL,
+	"db_path"	TEXT NOT NULL,
+	"source_run_id"	INTEGER NOT NULL,
+	"host_id"	bigint,
+	"prefix"	TEXT NOT NULL
+);
+CREATE TABLE IF NOT EXISTS "run_signature" (
+	"run_id"	INTEGER PRIMARY KEY,
+	"algorithm"	TEXT NOT NULL,
+	"digest"	TEXT NOT NULL,
+	"public_key"	TEXT NOT NULL,
+	"signature"	TEXT NOT NULL,
+	"signed_at"	INTEGER NOT NULL
+);
+CREATE TABLE IF NOT EXISTS "run_link" (
+	"run_id"	INTEGER PRIMARY KEY,
+	"digest"	TEXT NOT NULL,
+	"previous_db"	TEXT NOT NULL,
+	"previous_run_id"	INTEGER NOT NULL,
+	"previous_digest"	TEXT NOT NULL
+);
+CREATE INDEX IF NOT EXISTS "fs_node_run_id" ON "fs_node" ("run_id");
 
 COMMIT;
\ No newline at end of file
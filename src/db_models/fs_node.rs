@@ -1,13 +1,20 @@
+use std::collections::HashMap;
 use std::fmt;
 use std::fmt::Display;
-use std::path::Path;
-use crate::apperror::AppError;
-use crate::errorwrapper::ErrorWrapper;
+use crate::db_models::indexing_run::{IndexingRun, RunRef};
 
 ///
 /// sha1_checksum: 40 chars long
 /// links_to: for soft links (symlinks)
 /// nlinks: number of hard links to this inode
+/// unstable: the file changed while it was being hashed, so the checksum is unreliable
+/// device: `st_dev` of the filesystem the node resides on; links to `Mount::device`
+/// link_group: shared by all paths of the run that are hard links to the same inode; 0 if none
+/// run_id: the indexing run the node was recorded in; fk: IndexingRun::id
+/// removed: in a delta run, marks a node of the parent run that no longer exists
+/// source_id: in a merged run, the index the node was copied from; fk: MergeSource::id; 0 if indexed directly
+/// md5_checksum, sha256_checksum: only recorded if asked for, or if imported; empty if not
+/// unknown_attrs: `ATTR_*` bits of the attributes the source of the node did not record (see [FsNode::knows]())
 /// TODO: use diesel for ORM. https://github.com/diesel-rs/diesel
 /// i64 instead of u64 beacause of some sqlite spec.
 #[derive(Default, Debug, Clone)]
@@ -26,9 +33,61 @@ pub struct FsNode {
     pub links_to: String, // for soft links (symlinks)
     pub inode: i64,
     pub nlinks: i64, // number of hard links to this inode
+    pub unstable: bool, // changed while being hashed
+    pub device: i64, // st_dev
+    pub link_group: i64, // 0: not hard linked
+    pub run_id: i64, // fk: IndexingRun::id
+    pub removed: bool, // tombstone in a delta run
+    pub source_id: i64, // fk: MergeSource::id; 0: not merged
+    pub md5_checksum: String, // 32 chars; empty if not recorded
+    pub sha256_checksum: String, // 64 chars; empty if not recorded
+    pub unknown_attrs: u32, // ATTR_* bits
     //pub parent_id: i64, // fk: FsNode::id
 }
 
+// Bits of FsNode::unknown_attrs. Nodes indexed by magnetar know all attributes,
+// but those imported from e.g. checksum manifests only know some.
+pub const ATTR_NODE_TYPE: u32 = 1;
+pub const ATTR_SIZE: u32 = 1 << 1;
+pub const ATTR_UID: u32 = 1 << 2;
+pub const ATTR_GID: u32 = 1 << 3;
+pub const ATTR_PERMISSIONS: u32 = 1 << 4;
+pub const ATTR_CREATION_DATE: u32 = 1 << 5;
+pub const ATTR_MODIFIED_DATE: u32 = 1 << 6;
+pub const ATTR_LINKS_TO: u32 = 1 << 7;
+pub const ATTR_INODE: u32 = 1 << 8;
+pub const ATTR_NLINKS: u32 = 1 << 9;
+pub const ATTR_SHA1: u32 = 1 << 10;
+pub const ATTR_LINK_GROUP: u32 = 1 << 11;
+pub const ATTR_ALL: u32 = (1 << 12) - 1;
+
+const SELECT_BY_RUN: &str = "SELECT \
+                    id, \
+                    node_type, \
+                    sha1_checksum, \
+                    parent_path, \
+                    name, \
+                    size, \
+                    uid, \
+                    gid, \
+                    permissions, \
+                    creation_date, \
+                    modified_date, \
+                    links_to, \
+                    inode, \
+                    nlinks, \
+                    unstable, \
+                    device, \
+                    link_group, \
+                    run_id, \
+                    removed, \
+                    source_id, \
+                    md5_checksum, \
+                    sha256_checksum, \
+                    unknown_attrs \
+                    FROM fs_node \
+                    WHERE run_id = ?1";
+
 #[derive(Debug, Clone, Eq, PartialEq)]
 pub enum NodeType {
     File,
@@ -71,6 +130,17 @@ impl NodeType {
         }
     }
 
+    /// The name of the node type in exported and machine-readable output.
+    pub fn name(&self) -> &'static str {
+        match self {
+            NodeType::File => "file",
+            NodeType::Directory => "dir",
+            NodeType::Symlink => "symlink",
+            NodeType::Other => "other",
+            NodeType::Error => "error",
+        }
+    }
+
     pub fn is_dir(&self) -> bool {
         match self {
             NodeType::Directory => true,
@@ -130,8 +200,17 @@ impl FsNode {
                     modified_date, \
                     links_to, \
                     inode, \
-                    nlinks) \
-                    VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13)",
+                    nlinks, \
+                    unstable, \
+                    device, \
+                    link_group, \
+                    run_id, \
+                    removed, \
+                    source_id, \
+                    md5_checksum, \
+                    sha256_checksum, \
+                    unknown_attrs) \
+                    VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13, ?14, ?15, ?16, ?17, ?18, ?19, ?20, ?21, ?22)",
             rusqlite::params![
                 self.node_type.value(),
                 self.sha1_checksum,
@@ -145,31 +224,25 @@ impl FsNode {
                 self.modified_date,
                 self.links_to,
                 self.inode,
-                self.nlinks
+                self.nlinks,
+                self.unstable,
+                self.device,
+                self.link_group,
+                self.run_id,
+                self.removed,
+                self.source_id,
+                self.md5_checksum,
+                self.sha256_checksum,
+                self.unknown_attrs
             ]
         )?;
         Ok(())
     }
 
-    pub fn select(conn: &rusqlite::Connection) -> rusqlite::Result<Vec<FsNode>> {
+    pub fn select(conn: &rusqlite::Connection, run_id: i64) -> rusqlite::Result<Vec<FsNode>> {
         let mut fs_nodes = Vec::new();
-        let mut stmt = conn.prepare("SELECT \
-                    id, \
-                    node_type, \
-                    sha1_checksum, \
-                    parent_path, \
-                    name, \
-                    size, \
-                    uid, \
-                    gid, \
-                    permissions, \
-                    creation_date, \
-                    modified_date, \
-                    links_to, \
-                    inode, \
-                    nlinks \
-                    FROM fs_node")?;
-        let row_iterator = stmt.query_map(rusqlite::NO_PARAMS, |row| FsNode::map_from_row(row))?;
+        let mut stmt = conn.prepare(SELECT_BY_RUN)?;
+        let row_iterator = stmt.query_map(rusqlite::params![run_id], |row| FsNode::map_from_row(row))?;
         for fs_node in row_iterator {
             let fs_node = fs_node?;
             fs_nodes.push(fs_node);
@@ -193,33 +266,216 @@ impl FsNode {
             links_to: row.get("links_to")?,
             inode: row.get("inode")?,
             nlinks: row.get("nlinks")?,
+            unstable: row.get("unstable")?,
+            device: row.get("device")?,
+            link_group: row.get("link_group")?,
+            run_id: row.get("run_id")?,
+            removed: row.get("removed")?,
+            source_id: row.get("source_id")?,
+            md5_checksum: row.get("md5_checksum")?,
+            sha256_checksum: row.get("sha256_checksum")?,
+            unknown_attrs: row.get("unknown_attrs")?,
         })
     }
 
-    pub fn select_n(db_path: &str) -> crate::ConvertibleResult<Vec<FsNode>> {
-        log::debug!("fetching fs_nodes from '{}'", db_path);
+    pub fn delete(conn: &rusqlite::Transaction<'_>, run_id: i64) -> rusqlite::Result<usize> {
+        conn.execute("DELETE FROM fs_node WHERE run_id = ?1", rusqlite::params![run_id])
+    }
+
+    /// A row of a delta run, recording that the node `name` of the parent run no longer exists.
+    pub fn tombstone(name: String, parent_path: String) -> FsNode {
+        FsNode { name, parent_path, removed: true, ..FsNode::default() }
+    }
+
+    /// Whether the source of the node recorded all of the given `ATTR_*` attributes.
+    pub fn knows(&self, attrs: u32) -> bool {
+        self.unknown_attrs & attrs == 0
+    }
+
+    /// Whether both rows record the same state of the node; the row and run ids are not compared.
+    pub fn same_record(&self, other: &FsNode) -> bool {
+        self.node_type == other.node_type
+            && self.sha1_checksum == other.sha1_checksum
+            && self.parent_path == other.parent_path
+            && self.name == other.name
+            && self.size == other.size
+            && self.uid == other.uid
+            && self.gid == other.gid
+            && self.permissions == other.permissions
+            && self.creation_date == other.creation_date
+            && self.modified_date == other.modified_date
+            && self.links_to == other.links_to
+            && self.inode == other.inode
+            && self.nlinks == other.nlinks
+            && self.unstable == other.unstable
+            && self.device == other.device
+            && self.link_group == other.link_group
+            && self.removed == other.removed
+            && self.source_id == other.source_id
+            && self.md5_checksum == other.md5_checksum
+            && self.sha256_checksum == other.sha256_checksum
+            && self.unknown_attrs == other.unknown_attrs
+    }
+
+    /// Fetches all nodes of the run. If the run is stored as a delta, it is reconstructed by
+    /// applying the delta runs of its chain (see [IndexingRun::chain]()) to the full run they start from.
+    pub fn select_run(conn: &rusqlite::Connection, run: &IndexingRun) -> crate::ConvertibleResult<Vec<FsNode>> {
+        let chain = run.chain(conn)?;
+        if chain.len() > 1 {
+            log::debug!("run {}: reconstructing from run {} and {} delta(s)", run.id, chain[0].id, chain.len() - 1);
+        }
+
+        let mut fs_nodes: Vec<Option<FsNode>> = Vec::new();
+        let mut positions: HashMap<String, usize> = HashMap::new();
+        for chain_run in chain.iter() {
+            for fs_node in FsNode::select(conn, chain_run.id)? {
+                match (positions.get(&fs_node.name), fs_node.removed) {
+                    (Some(&i), true) => { fs_nodes[i] = None; },
+                    (Some(&i), false) => { fs_nodes[i] = Some(fs_node); },
+                    (None, true) => {},
+                    (None, false) => {
+                        positions.insert(fs_node.name.clone(), fs_nodes.len());
+                        fs_nodes.push(Some(fs_node));
+                    },
+                }
+            }
+        }
+
+        Ok(fs_nodes.into_iter()
+            .flatten()
+            .map(|fs_node| FsNode { run_id: run.id, ..fs_node })
+            .collect())
+    }
 
-        let index_db_path = Path::new(db_path);
-        if !index_db_path.exists() {
-            let error = AppError::WithMessage(
-                format!("database '{}' not found.", index_db_path.to_string_lossy())
-            );
-            log::error!("{}", error);
-            return Err(ErrorWrapper::AppError(error))
+    /// Calls `f` with each node of the run, like [FsNode::select_run]() would return them, but streamed
+    /// from the database: runs stored in full in the order they were stored, and delta runs merged from
+    /// their chain row by row (see [SortedRun]()), ordered by name.
+    pub fn for_each_in_run<F>(conn: &rusqlite::Connection, run: &IndexingRun, mut f: F) -> crate::ConvertibleResult<()>
+        where F: FnMut(FsNode) -> crate::ConvertibleResult<()>
+    {
+        if run.delta {
+            let mut sorted_run = SortedRun::prepare(conn, run, "")?;
+            for fs_node in sorted_run.nodes()? {
+                f(fs_node?)?;
+            }
+            return Ok(());
         }
 
+        let mut stmt = conn.prepare(SELECT_BY_RUN)?;
+        let row_iterator = stmt.query_map(rusqlite::params![run.id], FsNode::map_from_row)?;
+        for fs_node in row_iterator {
+            f(fs_node?)?;
+        }
+        Ok(())
+    }
+
+    /// Fetches the nodes of the run referenced by `db_ref`, written `DB[@RUN]` (see [RunRef]()).
+    pub fn select_n(db_ref: &str) -> crate::ConvertibleResult<Vec<FsNode>> {
+        log::debug!("fetching fs_nodes from '{}'", db_ref);
+
         let mut fs_nodes = Vec::new();
         { // open for db work
-            let conn = rusqlite::Connection::open(index_db_path)?;
-            log::debug!("{}: database connection opened", index_db_path.to_string_lossy().as_ref());
+            let (conn, run) = RunRef::parse(db_ref).open_read_only()?;
+            log::debug!("{}: database connection opened", db_ref);
             std::mem::drop(fs_nodes);
-            fs_nodes = FsNode::select(&conn)?;
-            log::debug!("{}: retrieved {} rows.", index_db_path.to_string_lossy().as_ref(), fs_nodes.len());
+            fs_nodes = FsNode::select_run(&conn, &run)?;
+            log::debug!("{}: retrieved {} rows.", db_ref, fs_nodes.len());
         } // drops all db connections
-        log::debug!("{}: database connection closed", index_db_path.to_string_lossy().as_ref());
+        log::debug!("{}: database connection closed", db_ref);
 
         Ok(fs_nodes)
     }
 }
 
+type RowMapper = fn(&rusqlite::Row<'_>) -> rusqlite::Result<FsNode>;
+
+/// The prepared statements to read the nodes of a run ordered by name, see [SortedRun::nodes]().
+/// Unlike [FsNode::select_run](), delta runs are not reconstructed in memory, but merged from
+/// their chain as they are read.
+pub struct SortedRun<'conn> {
+    run_id: i64,
+
+    /// Only nodes whose parent path starts with the prefix are read.
+    prefix: String,
+
+    /// The runs of the chain of the run, each with its statement.
+    chain: Vec<(i64, rusqlite::Statement<'conn>)>,
+}
+
+/// The nodes of a [SortedRun](), ordered by name.
+pub struct SortedNodes<'stmt> {
+    run_id: i64,
+    chain: Vec<std::iter::Peekable<rusqlite::MappedRows<'stmt, RowMapper>>>,
+}
+
+impl<'conn> SortedRun<'conn> {
+
+    pub fn prepare(conn: &'conn rusqlite::Connection, run: &IndexingRun, prefix: &str) -> crate::ConvertibleResult<SortedRun<'conn>> {
+        let sql = format!("{} AND substr(parent_path, 1, length(?2)) = ?2 ORDER BY name", SELECT_BY_RUN);
+        let mut chain = Vec::new();
+        for chain_run in run.chain(conn)? {
+            chain.push((chain_run.id, conn.prepare(&sql)?));
+        }
+        Ok(SortedRun { run_id: run.id, prefix: prefix.to_string(), chain })
+    }
+
+    pub fn nodes(&mut self) -> rusqlite::Result<SortedNodes<'_>> {
+        let prefix = &self.prefix;
+        let mut chain = Vec::new();
+        for (run_id, stmt) in self.chain.iter_mut() {
+            chain.push(stmt.query_map(rusqlite::params![*run_id, prefix], FsNode::map_from_row as RowMapper)?.peekable());
+        }
+        Ok(SortedNodes { run_id: self.run_id, chain })
+    }
+}
+
+impl Iterator for SortedNodes<'_> {
+    type Item = rusqlite::Result<FsNode>;
+
+    fn next(&mut self) -> Option<rusqlite::Result<FsNode>> {
+        loop {
+            let mut name: Option<String> = None;
+            for rows in self.chain.iter_mut() {
+                match rows.peek() {
+                    Some(Ok(fs_node)) if name.as_ref().is_none_or(|name| fs_node.name < *name) => {
+                        name = Some(fs_node.name.clone());
+                    },
+                    Some(Ok(_)) => {},
+                    Some(Err(_)) => return rows.next(),
+                    None => {},
+                }
+            }
+            let name = name?;
+
+            // like in select_run, the last run of the chain that has a row for the node decides
+            let mut latest = None;
+            for rows in self.chain.iter_mut() {
+                if let Some(Ok(fs_node)) = rows.peek() {
+                    if fs_node.name == name {
+                        latest = rows.next();
+                    }
+                }
+            }
+            match latest {
+                Some(Ok(fs_node)) if fs_node.removed => continue,
+                Some(Ok(fs_node)) => return Some(Ok(FsNode { run_id: self.run_id, ..fs_node })),
+                other => return other,
+            }
+        }
+    }
+}
+
+impl fmt::Debug for SortedRun<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let chain: Vec<i64> = self.chain.iter().map(|(run_id, _)| *run_id).collect();
+        write!(f, "SortedRun {{ run_id: {}, prefix: {:?}, chain: {:?} }}", self.run_id, self.prefix, chain)
+    }
+}
+
+impl fmt::Debug for SortedNodes<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "SortedNodes {{ run_id: {} }}", self.run_id)
+    }
+}
+
 
@@ -0,0 +1,44 @@
+use rusqlite::OptionalExtension;
+
+/// A host that indexing runs were made on.
+#[derive(Default, Debug, Clone)]
+pub struct Host {
+    pub id: i64,
+    pub display_name: String,
+    pub fqdn: Option<String>,
+}
+
+impl Host {
+
+    /// Finds the host with the given name, or adds it. Returns the id of the host.
+    pub fn select_or_insert(conn: &rusqlite::Transaction<'_>, display_name: &str) -> rusqlite::Result<i64> {
+        let existing: Option<i64> = conn.query_row(
+            "SELECT id FROM host WHERE display_name = ?1",
+            rusqlite::params![display_name],
+            |row| row.get(0)
+        ).optional()?;
+
+        if let Some(id) = existing {
+            return Ok(id);
+        }
+
+        let id: i64 = conn.query_row("SELECT IFNULL(MAX(id), 0) + 1 FROM host", rusqlite::NO_PARAMS, |row| row.get(0))?;
+        conn.execute(
+            "INSERT INTO host (id, display_name) VALUES (?1, ?2)",
+            rusqlite::params![id, display_name]
+        )?;
+        Ok(id)
+    }
+
+    pub fn select_one(conn: &rusqlite::Connection, id: i64) -> rusqlite::Result<Option<Host>> {
+        conn.query_row(
+            "SELECT id, display_name, fqdn FROM host WHERE id = ?1",
+            rusqlite::params![id],
+            |row| Ok(Host {
+                id: row.get("id")?,
+                display_name: row.get("display_name")?,
+                fqdn: row.get("fqdn")?,
+            })
+        ).optional()
+    }
+}
@@ -0,0 +1,189 @@
+use crate::apperror::AppError;
+use crate::db_models::host::Host;
+use crate::errorwrapper::ErrorWrapper;
+use crate::schema;
+use rusqlite::OptionalExtension;
+use std::collections::HashSet;
+use std::fmt;
+
+/// One run of the indexer. An index database holds one or more runs (a database with
+/// several runs is called a catalog), and the rows of the other tables belong to a run by `run_id`.
+#[derive(Default, Debug, Clone)]
+pub struct IndexingRun {
+    pub id: i64,
+
+    /// Unix time the run started. Unknown for runs of databases from before runs were recorded.
+    pub timestamp: Option<i64>,
+
+    pub host_id: Option<i64>,
+
+    /// The run that was the latest in the database when this run was made.
+    pub parent_run_id: Option<i64>,
+
+    /// Whether the nodes of this run are stored as the difference to the parent run,
+    /// rather than in full. See [FsNode::select_run]().
+    pub delta: bool,
+}
+
+/// A reference to a run in an index database, written `DB[@RUN]`.
+/// Without `@RUN`, the latest run of the database is referenced.
+#[derive(Debug, Clone)]
+pub struct RunRef {
+    pub db_path: String,
+    pub run_id: Option<i64>,
+}
+
+impl IndexingRun {
+
+    /// The id the next run inserted into the database should get.
+    pub fn next_id(conn: &rusqlite::Connection) -> rusqlite::Result<i64> {
+        conn.query_row("SELECT IFNULL(MAX(id), 0) + 1 FROM indexing_run", rusqlite::NO_PARAMS, |row| row.get(0))
+    }
+
+    pub fn insert(&self, conn: &rusqlite::Transaction<'_>) -> rusqlite::Result<()> {
+        conn.execute(
+            "INSERT INTO indexing_run (id, timestamp, host_id, parent_run_id, delta) VALUES (?1, ?2, ?3, ?4, ?5)",
+            rusqlite::params![self.id, self.timestamp, self.host_id, self.parent_run_id, self.delta]
+        )?;
+        Ok(())
+    }
+
+    pub fn update(&self, conn: &rusqlite::Transaction<'_>) -> rusqlite::Result<()> {
+        conn.execute(
+            "UPDATE indexing_run SET timestamp = ?2, host_id = ?3, parent_run_id = ?4, delta = ?5 WHERE id = ?1",
+            rusqlite::params![self.id, self.timestamp, self.host_id, self.parent_run_id, self.delta]
+        )?;
+        Ok(())
+    }
+
+    /// All runs of the database, in the order they were made.
+    pub fn select(conn: &rusqlite::Connection) -> rusqlite::Result<Vec<IndexingRun>> {
+        let mut stmt = conn.prepare("SELECT id, timestamp, host_id, parent_run_id, delta FROM indexing_run ORDER BY id")?;
+        let row_iterator = stmt.query_map(rusqlite::NO_PARAMS, IndexingRun::map_from_row)?;
+        row_iterator.collect()
+    }
+
+    pub fn select_one(conn: &rusqlite::Connection, id: i64) -> rusqlite::Result<Option<IndexingRun>> {
+        conn.query_row(
+            "SELECT id, timestamp, host_id, parent_run_id, delta FROM indexing_run WHERE id = ?1",
+            rusqlite::params![id],
+            IndexingRun::map_from_row
+        ).optional()
+    }
+
+    pub fn select_latest(conn: &rusqlite::Connection) -> rusqlite::Result<Option<IndexingRun>> {
+        conn.query_row(
+            "SELECT id, timestamp, host_id, parent_run_id, delta FROM indexing_run ORDER BY id DESC LIMIT 1",
+            rusqlite::NO_PARAMS,
+            IndexingRun::map_from_row
+        ).optional()
+    }
+
+    fn map_from_row(row: &rusqlite::Row<'_>) -> rusqlite::Result<IndexingRun> {
+        Ok(IndexingRun {
+            id: row.get("id")?,
+            timestamp: row.get("timestamp")?,
+            host_id: row.get("host_id")?,
+            parent_run_id: row.get("parent_run_id")?,
+            delta: row.get("delta")?,
+        })
+    }
+
+    /// The runs needed to reconstruct the nodes of this run: the closest ancestor that is
+    /// stored in full, followed by the delta runs leading from it to this run (this run last).
+    pub fn chain(&self, conn: &rusqlite::Connection) -> crate::ConvertibleResult<Vec<IndexingRun>> {
+        let mut chain = vec![self.clone()];
+        let mut visited = HashSet::new();
+        visited.insert(self.id);
+
+        while chain[chain.len() - 1].delta {
+            let run = &chain[chain.len() - 1];
+            let parent = match run.parent_run_id {
+                Some(parent_id) if visited.insert(parent_id) => IndexingRun::select_one(conn, parent_id)?,
+                _ => None,
+            };
+            match parent {
+                Some(parent) => chain.push(parent),
+                None => {
+                    let error = AppError::WithMessage(format!(
+                        "run {} is stored as a delta, but its parent run {:?} is missing", run.id, run.parent_run_id
+                    ));
+                    log::error!("{}", error);
+                    return Err(ErrorWrapper::AppError(error));
+                }
+            }
+        }
+
+        chain.reverse();
+        Ok(chain)
+    }
+}
+
+impl RunRef {
+
+    /// Parses `DB[@RUN]`. Since file names may contain `@` too, the part after
+    /// the last `@` is only taken as the run if it is a number.
+    pub fn parse(db_ref: &str) -> RunRef {
+        if let Some(i) = db_ref.rfind('@') {
+            if let Ok(run_id) = db_ref[i + 1..].parse::<i64>() {
+                return RunRef { db_path: db_ref[..i].to_string(), run_id: Some(run_id) };
+            }
+        }
+        RunRef { db_path: db_ref.to_string(), run_id: None }
+    }
+
+    /// Opens the database (see [schema::open]()) and resolves the referenced run.
+    pub fn open(&self) -> crate::ConvertibleResult<(rusqlite::Connection, IndexingRun)> {
+        let conn = schema::open(&self.db_path)?;
+        let run = self.select_run(&conn)?;
+        Ok((conn, run))
+    }
+
+    /// Like [open](), but without writing to the database (see [schema::open_read_only]()).
+    pub fn open_read_only(&self) -> crate::ConvertibleResult<(rusqlite::Connection, IndexingRun)> {
+        let conn = schema::open_read_only(&self.db_path)?;
+        let run = self.select_run(&conn)?;
+        Ok((conn, run))
+    }
+
+    /// Like [open_read_only](), in a read transaction that lasts as long as the connection: everything read
+    /// through it comes from one snapshot of the database, even if another process writes to it meanwhile.
+    pub fn open_snapshot(&self) -> crate::ConvertibleResult<(rusqlite::Connection, IndexingRun)> {
+        let conn = schema::open_read_only(&self.db_path)?;
+        conn.execute_batch("BEGIN")?;
+        let run = self.select_run(&conn)?;
+        Ok((conn, run))
+    }
+
+    fn select_run(&self, conn: &rusqlite::Connection) -> crate::ConvertibleResult<IndexingRun> {
+        let run = match self.run_id {
+            Some(run_id) => IndexingRun::select_one(conn, run_id)?,
+            None => IndexingRun::select_latest(conn)?,
+        };
+
+        match run {
+            Some(run) => {
+                let host = match run.host_id {
+                    Some(host_id) => Host::select_one(conn, host_id)?.map(|host| host.display_name),
+                    None => None,
+                };
+                log::debug!("'{}': using run {} (host: {})", self.db_path, run.id, host.as_deref().unwrap_or("unknown"));
+                Ok(run)
+            },
+            None => {
+                let error = AppError::WithMessage(format!("'{}': no such indexing run in database", self));
+                log::error!("{}", error);
+                Err(ErrorWrapper::AppError(error))
+            }
+        }
+    }
+}
+
+impl fmt::Display for RunRef {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.run_id {
+            Some(run_id) => write!(f, "{}@{}", self.db_path, run_id),
+            None => write!(f, "{}", self.db_path),
+        }
+    }
+}
@@ -0,0 +1,49 @@
+/// One of the indexes a merged run was made from. The nodes copied from it link to it by
+/// [FsNode::source_id](), so the host and run each node came from stay known.
+#[derive(Default, Debug, Clone)]
+pub struct MergeSource {
+    pub id: i64,
+
+    /// The merged run.
+    pub run_id: i64,
+
+    pub db_path: String,
+
+    /// The run of `db_path` the nodes were copied from.
+    pub source_run_id: i64,
+
+    pub host_id: Option<i64>,
+
+    /// Prepended to the paths of the nodes copied from this source; empty for none.
+    pub prefix: String,
+}
+
+impl MergeSource {
+
+    /// Inserts the source and returns the id it was assigned.
+    pub fn insert(&self, conn: &rusqlite::Transaction<'_>) -> rusqlite::Result<i64> {
+        conn.execute(
+            "INSERT INTO merge_source (run_id, db_path, source_run_id, host_id, prefix) VALUES (?1, ?2, ?3, ?4, ?5)",
+            rusqlite::params![self.run_id, self.db_path, self.source_run_id, self.host_id, self.prefix]
+        )?;
+        Ok(conn.last_insert_rowid())
+    }
+
+    /// The sources of the run, ordered by id; none unless the run was merged.
+    pub fn select(conn: &rusqlite::Connection, run_id: i64) -> rusqlite::Result<Vec<MergeSource>> {
+        let mut stmt = conn.prepare(
+            "SELECT id, run_id, db_path, source_run_id, host_id, prefix FROM merge_source WHERE run_id = ?1 ORDER BY id"
+        )?;
+        let row_iterator = stmt.query_map(rusqlite::params![run_id], |row| {
+            Ok(MergeSource {
+                id: row.get("id")?,
+                run_id: row.get("run_id")?,
+                db_path: row.get("db_path")?,
+                source_run_id: row.get("source_run_id")?,
+                host_id: row.get("host_id")?,
+                prefix: row.get("prefix")?,
+            })
+        })?;
+        row_iterator.collect()
+    }
+}
@@ -1 +1,9 @@
-pub mod fs_node;
\ No newline at end of file
+pub mod fs_node;
+pub mod host;
+pub mod indexing_run;
+pub mod merge_source;
+pub mod mount;
+pub mod run_link;
+pub mod run_signature;
+pub mod unix_group;
+pub mod unix_user;
@@ -0,0 +1,125 @@
+/// A filesystem mounted on the indexed host at index time.
+/// Nodes are linked to the filesystem they reside on by [FsNode::device](), i.e. `st_dev`,
+/// and in a merged run by [FsNode::source_id]() too, since device numbers are only unique per host.
+#[derive(Default, Debug, Clone)]
+pub struct Mount {
+    pub id: i64,
+    pub device: i64,
+    pub mount_point: String,
+    pub fs_type: String,
+    pub source: String,
+    pub options: String,
+    pub super_options: String,
+    pub run_id: i64,
+    pub source_id: i64, // fk: MergeSource::id; 0: not merged
+    /// The host the filesystem was mounted on, if known: that of the run, or of the merge source in a merged run.
+    /// Not stored with the mount, but looked up by [Mount::select]().
+    pub host: Option<String>,
+}
+
+/// Filesystems that only store modification times with a resolution of 2 seconds.
+const COARSE_MTIME_FS_TYPES: [&str; 3] = ["vfat", "msdos", "fat"];
+
+impl Mount {
+
+    pub fn insert(&self, conn: &rusqlite::Transaction<'_>) -> rusqlite::Result<()> {
+        conn.execute(
+            "INSERT INTO mount ( \
+                    device, \
+                    mount_point, \
+                    fs_type, \
+                    source, \
+                    options, \
+                    super_options, \
+                    run_id, \
+                    source_id) \
+                    VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)",
+            rusqlite::params![
+                self.device,
+                self.mount_point,
+                self.fs_type,
+                self.source,
+                self.options,
+                self.super_options,
+                self.run_id,
+                self.source_id
+            ]
+        )?;
+        Ok(())
+    }
+
+    pub fn select(conn: &rusqlite::Connection, run_id: i64) -> rusqlite::Result<Vec<Mount>> {
+        let mut stmt = conn.prepare("SELECT \
+                    mount.id, \
+                    mount.device, \
+                    mount.mount_point, \
+                    mount.fs_type, \
+                    mount.source, \
+                    mount.options, \
+                    mount.super_options, \
+                    mount.run_id, \
+                    mount.source_id, \
+                    CASE WHEN mount.source_id = 0 THEN run_host.display_name ELSE source_host.display_name END AS host \
+                    FROM mount \
+                    LEFT JOIN indexing_run ON indexing_run.id = mount.run_id \
+                    LEFT JOIN host AS run_host ON run_host.id = indexing_run.host_id \
+                    LEFT JOIN merge_source ON merge_source.id = mount.source_id \
+                    LEFT JOIN host AS source_host ON source_host.id = merge_source.host_id \
+                    WHERE mount.run_id = ?1")?;
+        let row_iterator = stmt.query_map(rusqlite::params![run_id], |row| {
+            Ok(Mount {
+                id: row.get("id")?,
+                device: row.get("device")?,
+                mount_point: row.get("mount_point")?,
+                fs_type: row.get("fs_type")?,
+                source: row.get("source")?,
+                options: row.get("options")?,
+                super_options: row.get("super_options")?,
+                run_id: row.get("run_id")?,
+                source_id: row.get("source_id")?,
+                host: row.get("host")?,
+            })
+        })?;
+        row_iterator.collect()
+    }
+
+    /// True if the filesystem stores modification times with a 2 second resolution (FAT).
+    pub fn has_coarse_mtime(&self) -> bool {
+        COARSE_MTIME_FS_TYPES.contains(&self.fs_type.as_str())
+    }
+
+    /// True if both mounts are the same filesystem, meaning inode numbers are comparable between them.
+    /// Device numbers and sources like `/dev/sda1` repeat across hosts, so the mounts must be on the same host too.
+    /// Mounts of an unknown host are only taken for those of another unknown host.
+    pub fn is_same_filesystem(&self, other: &Mount) -> bool {
+        self.host == other.host
+            && self.device == other.device
+            && self.fs_type == other.fs_type
+            && self.source == other.source
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sda1(host: Option<&str>) -> Mount {
+        Mount {
+            device: 0x801,
+            mount_point: "/".to_string(),
+            fs_type: "ext4".to_string(),
+            source: "/dev/sda1".to_string(),
+            host: host.map(str::to_string),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn same_filesystem_on_same_host_only() {
+        assert!(sda1(Some("web1")).is_same_filesystem(&sda1(Some("web1"))));
+        assert!(!sda1(Some("web1")).is_same_filesystem(&sda1(Some("web2"))));
+        assert!(!sda1(Some("web1")).is_same_filesystem(&sda1(None)));
+        assert!(sda1(None).is_same_filesystem(&sda1(None)));
+        assert!(!sda1(Some("web1")).is_same_filesystem(&Mount { device: 0x802, ..sda1(Some("web1")) }));
+    }
+}
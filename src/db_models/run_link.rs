@@ -0,0 +1,47 @@
+use rusqlite::OptionalExtension;
+
+/// Chains a run to the run indexed before it, possibly in another database file (see `idx --previous`).
+/// The digest of the run covers `previous_digest`, which in turn covered the digest before it,
+/// so a run of the chain cannot be modified or replaced without breaking the links after it.
+#[derive(Default, Debug, Clone)]
+pub struct RunLink {
+    /// fk: IndexingRun::id
+    pub run_id: i64,
+
+    /// The digest of this run, including the link (hex).
+    pub digest: String,
+
+    /// The file name of the database of the previous run; the same database for runs of a catalog.
+    pub previous_db: String,
+
+    pub previous_run_id: i64,
+
+    /// The digest of the previous run when this run was linked to it (hex).
+    pub previous_digest: String,
+}
+
+impl RunLink {
+
+    pub fn insert(&self, conn: &rusqlite::Transaction<'_>) -> rusqlite::Result<()> {
+        conn.execute(
+            "INSERT OR REPLACE INTO run_link (run_id, digest, previous_db, previous_run_id, previous_digest) \
+                    VALUES (?1, ?2, ?3, ?4, ?5)",
+            rusqlite::params![self.run_id, self.digest, self.previous_db, self.previous_run_id, self.previous_digest]
+        )?;
+        Ok(())
+    }
+
+    pub fn select_one(conn: &rusqlite::Connection, run_id: i64) -> rusqlite::Result<Option<RunLink>> {
+        conn.query_row(
+            "SELECT run_id, digest, previous_db, previous_run_id, previous_digest FROM run_link WHERE run_id = ?1",
+            rusqlite::params![run_id],
+            |row| Ok(RunLink {
+                run_id: row.get("run_id")?,
+                digest: row.get("digest")?,
+                previous_db: row.get("previous_db")?,
+                previous_run_id: row.get("previous_run_id")?,
+                previous_digest: row.get("previous_digest")?,
+            })
+        ).optional()
+    }
+}
@@ -0,0 +1,49 @@
+use rusqlite::OptionalExtension;
+
+/// The signed digest of a run, see [verifier](crate::verifier). Digest, key and signature are hex encoded.
+#[derive(Default, Debug, Clone)]
+pub struct RunSignature {
+    /// fk: IndexingRun::id; a run has at most one signature.
+    pub run_id: i64,
+
+    /// The signature algorithm, e.g. `ed25519`.
+    pub algorithm: String,
+
+    pub digest: String,
+
+    /// The public key the signature can be checked with. Whether the key is trusted is up to the reader.
+    pub public_key: String,
+
+    pub signature: String,
+
+    /// Unix time the run was signed.
+    pub signed_at: i64,
+}
+
+impl RunSignature {
+
+    /// Inserts the signature, replacing an earlier signature of the run.
+    pub fn insert(&self, conn: &rusqlite::Transaction<'_>) -> rusqlite::Result<()> {
+        conn.execute(
+            "INSERT OR REPLACE INTO run_signature (run_id, algorithm, digest, public_key, signature, signed_at) \
+                    VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+            rusqlite::params![self.run_id, self.algorithm, self.digest, self.public_key, self.signature, self.signed_at]
+        )?;
+        Ok(())
+    }
+
+    pub fn select_one(conn: &rusqlite::Connection, run_id: i64) -> rusqlite::Result<Option<RunSignature>> {
+        conn.query_row(
+            "SELECT run_id, algorithm, digest, public_key, signature, signed_at FROM run_signature WHERE run_id = ?1",
+            rusqlite::params![run_id],
+            |row| Ok(RunSignature {
+                run_id: row.get("run_id")?,
+                algorithm: row.get("algorithm")?,
+                digest: row.get("digest")?,
+                public_key: row.get("public_key")?,
+                signature: row.get("signature")?,
+                signed_at: row.get("signed_at")?,
+            })
+        ).optional()
+    }
+}
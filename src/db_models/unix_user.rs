@@ -0,0 +1,35 @@
+/// A user name table entry, as captured on the indexed host at index time.
+#[derive(Default, Debug, Clone)]
+pub struct UnixUser {
+    pub id: i64,
+    pub name: String,
+    pub uid: u32,
+    pub run_id: i64,
+    pub source_id: i64, // fk: MergeSource::id; 0: not merged
+}
+
+impl UnixUser {
+
+    pub fn insert(&self, conn: &rusqlite::Transaction<'_>) -> rusqlite::Result<()> {
+        conn.execute(
+            "INSERT INTO unix_user (id, name, uid, run_id, source_id) \
+                    VALUES ((SELECT IFNULL(MAX(id), 0) + 1 FROM unix_user), ?1, ?2, ?3, ?4)",
+            rusqlite::params![self.name, self.uid, self.run_id, self.source_id]
+        )?;
+        Ok(())
+    }
+
+    pub fn select(conn: &rusqlite::Connection, run_id: i64) -> rusqlite::Result<Vec<UnixUser>> {
+        let mut stmt = conn.prepare("SELECT id, name, uid, run_id, source_id FROM unix_user WHERE run_id = ?1")?;
+        let row_iterator = stmt.query_map(rusqlite::params![run_id], |row| {
+            Ok(UnixUser {
+                id: row.get("id")?,
+                name: row.get("name")?,
+                uid: row.get("uid")?,
+                run_id: row.get("run_id")?,
+                source_id: row.get("source_id")?,
+            })
+        })?;
+        row_iterator.collect()
+    }
+}
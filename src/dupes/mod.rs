@@ -1,5 +1,4 @@
 
-use clap;
 use crate::ConvertibleResult;
 
 pub fn run(args: &clap::ArgMatches<'_>) -> ConvertibleResult<()>{
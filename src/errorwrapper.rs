@@ -9,9 +9,17 @@ pub enum ErrorWrapper {
     Rusqlite(rusqlite::Error),
     IO(io::Error),
     SystemTimeError(time::SystemTimeError),
+    Parquet(parquet::errors::ParquetError),
     AppError(AppError),
 }
 
+/// An [AppError::WithMessage]() for `message`, logged as an error.
+pub fn app_error(message: String) -> ErrorWrapper {
+    let error = AppError::WithMessage(message);
+    log::error!("{}", error);
+    ErrorWrapper::AppError(error)
+}
+
 impl From<rusqlite::Error> for ErrorWrapper {
     fn from(e: rusqlite::Error) -> ErrorWrapper {
         ErrorWrapper::Rusqlite(e)
@@ -37,6 +45,12 @@ impl From<time::SystemTimeError> for ErrorWrapper {
     }
 }
 
+impl From<parquet::errors::ParquetError> for ErrorWrapper {
+    fn from(e: parquet::errors::ParquetError) -> ErrorWrapper {
+        ErrorWrapper::Parquet(e)
+    }
+}
+
 impl From<AppError> for ErrorWrapper {
     fn from(e: AppError) -> Self {
         ErrorWrapper::AppError(e)
@@ -49,6 +63,7 @@ impl fmt::Display for ErrorWrapper {
             ErrorWrapper::Rusqlite(e) =>        { format!("{}", e) },
             ErrorWrapper::IO(e) =>              { format!("{}", e) },
             ErrorWrapper::SystemTimeError(e) => { format!("{}", e) },
+            ErrorWrapper::Parquet(e) =>         { format!("{}", e) },
             ErrorWrapper::AppError(e) =>        { format!("{}", e) },
         };
         write!(f, "ErrorWrapper: {}", buf)
@@ -0,0 +1,91 @@
+use crate::ConvertibleResult;
+use crate::exporter::{relative_path, ExportRecord, LeftOut, RecordWriter};
+use std::io::Write;
+use std::path::PathBuf;
+
+/// The digests a checksum list can be written with.
+#[derive(Debug, Clone, Copy)]
+pub enum Digest {
+    Sha1,
+    Sha256,
+    Md5,
+}
+
+impl Digest {
+
+    fn name(self) -> &'static str {
+        match self {
+            Digest::Sha1 => "sha1",
+            Digest::Sha256 => "sha256",
+            Digest::Md5 => "md5",
+        }
+    }
+}
+
+/// Checksum lists as written by `sha1sum`, `sha256sum` and `md5sum`, to be checked with their `-c` option.
+/// Only regular files are listed, relative to the root. Files whose digest could not be calculated reliably are left out.
+pub struct ChecksumListWriter {
+    output: Box<dyn Write + Send>,
+    root: PathBuf,
+    digest: Digest,
+    missing: usize,
+    left_out: LeftOut,
+}
+
+impl ChecksumListWriter {
+
+    pub fn new(output: Box<dyn Write + Send>, root: PathBuf, digest: Digest) -> ChecksumListWriter {
+        ChecksumListWriter { output, root, digest, missing: 0, left_out: LeftOut::default() }
+    }
+}
+
+impl RecordWriter for ChecksumListWriter {
+
+    fn write(&mut self, record: ExportRecord) -> ConvertibleResult<()> {
+        if record.node_type != "file" {
+            return Ok(());
+        }
+        let path = match relative_path(&record.path, &self.root) {
+            Some(path) => path,
+            None => return Ok(()),
+        };
+        let hex = match self.digest {
+            Digest::Sha1 => &record.sha1_checksum,
+            Digest::Sha256 => &record.sha256_checksum,
+            Digest::Md5 => &record.md5_checksum,
+        };
+        if hex.is_empty() {
+            log::debug!("'{}': no {} checksum; leaving it out", record.path, self.digest.name());
+            self.missing += 1;
+            return Ok(());
+        }
+        if let Some(reason) = record.unverifiable() {
+            log::debug!("'{}': {}; leaving it out", record.path, reason.describe());
+            self.left_out.count(reason);
+            return Ok(());
+        }
+
+        // like coreutils, names with a backslash or newline are escaped, marked by a leading backslash
+        if path.contains(&['\\', '\n'][..]) {
+            writeln!(self.output, "\\{}  {}", hex, path.replace('\\', "\\\\").replace('\n', "\\n"))?;
+        } else {
+            writeln!(self.output, "{}  {}", hex, path)?;
+        }
+        Ok(())
+    }
+
+    fn finish(mut self: Box<Self>) -> ConvertibleResult<()> {
+        match self.digest {
+            _ if self.missing == 0 => {},
+            // sha1 is always calculated, unless the file could not be read
+            Digest::Sha1 => log::warn!("{} files have no sha1 checksum and were left out", self.missing),
+            digest => log::warn!(
+                "{} files have no {} checksum and were left out; index with idx --digest {} to calculate it",
+                self.missing, digest.name(), digest.name()
+            ),
+        }
+        self.left_out.warn("they were left out");
+        self.output.flush()?;
+        Ok(())
+    }
+}
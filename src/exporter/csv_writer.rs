@@ -0,0 +1,42 @@
+use crate::ConvertibleResult;
+use crate::exporter::{COLUMNS, ExportRecord, RecordWriter, Value};
+use crate::util::quote;
+use std::io::Write;
+
+/// CSV as in RFC 4180, with a header row.
+pub struct CsvWriter {
+    output: Box<dyn Write + Send>,
+    columns: Vec<usize>,
+}
+
+impl CsvWriter {
+
+    pub fn new(mut output: Box<dyn Write + Send>, columns: Vec<usize>) -> ConvertibleResult<CsvWriter> {
+        let header: Vec<&str> = columns.iter().map(|&i| COLUMNS[i].0).collect();
+        write!(output, "{}\r\n", header.join(","))?;
+        Ok(CsvWriter { output, columns })
+    }
+}
+
+impl RecordWriter for CsvWriter {
+
+    fn write(&mut self, record: ExportRecord) -> ConvertibleResult<()> {
+        let values = record.values();
+        let fields: Vec<String> = self.columns.iter()
+            .map(|&i| match values[i] {
+                Value::Text(text) | Value::OptionalText(Some(text)) => quote::csv(text),
+                Value::OptionalText(None) => String::new(),
+                Value::Integer(number) | Value::OptionalInteger(Some(number)) => number.to_string(),
+                Value::OptionalInteger(None) => String::new(),
+                Value::Boolean(boolean) => boolean.to_string(),
+            })
+            .collect();
+        write!(self.output, "{}\r\n", fields.join(","))?;
+        Ok(())
+    }
+
+    fn finish(mut self: Box<Self>) -> ConvertibleResult<()> {
+        self.output.flush()?;
+        Ok(())
+    }
+}
@@ -0,0 +1,48 @@
+use crate::ConvertibleResult;
+use crate::exporter::{COLUMNS, ExportRecord, RecordWriter, Value};
+use crate::util::quote;
+use std::io::Write;
+
+/// JSON Lines: one JSON object per node and line.
+pub struct JsonlWriter {
+    output: Box<dyn Write + Send>,
+    columns: Vec<usize>,
+}
+
+impl JsonlWriter {
+
+    pub fn new(output: Box<dyn Write + Send>, columns: Vec<usize>) -> JsonlWriter {
+        JsonlWriter { output, columns }
+    }
+}
+
+impl RecordWriter for JsonlWriter {
+
+    fn write(&mut self, record: ExportRecord) -> ConvertibleResult<()> {
+        let values = record.values();
+        let mut line = String::from("{");
+        for (i, &column) in self.columns.iter().enumerate() {
+            if i > 0 {
+                line.push(',');
+            }
+            let (name, value) = (COLUMNS[column].0, values[column]);
+            line.push_str(&quote::json(name));
+            line.push(':');
+            match value {
+                Value::Text(text) | Value::OptionalText(Some(text)) => line.push_str(&quote::json(text)),
+                Value::OptionalText(None) => line.push_str("null"),
+                Value::Integer(number) | Value::OptionalInteger(Some(number)) => line.push_str(&number.to_string()),
+                Value::OptionalInteger(None) => line.push_str("null"),
+                Value::Boolean(boolean) => line.push_str(&boolean.to_string()),
+            }
+        }
+        line.push('}');
+        writeln!(self.output, "{}", line)?;
+        Ok(())
+    }
+
+    fn finish(mut self: Box<Self>) -> ConvertibleResult<()> {
+        self.output.flush()?;
+        Ok(())
+    }
+}
@@ -0,0 +1,360 @@
+mod checksum_list_writer;
+mod csv_writer;
+mod jsonl_writer;
+mod mtree_writer;
+mod parquet_writer;
+mod text_writer;
+
+use crate::ConvertibleResult;
+use crate::apperror::AppError;
+use crate::db_models::fs_node::{self, FsNode};
+use checksum_list_writer::Digest;
+use crate::db_models::indexing_run::RunRef;
+use crate::db_models::unix_group::UnixGroup;
+use crate::db_models::unix_user::UnixUser;
+use crate::errorwrapper::ErrorWrapper;
+use crate::indexer::fs_indexer::CHECKSUM_ERR_RESULT;
+use crate::util::unix_perms::Permission;
+use chrono::TimeZone;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+/// The kind of value of an exported column.
+#[derive(Debug, Clone, Copy)]
+pub enum Kind {
+    Text,
+    OptionalText,
+    Integer,
+    OptionalInteger,
+    Boolean,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum Value<'a> {
+    Text(&'a str),
+    OptionalText(Option<&'a str>),
+    Integer(i64),
+    OptionalInteger(Option<i64>),
+    Boolean(bool),
+}
+
+/// The exported columns, in order. [ExportRecord::values]() returns the values in the same order.
+/// Attributes the index did not record (see [FsNode::knows]()), e.g. for imported manifests, are empty.
+pub const COLUMNS: [(&str, Kind); 21] = [
+    ("path", Kind::Text),
+    ("parent_path", Kind::Text),
+    ("node_type", Kind::OptionalText),
+    ("size", Kind::OptionalInteger),
+    ("permissions", Kind::OptionalText),
+    ("mode", Kind::OptionalInteger),
+    ("uid", Kind::OptionalInteger),
+    ("user", Kind::OptionalText),
+    ("gid", Kind::OptionalInteger),
+    ("group", Kind::OptionalText),
+    ("created", Kind::OptionalText),
+    ("modified", Kind::OptionalText),
+    ("sha1_checksum", Kind::OptionalText),
+    ("md5_checksum", Kind::OptionalText),
+    ("sha256_checksum", Kind::OptionalText),
+    ("links_to", Kind::OptionalText),
+    ("inode", Kind::OptionalInteger),
+    ("nlinks", Kind::OptionalInteger),
+    ("device", Kind::Integer),
+    ("link_group", Kind::OptionalInteger),
+    ("unstable", Kind::Boolean),
+];
+
+/// User or group names by merge source and id (see [FsNode::source_id]()), since in a merged run
+/// each index keeps the names of its host.
+pub type NameTable = HashMap<(i64, u32), String>;
+
+/// A node with its attributes decoded for use outside of magnetar.
+#[derive(Debug)]
+pub struct ExportRecord {
+    path: String,
+    parent_path: String,
+    node_type: &'static str,
+    size: i64,
+    permissions: String,
+    mode: u32,
+    uid: u32,
+    user: Option<String>,
+    gid: u32,
+    group: Option<String>,
+    created: Option<String>,
+    modified: Option<String>,
+    sha1_checksum: String,
+    md5_checksum: String,
+    sha256_checksum: String,
+    links_to: String,
+    inode: i64,
+    nlinks: i64,
+    device: i64,
+    link_group: i64,
+    unstable: bool,
+
+    // not exported as columns, for the manifest formats
+    modified_date: i64,
+    unknown_attrs: u32,
+}
+
+/// Files whose digests the manifest formats leave out.
+#[derive(Debug, Clone, Copy)]
+enum Unverifiable {
+    Unreadable,
+    Unstable,
+}
+
+/// The counts of the files the manifest formats left out, by reason.
+#[derive(Debug, Default)]
+struct LeftOut {
+    unreadable: usize,
+    unstable: usize,
+}
+
+impl Unverifiable {
+
+    fn describe(self) -> &'static str {
+        match self {
+            Unverifiable::Unreadable => "could not be read when indexed",
+            Unverifiable::Unstable => "kept changing while indexed",
+        }
+    }
+}
+
+impl LeftOut {
+
+    fn count(&mut self, reason: Unverifiable) {
+        match reason {
+            Unverifiable::Unreadable => self.unreadable += 1,
+            Unverifiable::Unstable => self.unstable += 1,
+        }
+    }
+
+    fn warn(&self, what: &str) {
+        for (count, reason) in [(self.unreadable, Unverifiable::Unreadable), (self.unstable, Unverifiable::Unstable)] {
+            if count > 0 {
+                log::warn!("{} files {}; {}", count, reason.describe(), what);
+            }
+        }
+    }
+}
+
+/// Writes records in one of the export formats.
+pub trait RecordWriter {
+    fn write(&mut self, record: ExportRecord) -> ConvertibleResult<()>;
+
+    /// Writes whatever is still buffered, and the trailer of the format if any.
+    fn finish(self: Box<Self>) -> ConvertibleResult<()>;
+}
+
+impl ExportRecord {
+
+    pub fn new(fs_node: FsNode, users: &NameTable, groups: &NameTable) -> ExportRecord {
+        ExportRecord {
+            node_type: fs_node.node_type.name(),
+            permissions: Permission::from_val(fs_node.permissions),
+            mode: fs_node.permissions,
+            user: users.get(&(fs_node.source_id, fs_node.uid)).cloned(),
+            group: groups.get(&(fs_node.source_id, fs_node.gid)).cloned(),
+            created: iso_timestamp(fs_node.creation_date),
+            modified: iso_timestamp(fs_node.modified_date),
+            modified_date: fs_node.modified_date,
+            unknown_attrs: fs_node.unknown_attrs,
+            path: fs_node.name,
+            parent_path: fs_node.parent_path,
+            size: fs_node.size,
+            uid: fs_node.uid,
+            gid: fs_node.gid,
+            sha1_checksum: fs_node.sha1_checksum,
+            md5_checksum: fs_node.md5_checksum,
+            sha256_checksum: fs_node.sha256_checksum,
+            links_to: fs_node.links_to,
+            inode: fs_node.inode,
+            nlinks: fs_node.nlinks,
+            device: fs_node.device,
+            link_group: fs_node.link_group,
+            unstable: fs_node.unstable,
+        }
+    }
+
+    /// Why the digests of the file cannot be used to verify it, if so: it could not be read, or kept changing while hashed.
+    fn unverifiable(&self) -> Option<Unverifiable> {
+        if self.node_type != "file" {
+            None
+        } else if [&self.sha1_checksum, &self.md5_checksum, &self.sha256_checksum].iter().any(|digest| *digest == CHECKSUM_ERR_RESULT) {
+            Some(Unverifiable::Unreadable)
+        } else if self.unstable {
+            Some(Unverifiable::Unstable)
+        } else {
+            None
+        }
+    }
+
+    /// The value, if the attribute (an `ATTR_*` bit) was recorded.
+    fn known<T>(&self, attr: u32, value: T) -> Option<T> {
+        Some(value).filter(|_| self.unknown_attrs & attr == 0)
+    }
+
+    pub fn values(&self) -> [Value<'_>; 21] {
+        [
+            Value::Text(&self.path),
+            Value::Text(&self.parent_path),
+            Value::OptionalText(self.known(fs_node::ATTR_NODE_TYPE, self.node_type)),
+            Value::OptionalInteger(self.known(fs_node::ATTR_SIZE, self.size)),
+            Value::OptionalText(self.known(fs_node::ATTR_PERMISSIONS, self.permissions.as_str())),
+            Value::OptionalInteger(self.known(fs_node::ATTR_PERMISSIONS, self.mode as i64)),
+            Value::OptionalInteger(self.known(fs_node::ATTR_UID, self.uid as i64)),
+            Value::OptionalText(self.known(fs_node::ATTR_UID, self.user.as_deref()).flatten()),
+            Value::OptionalInteger(self.known(fs_node::ATTR_GID, self.gid as i64)),
+            Value::OptionalText(self.known(fs_node::ATTR_GID, self.group.as_deref()).flatten()),
+            Value::OptionalText(self.known(fs_node::ATTR_CREATION_DATE, self.created.as_deref()).flatten()),
+            Value::OptionalText(self.known(fs_node::ATTR_MODIFIED_DATE, self.modified.as_deref()).flatten()),
+            Value::OptionalText(self.known(fs_node::ATTR_SHA1, self.sha1_checksum.as_str())),
+            Value::OptionalText(Some(self.md5_checksum.as_str()).filter(|digest| !digest.is_empty())),
+            Value::OptionalText(Some(self.sha256_checksum.as_str()).filter(|digest| !digest.is_empty())),
+            Value::OptionalText(self.known(fs_node::ATTR_LINKS_TO, self.links_to.as_str())),
+            Value::OptionalInteger(self.known(fs_node::ATTR_INODE, self.inode)),
+            Value::OptionalInteger(self.known(fs_node::ATTR_NLINKS, self.nlinks)),
+            Value::Integer(self.device),
+            Value::OptionalInteger(self.known(fs_node::ATTR_LINK_GROUP, self.link_group)),
+            Value::Boolean(self.unstable),
+        ]
+    }
+}
+
+/// ISO 8601 in UTC, e.g. `2020-11-25T13:48:54Z`.
+pub fn iso_timestamp(unix_time: i64) -> Option<String> {
+    chrono::Utc.timestamp_opt(unix_time, 0).single()
+        .map(|time| time.to_rfc3339_opts(chrono::SecondsFormat::Secs, true))
+}
+
+/// The path relative to `root`, or `None` if it is not below it.
+fn relative_path(path: &str, root: &Path) -> Option<String> {
+    match Path::new(path).strip_prefix(root) {
+        Ok(relative) if relative.as_os_str().is_empty() => None,
+        Ok(relative) => Some(relative.to_string_lossy().to_string()),
+        Err(_) => None,
+    }
+}
+
+/// The indexes into [COLUMNS]() of the comma-separated column names, or of all columns.
+pub fn select_columns(names: Option<&str>) -> ConvertibleResult<Vec<usize>> {
+    let names = match names {
+        Some(names) => names,
+        None => return Ok((0..COLUMNS.len()).collect()),
+    };
+    let mut columns = Vec::new();
+    for name in names.split(',').map(str::trim) {
+        match COLUMNS.iter().position(|(column, _)| *column == name) {
+            Some(i) => columns.push(i),
+            None => {
+                let error = AppError::WithMessage(format!(
+                    "unknown column '{}'; known are: {}", name, COLUMNS.iter().map(|(column, _)| *column).collect::<Vec<_>>().join(", ")
+                ));
+                log::error!("{}", error);
+                return Err(ErrorWrapper::AppError(error));
+            },
+        }
+    }
+    Ok(columns)
+}
+
+/// Opens the writer for `format`, writing to `path` or stdout.
+/// `columns` are written by the tabular formats, `root` is used by the manifest formats.
+pub fn writer(format: &str, path: Option<&str>, columns: Vec<usize>, root: PathBuf) -> ConvertibleResult<Box<dyn RecordWriter>> {
+    let output: Box<dyn Write + Send> = match path {
+        Some(path) => Box::new(BufWriter::new(File::create(path)?)),
+        None => Box::new(BufWriter::new(io::stdout())),
+    };
+
+    Ok(match format {
+        "text" => Box::new(text_writer::TextWriter::new(output, columns)),
+        "csv" => Box::new(csv_writer::CsvWriter::new(output, columns)?),
+        "jsonl" => Box::new(jsonl_writer::JsonlWriter::new(output, columns)),
+        "parquet" => Box::new(parquet_writer::ParquetWriter::new(output, columns)?),
+        "sha1sum" => Box::new(checksum_list_writer::ChecksumListWriter::new(output, root, Digest::Sha1)),
+        "sha256sum" => Box::new(checksum_list_writer::ChecksumListWriter::new(output, root, Digest::Sha256)),
+        "md5sum" => Box::new(checksum_list_writer::ChecksumListWriter::new(output, root, Digest::Md5)),
+        "mtree" => Box::new(mtree_writer::MtreeWriter::new(output, root)?),
+        format => {
+            let error = AppError::WithMessage(format!("unknown export format '{}'", format));
+            log::error!("{}", error);
+            return Err(ErrorWrapper::AppError(error));
+        },
+    })
+}
+
+/// The user and group names of the run.
+pub fn names_by_id(conn: &rusqlite::Connection, run_id: i64) -> ConvertibleResult<(NameTable, NameTable)> {
+    // the first name of an id wins, like in /etc/passwd lookups
+    let mut users = NameTable::new();
+    for user in UnixUser::select(conn, run_id)? {
+        users.entry((user.source_id, user.uid)).or_insert(user.name);
+    }
+    let mut groups = NameTable::new();
+    for group in UnixGroup::select(conn, run_id)? {
+        groups.entry((group.source_id, group.gid)).or_insert(group.name);
+    }
+    Ok((users, groups))
+}
+
+pub fn run(args: &clap::ArgMatches<'_>) -> ConvertibleResult<()> {
+    let db_ref = args.value_of("index").expect("path to database is required");
+    let (conn, run) = RunRef::parse(db_ref).open_read_only()?;
+
+    let mut writer = writer(
+        args.value_of("format").unwrap_or("csv"),
+        args.value_of("output"),
+        select_columns(args.value_of("columns"))?,
+        PathBuf::from(args.value_of("root").unwrap_or("/")),
+    )?;
+    let (users, groups) = names_by_id(&conn, run.id)?;
+
+    let mut count = 0;
+    FsNode::for_each_in_run(&conn, &run, |fs_node| {
+        count += 1;
+        writer.write(ExportRecord::new(fs_node, &users, &groups))
+    })?;
+    writer.finish()?;
+
+    log::debug!("'{}': exported {} nodes", db_ref, count);
+    Ok(())
+}
+
+pub fn cmdline<'a>() -> clap::App<'a, 'a> {
+    clap::App::new("export")
+        .about("Exports the nodes of an index for use in other tools, or as a manifest to verify the files with.")
+        .arg(clap::Arg::with_name("index")
+            .value_name("FILE[@RUN]")
+            .index(1)
+            .help("The index to export.")
+            .required(true))
+        .arg(clap::Arg::with_name("format")
+            .long("format")
+            .short("f")
+            .value_name("FORMAT")
+            .possible_values(&["csv", "jsonl", "parquet", "text", "sha1sum", "sha256sum", "md5sum", "mtree"])
+            .default_value("csv")
+            .help("Output format. text writes tab-separated values without a header, with backslashes, tabs,\n\
+                   newlines and other control characters escaped as \\\\, \\t, \\n, \\x{1b} etc.\n\
+                   sha1sum, sha256sum and md5sum write checksum lists to be checked with e.g. sha1sum -c,\n\
+                   mtree writes a spec to be checked with mtree -f SPEC -p ROOT."))
+        .arg(clap::Arg::with_name("columns")
+            .long("columns")
+            .short("c")
+            .value_name("COLUMNS")
+            .help("The columns to write, separated by commas, e.g. path,size,modified (default: all)."))
+        .arg(clap::Arg::with_name("root")
+            .long("root")
+            .value_name("PATH")
+            .help("For checksum lists and mtree specs: write paths relative to PATH, and leave out\n\
+                   the nodes outside of it (default: /)."))
+        .arg(clap::Arg::with_name("output")
+            .long("output")
+            .short("o")
+            .value_name("FILE")
+            .help("Write to FILE instead of stdout."))
+}
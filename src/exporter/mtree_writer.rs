@@ -0,0 +1,127 @@
+use crate::ConvertibleResult;
+use crate::db_models::fs_node;
+use crate::exporter::{relative_path, ExportRecord, LeftOut, RecordWriter};
+use crate::util::unix_perms::Permission;
+use std::io::Write;
+use std::path::PathBuf;
+
+const TYPE_MASK: u32 = 0o170000;
+
+/// A BSD `mtree` specification, see mtree(5), to be checked with `mtree -f SPEC -p ROOT`.
+/// Each node is written with its full path relative to the root, e.g. `./dir/file`.
+/// Attributes the index does not know, e.g. the owners of an imported checksum list, are left out.
+/// Files whose digests could not be calculated reliably are written without digests, so `mtree` still checks
+/// everything else about them.
+pub struct MtreeWriter {
+    output: Box<dyn Write + Send>,
+    root: PathBuf,
+    left_out: LeftOut,
+}
+
+impl MtreeWriter {
+
+    pub fn new(mut output: Box<dyn Write + Send>, root: PathBuf) -> ConvertibleResult<MtreeWriter> {
+        writeln!(output, "#mtree")?;
+        Ok(MtreeWriter { output, root, left_out: LeftOut::default() })
+    }
+}
+
+impl RecordWriter for MtreeWriter {
+
+    fn write(&mut self, record: ExportRecord) -> ConvertibleResult<()> {
+        let path = match relative_path(&record.path, &self.root) {
+            Some(path) => path,
+            None => return Ok(()),
+        };
+        let unverifiable = record.unverifiable();
+        if let Some(reason) = unverifiable {
+            self.left_out.count(reason);
+            writeln!(self.output, "# ./{}: {}, digests left out", vis(&path), reason.describe())?;
+        }
+        let knows = |attr: u32| record.unknown_attrs & attr == 0;
+
+        let mut line = format!("./{}", vis(&path));
+        if knows(fs_node::ATTR_NODE_TYPE) {
+            let node_type = match record.mode & TYPE_MASK {
+                bits if bits == Permission::File.bit() => Some("file"),
+                bits if bits == Permission::Dir.bit() => Some("dir"),
+                bits if bits == Permission::Symlink.bit() => Some("link"),
+                bits if bits == Permission::BlkDev.bit() => Some("block"),
+                bits if bits == Permission::CharDev.bit() => Some("char"),
+                bits if bits == Permission::Fifo.bit() => Some("fifo"),
+                bits if bits == Permission::Socket.bit() => Some("socket"),
+                // imported nodes may have a type, but no permissions
+                _ => match record.node_type {
+                    "file" => Some("file"),
+                    "dir" => Some("dir"),
+                    "symlink" => Some("link"),
+                    _ => None,
+                },
+            };
+            if let Some(node_type) = node_type {
+                line.push_str(&format!(" type={}", node_type));
+            }
+        }
+        if knows(fs_node::ATTR_PERMISSIONS) {
+            line.push_str(&format!(" mode={:04o}", record.mode & !TYPE_MASK));
+        }
+        if knows(fs_node::ATTR_UID) {
+            line.push_str(&format!(" uid={}", record.uid));
+            if let Some(user) = &record.user {
+                line.push_str(&format!(" uname={}", vis(user)));
+            }
+        }
+        if knows(fs_node::ATTR_GID) {
+            line.push_str(&format!(" gid={}", record.gid));
+            if let Some(group) = &record.group {
+                line.push_str(&format!(" gname={}", vis(group)));
+            }
+        }
+        if knows(fs_node::ATTR_NLINKS) && record.node_type != "dir" {
+            line.push_str(&format!(" nlink={}", record.nlinks));
+        }
+        if knows(fs_node::ATTR_MODIFIED_DATE) {
+            // the index has seconds only
+            line.push_str(&format!(" time={}.0", record.modified_date));
+        }
+        if record.node_type == "file" && knows(fs_node::ATTR_SIZE) {
+            line.push_str(&format!(" size={}", record.size));
+        }
+        if record.node_type == "file" && unverifiable.is_none() {
+            if !record.md5_checksum.is_empty() {
+                line.push_str(&format!(" md5digest={}", record.md5_checksum));
+            }
+            if knows(fs_node::ATTR_SHA1) && !record.sha1_checksum.is_empty() {
+                line.push_str(&format!(" sha1digest={}", record.sha1_checksum));
+            }
+            if !record.sha256_checksum.is_empty() {
+                line.push_str(&format!(" sha256digest={}", record.sha256_checksum));
+            }
+        }
+        if record.node_type == "symlink" && knows(fs_node::ATTR_LINKS_TO) {
+            line.push_str(&format!(" link={}", vis(&record.links_to)));
+        }
+
+        writeln!(self.output, "{}", line)?;
+        Ok(())
+    }
+
+    fn finish(mut self: Box<Self>) -> ConvertibleResult<()> {
+        self.left_out.warn("they were written without digests");
+        self.output.flush()?;
+        Ok(())
+    }
+}
+
+/// Encodes names like `vis(3)` does for mtree: whitespace, non-printable and glob characters,
+/// `#` and `\` as `\ooo` octal bytes.
+fn vis(name: &str) -> String {
+    let mut encoded = String::with_capacity(name.len());
+    for &byte in name.as_bytes() {
+        match byte {
+            b'!'..=b'~' if !b"\\#*?[".contains(&byte) => encoded.push(byte as char),
+            _ => encoded.push_str(&format!("\\{:03o}", byte)),
+        }
+    }
+    encoded
+}
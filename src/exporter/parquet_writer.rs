@@ -0,0 +1,129 @@
+use crate::ConvertibleResult;
+use crate::exporter::{COLUMNS, ExportRecord, Kind, RecordWriter, Value};
+use parquet::basic::{Compression, LogicalType, Repetition, Type as PhysicalType};
+use parquet::data_type::{BoolType, ByteArray, ByteArrayType, Int64Type};
+use parquet::file::properties::WriterProperties;
+use parquet::file::writer::SerializedFileWriter;
+use parquet::schema::types::Type;
+use std::io::Write;
+use std::sync::Arc;
+
+/// Records are buffered until a row group is full, so memory use does not grow with the index.
+const ROW_GROUP_SIZE: usize = 100_000;
+
+/// Apache Parquet, snappy-compressed.
+pub struct ParquetWriter {
+    writer: SerializedFileWriter<Box<dyn Write + Send>>,
+    buffer: Vec<ExportRecord>,
+    columns: Vec<usize>,
+}
+
+impl ParquetWriter {
+
+    pub fn new(output: Box<dyn Write + Send>, columns: Vec<usize>) -> ConvertibleResult<ParquetWriter> {
+        let mut fields = Vec::new();
+        for (name, kind) in columns.iter().map(|&i| &COLUMNS[i]) {
+            let (physical_type, repetition, logical_type) = match kind {
+                Kind::Text => (PhysicalType::BYTE_ARRAY, Repetition::REQUIRED, Some(LogicalType::String)),
+                Kind::OptionalText => (PhysicalType::BYTE_ARRAY, Repetition::OPTIONAL, Some(LogicalType::String)),
+                Kind::Integer => (PhysicalType::INT64, Repetition::REQUIRED, None),
+                Kind::OptionalInteger => (PhysicalType::INT64, Repetition::OPTIONAL, None),
+                Kind::Boolean => (PhysicalType::BOOLEAN, Repetition::REQUIRED, None),
+            };
+            let field = Type::primitive_type_builder(name, physical_type)
+                .with_repetition(repetition)
+                .with_logical_type(logical_type)
+                .build()?;
+            fields.push(Arc::new(field));
+        }
+        let schema = Type::group_type_builder("fs_node").with_fields(fields).build()?;
+        let properties = WriterProperties::builder().set_compression(Compression::SNAPPY).build();
+
+        Ok(ParquetWriter {
+            writer: SerializedFileWriter::new(output, Arc::new(schema), Arc::new(properties))?,
+            buffer: Vec::with_capacity(ROW_GROUP_SIZE),
+            columns,
+        })
+    }
+
+    fn write_row_group(&mut self) -> ConvertibleResult<()> {
+        if self.buffer.is_empty() {
+            return Ok(());
+        }
+
+        let rows: Vec<[Value<'_>; 21]> = self.buffer.iter().map(ExportRecord::values).collect();
+        let mut row_group = self.writer.next_row_group()?;
+        let mut columns = self.columns.iter();
+        while let (Some(mut column), Some(&i)) = (row_group.next_column()?, columns.next()) {
+            match COLUMNS[i].1 {
+                kind @ Kind::Text | kind @ Kind::OptionalText => {
+                    let mut values = Vec::with_capacity(rows.len());
+                    let mut definition_levels = Vec::with_capacity(rows.len());
+                    for row in rows.iter() {
+                        match row[i] {
+                            Value::Text(text) | Value::OptionalText(Some(text)) => {
+                                values.push(ByteArray::from(text));
+                                definition_levels.push(1);
+                            },
+                            _ => definition_levels.push(0),
+                        }
+                    }
+                    let definition_levels = match kind {
+                        Kind::OptionalText => Some(&definition_levels[..]),
+                        _ => None,
+                    };
+                    column.typed::<ByteArrayType>().write_batch(&values, definition_levels, None)?;
+                },
+                kind @ Kind::Integer | kind @ Kind::OptionalInteger => {
+                    let mut values = Vec::with_capacity(rows.len());
+                    let mut definition_levels = Vec::with_capacity(rows.len());
+                    for row in rows.iter() {
+                        match row[i] {
+                            Value::Integer(number) | Value::OptionalInteger(Some(number)) => {
+                                values.push(number);
+                                definition_levels.push(1);
+                            },
+                            _ => definition_levels.push(0),
+                        }
+                    }
+                    let definition_levels = match kind {
+                        Kind::OptionalInteger => Some(&definition_levels[..]),
+                        _ => None,
+                    };
+                    column.typed::<Int64Type>().write_batch(&values, definition_levels, None)?;
+                },
+                Kind::Boolean => {
+                    let values: Vec<bool> = rows.iter()
+                        .map(|row| match row[i] { Value::Boolean(boolean) => boolean, _ => false })
+                        .collect();
+                    column.typed::<BoolType>().write_batch(&values, None, None)?;
+                },
+            }
+            column.close()?;
+        }
+        row_group.close()?;
+
+        log::trace!("wrote parquet row group of {} rows", rows.len());
+        drop(rows);
+        self.buffer.clear();
+        Ok(())
+    }
+}
+
+impl RecordWriter for ParquetWriter {
+
+    fn write(&mut self, record: ExportRecord) -> ConvertibleResult<()> {
+        self.buffer.push(record);
+        if self.buffer.len() >= ROW_GROUP_SIZE {
+            self.write_row_group()?;
+        }
+        Ok(())
+    }
+
+    fn finish(mut self: Box<Self>) -> ConvertibleResult<()> {
+        self.write_row_group()?;
+        let mut output = self.writer.into_inner()?;
+        output.flush()?;
+        Ok(())
+    }
+}
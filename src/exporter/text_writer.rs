@@ -0,0 +1,78 @@
+use crate::ConvertibleResult;
+use crate::exporter::{ExportRecord, RecordWriter, Value};
+use crate::util::quote;
+use std::io::Write;
+
+/// Tab-separated values without a header, e.g. to pipe paths into other commands.
+/// Missing values are written as empty fields. Backslashes, tabs, newlines and other control characters
+/// in text are escaped (see [quote::visible]()), so that each node stays on a line and each value in a field.
+pub struct TextWriter {
+    output: Box<dyn Write + Send>,
+    columns: Vec<usize>,
+}
+
+impl TextWriter {
+
+    pub fn new(output: Box<dyn Write + Send>, columns: Vec<usize>) -> TextWriter {
+        TextWriter { output, columns }
+    }
+}
+
+impl RecordWriter for TextWriter {
+
+    fn write(&mut self, record: ExportRecord) -> ConvertibleResult<()> {
+        let values = record.values();
+        let fields: Vec<String> = self.columns.iter()
+            .map(|&i| match values[i] {
+                Value::Text(text) | Value::OptionalText(Some(text)) => quote::visible(text),
+                Value::OptionalText(None) => String::new(),
+                Value::Integer(number) | Value::OptionalInteger(Some(number)) => number.to_string(),
+                Value::OptionalInteger(None) => String::new(),
+                Value::Boolean(boolean) => boolean.to_string(),
+            })
+            .collect();
+        writeln!(self.output, "{}", fields.join("\t"))?;
+        Ok(())
+    }
+
+    fn finish(mut self: Box<Self>) -> ConvertibleResult<()> {
+        self.output.flush()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db_models::fs_node::FsNode;
+    use crate::exporter::NameTable;
+    use std::io;
+    use std::sync::{Arc, Mutex};
+
+    /// Keeps what is written, to be read after the writer is gone.
+    #[derive(Clone, Default)]
+    struct Shared(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Shared {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn one_line_per_node() {
+        let output = Shared::default();
+        let mut writer = Box::new(TextWriter::new(Box::new(output.clone()), vec![0, 3]));
+        for name in ["/srv/tab\tnl\nx", "/srv/back\\slash"].iter() {
+            let fs_node = FsNode { parent_path: "/srv".to_string(), name: name.to_string(), size: 3, ..Default::default() };
+            writer.write(ExportRecord::new(fs_node, &NameTable::new(), &NameTable::new())).unwrap();
+        }
+        writer.finish().unwrap();
+        let text = String::from_utf8(output.0.lock().unwrap().clone()).unwrap();
+        assert_eq!(text, "/srv/tab\\tnl\\nx\t3\n/srv/back\\\\slash\t3\n");
+    }
+}
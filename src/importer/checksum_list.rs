@@ -0,0 +1,157 @@
+use crate::db_models::fs_node::{self, NodeType};
+use crate::importer::{imported_node, set_digest, Manifest};
+
+/// Parses the output of `sha1sum`, `sha256sum`, `md5sum` and `md5deep`:
+///
+/// - `HASH  PATH`, or `HASH *PATH` for files read in binary mode.
+///   Lines starting with `\` have `\\` and `\n` escaped in the path.
+/// - `SIZE  HASH  PATH`, written by `md5deep -z`.
+/// - `ALGO (PATH) = HASH`, the BSD style written with `--tag`.
+pub fn parse(input: &str) -> Manifest {
+    let mut manifest = Manifest::default();
+
+    for (line_no, line) in input.lines().enumerate() {
+        if line.trim().is_empty() {
+            continue;
+        }
+        match parse_line(line) {
+            Some((size, hex, path)) => {
+                let mut fs_node = imported_node(0);
+                let digest_attr = match set_digest(&mut fs_node, hex) {
+                    Some(attr) => attr,
+                    None => {
+                        log::warn!("line {}: '{}' is not an md5, sha1 or sha256 digest; skipping", line_no + 1, hex);
+                        continue;
+                    },
+                };
+                let mut known = fs_node::ATTR_NODE_TYPE | digest_attr;
+                if let Some(size) = size {
+                    fs_node.size = size;
+                    known |= fs_node::ATTR_SIZE;
+                }
+                fs_node.node_type = NodeType::File;
+                fs_node.unknown_attrs &= !known;
+                manifest.nodes.push((path, fs_node));
+            },
+            None => log::warn!("line {}: not a checksum line; skipping: {}", line_no + 1, line),
+        }
+    }
+
+    manifest
+}
+
+/// Returns the size if given, the hex digest, and the path.
+fn parse_line(line: &str) -> Option<(Option<i64>, &str, String)> {
+    if let Some(parsed) = parse_tag_line(line) {
+        return Some(parsed);
+    }
+
+    let (escaped, line) = match line.strip_prefix('\\') {
+        Some(rest) => (true, rest),
+        None => (false, line),
+    };
+
+    // md5deep -z pads the size with spaces
+    let (first, rest) = line.trim_start().split_once(' ')?;
+    let (size, hex, rest) = match first.parse::<i64>() {
+        Ok(size) if first.len() < 32 => {
+            let (hex, rest) = rest.trim_start().split_once(' ')?;
+            (Some(size), hex, rest)
+        },
+        _ => (None, first, rest),
+    };
+
+    // the mode character: ' ' for text, '*' for binary
+    let path = rest.strip_prefix(' ').or_else(|| rest.strip_prefix('*')).unwrap_or(rest);
+    if path.is_empty() {
+        return None;
+    }
+    let path = if escaped { unescape(path) } else { path.to_string() };
+
+    Some((size, hex, path))
+}
+
+/// `ALGO (PATH) = HASH`
+fn parse_tag_line(line: &str) -> Option<(Option<i64>, &str, String)> {
+    let (algorithm, rest) = line.split_once(" (")?;
+    if !["MD5", "SHA1", "SHA256"].contains(&algorithm) {
+        return None;
+    }
+    let (path, hex) = rest.rsplit_once(") = ")?;
+    Some((None, hex.trim(), path.to_string()))
+}
+
+fn unescape(path: &str) -> String {
+    let mut unescaped = String::with_capacity(path.len());
+    let mut chars = path.chars();
+    while let Some(c) = chars.next() {
+        match (c, chars.clone().next()) {
+            ('\\', Some('n')) => { unescaped.push('\n'); chars.next(); },
+            ('\\', Some('\\')) => { unescaped.push('\\'); chars.next(); },
+            (c, _) => unescaped.push(c),
+        }
+    }
+    unescaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SHA1: &str = "2fd4e1c67a2d28fced849ee1bb76e7391b93eb12";
+    const MD5: &str = "9e107d9d372bb6826bd81d3542a419d6";
+
+    #[test]
+    fn sha1sum_line() {
+        let line = format!("{}  dir/file name", SHA1);
+        assert_eq!(parse_line(&line), Some((None, SHA1, "dir/file name".to_string())));
+    }
+
+    #[test]
+    fn binary_mode_line() {
+        let line = format!("{} *file", SHA1);
+        assert_eq!(parse_line(&line), Some((None, SHA1, "file".to_string())));
+    }
+
+    #[test]
+    fn escaped_line() {
+        let line = format!("\\{}  new\\nline\\\\back", SHA1);
+        assert_eq!(parse_line(&line), Some((None, SHA1, "new\nline\\back".to_string())));
+    }
+
+    #[test]
+    fn md5deep_size_line() {
+        let line = format!("1234  {}  file", MD5);
+        assert_eq!(parse_line(&line), Some((Some(1234), MD5, "file".to_string())));
+    }
+
+    #[test]
+    fn md5deep_padded_size_line() {
+        let line = format!("      1234  {}  /a/file", MD5);
+        assert_eq!(parse_line(&line), Some((Some(1234), MD5, "/a/file".to_string())));
+    }
+
+    #[test]
+    fn tag_line() {
+        let line = format!("SHA1 (a (b) = c) = {}", SHA1);
+        assert_eq!(parse_line(&line), Some((None, SHA1, "a (b) = c".to_string())));
+    }
+
+    #[test]
+    fn no_path() {
+        assert_eq!(parse_line(SHA1), None);
+    }
+
+    #[test]
+    fn parse_sets_known_attributes() {
+        let manifest = parse(&format!("      1234  {}  a\nnot a checksum line\n", MD5));
+        assert_eq!(manifest.nodes.len(), 1);
+        let (path, fs_node) = &manifest.nodes[0];
+        assert_eq!(path, "a");
+        assert_eq!(fs_node.size, 1234);
+        assert_eq!(fs_node.md5_checksum, MD5);
+        assert!(fs_node.knows(fs_node::ATTR_NODE_TYPE | fs_node::ATTR_SIZE));
+        assert!(!fs_node.knows(fs_node::ATTR_SHA1));
+        assert!(!fs_node.knows(fs_node::ATTR_UID));
+    }
+}
@@ -0,0 +1,106 @@
+use crate::db_models::fs_node::{self, NodeType};
+use crate::importer::{imported_node, set_digest, Manifest};
+
+/// Parses `hashdeep` output:
+///
+/// ```text
+/// %%%% HASHDEEP-1.0
+/// %%%% size,md5,sha256,filename
+/// ## Invoked from: /home/user
+/// 1024,9e107d9d372bb6826bd81d3542a419d6,d7a8fbb307d7809469ca9abcb0082e4f8d5651e46d3cdb762d02d0bf37c9e592,/home/user/file
+/// ```
+///
+/// The columns are given by the second header line; the file name is last and may contain commas.
+pub fn parse(input: &str) -> Manifest {
+    let mut manifest = Manifest::default();
+    let mut columns: Vec<&str> = Vec::new();
+
+    for (line_no, line) in input.lines().enumerate() {
+        if let Some(header) = line.strip_prefix("%%%% ") {
+            if !header.starts_with("HASHDEEP") {
+                columns = header.split(',').map(str::trim).collect();
+            }
+            continue;
+        }
+        if line.starts_with("##") || line.trim().is_empty() {
+            continue;
+        }
+        if columns.last() != Some(&"filename") {
+            log::warn!("line {}: no column header with a filename column before this line; skipping", line_no + 1);
+            continue;
+        }
+
+        let values: Vec<&str> = line.splitn(columns.len(), ',').collect();
+        if values.len() != columns.len() {
+            log::warn!("line {}: expected {} columns; skipping: {}", line_no + 1, columns.len(), line);
+            continue;
+        }
+
+        let mut fs_node = imported_node(0);
+        fs_node.node_type = NodeType::File;
+        let mut known = fs_node::ATTR_NODE_TYPE;
+        let mut path = String::new();
+        for (column, value) in columns.iter().zip(values) {
+            match *column {
+                "filename" => path = value.to_string(),
+                "size" => match value.parse() {
+                    Ok(size) => {
+                        fs_node.size = size;
+                        known |= fs_node::ATTR_SIZE;
+                    },
+                    Err(_) => log::warn!("line {}: invalid size '{}'", line_no + 1, value),
+                },
+                "md5" | "sha1" | "sha256" => match set_digest(&mut fs_node, value) {
+                    Some(attr) => known |= attr,
+                    None => log::warn!("line {}: invalid {} digest '{}'", line_no + 1, column, value),
+                },
+                // tiger, whirlpool
+                _ => {},
+            }
+        }
+        fs_node.unknown_attrs &= !known;
+        manifest.nodes.push((path, fs_node));
+    }
+
+    manifest
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MD5: &str = "9e107d9d372bb6826bd81d3542a419d6";
+    const SHA256: &str = "d7a8fbb307d7809469ca9abcb0082e4f8d5651e46d3cdb762d02d0bf37c9e592";
+
+    #[test]
+    fn columns_from_header() {
+        let input = format!(
+            "%%%% HASHDEEP-1.0\n%%%% size,md5,sha256,filename\n## Invoked from: /home/user\n##\n1024,{},{},/home/user/a,b\n",
+            MD5, SHA256
+        );
+        let manifest = parse(&input);
+        assert_eq!(manifest.nodes.len(), 1);
+        let (path, fs_node) = &manifest.nodes[0];
+        assert_eq!(path, "/home/user/a,b");
+        assert_eq!(fs_node.size, 1024);
+        assert_eq!(fs_node.md5_checksum, MD5);
+        assert_eq!(fs_node.sha256_checksum, SHA256);
+        assert!(fs_node.knows(fs_node::ATTR_NODE_TYPE | fs_node::ATTR_SIZE));
+        assert!(!fs_node.knows(fs_node::ATTR_SHA1));
+    }
+
+    #[test]
+    fn lines_before_header_are_skipped() {
+        let input = format!("1024,{},/a\n%%%% md5,filename\n{},/b\n", MD5, MD5);
+        let manifest = parse(&input);
+        assert_eq!(manifest.nodes.len(), 1);
+        assert_eq!(manifest.nodes[0].0, "/b");
+        assert!(!manifest.nodes[0].1.knows(fs_node::ATTR_SIZE));
+    }
+
+    #[test]
+    fn short_lines_are_skipped() {
+        let input = format!("%%%% size,md5,filename\n1024,{}\n", MD5);
+        assert!(parse(&input).nodes.is_empty());
+    }
+}
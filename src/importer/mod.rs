@@ -0,0 +1,220 @@
+mod checksum_list;
+mod hashdeep;
+mod mtree;
+
+use crate::ConvertibleResult;
+use crate::db_models::fs_node::{self, FsNode, NodeType};
+use crate::db_models::indexing_run::IndexingRun;
+use crate::db_models::unix_group::UnixGroup;
+use crate::db_models::unix_user::UnixUser;
+use crate::schema;
+use crate::verifier;
+use std::collections::{BTreeMap, HashMap};
+use std::fs::File;
+use std::io::{self, Read};
+use std::path::{Component, Path, PathBuf};
+use std::time::SystemTime;
+
+/// The nodes described by a manifest, with paths as given in the manifest.
+#[derive(Debug, Default)]
+pub struct Manifest {
+    pub nodes: Vec<(String, FsNode)>,
+
+    /// User and group names, if the manifest records them along with the ids.
+    pub users: HashMap<String, u32>,
+    pub groups: HashMap<String, u32>,
+}
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+enum Format {
+    /// `sha1sum`, `sha256sum`, `md5sum` and `md5deep` output; the digest is told by its length.
+    ChecksumList,
+    Hashdeep,
+    Mtree,
+}
+
+impl Format {
+
+    fn from_arg(arg: &str) -> Option<Format> {
+        match arg {
+            "sha1sum" | "sha256sum" | "md5sum" | "md5deep" => Some(Format::ChecksumList),
+            "hashdeep" => Some(Format::Hashdeep),
+            "mtree" => Some(Format::Mtree),
+            _ => None,
+        }
+    }
+
+    /// Tells the format from the first line that is not empty.
+    fn detect(input: &str) -> Format {
+        let first_line = input.lines().map(str::trim).find(|line| !line.is_empty()).unwrap_or("");
+        if first_line.starts_with("%%%% HASHDEEP") {
+            Format::Hashdeep
+        } else if first_line.starts_with("#mtree") || first_line.starts_with("/set ")
+            || first_line.split_whitespace().skip(1).any(|keyword| keyword.starts_with("type=")) {
+            Format::Mtree
+        } else {
+            Format::ChecksumList
+        }
+    }
+}
+
+pub fn run(args: &clap::ArgMatches<'_>) -> ConvertibleResult<()> {
+    let manifest_path = args.value_of("manifest").expect("path to manifest is required");
+    let db_path = args.value_of("output").expect("path to database is required");
+    let root = PathBuf::from(args.value_of("root").unwrap_or("/"));
+
+    let mut bytes = Vec::new();
+    if manifest_path == "-" {
+        io::stdin().read_to_end(&mut bytes)?;
+    } else {
+        File::open(manifest_path)?.read_to_end(&mut bytes)?;
+    }
+    let input = String::from_utf8_lossy(&bytes);
+
+    let format = match args.value_of("format").and_then(Format::from_arg) {
+        Some(format) => format,
+        None => Format::detect(&input),
+    };
+    log::debug!("'{}': reading as {:?}...", manifest_path, format);
+
+    let manifest = match format {
+        Format::ChecksumList => checksum_list::parse(&input),
+        Format::Hashdeep => hashdeep::parse(&input),
+        Format::Mtree => mtree::parse(&input),
+    };
+    let fs_nodes = resolve_paths(manifest.nodes, &root);
+    let seal = verifier::seal_from_args(args)?;
+
+    let mut conn = schema::open_or_create(db_path)?;
+    let transaction = conn.transaction()?;
+
+    let run = IndexingRun {
+        id: IndexingRun::next_id(&transaction)?,
+        timestamp: Some(SystemTime::now().duration_since(SystemTime::UNIX_EPOCH)?.as_secs() as i64),
+        host_id: None,
+        parent_run_id: IndexingRun::select_latest(&transaction)?.map(|parent| parent.id),
+        delta: false,
+    };
+    run.insert(&transaction)?;
+
+    for (name, uid) in manifest.users {
+        UnixUser { id: 0, name, uid, run_id: run.id, source_id: 0 }.insert(&transaction)?;
+    }
+    for (name, gid) in manifest.groups {
+        UnixGroup { id: 0, name, gid, run_id: run.id, source_id: 0 }.insert(&transaction)?;
+    }
+
+    let count = fs_nodes.len();
+    for fs_node in fs_nodes {
+        let fs_node = FsNode { run_id: run.id, ..fs_node };
+        log::trace!("INSERT {:?}", fs_node);
+        fs_node.insert(&transaction)?;
+    }
+    verifier::seal_run(&transaction, &run, &seal)?;
+    transaction.commit()?;
+
+    log::info!("'{}': imported {} nodes from '{}' as indexing run {}", db_path, count, manifest_path, run.id);
+    Ok(())
+}
+
+/// Makes the paths of the manifest absolute, relative to `root`, and adds the directories
+/// below `root` they imply, like `idx` records them for the directories it is given.
+/// If a path is listed more than once, the last entry wins.
+fn resolve_paths(nodes: Vec<(String, FsNode)>, root: &Path) -> Vec<FsNode> {
+    let root = normalize(root);
+    let mut resolved: BTreeMap<PathBuf, FsNode> = BTreeMap::new();
+    for (path, fs_node) in nodes {
+        let path = normalize(&root.join(path));
+        // like the directories given to idx, the root itself is not part of the index
+        if path != root {
+            resolved.insert(path, fs_node);
+        }
+    }
+
+    let implied_dirs: Vec<PathBuf> = resolved.keys()
+        .flat_map(|path| path.ancestors().skip(1))
+        .filter(|ancestor| ancestor.starts_with(&root) && *ancestor != root && !resolved.contains_key(*ancestor))
+        .map(PathBuf::from)
+        .collect();
+    for dir in implied_dirs {
+        resolved.entry(dir).or_insert_with(|| FsNode {
+            node_type: NodeType::Directory,
+            unknown_attrs: fs_node::ATTR_ALL & !fs_node::ATTR_NODE_TYPE,
+            ..FsNode::default()
+        });
+    }
+
+    resolved.into_iter()
+        .map(|(path, fs_node)| FsNode {
+            name: path.to_string_lossy().to_string(),
+            parent_path: path.parent().map(|parent| parent.to_string_lossy().to_string()).unwrap_or_default(),
+            ..fs_node
+        })
+        .collect()
+}
+
+/// Removes `.` and `..` components lexically.
+fn normalize(path: &Path) -> PathBuf {
+    let mut normalized = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::CurDir => {},
+            Component::ParentDir => { normalized.pop(); },
+            other => normalized.push(other.as_os_str()),
+        }
+    }
+    normalized
+}
+
+/// A node of the manifest that records only the given `ATTR_*` attributes.
+pub fn imported_node(known_attrs: u32) -> FsNode {
+    FsNode {
+        unknown_attrs: fs_node::ATTR_ALL & !known_attrs,
+        ..FsNode::default()
+    }
+}
+
+/// Sets the digest the hex string is, told by its length. Returns the `ATTR_*` bit to mark as known.
+pub fn set_digest(fs_node: &mut FsNode, hex: &str) -> Option<u32> {
+    if !hex.chars().all(|c| c.is_ascii_hexdigit()) {
+        return None;
+    }
+    let hex = hex.to_ascii_lowercase();
+    match hex.len() {
+        32 => { fs_node.md5_checksum = hex; Some(0) },
+        40 => { fs_node.sha1_checksum = hex; Some(fs_node::ATTR_SHA1) },
+        64 => { fs_node.sha256_checksum = hex; Some(0) },
+        _ => None,
+    }
+}
+
+pub fn cmdline<'a>() -> clap::App<'a, 'a> {
+    clap::App::new("import")
+        .about("Imports a checksum manifest or mtree spec as an index, e.g. to compare today's disks against it.")
+        .arg(clap::Arg::with_name("manifest")
+            .value_name("MANIFEST")
+            .index(1)
+            .help("The manifest to import, or - for stdin.")
+            .required(true))
+        .arg(clap::Arg::with_name("output")
+            .short("o")
+            .long("output")
+            .value_name("FILE")
+            .help("The index database to write. If it exists, the manifest is added to it as a new run.")
+            .required(true))
+        .arg(clap::Arg::with_name("format")
+            .long("format")
+            .short("f")
+            .value_name("FORMAT")
+            .possible_values(&["auto", "sha1sum", "sha256sum", "md5sum", "md5deep", "hashdeep", "mtree"])
+            .default_value("auto")
+            .help("The format of the manifest."))
+        .arg(clap::Arg::with_name("root")
+            .long("root")
+            .value_name("PATH")
+            .help("Where relative paths of the manifest are rooted (default: /)."))
+        .args(&verifier::seal_args())
+        .after_help("Attributes that the manifest does not record are left unknown, and are not compared by cmp.\n\
+                     To compare contents against a manifest with md5 or sha256 checksums only, index with\n\
+                     idx --digest md5 or --digest sha256.")
+}
@@ -0,0 +1,290 @@
+use crate::db_models::fs_node::{self, NodeType};
+use crate::importer::{imported_node, set_digest, Manifest};
+use crate::util::unix_perms::Permission;
+use std::collections::HashMap;
+
+/// Parses a BSD `mtree` specification, see mtree(5).
+///
+/// Entries are relative to the directory entries they follow, until `..` ends the directory;
+/// entries whose name contains a `/` are full paths. `/set` and `/unset` change the keywords
+/// that apply to the entries after them.
+pub fn parse(input: &str) -> Manifest {
+    let mut manifest = Manifest::default();
+    let mut defaults: HashMap<String, String> = HashMap::new();
+    let mut dirs: Vec<String> = Vec::new();
+
+    for (line_no, line) in logical_lines(input) {
+        let mut tokens = line.split_whitespace();
+        let first = match tokens.next() {
+            Some(first) => first,
+            None => continue,
+        };
+
+        match first {
+            "/set" => {
+                for (key, value) in tokens.filter_map(keyword) {
+                    defaults.insert(key.to_string(), value.to_string());
+                }
+            },
+            "/unset" => {
+                for key in tokens {
+                    if key == "all" {
+                        defaults.clear();
+                    } else {
+                        defaults.remove(key);
+                    }
+                }
+            },
+            ".." => { dirs.pop(); },
+            _ => {
+                let name = unvis(first);
+                let mut keywords = defaults.clone();
+                for (key, value) in tokens.filter_map(keyword) {
+                    keywords.insert(key.to_string(), value.to_string());
+                }
+
+                let path = if name.contains('/') {
+                    name
+                } else {
+                    let mut path = dirs.join("/");
+                    if !path.is_empty() {
+                        path.push('/');
+                    }
+                    path.push_str(&name);
+                    if keywords.get("type").map(String::as_str) == Some("dir") {
+                        dirs.push(name);
+                    }
+                    path
+                };
+
+                manifest.nodes.push((path, node_from_keywords(&keywords, line_no, &mut manifest.users, &mut manifest.groups)));
+            },
+        }
+    }
+
+    manifest
+}
+
+/// The lines of the spec that are not empty or comments, with continuation lines joined.
+fn logical_lines(input: &str) -> Vec<(usize, String)> {
+    let mut lines = Vec::new();
+    let mut pending = String::new();
+    let mut pending_line_no = 0;
+
+    for (line_no, line) in input.lines().enumerate() {
+        let line = line.trim();
+        if pending.is_empty() {
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            pending_line_no = line_no + 1;
+        }
+        match line.strip_suffix('\\') {
+            Some(continued) => {
+                pending.push_str(continued);
+                pending.push(' ');
+            },
+            None => {
+                pending.push_str(line);
+                lines.push((pending_line_no, std::mem::take(&mut pending)));
+            },
+        }
+    }
+    if !pending.is_empty() {
+        lines.push((pending_line_no, pending));
+    }
+
+    lines
+}
+
+fn keyword(token: &str) -> Option<(&str, &str)> {
+    token.split_once('=')
+}
+
+fn node_from_keywords(
+    keywords: &HashMap<String, String>,
+    line_no: usize,
+    users: &mut HashMap<String, u32>,
+    groups: &mut HashMap<String, u32>
+) -> crate::db_models::fs_node::FsNode {
+    let mut fs_node = imported_node(0);
+    let mut known = 0;
+    let mut type_bits = None;
+    let mut mode = None;
+
+    for (key, value) in keywords.iter() {
+        let value = value.as_str();
+        let invalid = || log::warn!("line {}: invalid value for '{}': '{}'", line_no, key, value);
+        match key.as_str() {
+            "type" => {
+                let (node_type, bits) = match value {
+                    "file" => (NodeType::File, Permission::File.bit()),
+                    "dir" => (NodeType::Directory, Permission::Dir.bit()),
+                    "link" => (NodeType::Symlink, Permission::Symlink.bit()),
+                    "block" => (NodeType::Other, Permission::BlkDev.bit()),
+                    "char" => (NodeType::Other, Permission::CharDev.bit()),
+                    "fifo" => (NodeType::Other, Permission::Fifo.bit()),
+                    "socket" => (NodeType::Other, Permission::Socket.bit()),
+                    _ => { invalid(); continue; },
+                };
+                fs_node.node_type = node_type;
+                type_bits = Some(bits);
+                known |= fs_node::ATTR_NODE_TYPE;
+            },
+            "mode" => match u32::from_str_radix(value, 8) {
+                Ok(bits) => mode = Some(bits),
+                Err(_) => invalid(),
+            },
+            "uid" => match value.parse() {
+                Ok(uid) => { fs_node.uid = uid; known |= fs_node::ATTR_UID; },
+                Err(_) => invalid(),
+            },
+            "gid" => match value.parse() {
+                Ok(gid) => { fs_node.gid = gid; known |= fs_node::ATTR_GID; },
+                Err(_) => invalid(),
+            },
+            "size" => match value.parse() {
+                Ok(size) => { fs_node.size = size; known |= fs_node::ATTR_SIZE; },
+                Err(_) => invalid(),
+            },
+            "nlink" => match value.parse() {
+                Ok(nlinks) => { fs_node.nlinks = nlinks; known |= fs_node::ATTR_NLINKS; },
+                Err(_) => invalid(),
+            },
+            // seconds.nanoseconds
+            "time" => match value.split('.').next().unwrap_or("").parse() {
+                Ok(seconds) => { fs_node.modified_date = seconds; known |= fs_node::ATTR_MODIFIED_DATE; },
+                Err(_) => invalid(),
+            },
+            "link" => {
+                fs_node.links_to = unvis(value);
+                known |= fs_node::ATTR_LINKS_TO;
+            },
+            "md5" | "md5digest" | "sha1" | "sha1digest" | "sha256" | "sha256digest" => match set_digest(&mut fs_node, value) {
+                Some(attr) => known |= attr,
+                None => invalid(),
+            },
+            _ => {},
+        }
+    }
+
+    // the permissions field holds st_mode, which includes the file type
+    if let (Some(type_bits), Some(mode)) = (type_bits, mode) {
+        fs_node.permissions = type_bits | mode;
+        known |= fs_node::ATTR_PERMISSIONS;
+    }
+    if let (Some(name), true) = (keywords.get("uname"), known & fs_node::ATTR_UID != 0) {
+        users.entry(name.clone()).or_insert(fs_node.uid);
+    }
+    if let (Some(name), true) = (keywords.get("gname"), known & fs_node::ATTR_GID != 0) {
+        groups.entry(name.clone()).or_insert(fs_node.gid);
+    }
+
+    fs_node.unknown_attrs &= !known;
+    fs_node
+}
+
+/// Decodes the `vis(3)` encoding of names: `\ooo` octal bytes and backslash escapes.
+fn unvis(encoded: &str) -> String {
+    let bytes = encoded.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] != b'\\' || i + 1 == bytes.len() {
+            decoded.push(bytes[i]);
+            i += 1;
+            continue;
+        }
+        let octal = &bytes[i + 1..(i + 4).min(bytes.len())];
+        if octal.len() == 3 && octal.iter().all(|b| (b'0'..=b'7').contains(b)) {
+            let value = octal.iter().fold(0u32, |value, b| value * 8 + u32::from(b - b'0'));
+            decoded.push(value as u8);
+            i += 4;
+            continue;
+        }
+        decoded.push(match bytes[i + 1] {
+            b's' => b' ',
+            b't' => b'\t',
+            b'n' => b'\n',
+            b'r' => b'\r',
+            other => other,
+        });
+        i += 2;
+    }
+    String::from_utf8_lossy(&decoded).to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SHA256: &str = "d7a8fbb307d7809469ca9abcb0082e4f8d5651e46d3cdb762d02d0bf37c9e592";
+
+    fn node<'a>(manifest: &'a Manifest, path: &str) -> &'a crate::db_models::fs_node::FsNode {
+        &manifest.nodes.iter().find(|(node_path, _)| node_path == path).expect(path).1
+    }
+
+    #[test]
+    fn relative_entries_and_set() {
+        let input = format!("\
+#\t   user: root
+/set type=file uid=0 gid=0 mode=0644
+.               type=dir mode=0755
+    sub         type=dir
+        file\\040name size=3 time=1600000000.000000000 \\
+                sha256digest={}
+    ..
+    link        type=link link=sub/file\\040name
+/unset all
+..
+", SHA256);
+        let manifest = parse(&input);
+        let paths: Vec<&str> = manifest.nodes.iter().map(|(path, _)| path.as_str()).collect();
+        assert_eq!(paths, vec![".", "./sub", "./sub/file name", "./link"]);
+
+        let file = node(&manifest, "./sub/file name");
+        assert_eq!(file.node_type, NodeType::File);
+        assert_eq!(file.size, 3);
+        assert_eq!(file.modified_date, 1600000000);
+        assert_eq!(file.sha256_checksum, SHA256);
+        assert_eq!(file.permissions, Permission::File.bit() | 0o644);
+        assert!(file.knows(fs_node::ATTR_PERMISSIONS | fs_node::ATTR_UID | fs_node::ATTR_SIZE));
+        assert!(!file.knows(fs_node::ATTR_SHA1 | fs_node::ATTR_INODE));
+
+        let dir = node(&manifest, "./sub");
+        assert_eq!(dir.node_type, NodeType::Directory);
+        assert_eq!(dir.permissions, Permission::Dir.bit() | 0o644);
+
+        let link = node(&manifest, "./link");
+        assert_eq!(link.node_type, NodeType::Symlink);
+        assert_eq!(link.links_to, "sub/file name");
+    }
+
+    #[test]
+    fn full_paths_and_names() {
+        let input = "\
+/set uname=alice gname=staff
+./a/b type=file uid=1000 gid=50 mode=0600
+";
+        let manifest = parse(input);
+        assert_eq!(manifest.nodes.len(), 1);
+        assert_eq!(manifest.nodes[0].0, "./a/b");
+        assert_eq!(manifest.users.get("alice"), Some(&1000));
+        assert_eq!(manifest.groups.get("staff"), Some(&50));
+    }
+
+    #[test]
+    fn unknown_keywords_stay_unknown() {
+        let manifest = parse("./f type=file\n");
+        let fs_node = &manifest.nodes[0].1;
+        assert!(fs_node.knows(fs_node::ATTR_NODE_TYPE));
+        assert!(!fs_node.knows(fs_node::ATTR_PERMISSIONS));
+        assert!(!fs_node.knows(fs_node::ATTR_MODIFIED_DATE));
+    }
+
+    #[test]
+    fn vis_encoding() {
+        assert_eq!(unvis("a\\040b\\tc\\\\d"), "a b\tc\\d");
+        assert_eq!(unvis("trailing\\"), "trailing\\");
+    }
+}
@@ -1,22 +1,124 @@
 use crate::apperror::AppError;
 use crate::db_models::fs_node::{FsNode, NodeType};
 use crate::errorwrapper::ErrorWrapper;
+use sha2::Digest;
 use std::cell::RefCell;
+use std::collections::{BTreeSet, HashMap};
+use std::env;
+use std::fmt;
 use std::fs::{DirEntry, ReadDir};
 use std::fs;
 use std::io::{Read};
 use std::io;
 use std::os::linux::fs::MetadataExt;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Component, Path, PathBuf};
 use std::thread;
 use std::time::{SystemTime, Instant};
 
 const READ_BUF_SZ: usize = 1024 * 1024;
 
+/// Options and state shared by every node indexed during an indexing run.
+#[derive(Debug)]
+pub struct IndexContext {
+    read_buf: Vec<u8>,
+
+    pub summary: RunSummary,
+
+    /// Hard-linked files seen so far, by (device, inode).
+    link_groups: HashMap<(i64, i64), LinkGroup>,
+
+    /// The digests to calculate in addition to sha1.
+    pub digests: Digests,
+
+    /// Called as the walk goes, in order.
+    pub hooks: Vec<Box<dyn IndexHook>>,
+}
+
+/// Observes the walk of the indexer, e.g. to report progress or to leave out parts of the tree.
+pub trait IndexHook: fmt::Debug {
+
+    /// Called before descending into a directory. If any hook returns `false`,
+    /// the directory itself is indexed, but not its contents.
+    fn descend(&mut self, _dir_path: &Path) -> bool {
+        true
+    }
+
+    /// Called with each node once it has been indexed.
+    fn indexed(&mut self, _fs_node: &FsNode) {}
+}
+
+/// Digests that are calculated in addition to sha1, if asked for.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Digests {
+    pub md5: bool,
+    pub sha256: bool,
+}
+
+#[derive(Debug, Default, Clone)]
+struct Checksums {
+    sha1: String,
+    md5: String,
+    sha256: String,
+}
+
+/// The paths of a run that are hard links to the same inode share a link group,
+/// and are hashed only once.
+#[derive(Debug)]
+struct LinkGroup {
+    id: i64,
+    checksums: Checksums,
+    unstable: bool,
+}
+
+/// Counters reported at the end of an indexing run.
+#[derive(Debug, Default)]
+pub struct RunSummary {
+    /// Files that kept changing while being hashed.
+    pub unstable: u64,
+
+    /// Files hashed without touching their access time, using `O_NOATIME`.
+    pub atime_untouched: u64,
+
+    /// Files whose access time may have been updated by hashing, since only their owner (or root)
+    /// may open them with `O_NOATIME`. Setting the access time back needs the same right, so it isn't tried.
+    pub atime_touched: u64,
+
+    /// Hard links whose checksum was taken from an already hashed link to the same inode.
+    pub hardlinks_reused: u64,
+}
+
+impl IndexContext {
+    pub fn new() -> IndexContext {
+        IndexContext::default()
+    }
+}
+
+impl Default for IndexContext {
+    fn default() -> IndexContext {
+        IndexContext {
+            read_buf: vec![0; READ_BUF_SZ],
+            summary: RunSummary::default(),
+            link_groups: HashMap::new(),
+            digests: Digests::default(),
+            hooks: Vec::new(),
+        }
+    }
+}
+
+impl RunSummary {
+    pub fn log(&self) {
+        log::info!("run summary: unstable files: {}", self.unstable);
+        log::info!("run summary: access times: untouched (O_NOATIME): {}, touched (not the owner): {}",
+                   self.atime_untouched, self.atime_touched);
+        log::info!("run summary: hard links not re-hashed: {}", self.hardlinks_reused);
+    }
+}
+
 /// Assumes you won't run this function twice on the same path.
 /// I.e., you must ensure the paths you put in here are NOT subdirs of eachother.
-pub fn depth_first_indexer(dir_path: &str) -> io::Result<Vec<FsNode>> {
+pub fn depth_first_indexer(dir_path: &str, ctx: &mut IndexContext) -> io::Result<Vec<FsNode>> {
     let mut fs_nodes: Vec<FsNode> = Vec::new();
-    let mut read_buf = [0 as u8; READ_BUF_SZ];
     let mut dir_iter_stack: Vec<RefCell<ReadDir>> = Vec::new();
     let mut visit_log_stack: Vec<(Instant, String)> = Vec::new(); // for logging purposes
 
@@ -33,13 +135,19 @@ pub fn depth_first_indexer(dir_path: &str) -> io::Result<Vec<FsNode>> {
         if let Some(child) = next_child {
             match child {
                 Ok(child) => {
-                    let fs_node = process_single_dir_entry(&child, &mut read_buf);
+                    let fs_node = process_single_dir_entry(&child, ctx);
+                    ctx.hooks.iter_mut().for_each(|hook| hook.indexed(&fs_node));
                     fs_nodes.push(fs_node);
 
                     if child.file_type().map_or(false, |c| c.is_dir()) {
                         let child_path = child.path();
                         let child_path_lossy = child_path.to_string_lossy();
 
+                        if !ctx.hooks.iter_mut().all(|hook| hook.descend(&child_path)) {
+                            log::debug!("'{}': not descending into, as a hook asked", child_path_lossy);
+                            continue;
+                        }
+
                         match fs::read_dir(child.path()) {
                             Ok(dir_iter) => {
                                 log::debug!("'{}': now descending into...", child_path_lossy);
@@ -72,14 +180,81 @@ pub fn depth_first_indexer(dir_path: &str) -> io::Result<Vec<FsNode>> {
     Ok(fs_nodes)
 }
 
-fn process_single_dir_entry(entry: &fs::DirEntry, read_buf: &mut [u8]) -> FsNode {
+/// Indexes exactly the given paths, without descending into directories.
+/// Every directory implied by the paths (i.e. each ancestor, except `/`) is indexed as well,
+/// so that the result can be pooled by roots like the result of a directory walk.
+/// Paths that can't be stat'ed are skipped.
+pub fn file_list_indexer(paths: &[PathBuf], ctx: &mut IndexContext) -> Vec<FsNode> {
+    let start_time = Instant::now();
+    log::debug!("file_list_indexer: {} paths: start...", paths.len());
 
-    if entry.path().is_relative() {
-        panic!("TODO: convert relative paths to absolute paths");
+    let mut listed: BTreeSet<PathBuf> = BTreeSet::new();
+    for path in paths {
+        match absolute_path(path) {
+            Ok(path) => { listed.insert(path); },
+            Err(e) => log::warn!("'{}': could not make path absolute: {}", path.to_string_lossy(), e),
+        }
+    }
+
+    let implied_dirs: BTreeSet<PathBuf> = listed.iter()
+        .flat_map(|path| path.ancestors().skip(1))
+        .filter(|ancestor| ancestor.parent().is_some() && !listed.contains(*ancestor))
+        .map(PathBuf::from)
+        .collect();
+    log::debug!("file_list_indexer: {} distinct paths listed, {} parent directories implied", listed.len(), implied_dirs.len());
+
+    let mut fs_nodes = Vec::new();
+    for path in implied_dirs.iter().chain(listed.iter()) {
+        match process_single_path(path, ctx) {
+            Ok(fs_node) => {
+                ctx.hooks.iter_mut().for_each(|hook| hook.indexed(&fs_node));
+                fs_nodes.push(fs_node);
+            },
+            Err(e) => log::warn!("'{}': skipping: {}", path.to_string_lossy(), e),
+        }
+    }
+
+    log::debug!("file_list_indexer: done. time elapsed: {} ms.", start_time.elapsed().as_millis());
+    fs_nodes
+}
+
+/// Makes the path absolute by prepending the working directory if it is relative,
+/// and removes `.` and `..` components lexically.
+fn absolute_path(path: &Path) -> io::Result<PathBuf> {
+    let path = if path.is_relative() {
+        env::current_dir()?.join(path)
+    } else {
+        path.to_path_buf()
+    };
+
+    let mut normalized = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::CurDir => {},
+            Component::ParentDir => { normalized.pop(); },
+            other => normalized.push(other.as_os_str()),
+        }
     }
+    Ok(normalized)
+}
+
+fn process_single_path(path: &Path, ctx: &mut IndexContext) -> io::Result<FsNode> {
+    let metadata = fs::symlink_metadata(path)?;
+    Ok(process_single_node(path, Ok(metadata.file_type()), Ok(metadata), ctx))
+}
+
+fn process_single_dir_entry(entry: &fs::DirEntry, ctx: &mut IndexContext) -> FsNode {
+    process_single_node(&entry.path(), entry.file_type(), entry.metadata(), ctx)
+}
+
+/// Collects the node's metadata, symlink target and checksum.
+/// `file_type` and `metadata` must not follow symlinks.
+fn process_single_node(entry_path: &Path, file_type: io::Result<fs::FileType>, metadata: io::Result<fs::Metadata>, ctx: &mut IndexContext) -> FsNode {
 
+    if entry_path.is_relative() {
+        panic!("TODO: convert relative paths to absolute paths");
+    }
 
-    let entry_path = entry.path();
     let entry_path_lossy = entry_path.to_string_lossy();
     let start_time = Instant::now();
 
@@ -89,7 +264,7 @@ fn process_single_dir_entry(entry: &fs::DirEntry, read_buf: &mut [u8]) -> FsNode
 
     fs_node.name = entry_path_lossy.clone().to_string();
     fs_node.node_type =
-        match entry.file_type() {
+        match file_type {
             Ok(ft) => {
                 if ft.is_dir() {
                     NodeType::Directory
@@ -107,50 +282,34 @@ fn process_single_dir_entry(entry: &fs::DirEntry, read_buf: &mut [u8]) -> FsNode
             }
         };
 
-    fn date_to_i64(path_for_log: &str, date: io::Result<SystemTime>) -> i64 {
-        match date {
-            Ok(systime) =>
-                systime.duration_since(SystemTime::UNIX_EPOCH)
-                .map_or(0, |d| d.as_secs() as i64),
-            Err(e) => {
-                log::warn!("'{}': could not read date: {}", path_for_log, e);
-                0
-            },
-        }
-    }
-
-    match entry.metadata() {
+    let metadata = match metadata {
         Ok(metadata) => {
-            fs_node.size = metadata.len() as i64;
-            fs_node.uid = metadata.st_uid();
-            fs_node.gid = metadata.st_gid();
-            fs_node.permissions = metadata.st_mode();
-            fs_node.inode = metadata.st_ino() as i64;
-            fs_node.nlinks = metadata.st_nlink() as i64;
-            fs_node.creation_date = date_to_i64(entry_path_lossy.as_ref(), metadata.created());
-            fs_node.modified_date = date_to_i64(entry_path_lossy.as_ref(), metadata.modified());
+            fill_metadata(&mut fs_node, &metadata, entry_path_lossy.as_ref());
+            Some(metadata)
         },
-        Err(e) => log::warn!("'{}': could not read metadata: {}", entry_path_lossy, e),
-    }
+        Err(e) => {
+            log::warn!("'{}': could not read metadata: {}", entry_path_lossy, e);
+            None
+        },
+    };
 
-    fs_node.parent_path = entry.path().parent().map_or_else(
+    fs_node.parent_path = entry_path.parent().map_or_else(
         || String::new(), // root or relative path
         |p| String::from(p.to_string_lossy())
     );
 
     if let NodeType::Symlink = fs_node.node_type {
-        match fs::read_link(entry.path()) {
+        match fs::read_link(entry_path) {
             Ok(path) => fs_node.links_to = path.to_string_lossy().to_string(),
             Err(e) => log::warn!("'{}': could not resolve symlink path: {}", entry_path_lossy, e),
         }
     }
 
-    fs_node.sha1_checksum =
-        if let NodeType::File = fs_node.node_type {
-            checksum(read_buf, &entry)
-        } else {
-            String::new()
-        };
+    if !fs_node.node_type.is_dir() && fs_node.nlinks > 1 {
+        assign_link_group(&mut fs_node, entry_path, metadata, ctx);
+    } else if let NodeType::File = fs_node.node_type {
+        hash_file(&mut fs_node, entry_path, metadata, ctx);
+    }
     // TODO: parent id
 
     log::trace!("'{}': indexing of file done. time elapsed: {} ms.", entry_path_lossy, start_time.elapsed().as_millis());
@@ -158,30 +317,202 @@ fn process_single_dir_entry(entry: &fs::DirEntry, read_buf: &mut [u8]) -> FsNode
     fs_node
 }
 
-const CHECKSUM_ERR_RESULT: &str = "ERR";
+fn date_to_i64(path_for_log: &str, date: io::Result<SystemTime>) -> i64 {
+    match date {
+        Ok(systime) =>
+            systime.duration_since(SystemTime::UNIX_EPOCH)
+            .map_or(0, |d| d.as_secs() as i64),
+        Err(e) => {
+            log::warn!("'{}': could not read date: {}", path_for_log, e);
+            0
+        },
+    }
+}
 
-fn checksum(read_buf: &mut [u8], file_entry: &fs::DirEntry) -> String {
+fn fill_metadata(fs_node: &mut FsNode, metadata: &fs::Metadata, path_for_log: &str) {
+    fs_node.size = metadata.len() as i64;
+    fs_node.uid = metadata.st_uid();
+    fs_node.gid = metadata.st_gid();
+    fs_node.permissions = metadata.st_mode();
+    fs_node.inode = metadata.st_ino() as i64;
+    fs_node.nlinks = metadata.st_nlink() as i64;
+    fs_node.device = metadata.st_dev() as i64;
+    fs_node.creation_date = date_to_i64(path_for_log, metadata.created());
+    fs_node.modified_date = date_to_i64(path_for_log, metadata.modified());
+}
+
+/// Puts a hard-linked node into the link group of its inode. Only the first link of
+/// a group that is encountered is hashed; the rest get their checksum from the group.
+fn assign_link_group(fs_node: &mut FsNode, path: &Path, metadata: Option<fs::Metadata>, ctx: &mut IndexContext) {
+    let key = (fs_node.device, fs_node.inode);
+
+    if let Some(group) = ctx.link_groups.get(&key) {
+        log::trace!("'{}': hard link of link group {}; not hashing again", path.to_string_lossy(), group.id);
+        fs_node.link_group = group.id;
+        group.checksums.apply_to(fs_node);
+        fs_node.unstable = group.unstable;
+        ctx.summary.hardlinks_reused += 1;
+        return;
+    }
+
+    if let NodeType::File = fs_node.node_type {
+        hash_file(fs_node, path, metadata, ctx);
+    }
+
+    fs_node.link_group = ctx.link_groups.len() as i64 + 1;
+    ctx.link_groups.insert(key, LinkGroup {
+        id: fs_node.link_group,
+        checksums: Checksums {
+            sha1: fs_node.sha1_checksum.clone(),
+            md5: fs_node.md5_checksum.clone(),
+            sha256: fs_node.sha256_checksum.clone(),
+        },
+        unstable: fs_node.unstable,
+    });
+}
+
+/// How many times a file that changed while being hashed is hashed again,
+/// before giving up and marking it as unstable.
+const UNSTABLE_RETRY_LIMIT: u32 = 3;
+
+/// True if nothing indicates that the file was written to between the two stats.
+fn is_same_version(before: &fs::Metadata, after: &fs::Metadata) -> bool {
+    before.st_size() == after.st_size()
+        && before.st_mtime() == after.st_mtime()
+        && before.st_mtime_nsec() == after.st_mtime_nsec()
+        && before.st_ctime() == after.st_ctime()
+        && before.st_ctime_nsec() == after.st_ctime_nsec()
+}
+
+/// Calculates the checksum of the file, taking care not to update its access time,
+/// and verifies that the file did not change while it was being read.
+/// If the file keeps changing, it is marked as unstable.
+fn hash_file(fs_node: &mut FsNode, path: &Path, metadata: Option<fs::Metadata>, ctx: &mut IndexContext) {
+    let path_lossy = path.to_string_lossy();
+
+    let mut atime_touched = false;
+    if !hash_until_stable(fs_node, path, metadata, &mut ctx.read_buf, ctx.digests, &mut atime_touched) {
+        log::warn!("'{}': file kept changing, or vanished, while hashing. marking as unstable.", path_lossy);
+        fs_node.unstable = true;
+        ctx.summary.unstable += 1;
+    }
+
+    if atime_touched {
+        ctx.summary.atime_touched += 1;
+    } else {
+        ctx.summary.atime_untouched += 1;
+    }
+}
+
+/// Calculates the checksum of the file and re-stats it afterwards, to make sure the file
+/// did not change while it was being read. If it did, the checksum would match neither
+/// the old nor the new contents, so hashing is retried up to [UNSTABLE_RETRY_LIMIT]() times.
+/// The metadata of the node is updated to the stat the checksum was verified against.
+/// Returns false if the file never stayed the same throughout the hashing, or could not be stat'ed afterwards.
+fn hash_until_stable(
+    fs_node: &mut FsNode,
+    path: &Path,
+    metadata: Option<fs::Metadata>,
+    read_buf: &mut [u8],
+    digests: Digests,
+    atime_touched: &mut bool
+) -> bool {
+    let path_lossy = path.to_string_lossy();
+    let mut before = metadata;
+
+    for attempt in 0..=UNSTABLE_RETRY_LIMIT {
+        checksum(read_buf, path, digests, atime_touched).apply_to(fs_node);
+
+        let after = match fs::symlink_metadata(path) {
+            Ok(after) => after,
+            Err(e) => {
+                // e.g. deleted or replaced while being read: nothing tells the checksum is of a whole version
+                log::warn!("'{}': could not re-read metadata after hashing: {}", path_lossy, e);
+                return false;
+            }
+        };
+
+        let stable = before.as_ref().is_some_and(|before| is_same_version(before, &after));
+        fill_metadata(fs_node, &after, path_lossy.as_ref());
+        if stable {
+            return true;
+        }
+
+        log::debug!("'{}': file changed while hashing (attempt {} of {})", path_lossy, attempt + 1, UNSTABLE_RETRY_LIMIT + 1);
+        before = Some(after);
+    }
+
+    false
+}
+
+/// Opens the file for reading with `O_NOATIME`, so that reading it doesn't update its access time.
+/// Only the owner of the file (or root) may do so; for anyone else, the file is opened normally.
+/// Returns the file, and whether reading it may update its access time.
+fn open_for_hashing(path: &Path) -> io::Result<(fs::File, bool)> {
+    let noatime_result = fs::OpenOptions::new()
+        .read(true)
+        .custom_flags(libc::O_NOATIME)
+        .open(path);
+
+    match noatime_result {
+        Ok(file) => Ok((file, false)),
+        Err(e) if e.raw_os_error() == Some(libc::EPERM) => {
+            log::trace!("'{}': not permitted to open with O_NOATIME", path.to_string_lossy());
+            Ok((fs::File::open(path)?, true))
+        },
+        Err(e) => Err(e),
+    }
+}
+
+/// The digest of files that could not be read.
+pub const CHECKSUM_ERR_RESULT: &str = "ERR";
+
+impl Checksums {
+
+    /// All asked-for digests set to [CHECKSUM_ERR_RESULT]().
+    fn error(digests: Digests) -> Checksums {
+        let err_if = |wanted: bool| if wanted { CHECKSUM_ERR_RESULT.to_string() } else { String::new() };
+        Checksums {
+            sha1: CHECKSUM_ERR_RESULT.to_string(),
+            md5: err_if(digests.md5),
+            sha256: err_if(digests.sha256),
+        }
+    }
+
+    fn apply_to(&self, fs_node: &mut FsNode) {
+        fs_node.sha1_checksum = self.sha1.clone();
+        fs_node.md5_checksum = self.md5.clone();
+        fs_node.sha256_checksum = self.sha256.clone();
+    }
+}
+
+/// Sets `atime_touched` if the file had to be read in a way that may update its access time.
+fn checksum(read_buf: &mut [u8], path: &Path, digests: Digests, atime_touched: &mut bool) -> Checksums {
 
-    let path = file_entry.path();
     let path_lossy = path.to_string_lossy();
     let start_time = Instant::now();
-    log::trace!("'{}': calculating sha1 checksum...", file_entry.path().to_string_lossy());
+    log::trace!("'{}': calculating sha1 checksum...", path_lossy);
 
-    let mut file = match fs::File::open(file_entry.path()) {
-        Ok(f) => f,
+    let mut file = match open_for_hashing(path) {
+        Ok((f, touches_atime)) => {
+            *atime_touched |= touches_atime;
+            f
+        },
         Err(e) => {
             log::warn!("'{}': could not open file for reading: {}", path_lossy, e);
-            return CHECKSUM_ERR_RESULT.to_string();
+            return Checksums::error(digests);
         },
     };
 
     let mut sha1digest = sha1::Sha1::new();
+    let mut md5digest = if digests.md5 { Some(md5::Md5::new()) } else { None };
+    let mut sha256digest = if digests.sha256 { Some(sha2::Sha256::new()) } else { None };
     let mut read_retries = 0;
 
     loop {
         if read_retries > 10 {
             log::warn!("'{}': exceeded maximum read retry limit. abort.", path_lossy);
-            return CHECKSUM_ERR_RESULT.to_string();
+            return Checksums::error(digests);
         }
         let bytes_read = match file.read(read_buf) {
             Ok(n) => n,
@@ -195,13 +526,19 @@ fn checksum(read_buf: &mut [u8], file_entry: &fs::DirEntry) -> String {
                     }
                     _ => {
                         log::warn!("'{}': failed while reading file: {}", path_lossy, e);
-                        return CHECKSUM_ERR_RESULT.to_string();
+                        return Checksums::error(digests);
                     }
                 }
             },
         };
         if bytes_read > 0 {
             sha1digest.update(&read_buf[..bytes_read]);
+            if let Some(md5digest) = md5digest.as_mut() {
+                md5digest.update(&read_buf[..bytes_read]);
+            }
+            if let Some(sha256digest) = sha256digest.as_mut() {
+                sha256digest.update(&read_buf[..bytes_read]);
+            }
         } else {
             break;
         }
@@ -209,5 +546,34 @@ fn checksum(read_buf: &mut [u8], file_entry: &fs::DirEntry) -> String {
 
     log::trace!("'{}': sha1 checksum calculated. time elapsed: {} ms.", path_lossy, start_time.elapsed().as_millis());
 
-    sha1digest.digest().to_string()
-}
\ No newline at end of file
+    Checksums {
+        sha1: sha1digest.digest().to_string(),
+        md5: md5digest.map(|digest| format!("{:x}", digest.finalize())).unwrap_or_default(),
+        sha256: sha256digest.map(|digest| format!("{:x}", digest.finalize())).unwrap_or_default(),
+    }
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn vanished_file_is_unstable() {
+        let dir = std::env::temp_dir().join(format!("magnetar-hash-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("f");
+        fs::write(&path, "one").unwrap();
+        let mut read_buf = vec![0; 16];
+        let mut atime_touched = false;
+
+        let mut fs_node = FsNode::default();
+        let metadata = fs::symlink_metadata(&path).ok();
+        assert!(hash_until_stable(&mut fs_node, &path, metadata, &mut read_buf, Digests::default(), &mut atime_touched));
+        assert_eq!(fs_node.sha1_checksum, "fe05bcdcdc4928012781a5f1a2a77cbb5398e106");
+
+        let metadata = fs::symlink_metadata(&path).ok();
+        fs::remove_file(&path).unwrap();
+        assert!(!hash_until_stable(&mut fs_node, &path, metadata, &mut read_buf, Digests::default(), &mut atime_touched));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
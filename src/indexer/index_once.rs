@@ -1,40 +1,114 @@
-use crate::{create_tables, fs_indexer};
-use std::time::Instant;
+use crate::indexer::fs_indexer;
+use crate::schema;
+use crate::db_models::host::Host;
+use crate::db_models::indexing_run::IndexingRun;
+use crate::db_models::mount::Mount;
+use crate::db_models::unix_group::UnixGroup;
+use crate::db_models::unix_user::UnixUser;
+use crate::db_models::fs_node::FsNode;
+use crate::util::{mountinfo, unix_names};
+use crate::verifier;
+use std::collections::HashMap;
+use std::fs;
+use std::path::PathBuf;
+use std::time::{Instant, SystemTime};
 
-pub fn start(db_path: &str, directories: clap::Values<'_>) -> crate::ConvertibleResult<()> {
+const HOSTNAME_PATH: &str = "/proc/sys/kernel/hostname";
+
+/// What to index.
+#[derive(Debug)]
+pub enum Selection {
+    /// Walk these directories recursively.
+    Directories(Vec<String>),
+
+    /// Index exactly these paths, plus the directories they imply.
+    Paths(Vec<PathBuf>),
+}
+
+/// When storing a run as a delta, the nodes of the parent run that no node of this run
+/// has been matched against yet.
+#[derive(Debug)]
+struct DeltaBase {
+    parent_nodes: HashMap<String, FsNode>,
+    unchanged: usize,
+    stored: usize,
+}
+
+/// Indexes the selection into a new run of the database at `db_path`, which is created if needed.
+/// If `delta` is set and the database already has a run, the new run is stored as the difference to it.
+/// `hooks` are called as the filesystem is walked, see [fs_indexer::IndexHook]().
+/// Once complete, the run is chained and signed as `seal` asks for, see [verifier::seal_run]().
+pub fn start(
+    db_path: &str,
+    selection: Selection,
+    digests: fs_indexer::Digests,
+    delta: bool,
+    hooks: Vec<Box<dyn fs_indexer::IndexHook>>,
+    seal: &verifier::Seal
+) -> crate::ConvertibleResult<()> {
 
     let start_time = Instant::now();
     log::debug!("index_once.start: begin...");
 
     log::debug!("'{}': opening connection to database...", db_path);
-    let mut conn = rusqlite::Connection::open(db_path)?;
-    create_tables::execute(&conn)?;
+    let mut conn = schema::open_or_create(db_path)?;
     log::debug!("'{}': open OK; tables initialized", db_path);
 
     log::debug!("'{}': beginning transaction...", db_path);
     let transaction = conn.transaction()?;
 
-    let directories: Vec<String> = directories.map(|v| v.to_string()).collect();
-    log::debug!("directories selected for indexing: '{}'", directories.join(", "));
-    for dir in directories {
-        match fs_indexer::depth_first_indexer(dir.as_str()) {
-            Ok(fs_nodes) => {
-                log::debug!("'{}': indexing done, inserting into database...", dir);
-                for fs_node in fs_nodes {
-                    log::trace!("INSERT {:?}", fs_node);
-                    if let Err(e) = fs_node.insert(&transaction) {
-                        log::error!("could not insert fsnode entry into db: {}. {:?}", e, fs_node);
-                    }
-                }
-                log::debug!("'{}': db insertions OK.", dir);
-            },
-            Err(e) => {
-                log::warn!("'{}': abort indexing of directory. reason: {}", dir, e);
-            },
-        };
+    let parent = IndexingRun::select_latest(&transaction)?;
+    let run = record_run(&transaction, parent.as_ref(), delta)?;
+    record_name_tables(&transaction, run.id)?;
+    record_mounts(&transaction, run.id)?;
+
+    let mut delta_base = match parent {
+        Some(parent) if run.delta => {
+            log::debug!("storing run {} as delta to run {}...", run.id, parent.id);
+            let parent_nodes = FsNode::select_run(&transaction, &parent)?.into_iter()
+                .map(|fs_node| (fs_node.name.clone(), fs_node))
+                .collect();
+            Some(DeltaBase { parent_nodes, unchanged: 0, stored: 0 })
+        },
+        _ => None,
+    };
+
+    let mut ctx = fs_indexer::IndexContext::new();
+    ctx.digests = digests;
+    ctx.hooks = hooks;
+    match selection {
+        Selection::Directories(directories) => {
+            log::debug!("directories selected for indexing: '{}'", directories.join(", "));
+            for dir in directories {
+                match fs_indexer::depth_first_indexer(dir.as_str(), &mut ctx) {
+                    Ok(fs_nodes) => insert_fs_nodes(&transaction, run.id, dir.as_str(), fs_nodes, &mut delta_base),
+                    Err(e) => {
+                        log::warn!("'{}': abort indexing of directory. reason: {}", dir, e);
+                    },
+                };
+            }
+        },
+        Selection::Paths(paths) => {
+            log::debug!("{} paths selected for indexing", paths.len());
+            let fs_nodes = fs_indexer::file_list_indexer(&paths, &mut ctx);
+            insert_fs_nodes(&transaction, run.id, "(file list)", fs_nodes, &mut delta_base);
+        },
+    }
+
+    if let Some(delta_base) = delta_base {
+        insert_tombstones(&transaction, run.id, &delta_base);
+        log::info!(
+            "run {}: stored {} new or changed nodes and {} removals; {} nodes unchanged from run {}",
+            run.id, delta_base.stored, delta_base.parent_nodes.len(), delta_base.unchanged,
+            run.parent_run_id.unwrap_or_default()
+        );
     }
 
+    verifier::seal_run(&transaction, &run, seal)?;
+
     transaction.commit()?;
+    ctx.summary.log();
+    log::info!("'{}': index stored as indexing run {}", db_path, run.id);
 
     conn.close()?;
     log::debug!("{}: closed database connection.", db_path);
@@ -42,3 +116,191 @@ pub fn start(db_path: &str, directories: clap::Values<'_>) -> crate::Convertible
 
     Ok(())
 }
+
+/// Adds this run to the database. The run that was the latest until now becomes its parent.
+fn record_run(transaction: &rusqlite::Transaction<'_>, parent: Option<&IndexingRun>, delta: bool) -> crate::ConvertibleResult<IndexingRun> {
+    let timestamp = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH)?.as_secs() as i64;
+
+    let run = IndexingRun {
+        id: IndexingRun::next_id(transaction)?,
+        timestamp: Some(timestamp),
+        host_id: Some(Host::select_or_insert(transaction, hostname().as_str())?),
+        parent_run_id: parent.map(|parent| parent.id),
+        delta: delta && parent.is_some(),
+    };
+    log::debug!("INSERT {:?}", run);
+    run.insert(transaction)?;
+
+    Ok(run)
+}
+
+fn hostname() -> String {
+    match fs::read_to_string(HOSTNAME_PATH) {
+        Ok(hostname) => hostname.trim().to_string(),
+        Err(e) => {
+            log::warn!("'{}': could not read host name: {}", HOSTNAME_PATH, e);
+            "localhost".to_string()
+        }
+    }
+}
+
+/// Stores the user and group name tables of this host, so that indexes
+/// from different hosts can later be compared by user/group name.
+fn record_name_tables(transaction: &rusqlite::Transaction<'_>, run_id: i64) -> crate::ConvertibleResult<()> {
+    log::debug!("recording user and group name tables...");
+
+    match unix_names::read_name_table(unix_names::PASSWD_PATH) {
+        Ok(users) => {
+            for (name, uid) in users {
+                UnixUser { id: 0, name, uid, run_id, source_id: 0 }.insert(transaction)?;
+            }
+        },
+        Err(e) => log::warn!("'{}': could not read user names: {}", unix_names::PASSWD_PATH, e),
+    }
+
+    match unix_names::read_name_table(unix_names::GROUP_PATH) {
+        Ok(groups) => {
+            for (name, gid) in groups {
+                UnixGroup { id: 0, name, gid, run_id, source_id: 0 }.insert(transaction)?;
+            }
+        },
+        Err(e) => log::warn!("'{}': could not read group names: {}", unix_names::GROUP_PATH, e),
+    }
+
+    log::debug!("user and group name tables recorded.");
+    Ok(())
+}
+
+/// Stores the mounted filesystems of this host, so that the filesystem
+/// type of each node can be looked up by its device number.
+fn record_mounts(transaction: &rusqlite::Transaction<'_>, run_id: i64) -> crate::ConvertibleResult<()> {
+    log::debug!("recording mounted filesystems...");
+
+    match mountinfo::read_mountinfo() {
+        Ok(mounts) => {
+            for mount_info in mounts {
+                let mount = Mount {
+                    id: 0, // assigned by the database
+                    device: mount_info.device as i64,
+                    mount_point: mount_info.mount_point,
+                    fs_type: mount_info.fs_type,
+                    source: mount_info.source,
+                    options: mount_info.options,
+                    super_options: mount_info.super_options,
+                    run_id,
+                    source_id: 0,
+                    host: None,
+                };
+                log::trace!("INSERT {:?}", mount);
+                mount.insert(transaction)?;
+            }
+        },
+        Err(e) => log::warn!("'{}': could not read mounted filesystems: {}", mountinfo::MOUNTINFO_PATH, e),
+    }
+
+    log::debug!("mounted filesystems recorded.");
+    Ok(())
+}
+
+/// Inserts the nodes into the run. When storing a delta, nodes unchanged from the parent run are left out.
+fn insert_fs_nodes(
+    transaction: &rusqlite::Transaction<'_>,
+    run_id: i64,
+    source: &str,
+    fs_nodes: Vec<FsNode>,
+    delta_base: &mut Option<DeltaBase>
+) {
+    log::debug!("'{}': indexing done, inserting into database...", source);
+    for mut fs_node in fs_nodes {
+        fs_node.run_id = run_id;
+        if let Some(delta_base) = delta_base {
+            let parent_node = delta_base.parent_nodes.remove(&fs_node.name);
+            if parent_node.is_some_and(|parent_node| parent_node.same_record(&fs_node)) {
+                delta_base.unchanged += 1;
+                continue;
+            }
+            delta_base.stored += 1;
+        }
+        log::trace!("INSERT {:?}", fs_node);
+        if let Err(e) = fs_node.insert(transaction) {
+            log::error!("could not insert fsnode entry into db: {}. {:?}", e, fs_node);
+        }
+    }
+    log::debug!("'{}': db insertions OK.", source);
+}
+
+/// Records the nodes of the parent run that were not found again as removed.
+fn insert_tombstones(transaction: &rusqlite::Transaction<'_>, run_id: i64, delta_base: &DeltaBase) {
+    for parent_node in delta_base.parent_nodes.values() {
+        let tombstone = FsNode {
+            run_id,
+            ..FsNode::tombstone(parent_node.name.clone(), parent_node.parent_path.clone())
+        };
+        log::trace!("INSERT {:?}", tombstone);
+        if let Err(e) = tombstone.insert(transaction) {
+            log::error!("could not insert fsnode entry into db: {}. {:?}", e, tombstone);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::indexer::test_runs::{self, Catalog};
+
+    fn removed_names(conn: &rusqlite::Connection, run_id: i64) -> Vec<String> {
+        let mut stmt = conn.prepare("SELECT name FROM fs_node WHERE run_id = ?1 AND removed ORDER BY name").unwrap();
+        let names = stmt.query_map(rusqlite::params![run_id], |row| row.get(0)).unwrap();
+        names.collect::<rusqlite::Result<_>>().unwrap()
+    }
+
+    /// Every run of a delta chain is reconstructed, in memory and streamed, as a full index of the same state.
+    #[test]
+    fn delta_runs_reconstruct_each_state() {
+        let catalog = Catalog::new("delta-runs");
+        for state in 0..test_runs::STATES {
+            catalog.make_state(state);
+            let delta_run = catalog.index("delta.db", true);
+            let full_run = catalog.index("full.db", false);
+            assert_eq!(delta_run.delta, state > 0);
+            assert_eq!(delta_run.chain(&catalog.open("delta.db")).unwrap().len(), state + 1);
+
+            let expected = test_runs::nodes(&catalog.open("full.db"), &full_run);
+            let conn = catalog.open("delta.db");
+            test_runs::assert_same_nodes(&test_runs::nodes(&conn, &delta_run), &expected);
+            let mut streamed = Vec::new();
+            FsNode::for_each_in_run(&conn, &delta_run, |fs_node| {
+                streamed.push(fs_node);
+                Ok(())
+            }).unwrap();
+            test_runs::assert_same_nodes(&test_runs::sorted(streamed), &expected);
+        }
+    }
+
+    /// A delta run stores a tombstone for each node of its parent run that is gone, and only for those.
+    #[test]
+    fn tombstones_for_removed_nodes() {
+        let catalog = Catalog::new("tombstones");
+        let mut runs = Vec::new();
+        for state in 0..test_runs::STATES {
+            catalog.make_state(state);
+            runs.push(catalog.index("delta.db", true));
+        }
+
+        let conn = catalog.open("delta.db");
+        let tree = catalog.tree().to_string_lossy().to_string();
+        let in_tree = |names: &[&str]| names.iter().map(|name| format!("{}/{}", tree, name)).collect::<Vec<_>>();
+        assert_eq!(removed_names(&conn, runs[0].id), in_tree(&[]));
+        assert_eq!(removed_names(&conn, runs[1].id), in_tree(&["b"]));
+        assert_eq!(removed_names(&conn, runs[2].id), in_tree(&["sub", "sub/c", "sub/d"]));
+        assert_eq!(removed_names(&conn, runs[3].id), in_tree(&["e"]));
+
+        // unchanged nodes are not stored again
+        let stored: i64 = conn.query_row(
+            "SELECT COUNT(*) FROM fs_node WHERE run_id = ?1 AND name = ?2",
+            rusqlite::params![runs[3].id, format!("{}/b", tree)],
+            |row| row.get(0)
+        ).unwrap();
+        assert_eq!(stored, 0);
+    }
+}
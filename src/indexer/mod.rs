@@ -1,29 +1,45 @@
 pub mod fs_indexer;
-pub mod index_once;
-pub mod listener;
+pub(crate) mod index_once;
+pub(crate) mod listener;
+#[cfg(test)]
+pub(crate) mod test_runs;
 
 use clap;
 use crate::consts;
+use crate::indexer::index_once::Selection;
+use crate::verifier;
 use std::env;
+use std::ffi::OsStr;
+use std::fs;
+use std::io;
+use std::io::Read;
+use std::os::unix::ffi::OsStrExt;
 use std::process::exit;
 use std::time::SystemTime;
 use std::path::PathBuf;
 
-pub fn run(args: &clap::ArgMatches<'_>) -> crate::ConvertibleResult<()> {
-    let directories = args.values_of("directories").unwrap();
+pub(crate) fn run(args: &clap::ArgMatches<'_>) -> crate::ConvertibleResult<()> {
+    let selection = match args.value_of("files-from") {
+        Some(list_path) => Selection::Paths(read_file_list(list_path, args.is_present("null"))?),
+        None => {
+            let directories = args.values_of("directories").unwrap();
 
-    // disallow indexing of subdirectories
-    for dir in directories.clone() { // TODO: naive subdir check. doesn't guard against links
-        for other_dir in directories.clone() {
-            if dir == other_dir {
-                continue;
+            // disallow indexing of subdirectories
+            for dir in directories.clone() { // TODO: naive subdir check. doesn't guard against links
+                for other_dir in directories.clone() {
+                    if dir == other_dir {
+                        continue;
+                    }
+                    if dir.starts_with(other_dir) {
+                        log::error!("{} is subdirectory of {}. abort.", dir, other_dir);
+                        exit(consts::EXIT_INVALID_ARGS);
+                    }
+                }
             }
-            if dir.starts_with(other_dir) {
-                log::error!("{} is subdirectory of {}. abort.", dir, other_dir);
-                exit(consts::EXIT_INVALID_ARGS);
-            }
-        }
-    }
+
+            Selection::Directories(directories.map(|v| v.to_string()).collect())
+        },
+    };
 
     if args.is_present("daemonize") {
         unimplemented!()
@@ -38,8 +54,11 @@ pub fn run(args: &clap::ArgMatches<'_>) -> crate::ConvertibleResult<()> {
     let mut out_dir = PathBuf::from(args.value_of("output-dir").unwrap_or("./"));
     out_dir.push(db_filename.as_str());
 
-    let db_path = out_dir.to_str()
-        .expect("could not create temporary database (illegal filename)");
+    let db_path = match args.value_of("catalog") {
+        Some(catalog) => catalog,
+        None => out_dir.to_str()
+            .expect("could not create temporary database (illegal filename)"),
+    };
 
     if args.is_present("listen") {
         #[cfg(target_os = "linux")]
@@ -52,7 +71,15 @@ pub fn run(args: &clap::ArgMatches<'_>) -> crate::ConvertibleResult<()> {
         }
     } else {
         #[cfg(target_family = "unix")]
-        index_once::start(db_path, directories)?;
+        {
+            let digests = args.values_of("digest").map(|values| values.collect::<Vec<_>>()).unwrap_or_default();
+            let digests = fs_indexer::Digests {
+                md5: digests.contains(&"md5"),
+                sha256: digests.contains(&"sha256"),
+            };
+            let seal = verifier::seal_from_args(args)?;
+            index_once::start(db_path, selection, digests, !args.is_present("full"), Vec::new(), &seal)?;
+        }
 
         #[cfg(target_family = "windows")]
         {
@@ -63,7 +90,7 @@ pub fn run(args: &clap::ArgMatches<'_>) -> crate::ConvertibleResult<()> {
     Ok(())
 }
 
-pub fn cmdline<'a>() -> clap::App<'a, 'a> {
+pub(crate) fn cmdline<'a>() -> clap::App<'a, 'a> {
     clap::App::new("idx")
         .about("Create index of chosen directories and store in a database file.")
         .setting(clap::AppSettings::TrailingVarArg)
@@ -77,9 +104,64 @@ pub fn cmdline<'a>() -> clap::App<'a, 'a> {
             .long("output-dir")
             .value_name("OUTPUT-DIR")
             .help("Store database file in OUTPUT-DIR (default: working directory)"))
+        .arg(clap::Arg::with_name("catalog")
+            .long("catalog")
+            .value_name("FILE")
+            .conflicts_with("output-dir")
+            .help("Add this run to the catalog database FILE, which is created if it does not exist,\n\
+                   instead of writing a new database file. Runs are referred to as FILE@RUN elsewhere."))
+        .arg(clap::Arg::with_name("digest")
+            .long("digest")
+            .value_name("DIGEST")
+            .possible_values(&["md5", "sha256"])
+            .multiple(true)
+            .number_of_values(1)
+            .help("Also calculate this digest of each file; sha1 is always calculated.\n\
+                   Needed to compare against imported manifests with md5 or sha256 checksums only."))
+        .arg(clap::Arg::with_name("full")
+            .long("full")
+            .requires("catalog")
+            .help("Store the run in full. By default, a run added to a catalog is stored as the difference\n\
+                   to the previous run of the catalog, and reconstructed from it when read."))
+        .args(&verifier::seal_args())
+        .arg(clap::Arg::with_name("files-from")
+            .long("files-from")
+            .value_name("FILE")
+            .conflicts_with("directories")
+            .help("Index the paths listed in FILE ('-' for stdin), one per line, instead of\n\
+                   walking directories. The parent directories of the listed paths are indexed too."))
+        .arg(clap::Arg::with_name("null")
+            .short("0")
+            .long("null")
+            .requires("files-from")
+            .help("Paths in the --files-from list are separated by NUL instead of newline characters,\n\
+                   e.g. the output of 'find -print0'")
+            .takes_value(false))
         .arg(clap::Arg::with_name("directories")
             .value_name("DIRECTORIES")
             .help("The directories to index")
-            .required(true)
+            .required_unless("files-from")
             .multiple(true))
+}
+
+/// Reads a list of paths from the file, or stdin if `list_path` is `-`.
+/// Paths are kept as raw bytes, so names that aren't valid UTF-8 survive until they're stat'ed.
+fn read_file_list(list_path: &str, nul_delimited: bool) -> io::Result<Vec<PathBuf>> {
+    let mut contents = Vec::new();
+    if list_path == "-" {
+        log::debug!("reading list of paths to index from stdin...");
+        io::stdin().read_to_end(&mut contents)?;
+    } else {
+        log::debug!("'{}': reading list of paths to index...", list_path);
+        contents = fs::read(list_path)?;
+    }
+
+    let delimiter = if nul_delimited { b'\0' } else { b'\n' };
+    let paths: Vec<PathBuf> = contents.split(|b| *b == delimiter)
+        .filter(|path| !path.is_empty())
+        .map(|path| PathBuf::from(OsStr::from_bytes(path)))
+        .collect();
+
+    log::debug!("read {} paths", paths.len());
+    Ok(paths)
 }
\ No newline at end of file
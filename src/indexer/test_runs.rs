@@ -0,0 +1,105 @@
+use crate::db_models::fs_node::FsNode;
+use crate::db_models::indexing_run::IndexingRun;
+use crate::indexer::index_once::{self, Selection};
+use std::fs;
+use std::path::PathBuf;
+use std::process;
+
+/// The number of states [Catalog::make_state]() can change the tree to.
+pub const STATES: usize = 4;
+
+/// A tree and the catalogs of it, in a temporary directory of their own that is removed when dropped.
+pub struct Catalog {
+    pub dir: PathBuf,
+}
+
+impl Catalog {
+    pub fn new(name: &str) -> Catalog {
+        let dir = std::env::temp_dir().join(format!("magnetar-{}-{}", name, process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(dir.join("tree")).unwrap();
+        Catalog { dir }
+    }
+
+    pub fn tree(&self) -> PathBuf {
+        self.dir.join("tree")
+    }
+
+    pub fn db_path(&self, db: &str) -> String {
+        self.dir.join(db).to_string_lossy().to_string()
+    }
+
+    /// Changes the tree to the given state, from the one before it:
+    /// 0. `a` with its hard link `a_link`, `b`, `sub/c`, `sub/d` and `other/f`
+    /// 1. `a` changed, `b` removed and `e` added
+    /// 2. `sub` removed with its files, `b` added again
+    /// 3. `sub/c` added again as it was in state 0, `e` removed and `other/f` changed
+    pub fn make_state(&self, state: usize) {
+        let tree = self.tree();
+        match state {
+            0 => {
+                fs::create_dir_all(tree.join("sub")).unwrap();
+                fs::create_dir_all(tree.join("other")).unwrap();
+                fs::write(tree.join("a"), "one").unwrap();
+                fs::hard_link(tree.join("a"), tree.join("a_link")).unwrap();
+                fs::write(tree.join("b"), "two").unwrap();
+                fs::write(tree.join("sub/c"), "three").unwrap();
+                fs::write(tree.join("sub/d"), "four").unwrap();
+                fs::write(tree.join("other/f"), "six").unwrap();
+            },
+            1 => {
+                fs::write(tree.join("a"), "one more").unwrap();
+                fs::remove_file(tree.join("b")).unwrap();
+                fs::write(tree.join("e"), "five").unwrap();
+            },
+            2 => {
+                fs::remove_dir_all(tree.join("sub")).unwrap();
+                fs::write(tree.join("b"), "two again").unwrap();
+            },
+            3 => {
+                fs::create_dir_all(tree.join("sub")).unwrap();
+                fs::write(tree.join("sub/c"), "three").unwrap();
+                fs::remove_file(tree.join("e")).unwrap();
+                fs::write(tree.join("other/f"), "six more").unwrap();
+            },
+            _ => panic!("no state {}", state),
+        }
+    }
+
+    /// Indexes the tree into a new run of the catalog `db`, and returns the run.
+    pub fn index(&self, db: &str, delta: bool) -> IndexingRun {
+        let db_path = self.db_path(db);
+        let selection = Selection::Directories(vec![self.tree().to_string_lossy().to_string()]);
+        index_once::start(&db_path, selection, Default::default(), delta, Vec::new(), &Default::default()).unwrap();
+        IndexingRun::select_latest(&self.open(db)).unwrap().unwrap()
+    }
+
+    pub fn open(&self, db: &str) -> rusqlite::Connection {
+        rusqlite::Connection::open(self.db_path(db)).unwrap()
+    }
+}
+
+impl Drop for Catalog {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.dir);
+    }
+}
+
+/// The nodes of the run as reconstructed, ordered by name.
+pub fn nodes(conn: &rusqlite::Connection, run: &IndexingRun) -> Vec<FsNode> {
+    sorted(FsNode::select_run(conn, run).unwrap())
+}
+
+pub fn sorted(mut fs_nodes: Vec<FsNode>) -> Vec<FsNode> {
+    fs_nodes.sort_by(|a, b| a.name.cmp(&b.name));
+    fs_nodes
+}
+
+/// Asserts that both lists record the same nodes in the same order; row and run ids are not compared.
+pub fn assert_same_nodes(actual: &[FsNode], expected: &[FsNode]) {
+    let names = |fs_nodes: &[FsNode]| fs_nodes.iter().map(|fs_node| fs_node.name.clone()).collect::<Vec<_>>();
+    assert_eq!(names(actual), names(expected));
+    for (actual, expected) in actual.iter().zip(expected) {
+        assert!(actual.same_record(expected), "{:?} != {:?}", actual, expected);
+    }
+}
@@ -0,0 +1,46 @@
+//!
+//! magnetar: filesystem indexer & comparator.
+//!
+//! The library behind the `magnetar` command line tool. The parts meant for use by other programs:
+//!
+//! - [db_models::fs_node]: the [FsNode](db_models::fs_node::FsNode) model of an indexed node, and reading
+//!   the nodes of an indexing run with [FsNode::select_run](db_models::fs_node::FsNode::select_run)
+//!   and [FsNode::for_each_in_run](db_models::fs_node::FsNode::for_each_in_run).
+//! - [db_models::indexing_run]: the runs of an index database, referred to as `FILE[@RUN]` by [RunRef](db_models::indexing_run::RunRef).
+//! - [schema]: creating and opening index databases.
+//! - [indexer::fs_indexer]: the filesystem walker, with [IndexHook](indexer::fs_indexer::IndexHook)s
+//!   to observe or prune the walk.
+//! - [comparator::compare]: [make_pool](comparator::compare::make_pool) and [compare](comparator::compare::compare)
+//!   two indexes into [Delta](comparator::delta::Delta)s.
+//! - [query::filter]: the filter expression language of `magnetar query`.
+//! - [verifier]: signing runs, chaining them to the runs before them, and checking both.
+//!
+//! The rest, e.g. the subcommands behind the hidden `cli` module, is internal to the command line tool.
+//!
+
+#![warn(missing_debug_implementations, rust_2018_idioms)]
+
+pub mod apperror;
+#[doc(hidden)]
+pub mod cli;
+pub mod comparator;
+pub(crate) mod compactor;
+pub(crate) mod consts;
+pub mod db_models;
+pub(crate) mod dupes;
+pub mod errorwrapper;
+pub(crate) mod exporter;
+pub(crate) mod importer;
+pub mod indexer;
+pub(crate) mod merger;
+pub mod query;
+pub mod schema;
+pub(crate) mod util;
+pub mod verifier;
+
+use crate::errorwrapper::ErrorWrapper;
+use std::result;
+
+/// A `Result` type that uses [ErrorWrapper]() as error type, which implements the [From]() trait
+/// on every error type used in this program.
+pub type ConvertibleResult<T, E = ErrorWrapper> = result::Result<T, E>;
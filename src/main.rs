@@ -1,36 +1,19 @@
 //!
-//! magnetar
+//! magnetar: the command line tool. See the library for the indexer and comparator themselves.
 //!
 
 #![warn(missing_debug_implementations, rust_2018_idioms, missing_docs)]
 
-mod apperror;
-mod comparator;
-mod consts;
-mod create_tables;
-mod db_models;
-mod dupes;
-mod errorwrapper;
-mod indexer;
-mod util;
-
 use clap::App;
-use crate::errorwrapper::ErrorWrapper;
-use indexer::fs_indexer;
-use std::env;
-use std::result;
+use magnetar::cli;
 use flexi_logger;
 
 const LOGGING_LEVEL_VERBOSE: &str = "magnetar = trace";
 const LOGGING_LEVEL_DEFAULT: &str = "magnetar = debug";
 
-/// A `Result` type that uses [ErrorWrapper]() as error type, which implements the [From]() trait
-/// on every error type used in this program.
-pub type ConvertibleResult<T, E = ErrorWrapper> = result::Result<T, E>;
-
-fn main() -> crate::ConvertibleResult<()> {
+fn main() -> magnetar::ConvertibleResult<()> {
 
-    let args = App::new(consts::PROGRAM_NAME)
+    let args = App::new(cli::PROGRAM_NAME)
         .version(clap::crate_version!())
         .about("Filesystem indexer for archival management")
         .arg(clap::Arg::with_name("verbose")
@@ -42,9 +25,7 @@ fn main() -> crate::ConvertibleResult<()> {
             .long("log")
             .help("Directs log output from stderr to logfiles")
             .takes_value(false))
-        .subcommand(indexer::cmdline())
-        .subcommand(comparator::cmdline())
-        .subcommand(dupes::cmdline())
+        .subcommands(cli::subcommands())
         .get_matches();
 
     let logger = flexi_logger::Logger::with_str(
@@ -73,15 +54,5 @@ fn main() -> crate::ConvertibleResult<()> {
         .start()
         .unwrap_or_else(|e| panic!("Logger initialization failed with {}", e));
 
-    if let Some(args) = args.subcommand_matches("idx") {
-        indexer::run(args)?;
-    }
-    else if let Some(args) = args.subcommand_matches("cmp") {
-        comparator::run(args)?;
-    }
-    else if let Some(args) = args.subcommand_matches("dup") {
-        dupes::run(args)?;
-    }
-
-    return Ok(());
+    cli::run(&args)
 }
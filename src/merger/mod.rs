@@ -0,0 +1,218 @@
+use crate::ConvertibleResult;
+use crate::apperror::AppError;
+use crate::db_models::fs_node::{self, FsNode, NodeType};
+use crate::db_models::host::Host;
+use crate::db_models::indexing_run::{IndexingRun, RunRef};
+use crate::db_models::merge_source::MergeSource;
+use crate::db_models::mount::Mount;
+use crate::db_models::unix_group::UnixGroup;
+use crate::db_models::unix_user::UnixUser;
+use crate::errorwrapper::ErrorWrapper;
+use crate::schema;
+use crate::verifier;
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+/// An index to merge, given as `FILE[@RUN][=PREFIX]`.
+#[derive(Debug)]
+struct Input {
+    run_ref: RunRef,
+    prefix: Option<String>,
+}
+
+/// What has been copied into the merged run so far.
+#[derive(Debug, Default)]
+struct Merged {
+    names: HashSet<String>,
+    link_group_offset: i64,
+    /// The prefix given to the nodes of each merge source, by source id.
+    prefixes: Vec<(i64, String)>,
+}
+
+impl Input {
+
+    /// Since file names may contain `=` too, the part after the last `=` is only taken
+    /// as the prefix if it is an absolute path.
+    fn parse(input: &str) -> Input {
+        if let Some(i) = input.rfind('=') {
+            let prefix = &input[i + 1..];
+            if prefix.starts_with('/') {
+                return Input {
+                    run_ref: RunRef::parse(&input[..i]),
+                    prefix: Some(prefix.trim_end_matches('/').to_string()),
+                };
+            }
+        }
+        Input { run_ref: RunRef::parse(input), prefix: None }
+    }
+}
+
+pub fn run(args: &clap::ArgMatches<'_>) -> ConvertibleResult<()> {
+    let db_path = args.value_of("output").expect("path to output database is required");
+    let inputs: Vec<Input> = args.values_of("indexes").expect("indexes are required")
+        .map(Input::parse)
+        .collect();
+
+    if Path::new(db_path).exists() {
+        let error = AppError::WithMessage(format!("'{}': already exists; merge into a new file", db_path));
+        log::error!("{}", error);
+        return Err(ErrorWrapper::AppError(error));
+    }
+    let seal = verifier::seal_from_args(args)?;
+
+    let mut conn = rusqlite::Connection::open(db_path)?;
+    schema::create(&conn)?;
+    let transaction = conn.transaction()?;
+
+    let run = IndexingRun {
+        id: IndexingRun::next_id(&transaction)?,
+        timestamp: Some(SystemTime::now().duration_since(SystemTime::UNIX_EPOCH)?.as_secs() as i64),
+        ..IndexingRun::default()
+    };
+    run.insert(&transaction)?;
+
+    let mut merged = Merged::default();
+    for input in inputs.iter() {
+        merge_input(&transaction, run.id, input, args.is_present("prefix-by-host"), &mut merged)?;
+    }
+    add_prefix_dirs(&transaction, run.id, &mut merged)?;
+    verifier::seal_run(&transaction, &run, &seal)?;
+
+    transaction.commit()?;
+    log::info!("'{}': merged {} indexes, {} nodes", db_path, inputs.len(), merged.names.len());
+    Ok(())
+}
+
+/// Copies the nodes, mounts and name tables of the input into the merged run.
+fn merge_input(
+    transaction: &rusqlite::Transaction<'_>,
+    run_id: i64,
+    input: &Input,
+    prefix_by_host: bool,
+    merged: &mut Merged
+) -> ConvertibleResult<()> {
+    let (conn, source_run) = input.run_ref.open_read_only()?;
+
+    let host_name = match source_run.host_id {
+        Some(host_id) => Host::select_one(&conn, host_id)?.map(|host| host.display_name),
+        None => None,
+    };
+    let prefix = match (&input.prefix, &host_name) {
+        (Some(prefix), _) => prefix.clone(),
+        (None, Some(host_name)) if prefix_by_host => format!("/{}", host_name),
+        (None, None) if prefix_by_host => {
+            log::warn!("'{}': host unknown; merging without prefix", input.run_ref);
+            String::new()
+        },
+        _ => String::new(),
+    };
+
+    let source = MergeSource {
+        id: 0, // assigned by the database
+        run_id,
+        db_path: input.run_ref.db_path.clone(),
+        source_run_id: source_run.id,
+        host_id: match host_name {
+            Some(host_name) => Some(Host::select_or_insert(transaction, host_name.as_str())?),
+            None => None,
+        },
+        prefix,
+    };
+    let source_id = source.insert(transaction)?;
+    log::debug!("'{}': merging with prefix '{}'...", input.run_ref, source.prefix);
+
+    let mut max_link_group = 0;
+    for fs_node in FsNode::select_run(&conn, &source_run)? {
+        let name = format!("{}{}", source.prefix, fs_node.name);
+        if !merged.names.insert(name.clone()) {
+            log::warn!("'{}': '{}' was already merged from an earlier index; skipping. give the indexes a prefix to keep both", input.run_ref, name);
+            continue;
+        }
+        max_link_group = max_link_group.max(fs_node.link_group);
+
+        let fs_node = FsNode {
+            id: 0,
+            parent_path: format!("{}{}", source.prefix, fs_node.parent_path),
+            name,
+            // link groups are only unique within a run
+            link_group: if fs_node.link_group == 0 { 0 } else { fs_node.link_group + merged.link_group_offset },
+            run_id,
+            source_id,
+            ..fs_node
+        };
+        log::trace!("INSERT {:?}", fs_node);
+        fs_node.insert(transaction)?;
+    }
+    merged.link_group_offset += max_link_group;
+    merged.prefixes.push((source_id, source.prefix.clone()));
+
+    // device numbers and ids are only unique per host, so each index keeps its own
+    for mount in Mount::select(&conn, source_run.id)? {
+        Mount { id: 0, run_id, source_id, ..mount }.insert(transaction)?;
+    }
+    for user in UnixUser::select(&conn, source_run.id)? {
+        UnixUser { id: 0, run_id, source_id, ..user }.insert(transaction)?;
+    }
+    for group in UnixGroup::select(&conn, source_run.id)? {
+        UnixGroup { id: 0, run_id, source_id, ..group }.insert(transaction)?;
+    }
+
+    Ok(())
+}
+
+/// Adds the directories of the prefixes (e.g. `/archive` and `/archive/a` for `/archive/a`) that no index
+/// had a node for, so that the merged roots have their parents like any other path.
+/// Like the directories `import` implies, they are only known to be directories.
+fn add_prefix_dirs(transaction: &rusqlite::Transaction<'_>, run_id: i64, merged: &mut Merged) -> ConvertibleResult<()> {
+    for (source_id, prefix) in merged.prefixes.iter() {
+        let prefix = PathBuf::from(prefix);
+        let mut dirs: Vec<&Path> = prefix.ancestors()
+            .filter(|dir| dir.parent().is_some())
+            .collect();
+        dirs.reverse();
+
+        for dir in dirs {
+            let name = dir.to_string_lossy().to_string();
+            if !merged.names.insert(name.clone()) {
+                continue;
+            }
+            let fs_node = FsNode {
+                node_type: NodeType::Directory,
+                parent_path: dir.parent().map(|parent| parent.to_string_lossy().to_string()).unwrap_or_default(),
+                name,
+                run_id,
+                source_id: *source_id,
+                unknown_attrs: fs_node::ATTR_ALL & !fs_node::ATTR_NODE_TYPE,
+                ..FsNode::default()
+            };
+            log::trace!("INSERT {:?}", fs_node);
+            fs_node.insert(transaction)?;
+        }
+    }
+    Ok(())
+}
+
+pub fn cmdline<'a>() -> clap::App<'a, 'a> {
+    clap::App::new("merge")
+        .about("Combines several index databases into a new one, e.g. the indexes of several hosts.")
+        .setting(clap::AppSettings::TrailingVarArg)
+        .arg(clap::Arg::with_name("output")
+            .short("o")
+            .long("output")
+            .value_name("FILE")
+            .help("The database file to create.")
+            .required(true))
+        .arg(clap::Arg::with_name("prefix-by-host")
+            .long("prefix-by-host")
+            .help("Prefix the paths of each index with the name of the host it was made on, e.g. /hostA/...\n\
+                   (unless the index is given a prefix of its own)."))
+        .args(&verifier::seal_args())
+        .arg(clap::Arg::with_name("indexes")
+            .value_name("FILE[@RUN][=PREFIX]")
+            .index(1)
+            .help("The indexes to merge. PREFIX is prepended to the paths of the index; it must be absolute.\n\
+                   If a path occurs in several indexes, the first one is kept.")
+            .required(true)
+            .multiple(true))
+}
@@ -0,0 +1,845 @@
+use crate::apperror::AppError;
+use crate::db_models::fs_node::{self, FsNode, NodeType};
+pub use crate::exporter::{names_by_id, NameTable};
+use chrono::TimeZone;
+use std::time::SystemTime;
+
+/// A filter over the nodes of an index, parsed from an expression like
+/// `size > 1G and uid = 0 and mtime > -30d and path ~ '/srv/*'`.
+///
+/// Tests are combined with `and`, `or`, `not` and parentheses; tests next to each other are and-ed.
+/// Tests on attributes the index does not know, e.g. the owners of an imported checksum list, are false.
+#[derive(Debug)]
+pub struct Filter {
+    /// `None` for an empty expression, which matches all nodes.
+    expr: Option<Expr>,
+}
+
+#[derive(Debug)]
+enum Expr {
+    And(Box<Expr>, Box<Expr>),
+    Or(Box<Expr>, Box<Expr>),
+    Not(Box<Expr>),
+    Test(Test),
+}
+
+#[derive(Debug)]
+enum Test {
+    Text { field: Field, value: String, equal: bool },
+    Glob { field: Field, pattern: glob::Pattern, matching: bool },
+    Compare { field: Field, op: Op, value: Span },
+    Range { field: Field, from: Span, to: Span },
+    HasBits { bits: u32 },
+    Type { node_type: NodeType, equal: bool },
+    Unstable(bool),
+}
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+enum Field {
+    Path,
+    ParentPath,
+    Name,
+    NodeType,
+    Size,
+    Permissions,
+    Uid,
+    User,
+    Gid,
+    Group,
+    Created,
+    Modified,
+    Sha1,
+    Md5,
+    Sha256,
+    LinksTo,
+    Inode,
+    Nlinks,
+    Device,
+    LinkGroup,
+    Unstable,
+}
+
+/// How the value of a test is read.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+enum Kind {
+    Text,
+    Number,
+    Time,
+    Mode,
+    Type,
+    Flag,
+}
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+enum Op {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    Glob,
+    NotGlob,
+    HasBits,
+    In,
+}
+
+/// The values `start..=last` a literal stands for: a number is a span of one,
+/// a date like `2024-01-31` stands for the whole day.
+#[derive(Debug, Clone, Copy)]
+struct Span {
+    start: i64,
+    last: i64,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    LParen,
+    RParen,
+    And,
+    Or,
+    Not,
+    Op(Op),
+    Word(String),
+    Quoted(String),
+}
+
+const FIELDS: [(&str, Field); 29] = [
+    ("path", Field::Path),
+    ("parent_path", Field::ParentPath),
+    ("parent", Field::ParentPath),
+    ("name", Field::Name),
+    ("node_type", Field::NodeType),
+    ("type", Field::NodeType),
+    ("size", Field::Size),
+    ("permissions", Field::Permissions),
+    ("perm", Field::Permissions),
+    ("mode", Field::Permissions),
+    ("uid", Field::Uid),
+    ("user", Field::User),
+    ("gid", Field::Gid),
+    ("group", Field::Group),
+    ("created", Field::Created),
+    ("modified", Field::Modified),
+    ("mtime", Field::Modified),
+    ("sha1_checksum", Field::Sha1),
+    ("sha1", Field::Sha1),
+    ("md5_checksum", Field::Md5),
+    ("md5", Field::Md5),
+    ("sha256_checksum", Field::Sha256),
+    ("sha256", Field::Sha256),
+    ("links_to", Field::LinksTo),
+    ("inode", Field::Inode),
+    ("nlinks", Field::Nlinks),
+    ("device", Field::Device),
+    ("link_group", Field::LinkGroup),
+    ("unstable", Field::Unstable),
+];
+
+/// The help on the expression language, for the command line.
+pub const HELP: &str = "\
+EXPRESSIONS:
+    Tests are combined with and, or, not (also &&, ||, !) and parentheses.
+    Tests next to each other are and-ed. Quote values with spaces in '...' or \"...\".
+
+    path, parent_path, name, user, group, links_to, sha1, md5, sha256
+        = and != compare text, ~ and !~ match a glob, e.g. name ~ '*.mp3', path ~ '/srv/*'
+    size, uid, gid, inode, nlinks, device, link_group
+        =, !=, <, <=, >, >=, and in A..B (inclusive). Sizes take units: K, M, G, T (powers of 1024),
+        KB, MB, GB, TB (powers of 1000), e.g. size > 1G
+    modified (mtime), created
+        the same, with dates: 2024-01-31 (the whole day), 2024-01-31T12:00[:00] (UTC), @UNIXTIME,
+        or -30d for 30 days ago (s, min, h, d, w), e.g. mtime > -30d, mtime in 2024-01-01..2024-01-31
+    permissions (perm, mode)
+        = and != compare the permission bits, in octal or as rwxr-xr-x,
+        & tests that all given bits are set, e.g. perm & o+w, perm & u+s, perm & 022
+    type
+        = and != with file, dir, symlink, other, error
+    unstable
+        true if the file changed while it was hashed
+
+EXAMPLE:
+    magnetar query index.db 'size > 1G and uid = 0 and mtime > -30d and path ~ \"/srv/*\"'";
+
+impl Filter {
+
+    pub fn parse(expr: &str) -> Result<Filter, AppError> {
+        let tokens = tokenize(expr)?;
+        if tokens.is_empty() {
+            return Ok(Filter { expr: None });
+        }
+        let mut parser = Parser { tokens, pos: 0, now: now() };
+        let expr = parser.or()?;
+        if let Some(token) = parser.tokens.get(parser.pos) {
+            return Err(AppError::WithMessage(format!("unexpected {} in filter expression", describe(token))));
+        }
+        Ok(Filter { expr: Some(expr) })
+    }
+
+    /// `users` and `groups` are the name tables of the index (see [names_by_id]()).
+    pub fn matches(&self, fs_node: &FsNode, users: &NameTable, groups: &NameTable) -> bool {
+        match &self.expr {
+            Some(expr) => expr.matches(fs_node, users, groups),
+            None => true,
+        }
+    }
+}
+
+impl Expr {
+
+    fn matches(&self, fs_node: &FsNode, users: &NameTable, groups: &NameTable) -> bool {
+        match self {
+            Expr::And(left, right) => left.matches(fs_node, users, groups) && right.matches(fs_node, users, groups),
+            Expr::Or(left, right) => left.matches(fs_node, users, groups) || right.matches(fs_node, users, groups),
+            Expr::Not(expr) => !expr.matches(fs_node, users, groups),
+            Expr::Test(test) => test.matches(fs_node, users, groups),
+        }
+    }
+}
+
+impl Test {
+
+    fn matches(&self, fs_node: &FsNode, users: &NameTable, groups: &NameTable) -> bool {
+        match self {
+            Test::Text { field, value, equal } => match field.text(fs_node, users, groups) {
+                Some(text) => (text == value.as_str()) == *equal,
+                None => false,
+            },
+            Test::Glob { field, pattern, matching } => match field.text(fs_node, users, groups) {
+                Some(text) => pattern.matches(text) == *matching,
+                None => false,
+            },
+            Test::Compare { field, op, value } => match field.number(fs_node) {
+                Some(number) => match op {
+                    Op::Eq => value.start <= number && number <= value.last,
+                    Op::Ne => !(value.start <= number && number <= value.last),
+                    Op::Lt => number < value.start,
+                    Op::Le => number <= value.last,
+                    Op::Gt => number > value.last,
+                    Op::Ge => number >= value.start,
+                    _ => false,
+                },
+                None => false,
+            },
+            Test::Range { field, from, to } => match field.number(fs_node) {
+                Some(number) => from.start <= number && number <= to.last,
+                None => false,
+            },
+            Test::HasBits { bits } => fs_node.knows(fs_node::ATTR_PERMISSIONS) && fs_node.permissions & bits == *bits,
+            Test::Type { node_type, equal } => fs_node.knows(fs_node::ATTR_NODE_TYPE) && (fs_node.node_type == *node_type) == *equal,
+            Test::Unstable(unstable) => fs_node.unstable == *unstable,
+        }
+    }
+}
+
+impl Field {
+
+    fn from_name(name: &str) -> Option<Field> {
+        FIELDS.iter().find(|(field_name, _)| *field_name == name).map(|(_, field)| *field)
+    }
+
+    fn kind(self) -> Kind {
+        match self {
+            Field::Path | Field::ParentPath | Field::Name | Field::User | Field::Group
+                | Field::Sha1 | Field::Md5 | Field::Sha256 | Field::LinksTo => Kind::Text,
+            Field::Size | Field::Uid | Field::Gid | Field::Inode | Field::Nlinks
+                | Field::Device | Field::LinkGroup => Kind::Number,
+            Field::Created | Field::Modified => Kind::Time,
+            Field::Permissions => Kind::Mode,
+            Field::NodeType => Kind::Type,
+            Field::Unstable => Kind::Flag,
+        }
+    }
+
+    /// The `ATTR_*` bit the field is known by; paths are always known.
+    fn attr(self) -> u32 {
+        match self {
+            Field::Path | Field::ParentPath | Field::Name | Field::Unstable => 0,
+            Field::NodeType => fs_node::ATTR_NODE_TYPE,
+            Field::Size => fs_node::ATTR_SIZE,
+            Field::Permissions => fs_node::ATTR_PERMISSIONS,
+            Field::Uid | Field::User => fs_node::ATTR_UID,
+            Field::Gid | Field::Group => fs_node::ATTR_GID,
+            Field::Created => fs_node::ATTR_CREATION_DATE,
+            Field::Modified => fs_node::ATTR_MODIFIED_DATE,
+            Field::Sha1 => fs_node::ATTR_SHA1,
+            // recorded if not empty
+            Field::Md5 | Field::Sha256 => 0,
+            Field::LinksTo => fs_node::ATTR_LINKS_TO,
+            Field::Inode => fs_node::ATTR_INODE,
+            Field::Nlinks => fs_node::ATTR_NLINKS,
+            Field::Device => 0,
+            Field::LinkGroup => fs_node::ATTR_LINK_GROUP,
+        }
+    }
+
+    fn text<'a>(self, fs_node: &'a FsNode, users: &'a NameTable, groups: &'a NameTable) -> Option<&'a str> {
+        if !fs_node.knows(self.attr()) {
+            return None;
+        }
+        match self {
+            Field::Path => Some(fs_node.name.as_str()),
+            Field::ParentPath => Some(fs_node.parent_path.as_str()),
+            Field::Name => fs_node.name.rsplit('/').next(),
+            Field::User => users.get(&(fs_node.source_id, fs_node.uid)).map(String::as_str),
+            Field::Group => groups.get(&(fs_node.source_id, fs_node.gid)).map(String::as_str),
+            Field::Sha1 => Some(fs_node.sha1_checksum.as_str()),
+            Field::Md5 => Some(fs_node.md5_checksum.as_str()).filter(|digest| !digest.is_empty()),
+            Field::Sha256 => Some(fs_node.sha256_checksum.as_str()).filter(|digest| !digest.is_empty()),
+            Field::LinksTo => Some(fs_node.links_to.as_str()),
+            _ => None,
+        }
+    }
+
+    fn number(self, fs_node: &FsNode) -> Option<i64> {
+        if !fs_node.knows(self.attr()) {
+            return None;
+        }
+        match self {
+            Field::Size => Some(fs_node.size),
+            Field::Uid => Some(fs_node.uid as i64),
+            Field::Gid => Some(fs_node.gid as i64),
+            Field::Inode => Some(fs_node.inode),
+            Field::Nlinks => Some(fs_node.nlinks),
+            Field::Device => Some(fs_node.device),
+            Field::LinkGroup => Some(fs_node.link_group),
+            Field::Created => Some(fs_node.creation_date),
+            Field::Modified => Some(fs_node.modified_date),
+            Field::Permissions => Some((fs_node.permissions & 0o7777) as i64),
+            _ => None,
+        }
+    }
+}
+
+struct Parser {
+    tokens: Vec<Token>,
+    pos: usize,
+    now: i64,
+}
+
+impl Parser {
+
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos)
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.pos).cloned();
+        self.pos += 1;
+        token
+    }
+
+    fn or(&mut self) -> Result<Expr, AppError> {
+        let mut expr = self.and()?;
+        while self.peek() == Some(&Token::Or) {
+            self.pos += 1;
+            expr = Expr::Or(Box::new(expr), Box::new(self.and()?));
+        }
+        Ok(expr)
+    }
+
+    fn and(&mut self) -> Result<Expr, AppError> {
+        let mut expr = self.not()?;
+        loop {
+            match self.peek() {
+                Some(Token::And) => self.pos += 1,
+                // tests next to each other
+                Some(Token::LParen) | Some(Token::Not) | Some(Token::Word(_)) => {},
+                _ => return Ok(expr),
+            }
+            expr = Expr::And(Box::new(expr), Box::new(self.not()?));
+        }
+    }
+
+    fn not(&mut self) -> Result<Expr, AppError> {
+        if self.peek() == Some(&Token::Not) {
+            self.pos += 1;
+            return Ok(Expr::Not(Box::new(self.not()?)));
+        }
+        self.primary()
+    }
+
+    fn primary(&mut self) -> Result<Expr, AppError> {
+        let name = match self.next() {
+            Some(Token::LParen) => {
+                let expr = self.or()?;
+                return match self.next() {
+                    Some(Token::RParen) => Ok(expr),
+                    Some(token) => Err(AppError::WithMessage(format!("expected ')', got {}", describe(&token)))),
+                    None => Err(AppError::WithMessage("missing ')' at the end of the filter expression".to_string())),
+                };
+            },
+            Some(Token::Word(name)) => name,
+            Some(token) => return Err(AppError::WithMessage(format!("expected an attribute, got {}", describe(&token)))),
+            None => return Err(AppError::WithMessage("unexpected end of the filter expression".to_string())),
+        };
+        let field = Field::from_name(&name).ok_or_else(|| AppError::WithMessage(format!(
+            "unknown attribute '{}'; known are: {}", name, FIELDS.iter().map(|(name, _)| *name).collect::<Vec<_>>().join(", ")
+        )))?;
+
+        let op = match self.peek() {
+            Some(Token::Op(op)) => *op,
+            _ if field.kind() == Kind::Flag => return Ok(Expr::Test(Test::Unstable(true))),
+            _ => return Err(AppError::WithMessage(format!("expected an operator after '{}'", name))),
+        };
+        self.pos += 1;
+        let value = match self.next() {
+            Some(Token::Word(value)) | Some(Token::Quoted(value)) => value,
+            Some(token) => return Err(AppError::WithMessage(format!("expected a value after '{} {}', got {}", name, op.symbol(), describe(&token)))),
+            None => return Err(AppError::WithMessage(format!("expected a value after '{} {}'", name, op.symbol()))),
+        };
+
+        let invalid_op = || AppError::WithMessage(format!("'{}' cannot be used with '{}'", op.symbol(), name));
+        let test = match (field.kind(), op) {
+            (Kind::Text, Op::Eq) | (Kind::Text, Op::Ne) => Test::Text { field, value, equal: op == Op::Eq },
+            (Kind::Text, Op::Glob) | (Kind::Text, Op::NotGlob) => Test::Glob {
+                field,
+                pattern: glob::Pattern::new(&value)
+                    .map_err(|e| AppError::WithMessage(format!("invalid glob '{}': {}", value, e)))?,
+                matching: op == Op::Glob,
+            },
+            (Kind::Number, Op::In) | (Kind::Time, Op::In) => {
+                let (from, to) = value.split_once("..")
+                    .ok_or_else(|| AppError::WithMessage(format!("expected a range A..B after '{} in', got '{}'", name, value)))?;
+                Test::Range { field, from: self.span(field, from)?, to: self.span(field, to)? }
+            },
+            (Kind::Number, _) | (Kind::Time, _) if op.is_comparison() => Test::Compare { field, op, value: self.span(field, &value)? },
+            (Kind::Mode, Op::Eq) | (Kind::Mode, Op::Ne) => Test::Compare { field, op, value: Span::of(mode_bits(&value)? as i64) },
+            (Kind::Mode, Op::HasBits) => Test::HasBits { bits: mode_bits(&value)? },
+            (Kind::Type, Op::Eq) | (Kind::Type, Op::Ne) => Test::Type { node_type: node_type(&value)?, equal: op == Op::Eq },
+            (Kind::Flag, Op::Eq) | (Kind::Flag, Op::Ne) => match value.as_str() {
+                "true" => Test::Unstable(op == Op::Eq),
+                "false" => Test::Unstable(op != Op::Eq),
+                _ => return Err(AppError::WithMessage(format!("expected true or false after '{} {}', got '{}'", name, op.symbol(), value))),
+            },
+            _ => return Err(invalid_op()),
+        };
+        Ok(Expr::Test(test))
+    }
+
+    fn span(&self, field: Field, value: &str) -> Result<Span, AppError> {
+        match field.kind() {
+            Kind::Time => time_span(value, self.now),
+            _ => size(value).map(Span::of),
+        }
+    }
+}
+
+impl Op {
+
+    fn symbol(self) -> &'static str {
+        match self {
+            Op::Eq => "=",
+            Op::Ne => "!=",
+            Op::Lt => "<",
+            Op::Le => "<=",
+            Op::Gt => ">",
+            Op::Ge => ">=",
+            Op::Glob => "~",
+            Op::NotGlob => "!~",
+            Op::HasBits => "&",
+            Op::In => "in",
+        }
+    }
+
+    fn is_comparison(self) -> bool {
+        matches!(self, Op::Eq | Op::Ne | Op::Lt | Op::Le | Op::Gt | Op::Ge)
+    }
+}
+
+impl Span {
+
+    fn of(value: i64) -> Span {
+        Span { start: value, last: value }
+    }
+}
+
+fn tokenize(expr: &str) -> Result<Vec<Token>, AppError> {
+    let mut tokens = Vec::new();
+    let mut chars = expr.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        let next_is = |chars: &mut std::iter::Peekable<std::str::Chars<'_>>, expected: char| {
+            if chars.peek() == Some(&expected) {
+                chars.next();
+                true
+            } else {
+                false
+            }
+        };
+        let token = match c {
+            c if c.is_whitespace() => continue,
+            '(' => Token::LParen,
+            ')' => Token::RParen,
+            '&' if next_is(&mut chars, '&') => Token::And,
+            '&' => Token::Op(Op::HasBits),
+            '|' if next_is(&mut chars, '|') => Token::Or,
+            '!' if next_is(&mut chars, '=') => Token::Op(Op::Ne),
+            '!' if next_is(&mut chars, '~') => Token::Op(Op::NotGlob),
+            '!' => Token::Not,
+            '=' => { next_is(&mut chars, '='); Token::Op(Op::Eq) },
+            '<' if next_is(&mut chars, '=') => Token::Op(Op::Le),
+            '<' => Token::Op(Op::Lt),
+            '>' if next_is(&mut chars, '=') => Token::Op(Op::Ge),
+            '>' => Token::Op(Op::Gt),
+            '~' => Token::Op(Op::Glob),
+            '\'' | '"' => {
+                let mut quoted = String::new();
+                loop {
+                    match chars.next() {
+                        Some('\\') => quoted.extend(chars.next()),
+                        Some(q) if q == c => break,
+                        Some(other) => quoted.push(other),
+                        None => return Err(AppError::WithMessage(format!("missing closing {} in filter expression", c))),
+                    }
+                }
+                Token::Quoted(quoted)
+            },
+            '|' => return Err(AppError::WithMessage("'|' in filter expression; did you mean '||' or 'or'?".to_string())),
+            c => {
+                let mut word = c.to_string();
+                while let Some(&c) = chars.peek() {
+                    if c.is_whitespace() || "()&|!=<>~'\"".contains(c) {
+                        break;
+                    }
+                    word.push(c);
+                    chars.next();
+                }
+                match word.as_str() {
+                    "and" => Token::And,
+                    "or" => Token::Or,
+                    "not" => Token::Not,
+                    "in" => Token::Op(Op::In),
+                    _ => Token::Word(word),
+                }
+            },
+        };
+        tokens.push(token);
+    }
+
+    Ok(tokens)
+}
+
+fn describe(token: &Token) -> String {
+    match token {
+        Token::LParen => "'('".to_string(),
+        Token::RParen => "')'".to_string(),
+        Token::And => "'and'".to_string(),
+        Token::Or => "'or'".to_string(),
+        Token::Not => "'not'".to_string(),
+        Token::Op(op) => format!("'{}'", op.symbol()),
+        Token::Word(word) | Token::Quoted(word) => format!("'{}'", word),
+    }
+}
+
+/// A number with an optional unit, e.g. `1.5G`.
+fn size(value: &str) -> Result<i64, AppError> {
+    let invalid = || AppError::WithMessage(format!("'{}' is not a size; expected e.g. 100, 1.5G or 10MB", value));
+
+    let split = value.find(|c: char| !(c.is_ascii_digit() || c == '.')).unwrap_or(value.len());
+    let (number, unit) = value.split_at(split);
+    let factor: i64 = match unit {
+        "" | "B" => 1,
+        "K" | "k" | "KiB" => 1 << 10,
+        "M" | "MiB" => 1 << 20,
+        "G" | "GiB" => 1 << 30,
+        "T" | "TiB" => 1 << 40,
+        "KB" | "kB" => 1_000,
+        "MB" => 1_000_000,
+        "GB" => 1_000_000_000,
+        "TB" => 1_000_000_000_000,
+        _ => return Err(AppError::WithMessage(format!("unknown unit '{}' in '{}'", unit, value))),
+    };
+    if let Ok(number) = number.parse::<i64>() {
+        return number.checked_mul(factor).ok_or_else(invalid);
+    }
+    match number.parse::<f64>() {
+        // i64::MAX as f64 rounds up to 2^63, which is out of range already
+        Ok(number) if (i64::MIN as f64..i64::MAX as f64).contains(&(number * factor as f64)) => {
+            Ok((number * factor as f64) as i64)
+        }
+        _ => Err(invalid()),
+    }
+}
+
+/// A date, a time, `@UNIXTIME`, or a time relative to now like `-30d`.
+fn time_span(value: &str, now: i64) -> Result<Span, AppError> {
+    let invalid = || AppError::WithMessage(format!(
+        "'{}' is not a time; expected e.g. 2024-01-31, 2024-01-31T12:00, @1706702400 or -30d", value
+    ));
+
+    if let Some(unix_time) = value.strip_prefix('@') {
+        return unix_time.parse().map(Span::of).map_err(|_| invalid());
+    }
+    if let Some(ago) = value.strip_prefix('-') {
+        let split = ago.find(|c: char| !c.is_ascii_digit()).unwrap_or(ago.len());
+        let (number, unit) = ago.split_at(split);
+        let number: i64 = number.parse().map_err(|_| invalid())?;
+        let seconds = match unit {
+            "s" => 1,
+            "min" => 60,
+            "h" => 60 * 60,
+            "d" => 24 * 60 * 60,
+            "w" => 7 * 24 * 60 * 60,
+            _ => return Err(invalid()),
+        };
+        return number.checked_mul(seconds)
+            .and_then(|ago| now.checked_sub(ago))
+            .map(Span::of)
+            .ok_or_else(invalid);
+    }
+
+    // the span is as long as the precision the time is given with
+    let formats = [("%Y-%m-%dT%H:%M:%S", 1), ("%Y-%m-%dT%H:%M", 60)];
+    for (format, length) in formats.iter() {
+        if let Ok(time) = chrono::NaiveDateTime::parse_from_str(value, format) {
+            let start = chrono::Utc.from_utc_datetime(&time).timestamp();
+            return Ok(Span { start, last: start + length - 1 });
+        }
+    }
+    match chrono::NaiveDate::parse_from_str(value, "%Y-%m-%d") {
+        Ok(date) => {
+            let start = chrono::Utc.from_utc_datetime(&date.and_hms_opt(0, 0, 0).ok_or_else(invalid)?).timestamp();
+            Ok(Span { start, last: start + 24 * 60 * 60 - 1 })
+        },
+        Err(_) => Err(invalid()),
+    }
+}
+
+/// Octal (`644`, `0o644`), symbolic (`rw-r--r--`), or who+what (`o+w`, `ug+x,o+r`) permission bits.
+fn mode_bits(value: &str) -> Result<u32, AppError> {
+    let invalid = || AppError::WithMessage(format!("'{}' are not permissions; expected e.g. 644, rw-r--r-- or o+w", value));
+
+    let octal = value.strip_prefix("0o").unwrap_or(value);
+    if !octal.is_empty() && octal.chars().all(|c| ('0'..='7').contains(&c)) {
+        return u32::from_str_radix(octal, 8).ok().filter(|bits| *bits <= 0o7777).ok_or_else(invalid);
+    }
+
+    if value.len() == 9 && value.chars().all(|c| "rwxsStT-".contains(c)) {
+        let mut bits = 0;
+        for (i, c) in value.chars().enumerate() {
+            let shift = 6 - (i / 3) * 3;
+            bits |= match (i % 3, c) {
+                (_, '-') => 0,
+                (0, 'r') => 0o4 << shift,
+                (1, 'w') => 0o2 << shift,
+                (2, 'x') => 0o1 << shift,
+                // setuid, setgid and sticky; lowercase if also executable
+                (2, 's') if i < 6 => (0o1 << shift) | if i == 2 { 0o4000 } else { 0o2000 },
+                (2, 'S') if i < 6 => if i == 2 { 0o4000 } else { 0o2000 },
+                (2, 't') if i == 8 => 0o1001,
+                (2, 'T') if i == 8 => 0o1000,
+                _ => return Err(invalid()),
+            };
+        }
+        return Ok(bits);
+    }
+
+    let mut bits = 0;
+    for clause in value.split(',') {
+        let (who, what) = clause.split_once('+').ok_or_else(invalid)?;
+        let who = if who.is_empty() { "a" } else { who };
+        let mut who_mask = 0;
+        for c in who.chars() {
+            who_mask |= match c {
+                'u' => 0o4700,
+                'g' => 0o2070,
+                'o' => 0o1007,
+                'a' => 0o7777,
+                _ => return Err(invalid()),
+            };
+        }
+        for c in what.chars() {
+            bits |= who_mask & match c {
+                'r' => 0o444,
+                'w' => 0o222,
+                'x' => 0o111,
+                's' => 0o6000,
+                't' => 0o1000,
+                _ => return Err(invalid()),
+            };
+        }
+    }
+    Ok(bits)
+}
+
+fn node_type(value: &str) -> Result<NodeType, AppError> {
+    match value {
+        "file" | "f" => Ok(NodeType::File),
+        "dir" | "directory" | "d" => Ok(NodeType::Directory),
+        "symlink" | "link" | "l" => Ok(NodeType::Symlink),
+        "other" => Ok(NodeType::Other),
+        "error" => Ok(NodeType::Error),
+        _ => Err(AppError::WithMessage(format!("unknown node type '{}'; expected file, dir, symlink, other or error", value))),
+    }
+}
+
+fn now() -> i64 {
+    SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).map(|now| now.as_secs() as i64).unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 2024-01-31T12:00:00Z
+    const NOW: i64 = 1_706_702_400;
+    const DAY: i64 = 24 * 60 * 60;
+
+    fn parse(expr: &str) -> Result<Filter, AppError> {
+        let mut parser = Parser { tokens: tokenize(expr)?, pos: 0, now: NOW };
+        let expr = parser.or()?;
+        assert_eq!(parser.pos, parser.tokens.len(), "tokens left over");
+        Ok(Filter { expr: Some(expr) })
+    }
+
+    fn error(expr: &str) -> String {
+        match parse(expr) {
+            Err(AppError::WithMessage(message)) => message,
+            other => panic!("'{}' parsed to {:?}", expr, other),
+        }
+    }
+
+    fn file(name: &str, size: i64) -> FsNode {
+        FsNode {
+            node_type: NodeType::File,
+            parent_path: "/srv".to_string(),
+            name: format!("/srv/{}", name),
+            size,
+            uid: 1000,
+            gid: 100,
+            permissions: 0o100644,
+            modified_date: NOW - DAY,
+            ..Default::default()
+        }
+    }
+
+    fn matches(expr: &str, fs_node: &FsNode) -> bool {
+        let users: NameTable = vec![((0, 1000), "alice".to_string())].into_iter().collect();
+        parse(expr).unwrap().matches(fs_node, &users, &NameTable::new())
+    }
+
+    #[test]
+    fn tokens() {
+        let tokens = tokenize("(a&&b)||!c != 'x y' \"q\\\"\" !~ ~ & >= <= in not").unwrap();
+        assert_eq!(tokens, vec![
+            Token::LParen, Token::Word("a".to_string()), Token::And, Token::Word("b".to_string()), Token::RParen,
+            Token::Or, Token::Not, Token::Word("c".to_string()), Token::Op(Op::Ne),
+            Token::Quoted("x y".to_string()), Token::Quoted("q\"".to_string()),
+            Token::Op(Op::NotGlob), Token::Op(Op::Glob), Token::Op(Op::HasBits),
+            Token::Op(Op::Ge), Token::Op(Op::Le), Token::Op(Op::In), Token::Not,
+        ]);
+        assert!(tokenize("name = 'open").is_err());
+        assert!(tokenize("a | b").is_err());
+    }
+
+    #[test]
+    fn and_binds_tighter_than_or() {
+        let fs_node = file("a", 10);
+        assert!(matches("name = a or name = b and size > 100", &fs_node));
+        assert!(!matches("(name = a or name = b) and size > 100", &fs_node));
+        assert!(matches("not name = b and name = a", &fs_node));
+        assert!(!matches("not (name = b or name = a)", &fs_node));
+    }
+
+    #[test]
+    fn adjacent_tests_are_and_ed() {
+        let fs_node = file("a", 10);
+        assert!(matches("type = file size < 1K user = alice", &fs_node));
+        assert!(!matches("type = file size > 1K", &fs_node));
+    }
+
+    #[test]
+    fn text_and_glob() {
+        let fs_node = file("song.mp3", 10);
+        assert!(matches("path ~ '/srv/*' name ~ '*.mp3'", &fs_node));
+        assert!(matches("name !~ '*.ogg' parent = /srv", &fs_node));
+        assert!(!matches("group = users", &fs_node));
+    }
+
+    #[test]
+    fn syntax_errors() {
+        assert!(error("size >").starts_with("expected a value"));
+        assert!(error("(size > 1").starts_with("missing ')'"));
+        assert!(error("colour = red").starts_with("unknown attribute 'colour'"));
+        assert!(error("name < a").contains("cannot be used"));
+        assert!(error("and").starts_with("expected an attribute"));
+        assert!(Filter::parse("name = a )").is_err());
+        assert!(Filter::parse("").unwrap().matches(&file("a", 0), &NameTable::new(), &NameTable::new()));
+    }
+
+    #[test]
+    fn size_units() {
+        assert_eq!(size("100").unwrap(), 100);
+        assert_eq!(size("1K").unwrap(), 1024);
+        assert_eq!(size("1KB").unwrap(), 1000);
+        assert_eq!(size("1.5G").unwrap(), 3 << 29);
+        assert_eq!(size("2TB").unwrap(), 2_000_000_000_000);
+        assert!(size("1X").is_err());
+        assert!(size("one").is_err());
+        assert!(matches("size in 10..1K", &file("a", 10)));
+        assert!(!matches("size in 11..1K", &file("a", 10)));
+    }
+
+    #[test]
+    fn size_overflow() {
+        assert!(error("size > 9999999999999T").contains("is not a size"));
+        assert!(error("size > 99999999999999999999.5T").contains("is not a size"));
+        assert!(error("size > 99999999999999999999").contains("is not a size"));
+        assert_eq!(size("8388607T").unwrap(), 8_388_607 << 40);
+        let fs_node = FsNode { inode: i64::MAX, ..file("a", 0) };
+        assert!(matches("inode = 9223372036854775807 inode <= 9223372036854775807", &fs_node));
+        assert!(!matches("inode > 9223372036854775807", &fs_node));
+    }
+
+    #[test]
+    fn time_spans() {
+        let day = time_span("2024-01-31", NOW).unwrap();
+        assert_eq!((day.start, day.last), (NOW - 12 * 60 * 60, NOW + 12 * 60 * 60 - 1));
+        let minute = time_span("2024-01-31T12:00", NOW).unwrap();
+        assert_eq!((minute.start, minute.last), (NOW, NOW + 59));
+        let second = time_span("@1706702400", NOW).unwrap();
+        assert_eq!((second.start, second.last), (NOW, NOW));
+        assert_eq!(time_span("-30d", NOW).unwrap().start, NOW - 30 * DAY);
+        assert_eq!(time_span("-5min", NOW).unwrap().start, NOW - 300);
+        assert!(time_span("-30y", NOW).is_err());
+        assert!(time_span("yesterday", NOW).is_err());
+
+        let fs_node = file("a", 0);
+        assert!(matches("mtime = 2024-01-30 mtime > -2d mtime < -1h", &fs_node));
+        assert!(matches("mtime in 2024-01-01..2024-01-30", &fs_node));
+        assert!(!matches("mtime in 2024-01-01..2024-01-29", &fs_node));
+    }
+
+    #[test]
+    fn time_overflow() {
+        assert!(error("mtime > -9999999999999999w").contains("is not a time"));
+        assert!(error("mtime > @99999999999999999999").contains("is not a time"));
+    }
+
+    #[test]
+    fn permissions() {
+        assert_eq!(mode_bits("644").unwrap(), 0o644);
+        assert_eq!(mode_bits("0o4755").unwrap(), 0o4755);
+        assert_eq!(mode_bits("rwsr-xr-t").unwrap(), 0o5755);
+        assert_eq!(mode_bits("o+w").unwrap(), 0o002);
+        assert_eq!(mode_bits("ug+x,o+r").unwrap(), 0o114);
+        assert_eq!(mode_bits("u+s").unwrap(), 0o4000);
+        assert!(mode_bits("17777").is_err());
+        assert!(mode_bits("rwxrwxrwz").is_err());
+
+        let fs_node = file("a", 0);
+        assert!(matches("perm = rw-r--r-- perm & 044", &fs_node));
+        assert!(!matches("perm & o+w", &fs_node));
+    }
+
+    #[test]
+    fn unknown_attributes_are_false() {
+        let fs_node = FsNode { unknown_attrs: fs_node::ATTR_ALL & !fs_node::ATTR_NODE_TYPE, ..file("a", 10) };
+        assert!(!matches("size = 10", &fs_node));
+        assert!(!matches("size != 10", &fs_node));
+        assert!(!matches("perm & 044", &fs_node));
+        assert!(!matches("user = alice", &fs_node));
+        assert!(matches("type = file name = a", &fs_node));
+    }
+}
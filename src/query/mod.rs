@@ -0,0 +1,80 @@
+pub mod filter;
+
+use crate::ConvertibleResult;
+use crate::db_models::fs_node::FsNode;
+use crate::db_models::indexing_run::RunRef;
+use crate::errorwrapper::ErrorWrapper;
+use crate::exporter::{self, ExportRecord};
+use crate::query::filter::Filter;
+use std::path::PathBuf;
+
+pub(crate) fn run(args: &clap::ArgMatches<'_>) -> ConvertibleResult<()> {
+    let db_ref = args.value_of("index").expect("path to database is required");
+    let expr = args.value_of("expression").unwrap_or("");
+
+    let filter = match Filter::parse(expr) {
+        Ok(filter) => filter,
+        Err(error) => {
+            log::error!("{}", error);
+            return Err(ErrorWrapper::AppError(error));
+        },
+    };
+    log::debug!("'{}': querying with {:?}", db_ref, filter);
+
+    let (conn, run) = RunRef::parse(db_ref).open_read_only()?;
+    let mut writer = exporter::writer(
+        args.value_of("format").unwrap_or("text"),
+        args.value_of("output"),
+        exporter::select_columns(Some(args.value_of("columns").unwrap_or("path")))?,
+        PathBuf::from("/"),
+    )?;
+    let (users, groups) = exporter::names_by_id(&conn, run.id)?;
+
+    let (mut count, mut matched) = (0, 0);
+    FsNode::for_each_in_run(&conn, &run, |fs_node| {
+        count += 1;
+        if !filter.matches(&fs_node, &users, &groups) {
+            return Ok(());
+        }
+        matched += 1;
+        writer.write(ExportRecord::new(fs_node, &users, &groups))
+    })?;
+    writer.finish()?;
+
+    log::debug!("'{}': {} of {} nodes matched", db_ref, matched, count);
+    Ok(())
+}
+
+pub(crate) fn cmdline<'a>() -> clap::App<'a, 'a> {
+    clap::App::new("query")
+        .about("Lists the nodes of an index that match a filter expression.")
+        .arg(clap::Arg::with_name("index")
+            .value_name("FILE[@RUN]")
+            .index(1)
+            .help("The index to query.")
+            .required(true))
+        .arg(clap::Arg::with_name("expression")
+            .value_name("EXPRESSION")
+            .index(2)
+            .help("The filter, e.g. 'size > 1G and uid = 0'. Without one, all nodes are listed."))
+        .arg(clap::Arg::with_name("columns")
+            .long("columns")
+            .short("c")
+            .value_name("COLUMNS")
+            .help("The columns to write, separated by commas, e.g. path,size,user,modified (default: path).\n\
+                   The columns are those of export."))
+        .arg(clap::Arg::with_name("format")
+            .long("format")
+            .short("f")
+            .value_name("FORMAT")
+            .possible_values(&["text", "csv", "jsonl"])
+            .default_value("text")
+            .help("Output format. text writes tab-separated values without a header, with backslashes, tabs,\n\
+                   newlines and other control characters escaped as \\\\, \\t, \\n, \\x{1b} etc."))
+        .arg(clap::Arg::with_name("output")
+            .long("output")
+            .short("o")
+            .value_name("FILE")
+            .help("Write to FILE instead of stdout."))
+        .after_help(filter::HELP)
+}
@@ -0,0 +1,320 @@
+use crate::apperror::AppError;
+use crate::db_models::fs_node;
+use crate::errorwrapper::ErrorWrapper;
+use rusqlite::{Connection, OpenFlags, Transaction};
+use std::env;
+use std::fs;
+use std::io;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::Path;
+use std::process;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// The version of the index database schema this program reads and writes.
+/// Stored in each database as `PRAGMA user_version`.
+///
+/// - 0: magnetar 0.1.1 and earlier.
+/// - 1: `fs_node.unstable`, `fs_node.device`, `fs_node.link_group` and the `mount` table.
+///   Drops the unused `user` and `sshkey` tables.
+/// - 2: `run_id` on `fs_node`, `mount`, `unix_user` and `unix_group`, to hold several indexing runs
+///   in one database. Existing rows become run 1.
+/// - 3: `indexing_run.delta` and `fs_node.removed`, to store a run as the difference to its parent run.
+/// - 4: the `merge_source` table and `source_id` on `fs_node`, `mount`, `unix_user` and `unix_group`, recording
+///   where the nodes, filesystems and name tables of a merged run came from.
+/// - 5: `fs_node.md5_checksum`, `fs_node.sha256_checksum` and `fs_node.unknown_attrs`, for imported manifests.
+/// - 6: the `run_signature` table, holding the signed digests of runs.
+/// - 7: the `run_link` table, chaining runs to the run indexed before them.
+pub const SCHEMA_VERSION: i64 = 7;
+
+/// Numbers the temporary copies made by [open_migrated_copy]() within this process.
+static COPY_COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+const UNKNOWN_ATTRS_COLUMN: &str = "INTEGER NOT NULL DEFAULT 0";
+
+/// `MIGRATIONS[n]` upgrades a database from version `n` to `n + 1`.
+const MIGRATIONS: [fn(&Transaction<'_>) -> rusqlite::Result<()>; SCHEMA_VERSION as usize] = [
+    migrate_0_to_1,
+    migrate_1_to_2,
+    migrate_2_to_3,
+    migrate_3_to_4,
+    migrate_4_to_5,
+    migrate_5_to_6,
+    migrate_6_to_7,
+];
+
+/// Creates the tables of a new index database, at the current schema version.
+pub fn create(conn: &Connection) -> rusqlite::Result<()> {
+    conn.execute_batch(
+        include_str!("create_tables.sql")
+    )?;
+    conn.pragma_update(None, "user_version", &SCHEMA_VERSION)
+}
+
+/// Opens an existing index database, upgrading it to the current schema version if needed.
+/// The upgrade is done in place. If the database can't be written to (e.g. it is archived
+/// on read-only storage), a temporary copy of it is upgraded and opened instead.
+pub fn open(db_path: &str) -> crate::ConvertibleResult<Connection> {
+    require_exists(db_path)?;
+    let mut conn = Connection::open(db_path)?;
+    let version = supported_version(db_path, &conn)?;
+
+    if version == SCHEMA_VERSION {
+        return Ok(conn);
+    }
+
+    log::info!("'{}': upgrading database from schema version {} to {}...", db_path, version, SCHEMA_VERSION);
+    match migrate(&mut conn, version) {
+        Ok(()) => Ok(conn),
+        Err(e) if is_write_failure(&e) => {
+            log::warn!("'{}': could not upgrade in place ({}); upgrading a temporary copy instead", db_path, e);
+            drop(conn);
+            open_migrated_copy(db_path, version)
+        },
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Opens an existing index database for reading only. Unlike [open](), this never writes to the database file:
+/// a database at an older schema version is upgraded in a temporary copy, which is opened instead.
+/// Use it for commands that only read indexes, so that archived indexes stay as they were.
+pub fn open_read_only(db_path: &str) -> crate::ConvertibleResult<Connection> {
+    require_exists(db_path)?;
+    let conn = Connection::open_with_flags(db_path, OpenFlags::SQLITE_OPEN_READ_ONLY | OpenFlags::SQLITE_OPEN_NO_MUTEX)?;
+    let version = supported_version(db_path, &conn)?;
+
+    if version == SCHEMA_VERSION {
+        return Ok(conn);
+    }
+
+    log::debug!("'{}': schema version {}; upgrading a temporary copy to {}...", db_path, version, SCHEMA_VERSION);
+    drop(conn);
+    open_migrated_copy(db_path, version)
+}
+
+/// Opens the index database at `db_path` like [open]() if it exists, or creates it.
+pub fn open_or_create(db_path: &str) -> crate::ConvertibleResult<Connection> {
+    if Path::new(db_path).exists() {
+        open(db_path)
+    } else {
+        let conn = Connection::open(db_path)?;
+        create(&conn)?;
+        Ok(conn)
+    }
+}
+
+fn version(conn: &Connection) -> rusqlite::Result<i64> {
+    conn.pragma_query_value(None, "user_version", |row| row.get(0))
+}
+
+fn require_exists(db_path: &str) -> crate::ConvertibleResult<()> {
+    if !Path::new(db_path).exists() {
+        let error = AppError::WithMessage(format!("database '{}' not found.", db_path));
+        log::error!("{}", error);
+        return Err(ErrorWrapper::AppError(error));
+    }
+    Ok(())
+}
+
+/// The schema version of the database, failing if it is newer than this program supports.
+fn supported_version(db_path: &str, conn: &Connection) -> crate::ConvertibleResult<i64> {
+    let version = version(conn)?;
+    if version > SCHEMA_VERSION {
+        let error = AppError::WithMessage(format!(
+            "database '{}' has schema version {}, but this version of {} only supports up to {}. please upgrade.",
+            db_path, version, crate::consts::PROGRAM_NAME, SCHEMA_VERSION
+        ));
+        log::error!("{}", error);
+        return Err(ErrorWrapper::AppError(error));
+    }
+    Ok(version)
+}
+
+/// Runs the migrations from `from_version` up to the current version in a single transaction,
+/// so that a failed upgrade leaves the database untouched.
+fn migrate(conn: &mut Connection, from_version: i64) -> rusqlite::Result<()> {
+    let transaction = conn.transaction()?;
+    for version in from_version..SCHEMA_VERSION {
+        log::debug!("migrating schema version {} to {}...", version, version + 1);
+        MIGRATIONS[version as usize](&transaction)?;
+    }
+    transaction.pragma_update(None, "user_version", &SCHEMA_VERSION)?;
+    transaction.commit()
+}
+
+fn is_write_failure(e: &rusqlite::Error) -> bool {
+    match e {
+        rusqlite::Error::SqliteFailure(error, _) => matches!(
+            error.code,
+            rusqlite::ErrorCode::ReadOnly | rusqlite::ErrorCode::CannotOpen | rusqlite::ErrorCode::PermissionDenied
+        ),
+        _ => false,
+    }
+}
+
+/// Copies the database to the temp directory and upgrades the copy. The copy is unlinked
+/// right away, and lives on only as long as the returned connection.
+fn open_migrated_copy(db_path: &str, version: i64) -> crate::ConvertibleResult<Connection> {
+    let file_name = Path::new(db_path).file_name()
+        .map_or_else(|| "index.db".to_string(), |name| name.to_string_lossy().to_string());
+    let mut original = fs::File::open(db_path)?;
+
+    // the temp directory may be shared: create_new fails on anything already there, e.g. a symlink
+    // planted to redirect the copy, or the copy of another database of the same name
+    let (copy_path, copy) = loop {
+        let n = COPY_COUNTER.fetch_add(1, Ordering::Relaxed);
+        let path = env::temp_dir().join(format!("{}-{}-{}-{}", crate::consts::PROGRAM_NAME, process::id(), n, file_name));
+        match fs::OpenOptions::new().write(true).create_new(true).mode(0o600).open(&path) {
+            Ok(copy) => break (path, copy),
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists => continue,
+            Err(e) => return Err(e.into()),
+        }
+    };
+
+    let result = copy_and_migrate(&mut original, copy, &copy_path, version);
+    fs::remove_file(&copy_path)?;
+    result
+}
+
+fn copy_and_migrate(original: &mut fs::File, mut copy: fs::File, copy_path: &Path, version: i64) -> crate::ConvertibleResult<Connection> {
+    io::copy(original, &mut copy)?;
+    drop(copy);
+    let mut conn = Connection::open(copy_path)?;
+    migrate(&mut conn, version)?;
+    Ok(conn)
+}
+
+/// Upgrades databases created by magnetar 0.1.1 and earlier.
+/// Databases created by development versions may already have some of the columns.
+/// Hard links weren't recorded before, so the migrated nodes are marked as not knowing them; otherwise every
+/// hard linked file would seem to have lost its links when compared with a new index. `unknown_attrs` is added
+/// here for that, ahead of [migrate_4_to_5](). Without a `mount` table, the devices are left unmatched.
+fn migrate_0_to_1(transaction: &Transaction<'_>) -> rusqlite::Result<()> {
+    add_column_if_missing(transaction, "fs_node", "unstable", "INTEGER NOT NULL DEFAULT 0")?;
+    add_column_if_missing(transaction, "fs_node", "device", "INTEGER NOT NULL DEFAULT 0")?;
+    if add_column_if_missing(transaction, "fs_node", "link_group", "INTEGER NOT NULL DEFAULT 0")? {
+        add_column_if_missing(transaction, "fs_node", "unknown_attrs", UNKNOWN_ATTRS_COLUMN)?;
+        transaction.execute(
+            "UPDATE \"fs_node\" SET \"unknown_attrs\" = \"unknown_attrs\" | ?1",
+            rusqlite::params![fs_node::ATTR_LINK_GROUP]
+        )?;
+    }
+    transaction.execute_batch(
+        "CREATE TABLE IF NOT EXISTS \"mount\" ( \
+            \"id\" INTEGER PRIMARY KEY AUTOINCREMENT, \
+            \"device\" INTEGER NOT NULL, \
+            \"mount_point\" TEXT NOT NULL, \
+            \"fs_type\" TEXT NOT NULL, \
+            \"source\" TEXT NOT NULL, \
+            \"options\" TEXT NOT NULL, \
+            \"super_options\" TEXT NOT NULL); \
+        DROP TABLE IF EXISTS \"user\"; \
+        DROP TABLE IF EXISTS \"sshkey\";"
+    )
+}
+
+fn migrate_1_to_2(transaction: &Transaction<'_>) -> rusqlite::Result<()> {
+    for table in ["fs_node", "mount", "unix_user", "unix_group"].iter() {
+        add_column_if_missing(transaction, table, "run_id", "INTEGER NOT NULL DEFAULT 1")?;
+    }
+    transaction.execute_batch(
+        "INSERT INTO \"indexing_run\" (\"id\") SELECT 1 WHERE NOT EXISTS (SELECT 1 FROM \"indexing_run\"); \
+        CREATE INDEX IF NOT EXISTS \"fs_node_run_id\" ON \"fs_node\" (\"run_id\");"
+    )
+}
+
+fn migrate_2_to_3(transaction: &Transaction<'_>) -> rusqlite::Result<()> {
+    add_column_if_missing(transaction, "indexing_run", "delta", "INTEGER NOT NULL DEFAULT 0")?;
+    add_column_if_missing(transaction, "fs_node", "removed", "INTEGER NOT NULL DEFAULT 0")?;
+    Ok(())
+}
+
+fn migrate_3_to_4(transaction: &Transaction<'_>) -> rusqlite::Result<()> {
+    for table in ["fs_node", "mount", "unix_user", "unix_group"].iter() {
+        add_column_if_missing(transaction, table, "source_id", "INTEGER NOT NULL DEFAULT 0")?;
+    }
+    transaction.execute_batch(
+        "CREATE TABLE IF NOT EXISTS \"merge_source\" ( \
+            \"id\" INTEGER PRIMARY KEY AUTOINCREMENT, \
+            \"run_id\" INTEGER NOT NULL, \
+            \"db_path\" TEXT NOT NULL, \
+            \"source_run_id\" INTEGER NOT NULL, \
+            \"host_id\" bigint, \
+            \"prefix\" TEXT NOT NULL);"
+    )
+}
+
+fn migrate_4_to_5(transaction: &Transaction<'_>) -> rusqlite::Result<()> {
+    add_column_if_missing(transaction, "fs_node", "md5_checksum", "TEXT NOT NULL DEFAULT ''")?;
+    add_column_if_missing(transaction, "fs_node", "sha256_checksum", "TEXT NOT NULL DEFAULT ''")?;
+    add_column_if_missing(transaction, "fs_node", "unknown_attrs", UNKNOWN_ATTRS_COLUMN)?;
+    Ok(())
+}
+
+fn migrate_5_to_6(transaction: &Transaction<'_>) -> rusqlite::Result<()> {
+    transaction.execute_batch(
+        "CREATE TABLE IF NOT EXISTS \"run_signature\" ( \
+            \"run_id\" INTEGER PRIMARY KEY, \
+            \"algorithm\" TEXT NOT NULL, \
+            \"digest\" TEXT NOT NULL, \
+            \"public_key\" TEXT NOT NULL, \
+            \"signature\" TEXT NOT NULL, \
+            \"signed_at\" INTEGER NOT NULL);"
+    )
+}
+
+fn migrate_6_to_7(transaction: &Transaction<'_>) -> rusqlite::Result<()> {
+    transaction.execute_batch(
+        "CREATE TABLE IF NOT EXISTS \"run_link\" ( \
+            \"run_id\" INTEGER PRIMARY KEY, \
+            \"digest\" TEXT NOT NULL, \
+            \"previous_db\" TEXT NOT NULL, \
+            \"previous_run_id\" INTEGER NOT NULL, \
+            \"previous_digest\" TEXT NOT NULL);"
+    )
+}
+
+/// Adds the column unless the table already has it. Returns whether it was added.
+fn add_column_if_missing(transaction: &Transaction<'_>, table: &str, column: &str, declaration: &str) -> rusqlite::Result<bool> {
+    let mut exists = false;
+    transaction.pragma(None, "table_info", &table, |row| {
+        let name: String = row.get("name")?;
+        exists |= name == column;
+        Ok(())
+    })?;
+
+    if !exists {
+        log::debug!("adding column '{}.{}'", table, column);
+        transaction.execute_batch(&format!("ALTER TABLE \"{}\" ADD COLUMN \"{}\" {}", table, column, declaration))?;
+    }
+    Ok(!exists)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn count_nodes(conn: &Connection) -> i64 {
+        conn.query_row("SELECT COUNT(*) FROM fs_node", rusqlite::NO_PARAMS, |row| row.get(0)).unwrap()
+    }
+
+    /// Databases of the same name, opened at once, are upgraded in copies of their own, and left as they were.
+    #[test]
+    fn migrated_copies_of_same_name() {
+        let dir = env::temp_dir().join(format!("magnetar-schema-{}", process::id()));
+        let fixture = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/v0.db");
+        let (a, b) = (dir.join("a/x.db"), dir.join("b/x.db"));
+        for path in [&a, &b].iter() {
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::copy(&fixture, path).unwrap();
+        }
+        Connection::open(&b).unwrap().execute("DELETE FROM fs_node WHERE name LIKE '%/a_link'", rusqlite::NO_PARAMS).unwrap();
+
+        let conn_a = open_read_only(a.to_str().unwrap()).unwrap();
+        let conn_b = open_read_only(b.to_str().unwrap()).unwrap();
+        assert_eq!((version(&conn_a).unwrap(), version(&conn_b).unwrap()), (SCHEMA_VERSION, SCHEMA_VERSION));
+        assert_eq!((count_nodes(&conn_a), count_nodes(&conn_b)), (4, 3));
+        assert_eq!(version(&Connection::open(&a).unwrap()).unwrap(), 0);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
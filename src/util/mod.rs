@@ -1 +1,4 @@
-pub mod unix_perms;
\ No newline at end of file
+pub mod mountinfo;
+pub mod quote;
+pub mod unix_names;
+pub mod unix_perms;
@@ -0,0 +1,134 @@
+use std::fs;
+use std::io;
+
+pub const MOUNTINFO_PATH: &str = "/proc/self/mountinfo";
+
+/// A single line of `/proc/self/mountinfo`. See proc(5).
+#[derive(Debug, Clone)]
+pub struct MountInfo {
+    /// The device number, as `st_dev` reports it for files on this mount.
+    pub device: u64,
+    pub mount_point: String,
+    pub fs_type: String,
+    pub source: String,
+    pub options: String,
+    pub super_options: String,
+}
+
+/// Reads the mounts visible to this process.
+pub fn read_mountinfo() -> io::Result<Vec<MountInfo>> {
+    let contents = fs::read_to_string(MOUNTINFO_PATH)?;
+    let mut mounts = Vec::new();
+
+    for line in contents.lines() {
+        match parse_line(line) {
+            Some(mount) => mounts.push(mount),
+            None => log::warn!("'{}': skipping malformed line: '{}'", MOUNTINFO_PATH, line),
+        }
+    }
+
+    Ok(mounts)
+}
+
+/// Format: `id parent major:minor root mount-point options [optional fields...] - fstype source super-options`
+fn parse_line(line: &str) -> Option<MountInfo> {
+    let fields: Vec<&str> = line.split(' ').collect();
+    let separator = fields.iter().position(|field| *field == "-")?;
+    if separator < 6 || fields.len() < separator + 4 {
+        return None;
+    }
+
+    let mut dev_numbers = fields[2].split(':');
+    let major = dev_numbers.next()?.parse::<u64>().ok()?;
+    let minor = dev_numbers.next()?.parse::<u64>().ok()?;
+
+    Some(MountInfo {
+        device: makedev(major, minor),
+        mount_point: unescape(fields[4]),
+        fs_type: unescape(fields[separator + 1]),
+        source: unescape(fields[separator + 2]),
+        options: fields[5].to_string(),
+        super_options: fields[separator + 3].to_string(),
+    })
+}
+
+/// Same encoding as glibc's `makedev`.
+fn makedev(major: u64, minor: u64) -> u64 {
+    ((major & 0xffff_f000) << 32)
+        | ((major & 0x0000_0fff) << 8)
+        | ((minor & 0xffff_ff00) << 12)
+        | (minor & 0x0000_00ff)
+}
+
+/// Whitespace and backslashes in mountinfo paths are escaped as octal, e.g. `\040` for space.
+fn unescape(field: &str) -> String {
+    let bytes = field.as_bytes();
+    let mut unescaped = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+
+    while i < bytes.len() {
+        let is_octal_escape = bytes[i] == b'\\'
+            && i + 4 <= bytes.len()
+            && bytes[i + 1..i + 4].iter().all(|b| (b'0'..=b'7').contains(b));
+        if is_octal_escape {
+            let value = bytes[i + 1..i + 4].iter().fold(0u32, |acc, b| acc * 8 + u32::from(b - b'0'));
+            unescaped.push(value as u8);
+            i += 4;
+            continue;
+        }
+        unescaped.push(bytes[i]);
+        i += 1;
+    }
+
+    String::from_utf8_lossy(&unescaped).to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn optional_fields() {
+        let mount = parse_line("36 35 98:0 /mnt1 /mnt/parent rw,noatime master:1 shared:2 - ext3 /dev/root rw,errors=continue").unwrap();
+        assert_eq!(mount.device, makedev(98, 0));
+        assert_eq!(mount.mount_point, "/mnt/parent");
+        assert_eq!(mount.options, "rw,noatime");
+        assert_eq!(mount.fs_type, "ext3");
+        assert_eq!(mount.source, "/dev/root");
+        assert_eq!(mount.super_options, "rw,errors=continue");
+
+        let mount = parse_line("22 1 8:1 / / rw,relatime - ext4 /dev/sda1 rw").unwrap();
+        assert_eq!((mount.device, mount.mount_point.as_str(), mount.fs_type.as_str()), (2049, "/", "ext4"));
+    }
+
+    #[test]
+    fn escaped_paths() {
+        let mount = parse_line("40 22 0:45 / /media/my\\040disk\\011x\\134y rw shared:5 - fuse.sshfs me@host:/a\\040b rw").unwrap();
+        assert_eq!(mount.mount_point, "/media/my disk\tx\\y");
+        assert_eq!(mount.fs_type, "fuse.sshfs");
+        assert_eq!(mount.source, "me@host:/a b");
+        // not an escape: too short, or not octal
+        assert_eq!(unescape("/a\\04"), "/a\\04");
+        assert_eq!(unescape("/a\\089"), "/a\\089");
+    }
+
+    #[test]
+    fn separator() {
+        // a '-' among the optional fields can only be the separator, so the fields after it are taken
+        let mount = parse_line("50 22 0:50 / /srv rw - tmpfs - rw,size=10m").unwrap();
+        assert_eq!((mount.fs_type.as_str(), mount.source.as_str()), ("tmpfs", "-"));
+        assert!(parse_line("22 1 8:1 / / rw,relatime ext4 /dev/sda1 rw").is_none());
+        assert!(parse_line("22 1 8:1 / / rw,relatime - ext4 /dev/sda1").is_none());
+        assert!(parse_line("22 1 8:1 / - ext4 /dev/sda1 rw").is_none());
+        assert!(parse_line("22 1 8 / / rw - ext4 /dev/sda1 rw").is_none());
+        assert!(parse_line("").is_none());
+    }
+
+    #[test]
+    fn device_numbers() {
+        assert_eq!(makedev(8, 1), 0x801);
+        assert_eq!(makedev(259, 1), 0x10301);
+        assert_eq!(makedev(8, 256), 0x100800);
+        assert_eq!(makedev(0x1000, 0), 1 << 44);
+    }
+}
@@ -0,0 +1,51 @@
+use std::fmt::Write;
+
+/// A JSON string literal.
+pub fn json(text: &str) -> String {
+    let mut quoted = String::with_capacity(text.len() + 2);
+    quoted.push('"');
+    for c in text.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            '\r' => quoted.push_str("\\r"),
+            '\t' => quoted.push_str("\\t"),
+            c if (c as u32) < 0x20 => { let _ = write!(quoted, "\\u{:04x}", c as u32); },
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+/// A word for `sh`, single-quoted.
+pub fn shell(text: &str) -> String {
+    format!("'{}'", text.replace('\'', "'\\''"))
+}
+
+/// The text with backslashes and control characters escaped (`\n`, `\x{1b}`, ...), to go on a single line
+/// where nothing is interpreted, e.g. in a shell comment.
+pub fn visible(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '\\' => escaped.push_str("\\\\"),
+            '\n' => escaped.push_str("\\n"),
+            '\r' => escaped.push_str("\\r"),
+            '\t' => escaped.push_str("\\t"),
+            c if c.is_control() => { let _ = write!(escaped, "\\x{{{:x}}}", c as u32); },
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// A CSV field as in RFC 4180, quoted only if needed.
+pub fn csv(text: &str) -> String {
+    if text.contains(&[',', '"', '\r', '\n'][..]) {
+        format!("\"{}\"", text.replace('"', "\"\""))
+    } else {
+        text.to_string()
+    }
+}
@@ -0,0 +1,31 @@
+use std::fs;
+use std::io;
+
+pub const PASSWD_PATH: &str = "/etc/passwd";
+pub const GROUP_PATH: &str = "/etc/group";
+
+/// Reads the (name, id) pairs from a file in `/etc/passwd` or `/etc/group` format.
+/// Both formats have the name in the first field and the numeric id in the third.
+/// Only the local files are read; users known only through NSS (LDAP etc.) are not included.
+pub fn read_name_table(path: &str) -> io::Result<Vec<(String, u32)>> {
+    let contents = fs::read_to_string(path)?;
+    let mut entries = Vec::new();
+
+    for line in contents.lines() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let fields: Vec<&str> = line.split(':').collect();
+        if fields.len() < 3 {
+            log::warn!("'{}': skipping malformed line: '{}'", path, line);
+            continue;
+        }
+        match fields[2].parse::<u32>() {
+            Ok(id) => entries.push((fields[0].to_string(), id)),
+            Err(e) => log::warn!("'{}': skipping line with invalid id '{}': {}", path, fields[2], e),
+        }
+    }
+
+    Ok(entries)
+}
@@ -1,4 +1,5 @@
 
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
 pub enum Permission {
     Socket,           // socket
     Symlink,          // symbolic link
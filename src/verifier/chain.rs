@@ -0,0 +1,208 @@
+use crate::ConvertibleResult;
+use crate::db_models::indexing_run::{IndexingRun, RunRef};
+use crate::db_models::run_link::RunLink;
+use crate::schema;
+use crate::verifier::{self, app_error, digest, Verification};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fs;
+use std::path::Path;
+
+/// A run of one of the databases of the directory being checked, `FILE@RUN` with `FILE` the file name.
+type RunKey = (String, i64);
+
+/// The run a new run is chained to, checked to be intact when it was resolved.
+#[derive(Debug, Clone)]
+pub struct Previous {
+    /// The file name of its database.
+    pub db_name: String,
+    pub run_id: i64,
+    pub digest: String,
+}
+
+/// What is known about a run of the directory being checked.
+#[derive(Debug)]
+struct ChainRun {
+    /// Its digest as computed now.
+    digest: String,
+    link: Option<RunLink>,
+    verification: Verification,
+}
+
+/// Resolves the run referenced by `db_ref`, failing if it was modified since it was chained or signed,
+/// since chaining a new run to it would make the modification look legitimate.
+pub fn resolve_previous(db_ref: &str) -> ConvertibleResult<Previous> {
+    let run_ref = RunRef::parse(db_ref);
+    let (conn, run) = run_ref.open_read_only()?;
+
+    let digest = hex::encode(digest::run_digest(&conn, &run)?);
+    if let Some(link) = RunLink::select_one(&conn, run.id)? {
+        if link.digest != digest {
+            return Err(app_error(format!("'{}': run {} was modified since it was chained; not chaining to it", db_ref, run.id)));
+        }
+    }
+    if let Verification::Invalid(reason) = verifier::verify_run(&conn, &run, None)? {
+        return Err(app_error(format!("'{}': run {} failed verification: {}; not chaining to it", db_ref, run.id, reason)));
+    }
+
+    Ok(Previous { db_name: file_name(&run_ref.db_path), run_id: run.id, digest })
+}
+
+/// Links the run to the previous run, so that its digest covers the digest of the previous run.
+pub fn link_run(transaction: &rusqlite::Transaction<'_>, run: &IndexingRun, previous: &Previous) -> ConvertibleResult<()> {
+    let mut link = RunLink {
+        run_id: run.id,
+        digest: String::new(),
+        previous_db: previous.db_name.clone(),
+        previous_run_id: previous.run_id,
+        previous_digest: previous.digest.clone(),
+    };
+    link.digest = hex::encode(digest::linked_run_digest(transaction, run, Some(&link))?);
+    log::debug!("INSERT {:?}", link);
+    link.insert(transaction)?;
+
+    log::info!("run {}: chained to {}@{}", run.id, previous.db_name, previous.run_id);
+    Ok(())
+}
+
+pub(crate) fn run(args: &clap::ArgMatches<'_>) -> ConvertibleResult<()> {
+    let dir = args.value_of("directory").expect("directory is required");
+    let trusted_key = match args.value_of("public-key") {
+        Some(path) => Some(verifier::read_verifying_key(path)?),
+        None => None,
+    };
+
+    let runs = read_runs(Path::new(dir), trusted_key.as_ref())?;
+    let mut problems = Vec::new();
+
+    // the runs chained to each run
+    let mut successors: HashMap<&RunKey, Vec<&RunKey>> = HashMap::new();
+    for (key, chain_run) in runs.iter() {
+        let link = match &chain_run.link {
+            Some(link) => link,
+            None => continue,
+        };
+        if link.digest != chain_run.digest {
+            problems.push(format!("break: {} was modified after it was chained", display(key)));
+        }
+
+        let previous_key = (link.previous_db.clone(), link.previous_run_id);
+        match runs.get_key_value(&previous_key) {
+            None => problems.push(format!("gap: {} follows {}, which is missing", display(key), display(&previous_key))),
+            Some((previous_key, previous)) => {
+                if previous.digest != link.previous_digest {
+                    problems.push(format!(
+                        "break: {} is not the run {} was chained to; it was modified or replaced", display(previous_key), display(key)
+                    ));
+                }
+                successors.entry(previous_key).or_default().push(key);
+            },
+        }
+    }
+
+    for (previous_key, next) in successors.iter() {
+        if next.len() > 1 {
+            let next: Vec<String> = next.iter().map(|key| display(key)).collect();
+            problems.push(format!("fork: {} is followed by each of {}", display(previous_key), next.join(", ")));
+        }
+    }
+
+    let in_chain = |key: &RunKey| runs[key].link.is_some() || successors.contains_key(key);
+    for (key, chain_run) in runs.iter() {
+        if !in_chain(key) {
+            log::debug!("{}: not chained", display(key));
+            continue;
+        }
+        match &chain_run.verification {
+            Verification::Valid(_) => {},
+            Verification::Unsigned if trusted_key.is_none() => {},
+            Verification::Unsigned => problems.push(format!("unsigned: {} is not signed", display(key))),
+            Verification::Invalid(reason) => problems.push(format!("break: {}: {}", display(key), reason)),
+        }
+    }
+
+    // walk each chain back from its last run
+    for (key, _) in runs.iter().filter(|(key, _)| in_chain(key) && !successors.contains_key(key)) {
+        let mut length = 1;
+        let mut first = key;
+        let mut visited = HashSet::new();
+        while let Some(link) = &runs[first].link {
+            match runs.get_key_value(&(link.previous_db.clone(), link.previous_run_id)) {
+                Some((previous_key, _)) if visited.insert(previous_key) => {
+                    first = previous_key;
+                    length += 1;
+                },
+                _ => break,
+            }
+        }
+        log::info!("chain of {} runs: {} .. {}", length, display(first), display(key));
+    }
+
+    for problem in problems.iter() {
+        log::error!("{}", problem);
+    }
+    if !problems.is_empty() {
+        return Err(app_error(format!("'{}': {} problems in the chain of runs", dir, problems.len())));
+    }
+    log::info!("'{}': chain OK", dir);
+    Ok(())
+}
+
+/// Reads and verifies every run of the index databases (`*.db`) in the directory.
+fn read_runs(dir: &Path, trusted_key: Option<&ed25519_dalek::VerifyingKey>) -> ConvertibleResult<BTreeMap<RunKey, ChainRun>> {
+    let mut paths: Vec<_> = fs::read_dir(dir)?
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(|path| path.is_file() && path.extension().is_some_and(|extension| extension == "db"))
+        .collect();
+    paths.sort();
+
+    let mut runs = BTreeMap::new();
+    for path in paths {
+        let db_path = path.to_string_lossy();
+        let conn = match schema::open_read_only(&db_path) {
+            Ok(conn) => conn,
+            Err(e) => {
+                log::warn!("'{}': not an index database, skipping: {}", db_path, e);
+                continue;
+            },
+        };
+        for run in IndexingRun::select(&conn)? {
+            let run_digest = digest::run_digest(&conn, &run)?;
+            let chain_run = ChainRun {
+                digest: hex::encode(run_digest),
+                link: RunLink::select_one(&conn, run.id)?,
+                verification: verifier::verify_run_digest(&conn, &run, &run_digest, trusted_key)?,
+            };
+            runs.insert((file_name(&db_path), run.id), chain_run);
+        }
+    }
+
+    log::debug!("'{}': read {} runs", dir.display(), runs.len());
+    Ok(runs)
+}
+
+fn file_name(db_path: &str) -> String {
+    Path::new(db_path).file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_else(|| db_path.to_string())
+}
+
+fn display(key: &RunKey) -> String {
+    format!("{}@{}", key.0, key.1)
+}
+
+pub(crate) fn cmdline<'a>() -> clap::App<'a, 'a> {
+    clap::App::new("verify-chain")
+        .about("Checks the chain of runs of the index databases in a directory (see idx --previous),\n\
+                reporting runs that are missing (gaps), modified or replaced (breaks), or chained to twice (forks).")
+        .arg(clap::Arg::with_name("directory")
+            .value_name("DIRECTORY")
+            .index(1)
+            .help("The directory holding the index databases (*.db) of the chain.")
+            .required(true))
+        .arg(clap::Arg::with_name("public-key")
+            .long("public-key")
+            .short("k")
+            .value_name("FILE")
+            .help("The Ed25519 public key (PEM) every run of the chain must be signed with. Without it,\n\
+                   a chain rewritten from the modified run onwards cannot be told from the original."))
+}
@@ -0,0 +1,209 @@
+use crate::ConvertibleResult;
+use crate::db_models::fs_node::FsNode;
+use crate::db_models::host::Host;
+use crate::db_models::indexing_run::IndexingRun;
+use crate::db_models::merge_source::MergeSource;
+use crate::db_models::mount::Mount;
+use crate::db_models::run_link::RunLink;
+use crate::db_models::unix_group::UnixGroup;
+use crate::db_models::unix_user::UnixUser;
+use sha2::{Digest, Sha256};
+
+/// Identifies the encoding below; change it if the encoding changes.
+const DIGEST_VERSION: &str = "magnetar run digest v1";
+
+/// The encoding of runs chained to a previous run, which adds the link after the run metadata.
+const DIGEST_VERSION_CHAINED: &str = "magnetar run digest v2";
+
+/// The encoding of merged runs, which adds whether the run is chained, the link if it is,
+/// and then the merge sources after the run metadata.
+const DIGEST_VERSION_MERGED: &str = "magnetar run digest v3";
+
+/// The sha256 digest of the contents of a run: its metadata, the user and group name tables,
+/// the mounts and the nodes, each in a fixed order and with every field length-prefixed.
+///
+/// The nodes are taken as reconstructed (see [FsNode::select_run]()), without row ids, so the digest
+/// does not change when the run is compacted, but does when a run it is reconstructed from is modified.
+/// Whether the run is stored as a delta is left out for the same reason.
+/// If the run is chained to a previous run (see [RunLink]()), the link is covered as well,
+/// and if the run was merged, the indexes it was merged from (see [MergeSource]()), by source id.
+pub fn run_digest(conn: &rusqlite::Connection, run: &IndexingRun) -> ConvertibleResult<[u8; 32]> {
+    let link = RunLink::select_one(conn, run.id)?;
+    linked_run_digest(conn, run, link.as_ref())
+}
+
+/// [run_digest]() with the given link instead of the one stored with the run, for runs being linked.
+pub fn linked_run_digest(conn: &rusqlite::Connection, run: &IndexingRun, link: Option<&RunLink>) -> ConvertibleResult<[u8; 32]> {
+    let sources = MergeSource::select(conn, run.id)?;
+    let mut hasher = Canonical(Sha256::new());
+    hasher.text(if !sources.is_empty() {
+        DIGEST_VERSION_MERGED
+    } else if link.is_some() {
+        DIGEST_VERSION_CHAINED
+    } else {
+        DIGEST_VERSION
+    });
+
+    hasher.number(run.id);
+    hasher.optional_number(run.timestamp);
+    hasher.optional_text(host_name(conn, run.host_id)?.as_deref());
+    hasher.optional_number(run.parent_run_id);
+    if !sources.is_empty() {
+        hasher.number(link.is_some() as i64);
+    }
+    if let Some(link) = link {
+        hasher.text(&link.previous_db);
+        hasher.number(link.previous_run_id);
+        hasher.text(&link.previous_digest);
+    }
+    if !sources.is_empty() {
+        hasher.number(sources.len() as i64);
+        for source in sources.iter() {
+            hasher.number(source.id);
+            hasher.text(&source.db_path);
+            hasher.number(source.source_run_id);
+            hasher.optional_text(host_name(conn, source.host_id)?.as_deref());
+            hasher.text(&source.prefix);
+        }
+    }
+
+    let mut users = UnixUser::select(conn, run.id)?;
+    users.sort_by(|a, b| (a.source_id, a.uid, &a.name).cmp(&(b.source_id, b.uid, &b.name)));
+    hasher.number(users.len() as i64);
+    for user in users.iter() {
+        hasher.number(user.source_id);
+        hasher.text(&user.name);
+        hasher.number(user.uid as i64);
+    }
+
+    let mut groups = UnixGroup::select(conn, run.id)?;
+    groups.sort_by(|a, b| (a.source_id, a.gid, &a.name).cmp(&(b.source_id, b.gid, &b.name)));
+    hasher.number(groups.len() as i64);
+    for group in groups.iter() {
+        hasher.number(group.source_id);
+        hasher.text(&group.name);
+        hasher.number(group.gid as i64);
+    }
+
+    let mut mounts = Mount::select(conn, run.id)?;
+    mounts.sort_by(|a, b| (a.source_id, a.device, &a.mount_point).cmp(&(b.source_id, b.device, &b.mount_point)));
+    hasher.number(mounts.len() as i64);
+    for mount in mounts.iter() {
+        hasher.number(mount.source_id);
+        hasher.number(mount.device);
+        hasher.text(&mount.mount_point);
+        hasher.text(&mount.fs_type);
+        hasher.text(&mount.source);
+        hasher.text(&mount.options);
+        hasher.text(&mount.super_options);
+    }
+
+    let mut fs_nodes = FsNode::select_run(conn, run)?;
+    fs_nodes.sort_by(|a, b| a.name.cmp(&b.name));
+    hasher.number(fs_nodes.len() as i64);
+    for fs_node in fs_nodes.iter() {
+        hasher.number(fs_node.node_type.value() as i64);
+        hasher.text(&fs_node.sha1_checksum);
+        hasher.text(&fs_node.parent_path);
+        hasher.text(&fs_node.name);
+        hasher.number(fs_node.size);
+        hasher.number(fs_node.uid as i64);
+        hasher.number(fs_node.gid as i64);
+        hasher.number(fs_node.permissions as i64);
+        hasher.number(fs_node.creation_date);
+        hasher.number(fs_node.modified_date);
+        hasher.text(&fs_node.links_to);
+        hasher.number(fs_node.inode);
+        hasher.number(fs_node.nlinks);
+        hasher.number(fs_node.unstable as i64);
+        hasher.number(fs_node.device);
+        hasher.number(fs_node.link_group);
+        hasher.number(fs_node.source_id);
+        hasher.text(&fs_node.md5_checksum);
+        hasher.text(&fs_node.sha256_checksum);
+        hasher.number(fs_node.unknown_attrs as i64);
+    }
+    log::debug!("run {}: digest over {} nodes, {} users, {} groups and {} mounts",
+        run.id, fs_nodes.len(), users.len(), groups.len(), mounts.len());
+
+    Ok(hasher.0.finalize().into())
+}
+
+fn host_name(conn: &rusqlite::Connection, host_id: Option<i64>) -> ConvertibleResult<Option<String>> {
+    match host_id {
+        Some(host_id) => Ok(Host::select_one(conn, host_id)?.map(|host| host.display_name)),
+        None => Ok(None),
+    }
+}
+
+/// Feeds fields to the hasher so that no two different sequences of fields hash the same.
+struct Canonical(Sha256);
+
+impl Canonical {
+
+    fn number(&mut self, value: i64) {
+        self.0.update(value.to_be_bytes());
+    }
+
+    fn text(&mut self, value: &str) {
+        self.number(value.len() as i64);
+        self.0.update(value.as_bytes());
+    }
+
+    fn optional_number(&mut self, value: Option<i64>) {
+        self.number(value.is_some() as i64);
+        self.number(value.unwrap_or_default());
+    }
+
+    fn optional_text(&mut self, value: Option<&str>) {
+        self.number(value.is_some() as i64);
+        self.text(value.unwrap_or_default());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A run merged from the indexes of two hosts, in an in-memory database.
+    fn merged_run() -> (rusqlite::Connection, IndexingRun) {
+        let mut conn = rusqlite::Connection::open_in_memory().unwrap();
+        crate::schema::create(&conn).unwrap();
+        let run = IndexingRun { id: 1, timestamp: Some(1_600_000_000), ..Default::default() };
+        let transaction = conn.transaction().unwrap();
+        run.insert(&transaction).unwrap();
+        for host in ["web1", "web2"].iter() {
+            MergeSource {
+                run_id: run.id,
+                db_path: format!("{}.db", host),
+                source_run_id: 3,
+                host_id: Some(Host::select_or_insert(&transaction, host).unwrap()),
+                prefix: host.to_string(),
+                ..Default::default()
+            }.insert(&transaction).unwrap();
+        }
+        transaction.commit().unwrap();
+        (conn, run)
+    }
+
+    /// Where a merged run came from is part of its contents: changing a source changes the digest.
+    #[test]
+    fn covers_merge_sources() {
+        let (conn, run) = merged_run();
+        let digest = run_digest(&conn, &run).unwrap();
+        assert_eq!(run_digest(&conn, &run).unwrap(), digest);
+
+        for change in [
+            "UPDATE merge_source SET db_path = 'web3.db' WHERE id = 2",
+            "UPDATE merge_source SET source_run_id = 4 WHERE id = 2",
+            "UPDATE merge_source SET prefix = 'web3' WHERE id = 2",
+            "UPDATE merge_source SET host_id = 1 WHERE id = 2",
+            "UPDATE merge_source SET id = 3 WHERE id = 2",
+            "DELETE FROM merge_source WHERE id = 2",
+        ].iter() {
+            let (conn, run) = merged_run();
+            conn.execute(change, rusqlite::NO_PARAMS).unwrap();
+            assert_ne!(run_digest(&conn, &run).unwrap(), digest, "{}", change);
+        }
+    }
+}
@@ -0,0 +1,250 @@
+pub mod chain;
+pub mod digest;
+
+use crate::ConvertibleResult;
+use crate::db_models::indexing_run::{IndexingRun, RunRef};
+use crate::db_models::run_signature::RunSignature;
+use crate::errorwrapper::app_error;
+use ed25519_dalek::pkcs8::{DecodePrivateKey, DecodePublicKey};
+use ed25519_dalek::{Signature, Signer, SigningKey, VerifyingKey};
+use std::convert::TryInto;
+use std::fs;
+use std::time::SystemTime;
+
+const ALGORITHM: &str = "ed25519";
+
+/// The outcome of checking a run against its signature.
+#[derive(Debug)]
+pub enum Verification {
+    /// The run is as it was when signed.
+    Valid(RunSignature),
+
+    Unsigned,
+
+    /// The run, or a run it is reconstructed from, was modified after it was signed,
+    /// or the signature is not from the expected key.
+    Invalid(String),
+}
+
+/// What to do with a run once it is indexed, see [seal_run]().
+#[derive(Debug, Default)]
+pub struct Seal {
+    /// Sign the run with this key.
+    pub sign_key: Option<SigningKey>,
+
+    /// Chain the run to this run.
+    pub previous: Option<chain::Previous>,
+}
+
+pub(crate) fn run(args: &clap::ArgMatches<'_>) -> ConvertibleResult<()> {
+    let db_ref = args.value_of("index").expect("path to database is required");
+    let run_ref = RunRef::parse(db_ref);
+
+    let trusted_key = match args.value_of("public-key") {
+        Some(path) => Some(read_verifying_key(path)?),
+        None => {
+            log::warn!("no --public-key given: runs are checked against the keys stored with them, \
+                        which shows they are intact, but not who signed them");
+            None
+        },
+    };
+
+    let (conn, runs) = match run_ref.run_id {
+        Some(_) => {
+            let (conn, run) = run_ref.open_read_only()?;
+            (conn, vec![run])
+        },
+        None => {
+            let conn = crate::schema::open_read_only(&run_ref.db_path)?;
+            let runs = IndexingRun::select(&conn)?;
+            (conn, runs)
+        },
+    };
+
+    let mut failed = 0;
+    for run in runs.iter() {
+        match verify_run(&conn, run, trusted_key.as_ref())? {
+            Verification::Valid(signature) => {
+                log::info!("run {}: OK, signed by key {}", run.id, signature.public_key);
+            },
+            Verification::Unsigned => {
+                log::error!("run {}: not signed", run.id);
+                failed += 1;
+            },
+            Verification::Invalid(reason) => {
+                log::error!("run {}: FAILED: {}", run.id, reason);
+                failed += 1;
+            },
+        }
+    }
+
+    if failed > 0 {
+        return Err(app_error(format!("'{}': {} of {} runs failed verification", db_ref, failed, runs.len())));
+    }
+    log::info!("'{}': {} runs verified", db_ref, runs.len());
+    Ok(())
+}
+
+/// The `--sign-key` and `--previous` options of the commands that write runs: idx, import and merge.
+pub(crate) fn seal_args<'a>() -> [clap::Arg<'a, 'a>; 2] {
+    [
+        clap::Arg::with_name("sign-key")
+            .long("sign-key")
+            .value_name("FILE")
+            .help("Sign the run with the Ed25519 private key in FILE (PEM, e.g. made with\n\
+                   'openssl genpkey -algorithm ed25519'), so that changes to it can be detected with verify-index."),
+        clap::Arg::with_name("previous")
+            .long("previous")
+            .value_name("FILE[@RUN]")
+            .help("Chain this run to the run FILE[@RUN] (default: its latest run), which may be in this catalog: \
+                   the digest of this run then covers the digest of that run. Check chains with verify-chain."),
+    ]
+}
+
+/// The [Seal]() asked for by the options of [seal_args]().
+pub(crate) fn seal_from_args(args: &clap::ArgMatches<'_>) -> ConvertibleResult<Seal> {
+    Ok(Seal {
+        sign_key: match args.value_of("sign-key") {
+            Some(path) => Some(read_signing_key(path)?),
+            None => None,
+        },
+        previous: match args.value_of("previous") {
+            Some(db_ref) => Some(chain::resolve_previous(db_ref)?),
+            None => None,
+        },
+    })
+}
+
+/// Chains the run to the previous run and signs it, as far as asked for by `seal`.
+/// The signature covers the link to the previous run.
+pub fn seal_run(transaction: &rusqlite::Transaction<'_>, run: &IndexingRun, seal: &Seal) -> ConvertibleResult<()> {
+    if let Some(previous) = &seal.previous {
+        chain::link_run(transaction, run, previous)?;
+    }
+    if let Some(sign_key) = &seal.sign_key {
+        let signature = sign_run(transaction, run, sign_key)?;
+        log::info!("run {}: signed with key {}", run.id, signature.public_key);
+    }
+    Ok(())
+}
+
+/// Signs the contents of the run (see [digest::run_digest]()) and stores the signature with it.
+pub fn sign_run(transaction: &rusqlite::Transaction<'_>, run: &IndexingRun, key: &SigningKey) -> ConvertibleResult<RunSignature> {
+    let digest = digest::run_digest(transaction, run)?;
+    let signature = RunSignature {
+        run_id: run.id,
+        algorithm: ALGORITHM.to_string(),
+        digest: hex::encode(digest),
+        public_key: hex::encode(key.verifying_key().to_bytes()),
+        signature: hex::encode(key.sign(&digest).to_bytes()),
+        signed_at: SystemTime::now().duration_since(SystemTime::UNIX_EPOCH)?.as_secs() as i64,
+    };
+    log::debug!("INSERT {:?}", signature);
+    signature.insert(transaction)?;
+    Ok(signature)
+}
+
+/// Checks the run against its stored signature. With `trusted_key`, the run must also have been signed with that key.
+pub fn verify_run(conn: &rusqlite::Connection, run: &IndexingRun, trusted_key: Option<&VerifyingKey>) -> ConvertibleResult<Verification> {
+    verify(conn, run, trusted_key, || digest::run_digest(conn, run))
+}
+
+/// [verify_run]() with the digest of the run already computed by [digest::run_digest]().
+pub fn verify_run_digest(
+    conn: &rusqlite::Connection,
+    run: &IndexingRun,
+    run_digest: &[u8; 32],
+    trusted_key: Option<&VerifyingKey>
+) -> ConvertibleResult<Verification> {
+    verify(conn, run, trusted_key, || Ok(*run_digest))
+}
+
+/// The digest of the run is only computed if the signature itself is valid.
+fn verify(
+    conn: &rusqlite::Connection,
+    run: &IndexingRun,
+    trusted_key: Option<&VerifyingKey>,
+    run_digest: impl FnOnce() -> ConvertibleResult<[u8; 32]>
+) -> ConvertibleResult<Verification> {
+    let signature = match RunSignature::select_one(conn, run.id)? {
+        Some(signature) => signature,
+        None => return Ok(Verification::Unsigned),
+    };
+    if signature.algorithm != ALGORITHM {
+        return Ok(Verification::Invalid(format!("unsupported signature algorithm '{}'", signature.algorithm)));
+    }
+
+    let public_key = match decode_key(&signature.public_key) {
+        Some(public_key) => public_key,
+        None => return Ok(Verification::Invalid(format!("malformed public key '{}'", signature.public_key))),
+    };
+    if trusted_key.is_some_and(|trusted_key| *trusted_key != public_key) {
+        return Ok(Verification::Invalid(format!("signed by key {}, not by the given public key", signature.public_key)));
+    }
+
+    let valid = hex::decode(&signature.signature).ok()
+        .and_then(|bytes| Signature::from_slice(&bytes).ok())
+        .zip(hex::decode(&signature.digest).ok())
+        .is_some_and(|(sig, digest)| public_key.verify_strict(&digest, &sig).is_ok());
+    if !valid {
+        return Ok(Verification::Invalid("the signature does not match the signed digest".to_string()));
+    }
+
+    let digest = hex::encode(run_digest()?);
+    if digest != signature.digest {
+        return Ok(Verification::Invalid(format!(
+            "contents do not match the signed digest (signed {}, now {}); the run or a run it builds on was modified",
+            signature.digest, digest
+        )));
+    }
+
+    Ok(Verification::Valid(signature))
+}
+
+/// Fails unless the run, referred to as `db_ref` in messages, verifies against `trusted_key`.
+/// To rely on what was verified, read the run through the same snapshot (see [RunRef::open_snapshot]()).
+pub fn require_valid(conn: &rusqlite::Connection, run: &IndexingRun, db_ref: &str, trusted_key: &VerifyingKey) -> ConvertibleResult<()> {
+    match verify_run(conn, run, Some(trusted_key))? {
+        Verification::Valid(_) => {
+            log::debug!("'{}': run {} verified", db_ref, run.id);
+            Ok(())
+        },
+        Verification::Unsigned => Err(app_error(format!("'{}': run {} is not signed", db_ref, run.id))),
+        Verification::Invalid(reason) => Err(app_error(format!("'{}': run {} failed verification: {}", db_ref, run.id, reason))),
+    }
+}
+
+/// Reads an Ed25519 private key in PKCS#8 PEM format, as written by `openssl genpkey -algorithm ed25519`.
+pub fn read_signing_key(path: &str) -> ConvertibleResult<SigningKey> {
+    log::debug!("'{}': reading signing key...", path);
+    SigningKey::from_pkcs8_pem(&fs::read_to_string(path)?)
+        .map_err(|e| app_error(format!("'{}': not an Ed25519 private key in PEM format: {}", path, e)))
+}
+
+/// Reads an Ed25519 public key in PEM format, as written by `openssl pkey -pubout`.
+pub fn read_verifying_key(path: &str) -> ConvertibleResult<VerifyingKey> {
+    log::debug!("'{}': reading public key...", path);
+    VerifyingKey::from_public_key_pem(&fs::read_to_string(path)?)
+        .map_err(|e| app_error(format!("'{}': not an Ed25519 public key in PEM format: {}", path, e)))
+}
+
+fn decode_key(hex_key: &str) -> Option<VerifyingKey> {
+    let bytes: [u8; 32] = hex::decode(hex_key).ok()?.try_into().ok()?;
+    VerifyingKey::from_bytes(&bytes).ok()
+}
+
+pub(crate) fn cmdline<'a>() -> clap::App<'a, 'a> {
+    clap::App::new("verify-index")
+        .about("Checks that the runs of an index database were not modified since they were signed (see idx --sign-key).")
+        .arg(clap::Arg::with_name("index")
+            .value_name("FILE[@RUN]")
+            .index(1)
+            .help("The run to verify (default: every run of FILE).")
+            .required(true))
+        .arg(clap::Arg::with_name("public-key")
+            .long("public-key")
+            .short("k")
+            .value_name("FILE")
+            .help("The Ed25519 public key (PEM) the runs must be signed with. Without it, runs are only\n\
+                   checked against the key stored with them, which anyone modifying the database could replace."))
+}
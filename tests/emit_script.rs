@@ -0,0 +1,88 @@
+//!
+//! Runs the sync script of `cmp --emit-script sh` against the destination it was made for.
+//!
+
+#![cfg(unix)]
+
+use std::fs;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+fn magnetar(args: &[&str]) -> String {
+    let output = Command::new(env!("CARGO_BIN_EXE_magnetar")).args(args).output().expect("magnetar runs");
+    assert!(output.status.success(), "magnetar {:?} failed: {}", args, String::from_utf8_lossy(&output.stderr));
+    String::from_utf8(output.stdout).expect("output is UTF-8")
+}
+
+fn index(tree: &Path, db: &Path) {
+    magnetar(&["idx", "--full", "--catalog", db.to_str().unwrap(), tree.to_str().unwrap()]);
+}
+
+fn set_mode(path: &Path, mode: u32) {
+    fs::set_permissions(path, fs::Permissions::from_mode(mode)).unwrap();
+}
+
+fn mode(path: &Path) -> u32 {
+    fs::metadata(path).unwrap().permissions().mode() & 0o7777
+}
+
+/// Asserts that each section of commands has as many commands as its header says, e.g. `# metadata changes (2)`;
+/// shell functions are defined outside of the sections.
+fn assert_section_counts(script: &str) {
+    let lines: Vec<&str> = script.lines().collect();
+    for (i, line) in lines.iter().enumerate() {
+        if !line.starts_with("# ") || line.starts_with("# DANGER") || !line.ends_with(')') {
+            continue;
+        }
+        let count: usize = match line.rsplit('(').next().unwrap().trim_end_matches(')').parse() {
+            Ok(count) => count,
+            Err(_) => continue,
+        };
+        let section: Vec<&&str> = lines[i + 1..].iter().take_while(|line| !line.is_empty()).collect();
+        assert!(section.iter().all(|line| !line.contains("() {")), "{}\n{}", line, script);
+        assert_eq!(section.len(), count, "{}\n{}", line, script);
+    }
+}
+
+/// A moved directory holding a file that changed besides being moved: the file moves with the directory,
+/// so the script must only update it at its new path.
+#[test]
+fn moved_directory_with_changed_file() {
+    let dir: PathBuf = std::env::temp_dir().join(format!("magnetar-emit-script-{}", std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    let tree = dir.join("tree");
+    fs::create_dir_all(tree.join("old")).unwrap();
+    fs::write(tree.join("old/f1"), "one").unwrap();
+    fs::write(tree.join("old/f2"), "two").unwrap();
+    let original_mode = mode(&tree.join("old/f1"));
+    let changed_mode = if original_mode == 0o600 { 0o640 } else { 0o600 };
+
+    // the destination is the tree before the move, the source the tree after it
+    index(&tree, &dir.join("b.db"));
+    fs::rename(tree.join("old"), tree.join("new")).unwrap();
+    set_mode(&tree.join("new/f1"), changed_mode);
+    index(&tree, &dir.join("a.db"));
+    set_mode(&tree.join("new/f1"), original_mode);
+    fs::rename(tree.join("new"), tree.join("old")).unwrap();
+
+    let root = tree.to_str().unwrap();
+    let script = magnetar(&[
+        "cmp", "--detect-renames", "--emit-script", "sh",
+        "-a", dir.join("a.db").to_str().unwrap(), "-b", dir.join("b.db").to_str().unwrap(),
+        "--root-a", root, "--root-b", root,
+    ]);
+    assert_eq!(script.lines().filter(|line| line.starts_with("mv ")).count(), 1, "{}", script);
+    assert_section_counts(&script);
+
+    let script_path = dir.join("sync.sh");
+    fs::write(&script_path, &script).unwrap();
+    let status = Command::new("sh").arg(&script_path).status().unwrap();
+    assert!(status.success(), "{}", script);
+
+    assert!(!tree.join("old").exists());
+    assert_eq!(fs::read_to_string(tree.join("new/f2")).unwrap(), "two");
+    assert_eq!(mode(&tree.join("new/f1")), changed_mode);
+
+    fs::remove_dir_all(&dir).unwrap();
+}
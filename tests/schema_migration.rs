@@ -0,0 +1,47 @@
+//!
+//! Compares an index made by magnetar 0.1.1 (schema version 0) with a new index of the same tree.
+//!
+
+#![cfg(unix)]
+
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+/// `fixtures/v0.db` was made by magnetar 0.1.1 with `idx` of this tree.
+const FIXTURE_ROOT: &str = "/tmp/magnetar-v0/tree";
+
+fn magnetar(args: &[&str]) -> String {
+    let output = Command::new(env!("CARGO_BIN_EXE_magnetar")).args(args).output().expect("magnetar runs");
+    assert!(output.status.success(), "magnetar {:?} failed: {}", args, String::from_utf8_lossy(&output.stderr));
+    String::from_utf8(output.stdout).expect("output is UTF-8")
+}
+
+/// The tree of the fixture: a file with a hard link to it, and a file in a directory.
+fn make_tree(tree: &Path) {
+    fs::create_dir_all(tree.join("sub")).unwrap();
+    fs::write(tree.join("a"), "one").unwrap();
+    fs::hard_link(tree.join("a"), tree.join("a_link")).unwrap();
+    fs::write(tree.join("sub/b"), "two").unwrap();
+}
+
+/// The old index didn't record hard links, so they must not show up as a change against a new index.
+#[test]
+fn version_0_compares_with_new_index() {
+    let dir: PathBuf = std::env::temp_dir().join(format!("magnetar-schema-migration-{}", std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    let tree = dir.join("tree");
+    make_tree(&tree);
+    let old_db = dir.join("old.db");
+    fs::copy(Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/v0.db"), &old_db).unwrap();
+    let new_db = dir.join("new.db");
+    magnetar(&["idx", "--full", "--catalog", new_db.to_str().unwrap(), tree.to_str().unwrap()]);
+
+    let report = magnetar(&[
+        "cmp", "-a", new_db.to_str().unwrap(), "-b", old_db.to_str().unwrap(),
+        "--root-a", tree.to_str().unwrap(), "--root-b", FIXTURE_ROOT, "--mode", "tch", "--format", "jsonl",
+    ]);
+    assert_eq!(report, "", "{}", report);
+
+    fs::remove_dir_all(&dir).unwrap();
+}